@@ -9,6 +9,29 @@ class LiveTSConnector {
   private maxReconnectAttempts = 5;
   private reconnectDelay = 1000;
   private pingInterval: number | null = null;
+  // Presented on reconnect so the server resumes this session instead of starting over
+  private reconnectToken: string | null = null;
+  // Sequence number (`q`) of the last message received, asked to replay from on reconnect
+  private lastSeq = 0;
+  // Version of the last versioned patch message applied, per component
+  private versions = new Map<string, number>();
+  // Handlers for server-pushed `q|command|args` messages, by command name
+  private static commands: Record<string, (args: any) => void> = {
+    focus: args => (document.querySelector(args.selector) as HTMLElement | null)?.focus(),
+    scrollTo: args => args.selector
+      ? document.querySelector(args.selector)?.scrollIntoView({ behavior: args.behavior })
+      : window.scrollTo({ top: args.top ?? 0, left: args.left ?? 0, behavior: args.behavior }),
+    playSound: args => void new Audio(args.url).play().catch(() => {}),
+    setLocalStorage: args => args.value === null
+      ? localStorage.removeItem(args.key)
+      : localStorage.setItem(args.key, String(args.value)),
+    navigate: args => window.location.assign(args.url),
+  };
+
+  /** Registers (or replaces) the handler for a server-pushed command */
+  static registerCommand(name: string, handler: (args: any) => void): void {
+    LiveTSConnector.commands[name] = handler;
+  }
 
   constructor() {
     this.init();
@@ -38,20 +61,71 @@ class LiveTSConnector {
     console.log('🔗 LiveTS connected');
     this.reconnectAttempts = 0;
     this.startPing();
+    // A resumed session replays what was missed; the server answers {t: 'r'} if it can't
+    if (this.reconnectToken && this.lastSeq > 0) {
+      this.ws?.send(`"r|${this.lastSeq}"`);
+    } else {
+      this.lastSeq = 0;
+      this.sendInit();
+    }
+  }
+
+  // Report each component's current HTML so the server diffs the first event against it
+  private sendInit(): void {
+    document.querySelectorAll<HTMLElement>('[data-livets-id]').forEach(element => {
+      const shortId = (element.dataset.livetsId || '').substring(0, 8);
+      if (shortId) this.ws?.send(`"c|init|${shortId}|${element.outerHTML}"`);
+    });
   }
 
   private onMessage(event: MessageEvent): void {
     try {
       const msg = JSON.parse(event.data);
-      if (msg.t === 'p') {
+      if (typeof msg.q === 'number') {
+        // A replay can repeat messages that were still queued
+        if (msg.q <= this.lastSeq) return;
+        this.lastSeq = msg.q;
+      }
+      if (msg.t === 'r') {
+        // Replay unavailable: start over from the DOM as on a fresh connection
+        this.lastSeq = 0;
+        this.sendInit();
+      } else if (msg.t === 's') {
+        // Session token: {t: 's', k: 'token'}
+        this.reconnectToken = msg.k || null;
+      } else if (msg.t === 'p') {
         // Ultra-compact format: {t: 'p', c: 'shortId', d: ['op|sel|data', ...]}
-        this.applyCompactPatches(msg.d || []);
+        // With a selector dictionary, {sel: ['#a', ...]} and 'sel' is an index into it
+        if (!this.acceptVersion(msg)) return;
+        this.applyCompactPatches(msg.d || [], msg.sel, msg.c);
+        // 'f' marks a full replace of the component's content: local state is stale
+        if (msg.f) document.dispatchEvent(new CustomEvent('livets:replaced', { detail: { c: msg.c } }));
+      } else if (msg.t === 'pm') {
+        // Multi-component format: {t: 'pm', u: [{c: 'shortId', d: [...]}, ...]}
+        for (const update of msg.u || []) {
+          this.applyCompactPatches(update.d || [], undefined, update.c);
+        }
       }
     } catch (error) {
       console.error('Failed to parse message:', error);
     }
   }
 
+  // Versioned messages ({b: base, s: seq}) apply only on top of version b; a stale DOM
+  // is reported with its HTML so the server diffs the next render against it
+  private acceptVersion(msg: any): boolean {
+    if (typeof msg.s !== 'number') return true;
+    const current = this.versions.get(msg.c);
+    if (!msg.f && current !== undefined && current !== msg.b) {
+      this.versions.delete(msg.c);
+      const element = document.querySelector<HTMLElement>(`[data-livets-id^="${msg.c}"]`);
+      if (element) this.ws?.send(`"c|init|${msg.c}|${element.outerHTML}"`);
+      return false;
+    }
+    this.versions.set(msg.c, msg.s);
+    return true;
+  }
+
   private onClose(): void {
     console.log('🔌 LiveTS disconnected');
     this.stopPing();
@@ -124,44 +198,101 @@ class LiveTSConnector {
 
   private sendEvent(componentId: string, eventName: string, payload: any): void {
     if (this.ws?.readyState === WebSocket.OPEN) {
-      // Ultra-compact event format: "e|shortId|eventName|value|checked|tagName"
+      // Ultra-compact event format: "e|shortId|eventName|value|checked|tagName[|dataKey:dataVal]"
       // Example: "e|abc123|increment||false|button" (~30 bytes vs ~200 bytes - 85% reduction)
       const shortId = componentId.substring(0, 8);
       const value = payload?.target?.value || '';
       const checked = payload?.target?.checked ? '1' : '0';
       const tagName = payload?.target?.tagName || '';
+      const data = this.boundDataField(payload?.target?.dataset || {});
 
-      const compactEvent = `"e|${shortId}|${eventName}|${value}|${checked}|${tagName}"`;
+      const compactEvent = `"e|${shortId}|${eventName}|${value}|${checked}|${tagName}${data}"`;
       this.ws.send(compactEvent);
     }
   }
 
-  private applyCompactPatches(compactPatches: string[]): void {
+  // First app-defined data-* attribute (e.g. data-id on a list item's delete button) as "|key:value"
+  private boundDataField(dataset: DOMStringMap): string {
+    const entry = Object.entries(dataset).find(([key]) => !/^(ts|livets)[A-Z]|^key$/.test(key));
+    if (!entry) return '';
+    const key = entry[0].replace(/[A-Z]/g, c => '-' + c.toLowerCase());
+    return `|${key}:${entry[1] ?? ''}`;
+  }
+
+  private applyCompactPatches(compactPatches: string[], selectors?: string[], componentId?: string): void {
     compactPatches.forEach(compact => {
       try {
         const parts = compact.split('|');
         const op = parts[0];
-        const selector = `[data-ts-sel="${parts[1]}"]`;
-        const element = document.querySelector(selector);
+        // The last field may itself contain '|' (HTML, data URIs), so take it to the end
+        const rest = (from: number) => parts.slice(from).join('|');
+        if (op === 'q') { // ClientCommand: q|command|{"arg":"value"} (no selector)
+          const handler = LiveTSConnector.commands[parts[1]];
+          if (handler) handler(JSON.parse(rest(2)));
+          else console.warn('Unknown LiveTS command:', parts[1]);
+          return;
+        }
+        if (selectors) parts[1] = selectors[Number(parts[1])] ?? parts[1];
+        const positions: Record<string, InsertPosition> = {
+          bb: 'beforebegin', ab: 'afterbegin', be: 'beforeend', ae: 'afterend',
+        };
+
+        if (op === 'x') { // RemoveElement (full CSS selector, e.g. a whole component)
+          const shadow = this.shadowTarget(parts[1], componentId);
+          (shadow !== undefined ? shadow : document.querySelector(parts[1]))?.remove();
+          return;
+        }
+        if (op === 'i') { // InsertElement: i|target|bb/ab/be/ae|html
+          const position = positions[parts[2]];
+          if (position) this.resolveTarget(parts[1], componentId)?.insertAdjacentHTML(position, rest(3));
+          return;
+        }
+        if (op === 'm') { // MoveElement: m|selector|bb/ab/be/ae|target
+          const moved = this.resolveTarget(parts[1], componentId);
+          const position = positions[parts[2]];
+          if (moved && position) this.resolveTarget(rest(3), componentId)?.insertAdjacentElement(position, moved);
+          return;
+        }
+        if (op === 'b') { // InsertBefore: b|reference|html
+          this.resolveTarget(parts[1], componentId)?.insertAdjacentHTML('beforebegin', rest(2));
+          return;
+        }
+        if (op === 'p') { // SetProperty: p|selector|property|value (e.g. a select's value)
+          const element = this.resolveTarget(parts[1], componentId);
+          if (element) (element as any)[parts[2]] = rest(3);
+          return;
+        }
+
+        const shadow = this.shadowTarget(parts[1], componentId);
+        const element = shadow !== undefined ? shadow : this.byTsSel(parts[1]);
 
         if (!element) return;
 
         switch (op) {
           case 't': // UpdateText
-            element.textContent = parts[2] || '';
+            element.textContent = rest(2);
             break;
           case 'a': // SetAttribute
-            element.setAttribute(parts[2], parts[3] || '');
+            element.setAttribute(parts[2], rest(3));
             break;
           case 'r': // RemoveAttribute
             element.removeAttribute(parts[2]);
             break;
+          case 'k': // ToggleClass: k|selector|class|1 adds, 0 removes
+            element.classList.toggle(parts[2], parts[3] === '1');
+            break;
           case 'h': // ReplaceInnerHtml
-            element.innerHTML = parts[2] || '';
+            element.innerHTML = rest(2);
             break;
           case 'e': // ReplaceElement
-            element.outerHTML = parts[2] || '';
+            element.outerHTML = rest(2);
             break;
+          case 'u': { // UpdateElement: u|selector|{"attr":"value"}|text, attributes first
+            const attrs: Record<string, string> = JSON.parse(parts[2]);
+            Object.entries(attrs).forEach(([name, value]) => element.setAttribute(name, value));
+            if (parts.length > 3) element.textContent = rest(3);
+            break;
+          }
         }
       } catch (error) {
         console.error('Failed to apply patch:', compact, error);
@@ -169,13 +300,41 @@ class LiveTSConnector {
     });
   }
 
+  /** Finds the element with a `data-ts-sel` value; `value~n` is the nth repeat of a duplicated value */
+  private byTsSel(value: string): Element | null {
+    const repeat = /^(.*)~(\d+)$/.exec(value);
+    if (!repeat) return document.querySelector(`[data-ts-sel="${value}"]`);
+    return document.querySelectorAll(`[data-ts-sel="${repeat[1]}"]`)[Number(repeat[2])] ?? null;
+  }
+
+  /** Finds a shadow-DOM target (`:host` or `[part~="name"]`) of the component; undefined for other selectors */
+  private shadowTarget(selector: string, componentId?: string): Element | null | undefined {
+    if (selector !== ':host' && !selector.startsWith('[part~=')) return undefined;
+    const host = componentId ? document.querySelector(`[data-livets-id^="${componentId}"]`) : null;
+    if (!host || selector === ':host') return host;
+    return host.shadowRoot?.querySelector(selector) ?? null;
+  }
+
+  /** Finds an insert target: a shadow-DOM or compact `data-ts-sel` value, or else a full CSS selector */
+  private resolveTarget(selector: string, componentId?: string): Element | null {
+    const shadow = this.shadowTarget(selector, componentId);
+    if (shadow !== undefined) return shadow;
+    const element = this.byTsSel(selector);
+    if (element) return element;
+    try {
+      return document.querySelector(selector);
+    } catch {
+      return null;
+    }
+  }
+
   private getWebSocketUrl(): string {
     // Allow server to inject custom WS URL
     const override = (window as any).LIVETS_WS_URL as string | undefined;
-    if (override) return override;
-
     const protocol = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-    return `${protocol}//${window.location.host}/ws`;
+    const url = override || `${protocol}//${window.location.host}/ws`;
+    if (!this.reconnectToken) return url;
+    return `${url}${url.includes('?') ? '&' : '?'}reconnect=${encodeURIComponent(this.reconnectToken)}`;
   }
 }
 
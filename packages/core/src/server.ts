@@ -17,6 +17,8 @@ try {
   const rustCore = require('@magnusbag/livets-rust-core');
   LiveTSEngine = rustCore.LiveTsEngine; // Note: lowercase 's' in generated binding
   LiveTSWebSocketBroker = rustCore.LiveTsWebSocketBroker;
+  // The core no longer installs a log subscriber on its own
+  rustCore.initLogging?.();
 } catch (error) {
   console.warn('Rust core not available, using fallback JavaScript implementation');
   LiveTSEngine = null;
@@ -228,7 +230,14 @@ export class LiveTSServer {
 
       switch (evt.type) {
         case 'Connected':
-          // Optionally handle new connections
+          // Hand the client its reconnect token, outside the replayed message sequence
+          if (evt.reconnect_token) {
+            this.rustBroker?.sendToConnectionPriority(
+              evt.connection_id,
+              JSON.stringify({ t: 's', k: evt.reconnect_token }),
+              true
+            );
+          }
           break;
         case 'Message': {
           const { connection_id, data } = evt;
@@ -240,6 +249,16 @@ export class LiveTSServer {
               return; // Skip ping processing
             }
 
+            // Client-reported HTML becomes the baseline for the component's first event
+            if (data.startsWith('"c|init|') && typeof this.rustEngine.applyInitMessage === 'function') {
+              try {
+                this.rustEngine.applyInitMessage(data);
+              } catch (e) {
+                console.warn('Invalid init message:', e);
+              }
+              return;
+            }
+
             try {
               // Step 1: Parse event and get cached HTML in Rust (no FFI)
               const eventDataJson = this.rustEngine.parseEventAndGetCache(data);
@@ -274,7 +293,7 @@ export class LiveTSServer {
           }
 
           // Fallback to original parsing logic for compatibility
-          if (data === '"p"') {
+          if (data === '"p"' || data.startsWith('"c|')) {
             return;
           } else if (data.startsWith('"e|')) {
             await this.handleCompactEvent(connection_id, data);
@@ -308,7 +327,7 @@ export class LiveTSServer {
 
   private async handleCompactEvent(connection_id: string, data: string): Promise<void> {
     try {
-      // Parse compact format: "e|shortId|eventName|value|checked|tagName"
+      // Parse compact format: "e|shortId|eventName|value|checked|tagName[|dataKey:dataVal]"
       // Remove quotes and split by pipes
       const content = data.slice(1, -1); // Remove surrounding quotes
       const parts = content.split('|');
@@ -323,6 +342,12 @@ export class LiveTSServer {
       const value = parts[3] || '';
       const checked = parts[4] === '1';
       const tagName = parts[5] || '';
+      const dataset: Record<string, string> = {};
+      const separator = parts[6]?.indexOf(':') ?? -1;
+      if (separator > 0) {
+        const key = parts[6].slice(0, separator).replace(/-([a-z])/g, (_, c) => c.toUpperCase());
+        dataset[key] = parts[6].slice(separator + 1);
+      }
 
       // Find full component ID from short ID
       const componentId = this.findComponentIdByShortId(shortId);
@@ -338,7 +363,7 @@ export class LiveTSServer {
           tagName: tagName.toLowerCase(),
           value: value || '',
           checked: checked,
-          dataset: {}
+          dataset
         }
       };
 
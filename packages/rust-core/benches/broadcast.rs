@@ -0,0 +1,43 @@
+//! Benchmarks for fanning a message out to a component's connections
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use livets_core::ConnectionManager;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::unbounded_channel;
+
+fn bench_broadcast_10k(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let manager = ConnectionManager::new();
+    let component_id = "room".to_string();
+    let mut receivers = Vec::with_capacity(10_000);
+
+    for i in 0..10_000 {
+        let conn_id = format!("conn-{}", i);
+        let (tx, rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+        manager.register_component(component_id.clone(), conn_id).unwrap();
+        receivers.push(rx);
+    }
+
+    c.bench_function("broadcast/10k_connections", |b| {
+        b.iter_custom(|iters| {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                let report = rt.block_on(manager.broadcast(&component_id, "t|#count|42"));
+                total += start.elapsed();
+                black_box(report);
+
+                // Drain outside the timed section so queues don't grow across iterations
+                for rx in receivers.iter_mut() {
+                    while rx.try_recv().is_ok() {}
+                }
+            }
+            total
+        })
+    });
+}
+
+criterion_group!(benches, bench_broadcast_10k);
+criterion_main!(benches);
@@ -0,0 +1,166 @@
+//! Benchmarks for the diff and event-parse hot paths
+//!
+//! Run with `cargo bench`. To guard against regressions, record a baseline on
+//! the main branch with `cargo bench -- --save-baseline main` and compare a
+//! change against it with `cargo bench -- --baseline main`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use livets_core::{EventParser, HtmlDiffer, JsDomPatch, JsParsedEvent};
+
+const COUNTER_OLD: &str = r#"
+    <div data-livets-id="counter-1">
+        <div class="text-center mb-6">
+            <div class="text-green-600 font-bold text-4xl">5</div>
+            <p class="text-gray-600 mt-2">Current count</p>
+        </div>
+        <button class="px-4 py-2 bg-red-500 text-white">-1</button>
+        <button class="px-4 py-2 bg-blue-500 text-white">+1</button>
+    </div>
+"#;
+
+const COUNTER_NEW: &str = r#"
+    <div data-livets-id="counter-1">
+        <div class="text-center mb-6">
+            <div class="text-red-600 font-bold text-4xl">-3</div>
+            <p class="text-gray-600 mt-2">Current count</p>
+        </div>
+        <button class="px-4 py-2 bg-red-500 text-white">-1</button>
+        <button class="px-4 py-2 bg-blue-500 text-white">+1</button>
+    </div>
+"#;
+
+/// Builds a table of `rows` x 5 cells, optionally changing one cell's text
+fn table_html(rows: usize, changed_row: Option<usize>) -> String {
+    let mut html = String::from(r#"<table data-livets-id="table-1"><tbody>"#);
+    for row in 0..rows {
+        html.push_str("<tr>");
+        for col in 0..5 {
+            let text = if Some(row) == changed_row && col == 2 {
+                "changed".to_string()
+            } else {
+                format!("r{}c{}", row, col)
+            };
+            html.push_str(&format!(
+                r#"<td data-ts-sel="t.{}.{}" class="px-2 py-1">{}</td>"#,
+                row, col, text
+            ));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</tbody></table>");
+    html
+}
+
+fn bench_counter_diff(c: &mut Criterion) {
+    let differ = HtmlDiffer::new();
+    c.bench_function("diff/counter", |b| {
+        b.iter(|| differ.diff(black_box(COUNTER_OLD), black_box(COUNTER_NEW)).unwrap())
+    });
+}
+
+/// A badge takes the single-element fast path; the same badge beside an unchanged icon
+/// goes through candidate matching and selector disambiguation
+fn bench_single_element(c: &mut Criterion) {
+    let differ = HtmlDiffer::new();
+    let badge = |count: u32| format!(r#"<span class="badge bg-red-500 text-white">{}</span>"#, count);
+    let icon = r#"<i class="icon icon-bell"></i>"#;
+    let mut group = c.benchmark_group("diff/single_element");
+    group.bench_function("fast_path", |b| {
+        let (old_html, new_html) = (badge(4), badge(5));
+        b.iter(|| differ.diff(black_box(&old_html), black_box(&new_html)).unwrap())
+    });
+    group.bench_function("general_path", |b| {
+        let (old_html, new_html) = (badge(4) + icon, badge(5) + icon);
+        b.iter(|| differ.diff(black_box(&old_html), black_box(&new_html)).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_large_table_one_cell(c: &mut Criterion) {
+    let differ = HtmlDiffer::new();
+    let old_html = table_html(200, None);
+    let new_html = table_html(200, Some(117));
+    c.bench_function("diff/table_200x5_one_cell", |b| {
+        b.iter(|| differ.diff(black_box(&old_html), black_box(&new_html)).unwrap())
+    });
+}
+
+fn bench_full_replace(c: &mut Criterion) {
+    let differ = HtmlDiffer::new();
+    let old_html = "<section>Loading...</section>".to_string();
+    let new_html = table_html(50, None).replace("<td", "<th").replace("</td>", "</th>");
+    c.bench_function("diff/full_replace_fallback", |b| {
+        b.iter(|| differ.diff(black_box(&old_html), black_box(&new_html)).unwrap())
+    });
+}
+
+fn bench_event_parse_batch(c: &mut Criterion) {
+    let parser = EventParser::new();
+    let messages: Vec<String> = (0..1000)
+        .map(|i| format!(r#""e|comp{:05}|input|value {}|0|input""#, i, i))
+        .collect();
+    c.bench_function("parse/compact_batch_1000", |b| {
+        b.iter(|| {
+            for message in &messages {
+                black_box(parser.parse_message(black_box(message)).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_event_output(c: &mut Criterion) {
+    let parser = EventParser::new();
+    let event = parser.parse_message(r#""e|comp00001|input|value 1|0|input""#).unwrap();
+    let old_html = COUNTER_OLD.to_string();
+    let mut group = c.benchmark_group("output/event");
+    group.bench_function("json_string", |b| {
+        b.iter(|| {
+            let event = black_box(&event).clone();
+            serde_json::json!({
+                "component_id": event.component_id,
+                "event_name": event.event_name,
+                "event_data": event.event_data,
+                "old_html": black_box(&old_html),
+            })
+            .to_string()
+        })
+    });
+    group.bench_function("object", |b| {
+        b.iter(|| JsParsedEvent::from_event(black_box(&event).clone(), black_box(&old_html).clone()))
+    });
+    group.finish();
+}
+
+fn bench_patch_output(c: &mut Criterion) {
+    let differ = HtmlDiffer::new();
+    let patches = differ.diff(COUNTER_OLD, COUNTER_NEW).unwrap();
+    let mut group = c.benchmark_group("output/counter");
+    group.bench_function("json_string", |b| {
+        b.iter(|| serde_json::to_string(black_box(&patches)).unwrap())
+    });
+    group.bench_function("objects", |b| {
+        b.iter(|| {
+            black_box(&patches)
+                .iter()
+                .cloned()
+                .map(JsDomPatch::from)
+                .collect::<Vec<_>>()
+        })
+    });
+    group.bench_function("compact", |b| {
+        b.iter(|| differ.patches_to_compact(black_box(patches.clone())))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_counter_diff,
+    bench_single_element,
+    bench_large_table_one_cell,
+    bench_full_replace,
+    bench_event_parse_batch,
+    bench_event_output,
+    bench_patch_output
+);
+criterion_main!(benches);
@@ -1,5 +1,8 @@
 extern crate napi_build;
 
 fn main() {
-    napi_build::setup();
-}
\ No newline at end of file
+    // Only the Node.js addon needs the napi link setup
+    if std::env::var_os("CARGO_FEATURE_NAPI").is_some() {
+        napi_build::setup();
+    }
+}
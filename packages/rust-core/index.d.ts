@@ -3,27 +3,193 @@
 
 /* auto-generated by NAPI-RS */
 
+/** Socket options for `listen` */
+export interface ListenOptions {
+  /** Pending connection queue length (defaults to 1024) */
+  backlog?: number
+  /**
+   * Set SO_REUSEADDR so a restarted broker can rebind while the old socket is in TIME_WAIT
+   * (defaults to true, except on Windows where it allows port hijacking)
+   */
+  reuseAddress?: boolean
+  /**
+   * Only accept upgrades to these request paths, e.g. `["/livets-ws"]`; others get a 404
+   * (defaults to any path, so other WebSocket endpoints can't share the port)
+   */
+  paths?: Array<string>
+}
+/** One component's compact patches for `build_multi_component_message` */
+export interface ComponentPatches {
+  componentId: string
+  patches: Array<string>
+}
+/** Kind of an inbound message, as told by `classify_message` */
+export const enum MessageKind {
+  /** `"p"` or `{"type":"ping",...}` */
+  Ping = 0,
+  /** `"e|id|name|value|checked|tag"` */
+  CompactEvent = 1,
+  /** `{"type":"event",...}` */
+  JsonEvent = 2,
+  /** Any other JSON object */
+  Control = 3,
+  Unknown = 4,
+  /** `"c|init|componentId|html"`, see `apply_init_message` */
+  Init = 5
+}
+/**
+ * napi representation of a parsed event plus the component's cached HTML
+ * Mirrors the JSON from `parse_event_and_get_cache`, with the target fields flattened
+ */
+export interface JsParsedEvent {
+  componentId: string
+  eventName: string
+  eventType: string
+  tagName: string
+  value?: string
+  checked?: boolean
+  attributes: Record<string, string>
+  oldHtml: string
+}
+/** One message of a `broadcast_many` batch */
+export interface ChannelMessage {
+  channel: string
+  message: string
+}
+/** napi representation of a `DomPatch`, tagged by `type` like the JSON form */
+export interface JsDomPatch {
+  type: string
+  selector?: string
+  content?: string
+  text?: string
+  attr?: string
+  value?: string
+  html?: string
+  parent?: string
+  position?: string
+  attrs?: Record<string, string>
+  property?: string
+  className?: string
+  add?: boolean
+  command?: string
+  /** Command arguments as a JSON string */
+  args?: string
+}
+
+/**
+ * Log to stdout at INFO and above through the core's global subscriber
+ * The core never installs one itself, so embedders with their own subscriber keep control.
+ * Does nothing if a global subscriber is already set, including one `set_log_sink` installed
+ */
+export declare function initLogging(): void
 export type LiveTSEngine = LiveTsEngine
 /** The main LiveTS engine that coordinates all core functionality */
 export declare class LiveTsEngine {
   /** Creates a new LiveTS engine instance */
   constructor()
+  /**
+   * Makes `render_component` return one-letter keyed JSON, e.g. `{"o":"t","s":"#count","x":"6"}`
+   * (see `ShortDomPatch`), for clients that want JSON patches at close to compact size
+   */
+  setShortJsonPatches(enabled: boolean): void
   /** Renders a component and returns the diff patches */
   renderComponent(componentId: string, oldHtml: string, newHtml: string): string
+  /**
+   * Renders a component and returns the diff patches as JS objects
+   * Avoids the JSON.parse round trip of `render_component` on the JS side
+   */
+  renderComponentObjects(componentId: string, oldHtml: string, newHtml: string): Array<JsDomPatch>
   /** Renders a component and returns compact string patches for ultra-efficient WebSocket transmission */
   renderComponentCompact(componentId: string, oldHtml: string, newHtml: string): string
   /**
    * Renders a component and returns complete ultra-compact WebSocket message
    * This eliminates ALL JSON operations in TypeScript layer
+   *
+   * The message carries the component's version before (`b`) and after (`s`) it. A client
+   * applies it only if `b` equals the version it last applied, otherwise it reports its
+   * current HTML (`c|init`) and waits for the next message. Full replaces (`f`) apply at
+   * any version. The version only advances when the diff succeeds
    */
   renderComponentMessage(componentId: string, oldHtml: string, newHtml: string): string
+  /**
+   * Diff two JSON documents, e.g. a component's synced state, into a JSON Patch (RFC 6902)
+   * array: `[{"op":"add"|"remove"|"replace","path":"/todos/1","value":...}]`
+   */
+  diffJson(oldJson: string, newJson: string): string
+  /**
+   * Computes the message `render_component_message` would send, without touching any engine state
+   * With opaque ids on, a component that has no token yet is shown by its id prefix
+   */
+  previewRender(componentId: string, oldHtml: string, newHtml: string): string
+  /**
+   * Element-level patches annotated with how each element was matched, for a devtools overlay
+   * JSON: `[{"patch":{"type":...},"info":{"strategy":"id"|"score"|...,"score","tag","old_index","new_index"}}]`
+   */
+  diffDebug(oldHtml: string, newHtml: string): string
+  /** Sets how long `queue_render` holds a component's renders before they become due (0 disables) */
+  setRenderCoalesceWindow(windowMs: number): void
+  /**
+   * Renders a component like `render_component_message`, coalescing rapid renders
+   *
+   * With no window set, returns the message straight away. Otherwise the render is held
+   * and `null` is returned; further renders of the component within the window only move
+   * its latest HTML, and `flush_renders` later returns one diff from the HTML the client had
+   * before the first render to the final one, skipping the intermediate states
+   */
+  queueRender(componentId: string, oldHtml: string, newHtml: string): string | null
+  /**
+   * Builds the messages for queued renders whose coalesce window has elapsed
+   * Components whose renders cancelled out (final HTML equals the baseline) produce no message
+   */
+  flushRenders(): Array<string>
+  /**
+   * Makes `render_component_message` list each selector once in a `sel` array
+   * and reference it by index from the patches
+   */
+  setSelectorDictionary(enabled: boolean): void
+  /**
+   * Use opaque tokens (`c1`, `c2`, ...) instead of component id prefixes on the wire
+   *
+   * Outbound messages carry the token, and inbound events naming a token are translated
+   * back to the full component id. Render the token (see `component_token`) as the
+   * component's `data-livets-id` so the client echoes it. Disabling forgets all tokens
+   */
+  setOpaqueComponentIds(enabled: boolean): void
+  /** Get the id a component goes by on the wire, assigning a token if opaque ids are on */
+  componentToken(componentId: string): string
+  /** Get the component id an opaque token stands for */
+  resolveComponentToken(token: string): string | null
+  /**
+   * Packs several components' compact patches into one WebSocket message
+   *
+   * Wire format: `{"t":"pm","u":[{"c":"shortId","d":["op|sel|data",...]},...]}`.
+   * Each `u` entry carries the same `c`/`d` fields as a single-component `"p"` message;
+   * clients apply the entries in order within one pass, and an empty `d` is a no-op.
+   */
+  buildMultiComponentMessage(renders: Array<ComponentPatches>): string
   /**
    * Parse WebSocket event message directly in Rust (Phase 1 optimization)
    * This eliminates Node.js parsing overhead and reduces FFI crossings
    */
   parseEventMessage(rawMessage: string): string
+  /**
+   * Parse and validate an event without throwing
+   *
+   * Always returns JSON: `{"ok":true,"event":{...}}` or
+   * `{"ok":false,"kind":"ping"|"invalid"|"validation","code":"E_...","message":"..."}`
+   */
+  tryParseEvent(rawMessage: string): string
+  /**
+   * Parse and validate a batch of events, e.g. a client's offline queue flushed on reconnect
+   *
+   * Returns a JSON array with one `try_parse_event` result per message, in order, so one
+   * bad message doesn't fail the rest. Dispatching the `ok` events is left to the caller
+   */
+  processEvents(rawMessages: Array<string>): string
   /** Fast check if message is a ping (avoids parsing overhead) */
   isPingMessage(rawMessage: string): boolean
+  /** Classifies an inbound message from its prefix alone, without parsing it */
+  classifyMessage(rawMessage: string): MessageKind
   /** Set the TypeScript event processor callback */
   setEventProcessor(callback: (...args: any[]) => any): NapiResult
   /**
@@ -31,34 +197,226 @@ export declare class LiveTsEngine {
    * Returns parsed event data with cached HTML for TypeScript processing
    */
   parseEventAndGetCache(rawMessage: string): string
+  /**
+   * Same as `parse_event_and_get_cache`, but returns the fields as an object so JS
+   * doesn't have to `JSON.parse` a string on the hot path
+   */
+  parseEventObject(rawMessage: string): JsParsedEvent
+  /**
+   * Caches the HTML a client reports on connect with `"c|init|componentId|html"`
+   *
+   * The client already shows the server-rendered HTML, so using it as the baseline lets
+   * the first event produce granular patches instead of a full replace. The id is the
+   * one the client's events carry. Returns the component id the HTML was cached under
+   */
+  applyInitMessage(rawMessage: string): string
   /**
    * Process response and generate message (Phase 2 step 2)
    * Takes new HTML from TypeScript and generates optimized diff response
    */
   processResponseAndGenerateMessage(componentId: string, oldHtml: string, newHtml: string): string
+  /**
+   * Renders a component against its cached HTML, skipping the diff when nothing changed
+   *
+   * Compares a hash of `new_html` with the one stored alongside the cached HTML and
+   * returns `null` when they match. Otherwise returns the `render_component_message`
+   * message and caches `new_html`. The component must have been cached first
+   */
+  renderIfChanged(componentId: string, newHtml: string): string | null
+  /** Get render counters as JSON `{diffs_run, diffs_skipped}` */
+  getRenderStats(): string
   /** Cache component HTML (useful for initial renders) */
   cacheComponentHtml(componentId: string, html: string): void
   /** Get cached component HTML */
   getCachedHtml(componentId: string): string | null
   /** Remove component from cache */
   removeComponentCache(componentId: string): boolean
+  /**
+   * Builds a message telling the client to remove a destroyed component's DOM
+   * Also drops the component's cached state so a later render starts from scratch
+   */
+  buildRemoveComponentMessage(componentId: string): string
+  /**
+   * Builds a message asking the client to run `command` with `argsJson`, e.g. `focus` with
+   * `{"selector":"#q"}`; the client dispatches it to the handler registered under that name
+   */
+  buildCommandMessage(componentId: string, command: string, argsJson: string): string
+  /** Clear all cached component state, including versions and renders held by `queue_render` */
+  clearCache(): void
+  /** Get the number of cached components */
+  cacheSize(): number
   /** Get cache statistics */
   getCacheStats(): string
+  /**
+   * Get how often each cached component's HTML was read, as a JSON array of
+   * `{component_id, access_count}`, most read first
+   */
+  componentAccessStats(): string
 }
 export type LiveTSWebSocketBroker = LiveTsWebSocketBroker
 /** Tokio-based WebSocket broker running inside the Rust core */
 export declare class LiveTsWebSocketBroker {
-  constructor()
+  /** `worker_threads` caps the runtime's worker threads (defaults to one per CPU core) */
+  constructor(workerThreads?: number | undefined | null)
   /** Register a JS callback that receives broker events as JSON strings */
   setEventHandler(callback: (...args: any[]) => any): NapiResult
-  /** Start listening on a TCP port for WebSocket upgrades (ws://host:port/livets-ws) */
-  listen(host: string, port: number): void
+  /**
+   * Send the core's logs to a JS callback instead of stdout, as JSON records
+   * `{level, target, message, fields}` at `level` (`"error"` to `"trace"`, default `"info"`) and above
+   *
+   * Applies process-wide and replaces the stdout logging `initLogging` sets up; calling again
+   * replaces the callback and level. Fails if a global subscriber from outside the core is set
+   */
+  setLogSink(callback: (...args: any[]) => any, level?: string | undefined | null): void
+  /**
+   * Bound the queue of events waiting for the JS handler (0, the default, is unbounded)
+   *
+   * When the queue is full, an event is dropped and counted in `health().dropped_events`,
+   * or with `block_when_full` the connection waits up to `block_timeout_ms` (default 1000)
+   * for room, slowing that client down, before dropping it. Must be called before `set_event_handler`
+   */
+  setEventQueue(maxQueueSize: number, blockWhenFull: boolean, blockTimeoutMs?: number | undefined | null): void
+  /**
+   * Configure the subprotocols offered during the handshake, in preference order
+   * When `required` is set, clients offering none of them are rejected. Must be called before `listen`
+   */
+  setSubprotocols(protocols: Array<string>, required: boolean): void
+  /**
+   * Require an application-level `"p"` ping from each client at least every `timeout_ms`
+   *
+   * Clients are answered with a `"P"` pong, and one that stays silent for longer is closed
+   * (code 4000, cause `timeout`). Protocol Ping frames don't count, since proxies may strip
+   * them. The bundled client pings every 30s, so leave room above that. 0 disables (the
+   * default). Must be called before `listen`
+   */
+  setPingTimeout(timeoutMs: number): void
+  /**
+   * Keep a closed connection's components and replay buffer for `ttl_ms` (default 30000),
+   * resumable by a client that reconnects with the `reconnect_token` from its `Connected`
+   * event. 0 disables reconnect tokens
+   */
+  setReconnectTtl(ttlMs: number): void
+  /**
+   * Reject new WebSocket upgrades with HTTP 503 while `max` connections are open
+   * (0, the default, is unlimited)
+   */
+  setMaxConnections(max: number): void
+  /**
+   * Tag each new connection with the flags whose rollout bucket it falls in, e.g.
+   * `{combined_patches: 10}` enables that flag for 10% of connections. Buckets come from a
+   * hash of the connection id, so they're stable. An empty map stops tagging new connections
+   */
+  setFlagRollouts(rollouts: Record<string, number>): void
+  /**
+   * Register a JS callback computing each new connection's feature flags from its id,
+   * as an array of strings; its flags replace any from `set_flag_rollouts`
+   *
+   * The connection keeps its rollout flags if the callback throws or takes longer than
+   * a second. Passing no callback removes it. Applies to connections accepted afterwards
+   */
+  setFlagCallback(callback?: (...args: any[]) => any | undefined | null): void
+  /** Get a connection's feature flags, sorted */
+  getConnectionFlags(connectionId: string): Array<string> | null
+  /** Replace a connection's feature flags */
+  setConnectionFlags(connectionId: string, flags: Array<string>): void
+  /**
+   * Build a component's patch message in the format a connection's flags select
+   *
+   * `combined_patches` folds each element's changes into one `u` patch and
+   * `selector_dictionary` sends selectors once in `sel`; without flags this is the
+   * message `update_component` broadcasts
+   */
+  renderForConnection(connectionId: string, componentId: string, oldHtml: string, newHtml: string): string
+  /** Get the subprotocol negotiated for a connection */
+  getConnectionSubprotocol(connectionId: string): string | null
+  /**
+   * Start listening on a TCP port for WebSocket upgrades (ws://host:port/livets-ws)
+   * Pass port 0 to bind an ephemeral port, then read it back with `port()`.
+   * Set `options.paths` to restrict which request paths are upgraded
+   */
+  listen(host: string, port: number, options?: ListenOptions | undefined | null): void
+  /**
+   * Liveness/readiness probe as JSON `{listening, connections, accept_errors, bytes_sent, uptime_ms, dropped_events}`
+   * `listening` turns false if the listener task has died, so orchestrators can restart the broker
+   */
+  health(): string
+  /** Get the port the broker is listening on, if any */
+  port(): number | null
   /** Stop the listener and close all connections */
   stop(): void
+  /**
+   * Get the messages sent to a connection after sequence number `seq`, for resuming after a brief disconnect
+   * Returns null when they are no longer buffered and the client needs a full resync. The
+   * broker answers clients' own `"r|seq"` replay requests with this itself
+   */
+  replaySince(connectionId: string, seq: number): Array<string> | null
+  /** Get the payload bytes written to a connection so far */
+  getConnectionBytesSent(connectionId: string): number | null
+  /**
+   * Get a connection's activity as JSON: `connected_at`, `last_ping` and `last_inbound`
+   * (milliseconds ago, `last_inbound` null until the client sends something),
+   * `component_count` and `bytes_sent`
+   */
+  getConnectionInfo(connectionId: string): string | null
+  /**
+   * Close a connection with a WebSocket close code (1000 or 3000-4999) and reason
+   * The client receives the Close frame and a `Closed` event with cause `server_request` follows
+   */
+  closeConnection(connectionId: string, code: number, reason: string): void
+  /** List every connection's activity as a JSON array (see `get_connection_info`) */
+  listConnections(): string
   /** Send a JSON-stringified message to a specific connection */
   sendToConnection(connectionId: string, message: string): void
+  /**
+   * Send a message to a connection, ahead of already queued messages when `high` is set
+   * Use for control messages (forced reconnects, errors) that must not wait behind patch updates
+   */
+  sendToConnectionPriority(connectionId: string, message: string, high: boolean): void
+  /**
+   * Send the same message to several connections in one call
+   * Returns a JSON report `{sent: [ids], failed: [{connection_id, error}]}`
+   */
+  sendToConnections(connectionIds: Array<string>, message: string): string
   /** Register a component to a connection (for targeted broadcasts) */
   registerComponent(componentId: string, connectionId: string): void
+  /** Cache the HTML a component's clients currently show, e.g. after its initial render */
+  cacheComponentHtml(componentId: string, html: string): void
+  /**
+   * Diff a component against its cached HTML and send the patch to all its connections
+   *
+   * Caching, diffing, message building and the broadcast all happen in Rust, so no
+   * message crosses FFI. The message is addressed by the component's id prefix. Nothing
+   * is sent when the HTML is unchanged. The component must have been cached first.
+   * Connections with feature flags get the format `render_for_connection` would build
+   */
+  updateComponent(componentId: string, newHtml: string): void
+  /**
+   * Snapshot component registrations as JSON (`{component_id: [connection_id]}`)
+   * Lets the JS layer re-sync after a hot reload without dropping connections
+   */
+  exportRegistrations(): string
+  /**
+   * Get the `top_n` components with the most connections as a JSON array of
+   * `{component_id, connection_count}`, busiest first
+   */
+  getComponentStats(topN: number): string
+  /**
+   * Restore component registrations from `export_registrations` output
+   * Returns the number of registrations restored; closed connections are skipped
+   */
+  importRegistrations(json: string): number
   /** Unregister a component from a connection */
   unregisterComponent(componentId: string, connectionId: string): void
+  /**
+   * Forward a pub/sub channel's messages to a connection on behalf of one of its components
+   * The subscription ends when the component's last connection closes
+   */
+  subscribeChannel(connectionId: string, componentId: string, channel: string): void
+  /** Stop forwarding a channel to a component */
+  unsubscribeChannel(componentId: string, channel: string): void
+  /**
+   * Publish to many channels in one call, e.g. one simulation tick's output
+   * Channels nobody subscribed to are skipped. Returns the number of receivers reached
+   */
+  broadcastMany(messages: Array<ChannelMessage>): number
 }
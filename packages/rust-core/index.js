@@ -310,7 +310,10 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { LiveTsEngine, LiveTsWebSocketBroker } = nativeBinding
+const { initLogging, LiveTsEngine, MessageKind, LiveTsWebSocketBroker } = nativeBinding
+
+module.exports.initLogging = initLogging
 
 module.exports.LiveTsEngine = LiveTsEngine
+module.exports.MessageKind = MessageKind
 module.exports.LiveTsWebSocketBroker = LiveTsWebSocketBroker
@@ -0,0 +1,3016 @@
+//! napi bindings: the engine and WebSocket broker exposed to Node.js
+
+use crate::*;
+use napi_derive::napi;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode};
+use futures_util::{StreamExt, SinkExt};
+use uuid::Uuid;
+use napi::{Env, JsFunction, Result as NapiResult, threadsafe_function::{ThreadsafeFunction, ThreadsafeFunctionCallMode}};
+use serde::{Serialize, Deserialize};
+
+/// The main LiveTS engine that coordinates all core functionality
+#[napi]
+pub struct LiveTSEngine {
+    html_differ: HtmlDiffer,
+    event_parser: EventParser,
+    component_cache: ComponentCache,
+    event_processor_callback: Option<ThreadsafeFunction<String>>,
+    selector_dictionary: bool,
+    /// `render_component` serializes `ShortDomPatch`es instead of `DomPatch`es
+    short_json_patches: bool,
+    /// When set, the wire carries opaque tokens instead of component id prefixes
+    component_tokens: Option<ComponentTokenMap>,
+    /// Window for `queue_render` in milliseconds (0 sends every render right away)
+    render_coalesce_window_ms: u32,
+    /// Renders held back by `queue_render`, keyed by component id
+    pending_renders: DashMap<String, PendingRender>,
+    /// Each component's version: how many render messages it has produced
+    component_versions: DashMap<String, u64>,
+    /// Diffs computed for outbound messages
+    diffs_run: AtomicU64,
+    /// Renders `render_if_changed` answered from the content hash without diffing
+    diffs_skipped: AtomicU64,
+}
+
+/// A component's buffered renders: the HTML the client last saw and the latest render
+struct PendingRender {
+    baseline_html: String,
+    latest_html: String,
+    queued_at: Instant,
+}
+
+#[napi]
+impl LiveTSEngine {
+    /// Creates a new LiveTS engine instance
+    #[napi(constructor)]
+    pub fn new() -> Self {
+        Self {
+            html_differ: HtmlDiffer::new(),
+            event_parser: EventParser::new(),
+            component_cache: ComponentCache::new(1000),
+            event_processor_callback: None,
+            selector_dictionary: false,
+            short_json_patches: false,
+            component_tokens: None,
+            render_coalesce_window_ms: 0,
+            pending_renders: DashMap::new(),
+            component_versions: DashMap::new(),
+            diffs_run: AtomicU64::new(0),
+            diffs_skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Use opaque tokens (`c1`, `c2`, ...) instead of component id prefixes on the wire
+    ///
+    /// Outbound messages carry the token, and inbound events naming a token are translated
+    /// back to the full component id. Render the token (see `component_token`) as the
+    /// component's `data-livets-id` so the client echoes it. Disabling forgets all tokens
+    #[napi]
+    pub fn set_opaque_component_ids(&mut self, enabled: bool) {
+        if enabled != self.component_tokens.is_some() {
+            self.component_tokens = enabled.then(ComponentTokenMap::new);
+        }
+    }
+
+    /// Get the id a component goes by on the wire, assigning a token if opaque ids are on
+    #[napi]
+    pub fn component_token(&self, component_id: String) -> String {
+        self.wire_id(&component_id)
+    }
+
+    /// Get the component id an opaque token stands for
+    #[napi]
+    pub fn resolve_component_token(&self, token: String) -> Option<String> {
+        self.component_tokens.as_ref()?.resolve(&token)
+    }
+
+    fn wire_id(&self, component_id: &str) -> String {
+        match &self.component_tokens {
+            Some(tokens) => tokens.assign(component_id),
+            None => short_id(component_id).to_string(),
+        }
+    }
+
+    /// Parses an inbound message, translating an opaque token back to its component id
+    fn parse_inbound(&self, raw_message: &str) -> Result<ParsedEvent> {
+        let mut parsed_event = self.event_parser.parse_message(raw_message)?;
+        if let Some(component_id) = self.resolve_component_token(parsed_event.component_id.clone()) {
+            parsed_event.component_id = component_id;
+        }
+        Ok(parsed_event)
+    }
+
+    /// Makes `render_component_message` list each selector once in a `sel` array
+    /// and reference it by index from the patches
+    #[napi]
+    pub fn set_selector_dictionary(&mut self, enabled: bool) {
+        self.selector_dictionary = enabled;
+    }
+
+    /// Makes `render_component` return one-letter keyed JSON, e.g. `{"o":"t","s":"#count","x":"6"}`
+    /// (see `ShortDomPatch`), for clients that want JSON patches at close to compact size
+    #[napi]
+    pub fn set_short_json_patches(&mut self, enabled: bool) {
+        self.short_json_patches = enabled;
+    }
+
+    /// Renders a component and returns the diff patches
+    #[napi]
+    pub fn render_component(
+        &self,
+        _component_id: String,
+        old_html: String,
+        new_html: String,
+    ) -> napi::Result<String> {
+        let patches = self
+            .html_differ
+            .diff(&old_html, &new_html)
+            .map_err(napi::Error::from)?;
+
+        let serialized = if self.short_json_patches {
+            let short: Vec<ShortDomPatch> = patches.into_iter().map(ShortDomPatch::from).collect();
+            serde_json::to_string(&short)
+        } else {
+            serde_json::to_string(&patches)
+        }
+        .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        Ok(serialized)
+    }
+
+    /// Renders a component and returns the diff patches as JS objects
+    /// Avoids the JSON.parse round trip of `render_component` on the JS side
+    #[napi]
+    pub fn render_component_objects(
+        &self,
+        _component_id: String,
+        old_html: String,
+        new_html: String,
+    ) -> napi::Result<Vec<JsDomPatch>> {
+        let patches = self
+            .html_differ
+            .diff(&old_html, &new_html)
+            .map_err(napi::Error::from)?;
+
+        Ok(patches.into_iter().map(JsDomPatch::from).collect())
+    }
+
+    /// Renders a component and returns compact string patches for ultra-efficient WebSocket transmission
+    #[napi]
+    pub fn render_component_compact(
+        &self,
+        _component_id: String,
+        old_html: String,
+        new_html: String,
+    ) -> napi::Result<String> {
+        let patches = self
+            .html_differ
+            .diff(&old_html, &new_html)
+            .map_err(napi::Error::from)?;
+
+        // Convert patches to compact string format
+        let compact_patches = self
+            .html_differ
+            .patches_to_compact(patches);
+
+        let serialized = serde_json::to_string(&compact_patches)
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+
+        Ok(serialized)
+    }
+
+    /// Renders a component and returns complete ultra-compact WebSocket message
+    /// This eliminates ALL JSON operations in TypeScript layer
+    ///
+    /// The message carries the component's version before (`b`) and after (`s`) it. A client
+    /// applies it only if `b` equals the version it last applied, otherwise it reports its
+    /// current HTML (`c|init`) and waits for the next message. Full replaces (`f`) apply at
+    /// any version. The version only advances when the diff succeeds
+    #[napi]
+    pub fn render_component_message(
+        &self,
+        component_id: String,
+        old_html: String,
+        new_html: String,
+    ) -> napi::Result<String> {
+        let wire_id = self.wire_id(&component_id);
+        // Held across the diff so concurrent renders of a component get consecutive versions
+        let mut version = self.component_versions.entry(component_id).or_insert(0);
+        self.diffs_run.fetch_add(1, Ordering::Relaxed);
+        let message = self.component_message(wire_id, &old_html, &new_html, Some(*version))?;
+        *version += 1;
+        Ok(message)
+    }
+
+    /// Diff two JSON documents, e.g. a component's synced state, into a JSON Patch (RFC 6902)
+    /// array: `[{"op":"add"|"remove"|"replace","path":"/todos/1","value":...}]`
+    #[napi]
+    pub fn diff_json(&self, old_json: String, new_json: String) -> napi::Result<String> {
+        let parse = |label: &str, json: &str| {
+            serde_json::from_str::<serde_json::Value>(json)
+                .map_err(|e| LiveTSError::InvalidInput(format!("{} JSON: {}", label, e)))
+        };
+        let old = parse("old", &old_json).map_err(napi::Error::from)?;
+        let new = parse("new", &new_json).map_err(napi::Error::from)?;
+        serde_json::to_string(&JsonDiffer::new().diff(&old, &new))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Computes the message `render_component_message` would send, without touching any engine state
+    /// With opaque ids on, a component that has no token yet is shown by its id prefix
+    #[napi]
+    pub fn preview_render(&self, component_id: String, old_html: String, new_html: String) -> napi::Result<String> {
+        let wire_id = self
+            .component_tokens
+            .as_ref()
+            .and_then(|tokens| tokens.token(&component_id))
+            .unwrap_or_else(|| short_id(&component_id).to_string());
+        let version = self.component_versions.get(&component_id).map_or(0, |version| *version);
+        self.component_message(wire_id, &old_html, &new_html, Some(version))
+    }
+
+    /// Element-level patches annotated with how each element was matched, for a devtools overlay
+    /// JSON: `[{"patch":{"type":...},"info":{"strategy":"id"|"score"|...,"score","tag","old_index","new_index"}}]`
+    #[napi]
+    pub fn diff_debug(&self, old_html: String, new_html: String) -> napi::Result<String> {
+        let annotated: Vec<serde_json::Value> = self
+            .html_differ
+            .diff_debug(&old_html, &new_html)
+            .into_iter()
+            .map(|(patch, info)| serde_json::json!({ "patch": patch, "info": info }))
+            .collect();
+        serde_json::to_string(&annotated).map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Sets how long `queue_render` holds a component's renders before they become due (0 disables)
+    #[napi]
+    pub fn set_render_coalesce_window(&mut self, window_ms: u32) {
+        self.render_coalesce_window_ms = window_ms;
+    }
+
+    /// Renders a component like `render_component_message`, coalescing rapid renders
+    ///
+    /// With no window set, returns the message straight away. Otherwise the render is held
+    /// and `null` is returned; further renders of the component within the window only move
+    /// its latest HTML, and `flush_renders` later returns one diff from the HTML the client had
+    /// before the first render to the final one, skipping the intermediate states
+    #[napi]
+    pub fn queue_render(&self, component_id: String, old_html: String, new_html: String) -> napi::Result<Option<String>> {
+        if self.render_coalesce_window_ms == 0 {
+            return self.render_component_message(component_id, old_html, new_html).map(Some);
+        }
+
+        self.pending_renders
+            .entry(component_id)
+            .and_modify(|pending| pending.latest_html = new_html.clone())
+            .or_insert_with(|| PendingRender {
+                baseline_html: old_html,
+                latest_html: new_html,
+                queued_at: Instant::now(),
+            });
+        Ok(None)
+    }
+
+    /// Builds the messages for queued renders whose coalesce window has elapsed
+    /// Components whose renders cancelled out (final HTML equals the baseline) produce no message
+    #[napi]
+    pub fn flush_renders(&self) -> napi::Result<Vec<String>> {
+        let window = std::time::Duration::from_millis(self.render_coalesce_window_ms as u64);
+        let due: Vec<String> = self
+            .pending_renders
+            .iter()
+            .filter(|entry| entry.queued_at.elapsed() >= window)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut messages = Vec::new();
+        for component_id in due {
+            let Some((_, pending)) = self.pending_renders.remove(&component_id) else {
+                continue;
+            };
+            if pending.baseline_html != pending.latest_html {
+                messages.push(self.render_component_message(component_id, pending.baseline_html, pending.latest_html)?);
+            }
+        }
+        Ok(messages)
+    }
+
+    fn component_message(
+        &self,
+        wire_id: String,
+        old_html: &str,
+        new_html: &str,
+        base_version: Option<u64>,
+    ) -> napi::Result<String> {
+        patch_message(&self.html_differ, wire_id, old_html, new_html, self.selector_dictionary, base_version)
+    }
+
+    /// Packs several components' compact patches into one WebSocket message
+    ///
+    /// Wire format: `{"t":"pm","u":[{"c":"shortId","d":["op|sel|data",...]},...]}`.
+    /// Each `u` entry carries the same `c`/`d` fields as a single-component `"p"` message;
+    /// clients apply the entries in order within one pass, and an empty `d` is a no-op.
+    #[napi]
+    pub fn build_multi_component_message(&self, renders: Vec<ComponentPatches>) -> napi::Result<String> {
+        let updates: Vec<serde_json::Value> = renders
+            .into_iter()
+            .map(|render| {
+                serde_json::json!({ "c": self.wire_id(&render.component_id), "d": render.patches })
+            })
+            .collect();
+
+        serde_json::to_string(&serde_json::json!({ "t": "pm", "u": updates }))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Parse WebSocket event message directly in Rust (Phase 1 optimization)
+    /// This eliminates Node.js parsing overhead and reduces FFI crossings
+    #[napi]
+    pub fn parse_event_message(&self, raw_message: String) -> napi::Result<String> {
+        match self.parse_inbound(&raw_message) {
+            Ok(parsed_event) => {
+                // Validate the parsed event
+                if let Err(e) = self.event_parser.validate_event(&parsed_event) {
+                    return Err(napi::Error::from_reason(format!("[{}] Event validation failed: {}", e.code(), e)));
+                }
+
+                // Serialize the parsed event for Node.js callback
+                match serde_json::to_string(&parsed_event) {
+                    Ok(json) => Ok(json),
+                    Err(e) => Err(napi::Error::from_reason(format!("Serialization failed: {}", e))),
+                }
+            }
+            Err(e) => Err(napi::Error::from_reason(format!("[{}] Event parsing failed: {}", e.code(), e))),
+        }
+    }
+
+    /// Parse and validate an event without throwing
+    ///
+    /// Always returns JSON: `{"ok":true,"event":{...}}` or
+    /// `{"ok":false,"kind":"ping"|"invalid"|"validation","code":"E_...","message":"..."}`
+    #[napi]
+    pub fn try_parse_event(&self, raw_message: String) -> String {
+        self.parse_outcome(&raw_message).to_string()
+    }
+
+    /// Parse and validate a batch of events, e.g. a client's offline queue flushed on reconnect
+    ///
+    /// Returns a JSON array with one `try_parse_event` result per message, in order, so one
+    /// bad message doesn't fail the rest. Dispatching the `ok` events is left to the caller
+    #[napi]
+    pub fn process_events(&self, raw_messages: Vec<String>) -> String {
+        let outcomes: Vec<serde_json::Value> = raw_messages
+            .iter()
+            .map(|raw_message| self.parse_outcome(raw_message))
+            .collect();
+        serde_json::Value::Array(outcomes).to_string()
+    }
+
+    fn parse_outcome(&self, raw_message: &str) -> serde_json::Value {
+        let failure = |kind: &str, error: LiveTSError| {
+            serde_json::json!({ "ok": false, "kind": kind, "code": error.code(), "message": error.to_string() })
+        };
+
+        if self.is_ping_message(raw_message.to_string()) {
+            return failure("ping", LiveTSError::InvalidInput("Ping message".to_string()));
+        }
+
+        let parsed_event = match self.parse_inbound(raw_message) {
+            Ok(parsed_event) => parsed_event,
+            Err(e) => return failure("invalid", e),
+        };
+
+        if let Err(e) = self.event_parser.validate_event(&parsed_event) {
+            return failure("validation", e);
+        }
+
+        serde_json::json!({ "ok": true, "event": parsed_event })
+    }
+
+    /// Fast check if message is a ping (avoids parsing overhead)
+    #[napi]
+    pub fn is_ping_message(&self, raw_message: String) -> bool {
+        raw_message == "\"p\""
+    }
+
+    /// Classifies an inbound message from its prefix alone, without parsing it
+    #[napi]
+    pub fn classify_message(&self, raw_message: String) -> MessageKind {
+        let raw = raw_message.as_str();
+        if raw == "\"p\"" || raw.starts_with("{\"type\":\"ping\"") {
+            MessageKind::Ping
+        } else if raw.starts_with("\"e|") {
+            MessageKind::CompactEvent
+        } else if raw.starts_with("{\"type\":\"event\"") {
+            MessageKind::JsonEvent
+        } else if raw.starts_with("\"c|init|") {
+            MessageKind::Init
+        } else if raw.starts_with('{') {
+            MessageKind::Control
+        } else {
+            MessageKind::Unknown
+        }
+    }
+
+    /// Set the TypeScript event processor callback
+    #[napi]
+    pub fn set_event_processor(&mut self, _env: Env, callback: JsFunction) -> NapiResult<()> {
+        let tsfn: ThreadsafeFunction<String> = callback.create_threadsafe_function(0, |ctx: napi::threadsafe_function::ThreadSafeCallContext<String>| {
+            match ctx.env.create_string(&ctx.value) {
+                Ok(js_string) => Ok(vec![js_string]),
+                Err(e) => Err(e),
+            }
+        })?;
+        
+        self.event_processor_callback = Some(tsfn);
+        Ok(())
+    }
+
+    /// Parse event and prepare for processing (Phase 2 step 1)
+    /// Returns parsed event data with cached HTML for TypeScript processing
+    #[napi]
+    pub fn parse_event_and_get_cache(&self, raw_message: String) -> napi::Result<String> {
+        // 1. Parse event in Rust (no FFI)
+        let parsed_event = match self.parse_inbound(&raw_message) {
+            Ok(event) => event,
+            Err(e) => return Err(napi::Error::from_reason(format!("[{}] Parse failed: {}", e.code(), e))),
+        };
+
+        // 2. Get cached HTML (no FFI)
+        let old_html = self.component_cache
+            .get_html(&parsed_event.component_id)
+            .unwrap_or_else(|| String::new());
+
+        // 3. Return structured data for TypeScript processing
+        let request_with_cache = serde_json::json!({
+            "component_id": parsed_event.component_id,
+            "event_name": parsed_event.event_name,
+            "event_data": parsed_event.event_data,
+            "old_html": old_html
+        });
+
+        Ok(request_with_cache.to_string())
+    }
+
+    /// Same as `parse_event_and_get_cache`, but returns the fields as an object so JS
+    /// doesn't have to `JSON.parse` a string on the hot path
+    #[napi]
+    pub fn parse_event_object(&self, raw_message: String) -> napi::Result<JsParsedEvent> {
+        let parsed_event = self
+            .parse_inbound(&raw_message)
+            .map_err(|e| napi::Error::from_reason(format!("[{}] Parse failed: {}", e.code(), e)))?;
+        let old_html = self.component_cache
+            .get_html(&parsed_event.component_id)
+            .unwrap_or_default();
+        Ok(JsParsedEvent::from_event(parsed_event, old_html))
+    }
+
+    /// Caches the HTML a client reports on connect with `"c|init|componentId|html"`
+    ///
+    /// The client already shows the server-rendered HTML, so using it as the baseline lets
+    /// the first event produce granular patches instead of a full replace. The id is the
+    /// one the client's events carry. Returns the component id the HTML was cached under
+    #[napi]
+    pub fn apply_init_message(&self, raw_message: String) -> napi::Result<String> {
+        let (wire_id, html) = self
+            .event_parser
+            .parse_init_message(&raw_message)
+            .map_err(napi::Error::from)?;
+        let component_id = self.resolve_component_token(wire_id.clone()).unwrap_or(wire_id);
+        self.component_cache.set_html(&component_id, html);
+        Ok(component_id)
+    }
+
+    /// Process response and generate message (Phase 2 step 2)
+    /// Takes new HTML from TypeScript and generates optimized diff response
+    #[napi]
+    pub fn process_response_and_generate_message(&self, component_id: String, old_html: String, new_html: String) -> napi::Result<String> {
+        // 1. Update cache with new HTML (no FFI)
+        self.component_cache.set_html(&component_id, new_html.clone());
+
+        // 2. Generate diff and compact message (no FFI)
+        let message = self
+            .render_component_message(component_id, old_html, new_html)
+            .map_err(|e| napi::Error::from_reason(format!("Diff generation failed: {}", e)))?;
+
+        Ok(message)
+    }
+
+    /// Renders a component against its cached HTML, skipping the diff when nothing changed
+    ///
+    /// Compares a hash of `new_html` with the one stored alongside the cached HTML and
+    /// returns `null` when they match. Otherwise returns the `render_component_message`
+    /// message and caches `new_html`. The component must have been cached first
+    #[napi]
+    pub fn render_if_changed(&self, component_id: String, new_html: String) -> napi::Result<Option<String>> {
+        let Some(cached_hash) = self.component_cache.get_hash(&component_id) else {
+            return Err(LiveTSError::ComponentNotFound(format!("no cached HTML for {}", component_id)).into());
+        };
+        if cached_hash == content_hash(&new_html) {
+            self.diffs_skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+
+        let old_html = self.component_cache.get_html(&component_id).unwrap_or_default();
+        self.process_response_and_generate_message(component_id, old_html, new_html).map(Some)
+    }
+
+    /// Get render counters as JSON `{diffs_run, diffs_skipped}`
+    #[napi]
+    pub fn get_render_stats(&self) -> String {
+        serde_json::json!({
+            "diffs_run": self.diffs_run.load(Ordering::Relaxed),
+            "diffs_skipped": self.diffs_skipped.load(Ordering::Relaxed),
+        })
+        .to_string()
+    }
+
+    /// Cache component HTML (useful for initial renders)
+    #[napi]
+    pub fn cache_component_html(&self, component_id: String, html: String) {
+        self.component_cache.set_html(&component_id, html);
+    }
+
+    /// Get cached component HTML
+    #[napi]
+    pub fn get_cached_html(&self, component_id: String) -> Option<String> {
+        self.component_cache.get_html(&component_id)
+    }
+
+    /// Remove component from cache
+    #[napi]
+    pub fn remove_component_cache(&self, component_id: String) -> bool {
+        self.component_cache.remove_component(&component_id).is_some()
+    }
+
+    /// Builds a message telling the client to remove a destroyed component's DOM
+    /// Also drops the component's cached state so a later render starts from scratch
+    #[napi]
+    pub fn build_remove_component_message(&self, component_id: String) -> napi::Result<String> {
+        self.component_cache.remove_component(&component_id);
+        self.component_versions.remove(&component_id);
+
+        let wire_id = self.wire_id(&component_id);
+        let dom_id = if self.component_tokens.is_some() { &wire_id } else { &component_id };
+        let patches = self.html_differ.patches_to_compact(vec![DomPatch::RemoveElement {
+            selector: format!("[data-livets-id=\"{}\"]", dom_id),
+        }]);
+        if let Some(tokens) = &self.component_tokens {
+            tokens.release(&component_id);
+        }
+        serde_json::to_string(&serde_json::json!({ "t": "p", "c": wire_id, "d": patches }))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Builds a message asking the client to run `command` with `args_json`, e.g. `focus` with
+    /// `{"selector":"#q"}`; the client dispatches it to the handler registered under that name
+    #[napi]
+    pub fn build_command_message(&self, component_id: String, command: String, args_json: String) -> napi::Result<String> {
+        if command.is_empty() || command.contains('|') {
+            return Err(LiveTSError::InvalidInput(format!("command name: {:?}", command)).into());
+        }
+        let args: serde_json::Value = serde_json::from_str(&args_json)
+            .map_err(|e| napi::Error::from(LiveTSError::InvalidInput(format!("command args JSON: {}", e))))?;
+        let patches = self.html_differ.patches_to_compact(vec![DomPatch::ClientCommand { command, args }]);
+        serde_json::to_string(&serde_json::json!({ "t": "p", "c": self.wire_id(&component_id), "d": patches }))
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Clear all cached component state, including versions and renders held by `queue_render`
+    #[napi]
+    pub fn clear_cache(&self) {
+        self.component_cache.clear();
+        self.component_versions.clear();
+        self.pending_renders.clear();
+    }
+
+    /// Get the number of cached components
+    #[napi]
+    pub fn cache_size(&self) -> u32 {
+        self.component_cache.len() as u32
+    }
+
+    /// Get cache statistics
+    #[napi]
+    pub fn get_cache_stats(&self) -> napi::Result<String> {
+        let stats = self.component_cache.stats();
+        serde_json::to_string(&stats)
+            .map_err(|e| napi::Error::from_reason(format!("Stats serialization failed: {}", e)))
+    }
+
+    /// Get how often each cached component's HTML was read, as a JSON array of
+    /// `{component_id, access_count}`, most read first
+    #[napi]
+    pub fn component_access_stats(&self) -> napi::Result<String> {
+        let stats: Vec<serde_json::Value> = self
+            .component_cache
+            .component_access_stats()
+            .into_iter()
+            .map(|(component_id, access_count)| serde_json::json!({ "component_id": component_id, "access_count": access_count }))
+            .collect();
+        serde_json::to_string(&stats)
+            .map_err(|e| napi::Error::from_reason(format!("Stats serialization failed: {}", e)))
+    }
+}
+
+impl Default for LiveTSEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Surfaces core errors to JS with their code first, e.g. `[E_INVALID_INPUT] Invalid input: ...`
+impl From<LiveTSError> for napi::Error {
+    fn from(error: LiveTSError) -> Self {
+        napi::Error::from_reason(format!("[{}] {}", error.code(), error))
+    }
+}
+
+/// Diffs a component's HTML into a compact `"p"` message addressed to `wire_id`
+/// With a `base_version`, the message carries it as `b` and the version it leads to as `s`
+fn patch_message(
+    differ: &HtmlDiffer,
+    wire_id: String,
+    old_html: &str,
+    new_html: &str,
+    selector_dictionary: bool,
+    base_version: Option<u64>,
+) -> napi::Result<String> {
+    let patches = differ
+        .diff(old_html, new_html)
+        .map_err(napi::Error::from)?;
+    let full_replace = HtmlDiffer::is_full_replace(&patches);
+
+    // Patch data can contain quotes and newlines, so let serde do the escaping
+    let mut message = if selector_dictionary {
+        let (selectors, compact_patches) = differ.patches_to_compact_with_dictionary(patches);
+        serde_json::json!({
+            "t": "p",
+            "c": wire_id,
+            "sel": selectors,
+            "d": compact_patches,
+        })
+    } else {
+        serde_json::json!({
+            "t": "p",
+            "c": wire_id,
+            "d": differ.patches_to_compact(patches),
+        })
+    };
+    // Full replaces are flagged so the client knows to reset any local state
+    if full_replace {
+        message["f"] = serde_json::json!(1);
+    }
+    if let Some(base) = base_version {
+        message["b"] = serde_json::json!(base);
+        message["s"] = serde_json::json!(base + 1);
+    }
+    serde_json::to_string(&message)
+    .map_err(|e| napi::Error::from_reason(e.to_string()))
+}
+
+/// Connection flag: patches for the connection fold each element's attribute and text
+/// changes into one `u` patch (see `DifferConfig::combine_element_patches`)
+pub const FLAG_COMBINED_PATCHES: &str = "combined_patches";
+
+/// Connection flag: messages for the connection list selectors once in a `sel` array
+pub const FLAG_SELECTOR_DICTIONARY: &str = "selector_dictionary";
+
+/// The first 8 characters of a component id, as used in compact messages
+fn short_id(component_id: &str) -> &str {
+    match component_id.char_indices().nth(8) {
+        Some((end, _)) => &component_id[..end],
+        None => component_id,
+    }
+}
+
+/// How long a new connection waits for the JS flag callback before keeping its rollout flags
+const FLAG_CALLBACK_TIMEOUT_MS: u64 = 1000;
+
+/// Asks the JS flag callback for a new connection's feature flags
+async fn js_connection_flags(tsfn: &ThreadsafeFunction<String>, connection_id: &str) -> Result<Vec<String>> {
+    let (tx, rx) = tokio::sync::oneshot::channel::<Vec<String>>();
+    let status = tsfn.call_with_return_value(
+        Ok(connection_id.to_string()),
+        ThreadsafeFunctionCallMode::NonBlocking,
+        move |flags: Vec<String>| {
+            let _ = tx.send(flags);
+            Ok(())
+        },
+    );
+    if status != napi::Status::Ok {
+        return Err(LiveTSError::InvalidInput(format!("flag callback call failed: {:?}", status)));
+    }
+    // The sender is dropped without a value when the callback throws
+    match tokio::time::timeout(std::time::Duration::from_millis(FLAG_CALLBACK_TIMEOUT_MS), rx).await {
+        Ok(Ok(flags)) => Ok(flags),
+        Ok(Err(_)) => Err(LiveTSError::InvalidInput("flag callback did not return a string array".to_string())),
+        Err(_) => Err(LiveTSError::InvalidInput("flag callback timed out".to_string())),
+    }
+}
+
+/// One component's compact patches for `build_multi_component_message`
+#[napi(object)]
+#[derive(Debug, Clone)]
+pub struct ComponentPatches {
+    pub component_id: String,
+    pub patches: Vec<String>,
+}
+
+/// One message of a `broadcast_many` batch
+#[napi(object)]
+#[derive(Debug, Clone)]
+pub struct ChannelMessage {
+    pub channel: String,
+    pub message: String,
+}
+
+/// napi representation of a `DomPatch`, tagged by `type` like the JSON form
+#[napi(object)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsDomPatch {
+    #[napi(js_name = "type")]
+    pub kind: String,
+    pub selector: Option<String>,
+    pub content: Option<String>,
+    pub text: Option<String>,
+    pub attr: Option<String>,
+    pub value: Option<String>,
+    pub html: Option<String>,
+    pub parent: Option<String>,
+    pub position: Option<String>,
+    pub attrs: Option<std::collections::HashMap<String, String>>,
+    pub property: Option<String>,
+    pub class_name: Option<String>,
+    pub add: Option<bool>,
+    pub command: Option<String>,
+    /// Command arguments as a JSON string
+    pub args: Option<String>,
+}
+
+impl JsDomPatch {
+    fn empty(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            selector: None,
+            content: None,
+            text: None,
+            attr: None,
+            value: None,
+            html: None,
+            parent: None,
+            position: None,
+            attrs: None,
+            property: None,
+            class_name: None,
+            add: None,
+            command: None,
+            args: None,
+        }
+    }
+}
+
+/// Kind of an inbound message, as told by `classify_message`
+#[napi]
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// `"p"` or `{"type":"ping",...}`
+    Ping,
+    /// `"e|id|name|value|checked|tag"`
+    CompactEvent,
+    /// `{"type":"event",...}`
+    JsonEvent,
+    /// Any other JSON object
+    Control,
+    Unknown,
+    /// `"c|init|componentId|html"`, see `apply_init_message`
+    Init,
+}
+
+/// napi representation of a parsed event plus the component's cached HTML
+/// Mirrors the JSON from `parse_event_and_get_cache`, with the target fields flattened
+#[napi(object)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsParsedEvent {
+    pub component_id: String,
+    pub event_name: String,
+    pub event_type: String,
+    pub tag_name: String,
+    pub value: Option<String>,
+    pub checked: Option<bool>,
+    pub attributes: std::collections::HashMap<String, String>,
+    pub old_html: String,
+}
+
+impl JsParsedEvent {
+    pub fn from_event(event: ParsedEvent, old_html: String) -> Self {
+        let EventData { event_type, target } = event.event_data;
+        Self {
+            component_id: event.component_id,
+            event_name: event.event_name,
+            event_type,
+            tag_name: target.tag_name,
+            value: target.value,
+            checked: target.checked,
+            attributes: target.attributes,
+            old_html,
+        }
+    }
+}
+
+impl From<DomPatch> for JsDomPatch {
+    fn from(patch: DomPatch) -> Self {
+        match patch {
+            DomPatch::ReplaceText { selector, content } => Self {
+                selector: Some(selector),
+                content: Some(content),
+                ..Self::empty("ReplaceText")
+            },
+            DomPatch::UpdateText { selector, text } => Self {
+                selector: Some(selector),
+                text: Some(text),
+                ..Self::empty("UpdateText")
+            },
+            DomPatch::SetAttribute { selector, attr, value } => Self {
+                selector: Some(selector),
+                attr: Some(attr),
+                value: Some(value),
+                ..Self::empty("SetAttribute")
+            },
+            DomPatch::RemoveAttribute { selector, attr } => Self {
+                selector: Some(selector),
+                attr: Some(attr),
+                ..Self::empty("RemoveAttribute")
+            },
+            DomPatch::ReplaceElement { selector, html } => Self {
+                selector: Some(selector),
+                html: Some(html),
+                ..Self::empty("ReplaceElement")
+            },
+            DomPatch::InsertElement { parent, position, html } => Self {
+                parent: Some(parent),
+                position: Some(format!("{:?}", position)),
+                html: Some(html),
+                ..Self::empty("InsertElement")
+            },
+            DomPatch::InsertBefore { reference_selector, html } => Self {
+                selector: Some(reference_selector),
+                html: Some(html),
+                ..Self::empty("InsertBefore")
+            },
+            DomPatch::RemoveElement { selector } => Self {
+                selector: Some(selector),
+                ..Self::empty("RemoveElement")
+            },
+            DomPatch::MoveElement { selector, target, position } => Self {
+                selector: Some(selector),
+                parent: Some(target),
+                position: Some(format!("{:?}", position)),
+                ..Self::empty("MoveElement")
+            },
+            DomPatch::ReplaceInnerHtml { selector, html } => Self {
+                selector: Some(selector),
+                html: Some(html),
+                ..Self::empty("ReplaceInnerHtml")
+            },
+            DomPatch::UpdateElement { selector, attrs, text } => Self {
+                selector: Some(selector),
+                attrs: Some(attrs),
+                text,
+                ..Self::empty("UpdateElement")
+            },
+            DomPatch::SetProperty { selector, property, value } => Self {
+                selector: Some(selector),
+                property: Some(property),
+                value: Some(value),
+                ..Self::empty("SetProperty")
+            },
+            DomPatch::ToggleClass { selector, class, add } => Self {
+                selector: Some(selector),
+                class_name: Some(class),
+                add: Some(add),
+                ..Self::empty("ToggleClass")
+            },
+            DomPatch::ClientCommand { command, args } => Self {
+                command: Some(command),
+                args: Some(args.to_string()),
+                ..Self::empty("ClientCommand")
+            },
+        }
+    }
+}
+
+/// Log to stdout at INFO and above through the core's global subscriber
+/// The core never installs one itself, so embedders with their own subscriber keep control.
+/// Does nothing if a global subscriber is already set, including one `set_log_sink` installed
+#[napi]
+pub fn init_logging() {
+    use tracing_subscriber::Layer;
+    if LOG_LAYER.get().is_none() {
+        // A global level filter rather than `with_filter`, since per-layer filters don't survive a reload
+        let stdout = Layer::<tracing_subscriber::Registry>::and_then(
+            tracing_subscriber::filter::LevelFilter::INFO,
+            tracing_subscriber::fmt::layer(),
+        );
+        let _ = install_log_layer(Box::new(stdout));
+    }
+}
+
+/// The layer currently receiving the core's logs: stdout from `init_logging` or a `SinkLayer`
+type LogLayer = Box<dyn tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync>;
+
+/// Handle swapping the global subscriber's layer, or `None` if another global subscriber
+/// was already installed
+type LogLayerHandle = tracing_subscriber::reload::Handle<LogLayer, tracing_subscriber::Registry>;
+static LOG_LAYER: std::sync::OnceLock<Option<LogLayerHandle>> = std::sync::OnceLock::new();
+
+/// Routes the core's logs to `layer`, installing the global subscriber on first use
+/// and replacing the previous layer afterwards
+fn install_log_layer(layer: LogLayer) -> napi::Result<()> {
+    let mut pending = Some(layer);
+    let handle = LOG_LAYER.get_or_init(|| {
+        use tracing_subscriber::prelude::*;
+        let (reloadable, handle) = tracing_subscriber::reload::Layer::new(pending.take()?);
+        tracing_subscriber::registry().with(reloadable).try_init().is_ok().then_some(handle)
+    });
+    let Some(handle) = handle else {
+        return Err(napi::Error::from_reason("A global tracing subscriber is already installed".to_string()));
+    };
+    match pending {
+        Some(layer) => handle
+            .reload(layer)
+            .map_err(|e| napi::Error::from_reason(format!("log level: {}", e))),
+        None => Ok(()),
+    }
+}
+
+/// Tokio-based WebSocket broker running inside the Rust core
+#[napi]
+pub struct LiveTSWebSocketBroker {
+    // Only taken in Drop, so it is always present while the broker is usable
+    rt: Option<Arc<Runtime>>,
+    listener_task: Option<JoinHandle<()>>,
+    local_port: Option<u16>,
+    connections: Arc<connection::ConnectionManager>,
+    /// Channels components subscribe to with `subscribe_channel`
+    pubsub: Arc<PubSubSystem>,
+    /// Last HTML sent for each component, the baseline for `update_component`
+    component_cache: ComponentCache,
+    html_differ: HtmlDiffer,
+    handshake_config: HandshakeConfig,
+    /// Disconnect clients that send no `"p"` ping for this long (None disables)
+    ping_timeout: Option<std::time::Duration>,
+    started_at: Instant,
+    accept_errors: Arc<AtomicU64>,
+    dropped_events: Arc<AtomicU64>,
+    event_queue_size: u32,
+    block_when_full: bool,
+    /// How long `block_when_full` waits for room before dropping the event
+    event_block_timeout: std::time::Duration,
+    // channel for shutdown signal
+    shutdown: Arc<DashMap<&'static str, bool>>, // simple flag map
+    // JS event handler
+    #[allow(dead_code)]
+    event_handler: Arc<DashMap<&'static str, EventSink>>,
+    // JS callback computing new connections' feature flags
+    flag_callback: Arc<DashMap<&'static str, ThreadsafeFunction<String>>>,
+}
+
+/// Destination for serialized broker events
+#[derive(Clone)]
+struct EventSink {
+    target: SinkTarget,
+    /// Wait for room in a full queue, up to `block_timeout`, instead of dropping the event
+    block_when_full: bool,
+    block_timeout: std::time::Duration,
+    /// Events that couldn't be delivered, shared with the broker's health report
+    dropped: Arc<AtomicU64>,
+}
+
+/// Default for how long a full event queue is waited on with `block_when_full`
+const DEFAULT_EVENT_BLOCK_TIMEOUT_MS: u32 = 1000;
+
+#[derive(Clone)]
+enum SinkTarget {
+    /// JS callback registered with `set_event_handler`
+    Js(ThreadsafeFunction<String>),
+    /// Rust channel registered with `set_event_sender`
+    Channel(tokio::sync::mpsc::UnboundedSender<String>),
+    /// Bounded Rust channel registered with `set_bounded_event_sender`
+    Bounded(tokio::sync::mpsc::Sender<String>),
+}
+
+impl EventSink {
+    /// Delivers an event, with `block_when_full` waiting up to `block_timeout` for room
+    /// without blocking the runtime thread. Events that still don't fit are dropped and counted
+    async fn emit(&self, json: String) -> napi::Status {
+        let status = match &self.target {
+            SinkTarget::Js(tsfn) if self.block_when_full => {
+                // The threadsafe function can't be awaited, so retry a non-blocking call
+                let deadline = Instant::now() + self.block_timeout;
+                loop {
+                    let status = tsfn.call(Ok(json.clone()), ThreadsafeFunctionCallMode::NonBlocking);
+                    if status != napi::Status::QueueFull || Instant::now() >= deadline {
+                        break status;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+            }
+            SinkTarget::Bounded(sender) if self.block_when_full => {
+                match tokio::time::timeout(self.block_timeout, sender.send(json)).await {
+                    Ok(Ok(())) => napi::Status::Ok,
+                    Ok(Err(_)) => napi::Status::Closing,
+                    Err(_) => napi::Status::QueueFull,
+                }
+            }
+            _ => return self.try_emit(json),
+        };
+        self.count(status)
+    }
+
+    /// Delivers an event if there's room right now, for callers that can't wait
+    fn try_emit(&self, json: String) -> napi::Status {
+        let status = match &self.target {
+            SinkTarget::Js(tsfn) => tsfn.call(Ok(json), ThreadsafeFunctionCallMode::NonBlocking),
+            SinkTarget::Channel(sender) => match sender.send(json) {
+                Ok(()) => napi::Status::Ok,
+                Err(_) => napi::Status::Closing,
+            },
+            SinkTarget::Bounded(sender) => match sender.try_send(json) {
+                Ok(()) => napi::Status::Ok,
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => napi::Status::QueueFull,
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => napi::Status::Closing,
+            },
+        };
+        self.count(status)
+    }
+
+    fn count(&self, status: napi::Status) -> napi::Status {
+        if status != napi::Status::Ok {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        status
+    }
+}
+
+/// Broker health snapshot returned by `health`
+#[derive(Debug, Clone, Serialize)]
+pub struct BrokerHealth {
+    pub listening: bool,
+    pub connections: usize,
+    pub accept_errors: u64,
+    pub bytes_sent: u64,
+    pub uptime_ms: u64,
+    /// Events the handler couldn't take (queue full or closed)
+    pub dropped_events: u64,
+}
+
+/// Socket options for `listen`
+#[napi(object)]
+#[derive(Debug, Clone, Default)]
+pub struct ListenOptions {
+    /// Pending connection queue length (defaults to 1024)
+    pub backlog: Option<u32>,
+    /// Set SO_REUSEADDR so a restarted broker can rebind while the old socket is in TIME_WAIT
+    /// (defaults to true, except on Windows where it allows port hijacking)
+    pub reuse_address: Option<bool>,
+    /// Only accept upgrades to these request paths, e.g. `["/livets-ws"]`; others get a 404
+    /// (defaults to any path, so other WebSocket endpoints can't share the port)
+    pub paths: Option<Vec<String>>,
+}
+
+/// Binds the listening socket with the requested options
+async fn bind_listener(addr: &str, options: &ListenOptions) -> std::io::Result<TcpListener> {
+    let socket_addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, format!("could not resolve {}", addr))
+    })?;
+
+    let socket = if socket_addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(options.reuse_address.unwrap_or(!cfg!(windows)))?;
+    socket.bind(socket_addr)?;
+    socket.listen(options.backlog.unwrap_or(1024))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum BrokerEvent {
+    Connected {
+        connection_id: String,
+        subprotocol: Option<String>,
+        /// Single-use token the client presents (`?reconnect=`) to resume this session after
+        /// a disconnect; null when reconnection is disabled
+        reconnect_token: Option<String>,
+        /// Whether the connection resumed an earlier session's components and replay buffer
+        resumed: bool,
+        /// Feature flags assigned at accept time, see `set_flag_rollouts`
+        flags: Vec<String>,
+    },
+    Message { connection_id: String, data: String },
+    Closed {
+        connection_id: String,
+        cause: CloseCause,
+        code: Option<u16>,
+        reason: Option<String>,
+    },
+}
+
+/// Why a broker connection was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseCause {
+    /// The client sent a Close frame
+    ClientClose,
+    /// The stream ended without a Close frame (e.g. network drop)
+    ClientDisconnect,
+    /// Reading from the socket failed (protocol or I/O error)
+    ReadError,
+    /// Writing to the socket failed
+    WriteError,
+    /// The connection was considered dead after a missed heartbeat
+    Timeout,
+    /// The server side dropped the connection's outbound channel
+    ServerClose,
+    /// The server closed the connection with `close_connection`
+    ServerRequest,
+    /// The connection's task panicked; the event's reason is `internal error`
+    InternalError,
+}
+
+/// Builds the `Closed` event, carrying the close frame's code and reason when there was one
+fn closed_event(connection_id: &str, cause: CloseCause, frame: Option<&CloseFrame<'_>>) -> BrokerEvent {
+    BrokerEvent::Closed {
+        connection_id: connection_id.to_string(),
+        cause,
+        code: frame.map(|f| u16::from(f.code)),
+        reason: frame
+            .map(|f| f.reason.to_string())
+            .filter(|reason| !reason.is_empty()),
+    }
+}
+
+#[napi]
+impl LiveTSWebSocketBroker {
+    /// `worker_threads` caps the runtime's worker threads (defaults to one per CPU core)
+    #[napi(constructor)]
+    pub fn new(worker_threads: Option<u32>) -> napi::Result<Self> {
+        tracing::info!("🦀 Initializing LiveTS WebSocket Broker");
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        match worker_threads {
+            Some(0) => return Err(napi::Error::from_reason("worker_threads must be at least 1".to_string())),
+            Some(threads) => {
+                builder.worker_threads(threads as usize);
+            }
+            None => {}
+        }
+        let rt = builder.build().map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        let connections = Arc::new(connection::ConnectionManager::new());
+        let pubsub = Arc::new(PubSubSystem::new());
+        connections.set_pubsub(Some(pubsub.clone()));
+        Ok(Self {
+            rt: Some(Arc::new(rt)),
+            listener_task: None,
+            local_port: None,
+            connections,
+            pubsub,
+            component_cache: ComponentCache::new(1000),
+            html_differ: HtmlDiffer::new(),
+            handshake_config: HandshakeConfig::new(),
+            ping_timeout: None,
+            started_at: Instant::now(),
+            accept_errors: Arc::new(AtomicU64::new(0)),
+            dropped_events: Arc::new(AtomicU64::new(0)),
+            event_queue_size: 0,
+            block_when_full: false,
+            event_block_timeout: std::time::Duration::from_millis(DEFAULT_EVENT_BLOCK_TIMEOUT_MS as u64),
+            shutdown: Arc::new(DashMap::new()),
+            event_handler: Arc::new(DashMap::new()),
+            flag_callback: Arc::new(DashMap::new()),
+        })
+    }
+
+    /// Register a JS callback that receives broker events as JSON strings
+    #[napi]
+    pub fn set_event_handler(&self, _env: Env, callback: JsFunction) -> NapiResult<()> {
+        let tsfn: ThreadsafeFunction<String> = callback.create_threadsafe_function(self.event_queue_size as usize, |ctx: napi::threadsafe_function::ThreadSafeCallContext<String>| {
+            // Create the JS string and return it as an argument to the callback
+            match ctx.env.create_string(&ctx.value) {
+                Ok(js_string) => {
+                    Ok(vec![js_string])
+                }
+                Err(e) => {
+                    tracing::error!("❌ Failed to create JS string: {:?}", e);
+                    Err(e)
+                }
+            }
+        })?;
+
+        self.event_handler.insert("handler", self.event_sink(SinkTarget::Js(tsfn)));
+        tracing::info!("✅ Event handler registered successfully");
+        Ok(())
+    }
+
+    /// Send the core's logs to a JS callback instead of stdout, as JSON records
+    /// `{level, target, message, fields}` at `level` (`"error"` to `"trace"`, default `"info"`) and above
+    ///
+    /// Applies process-wide and replaces the stdout logging `initLogging` sets up; calling again
+    /// replaces the callback and level. Fails if a global subscriber from outside the core is set
+    #[napi]
+    pub fn set_log_sink(&self, _env: Env, callback: JsFunction, level: Option<String>) -> napi::Result<()> {
+        let level = level
+            .as_deref()
+            .unwrap_or("info")
+            .parse::<tracing::Level>()
+            .map_err(|e| napi::Error::from(LiveTSError::InvalidInput(format!("log level: {}", e))))?;
+        let tsfn: ThreadsafeFunction<String> = callback.create_threadsafe_function(0, |ctx: napi::threadsafe_function::ThreadSafeCallContext<String>| {
+            ctx.env.create_string(&ctx.value).map(|js_string| vec![js_string])
+        })?;
+
+        self.set_log_sink_fn(
+            Arc::new(move |record: &LogRecord| {
+                if let Ok(json) = serde_json::to_string(record) {
+                    tsfn.call(Ok(json), ThreadsafeFunctionCallMode::NonBlocking);
+                }
+            }),
+            level,
+        )
+    }
+
+    /// Like `set_log_sink`, with a Rust callback receiving each `LogRecord`
+    /// For embedding the broker without Node.js
+    pub fn set_log_sink_fn(&self, sink: Arc<LogSinkFn>, level: tracing::Level) -> napi::Result<()> {
+        install_log_layer(Box::new(SinkLayer::new(sink, level)))
+    }
+
+    /// Deliver broker events as JSON strings to a Rust channel instead of a JS callback
+    /// For embedding the broker without Node.js. Must be called before `listen`
+    pub fn set_event_sender(&self, sender: tokio::sync::mpsc::UnboundedSender<String>) {
+        self.event_handler.insert("handler", self.event_sink(SinkTarget::Channel(sender)));
+    }
+
+    /// Like `set_event_sender`, with a bounded channel whose capacity acts as the event queue size
+    /// A full channel drops the event or waits, per `set_event_queue`. Must be called before `listen`
+    pub fn set_bounded_event_sender(&self, sender: tokio::sync::mpsc::Sender<String>) {
+        self.event_handler.insert("handler", self.event_sink(SinkTarget::Bounded(sender)));
+    }
+
+    /// Bound the queue of events waiting for the JS handler (0, the default, is unbounded)
+    ///
+    /// When the queue is full, an event is dropped and counted in `health().dropped_events`,
+    /// or with `block_when_full` the connection waits up to `block_timeout_ms` (default 1000)
+    /// for room, slowing that client down, before dropping it. Must be called before `set_event_handler`
+    #[napi]
+    pub fn set_event_queue(&mut self, max_queue_size: u32, block_when_full: bool, block_timeout_ms: Option<u32>) {
+        self.event_queue_size = max_queue_size;
+        self.block_when_full = block_when_full;
+        let timeout_ms = block_timeout_ms.unwrap_or(DEFAULT_EVENT_BLOCK_TIMEOUT_MS);
+        self.event_block_timeout = std::time::Duration::from_millis(timeout_ms as u64);
+    }
+
+    fn event_sink(&self, target: SinkTarget) -> EventSink {
+        EventSink {
+            target,
+            block_when_full: self.block_when_full,
+            block_timeout: self.event_block_timeout,
+            dropped: self.dropped_events.clone(),
+        }
+    }
+
+    /// Configure the subprotocols offered during the handshake, in preference order
+    /// When `required` is set, clients offering none of them are rejected. Must be called before `listen`
+    #[napi]
+    pub fn set_subprotocols(&mut self, protocols: Vec<String>, required: bool) {
+        self.handshake_config.subprotocols = protocols;
+        self.handshake_config.require_subprotocol = required;
+    }
+
+    /// Require an application-level `"p"` ping from each client at least every `timeout_ms`
+    ///
+    /// Clients are answered with a `"P"` pong, and one that stays silent for longer is closed
+    /// (code 4000, cause `timeout`). Protocol Ping frames don't count, since proxies may strip
+    /// them. The bundled client pings every 30s, so leave room above that. 0 disables (the
+    /// default). Must be called before `listen`
+    #[napi]
+    pub fn set_ping_timeout(&mut self, timeout_ms: u32) {
+        self.ping_timeout = (timeout_ms > 0).then(|| std::time::Duration::from_millis(timeout_ms as u64));
+    }
+
+    /// Keep a closed connection's components and replay buffer for `ttl_ms` (default 30000),
+    /// resumable by a client that reconnects with the `reconnect_token` from its `Connected`
+    /// event. 0 disables reconnect tokens
+    #[napi]
+    pub fn set_reconnect_ttl(&self, ttl_ms: u32) {
+        self.connections.set_reconnect_ttl(ttl_ms as u64);
+    }
+
+    /// Reject new WebSocket upgrades with HTTP 503 while `max` connections are open
+    /// (0, the default, is unlimited)
+    #[napi]
+    pub fn set_max_connections(&self, max: u32) {
+        self.connections.set_max_connections(max as usize);
+    }
+
+    /// Tag each new connection with the flags whose rollout bucket it falls in, e.g.
+    /// `{combined_patches: 10}` enables that flag for 10% of connections. Buckets come from a
+    /// hash of the connection id, so they're stable. An empty map stops tagging new connections
+    #[napi]
+    pub fn set_flag_rollouts(&self, rollouts: std::collections::HashMap<String, u32>) {
+        let rollouts: Vec<(String, u8)> = rollouts
+            .into_iter()
+            .map(|(flag, percent)| (flag, percent.min(100) as u8))
+            .collect();
+        self.connections
+            .set_flag_assigner((!rollouts.is_empty()).then(|| connection::rollout_flags(rollouts)));
+    }
+
+    /// Register a JS callback computing each new connection's feature flags from its id,
+    /// as an array of strings; its flags replace any from `set_flag_rollouts`
+    ///
+    /// The connection keeps its rollout flags if the callback throws or takes longer than
+    /// a second. Passing no callback removes it. Applies to connections accepted afterwards
+    #[napi]
+    pub fn set_flag_callback(&self, _env: Env, callback: Option<JsFunction>) -> NapiResult<()> {
+        let Some(callback) = callback else {
+            self.flag_callback.remove("callback");
+            return Ok(());
+        };
+        let tsfn: ThreadsafeFunction<String> = callback.create_threadsafe_function(0, |ctx: napi::threadsafe_function::ThreadSafeCallContext<String>| {
+            ctx.env.create_string(&ctx.value).map(|js_string| vec![js_string])
+        })?;
+        self.flag_callback.insert("callback", tsfn);
+        Ok(())
+    }
+
+    /// Get a connection's feature flags, sorted
+    #[napi]
+    pub fn get_connection_flags(&self, connection_id: String) -> Option<Vec<String>> {
+        self.connections.get_connection_flags(&connection_id)
+    }
+
+    /// Replace a connection's feature flags
+    #[napi]
+    pub fn set_connection_flags(&self, connection_id: String, flags: Vec<String>) -> napi::Result<()> {
+        self.connections
+            .set_connection_flags(&connection_id, flags)
+            .map_err(napi::Error::from)
+    }
+
+    /// Build a component's patch message in the format a connection's flags select
+    ///
+    /// `combined_patches` folds each element's changes into one `u` patch and
+    /// `selector_dictionary` sends selectors once in `sel`; without flags this is the
+    /// message `update_component` broadcasts
+    #[napi]
+    pub fn render_for_connection(
+        &self,
+        connection_id: String,
+        component_id: String,
+        old_html: String,
+        new_html: String,
+    ) -> napi::Result<String> {
+        let Some(flags) = self.connections.get_connection_flags(&connection_id) else {
+            return Err(LiveTSError::ConnectionNotFound(connection_id).into());
+        };
+        self.flagged_patch_message(&flags, short_id(&component_id).to_string(), &old_html, &new_html)
+    }
+
+    /// The patch message for a connection with `flags`, see `render_for_connection`
+    fn flagged_patch_message(&self, flags: &[String], wire_id: String, old_html: &str, new_html: &str) -> napi::Result<String> {
+        let has = |flag: &str| flags.iter().any(|f| f == flag);
+        let selector_dictionary = has(FLAG_SELECTOR_DICTIONARY);
+
+        if has(FLAG_COMBINED_PATCHES) {
+            let differ = HtmlDiffer::with_config(DifferConfig {
+                combine_element_patches: true,
+                ..self.html_differ.config().clone()
+            })
+            .with_extractor(self.html_differ.extractor().clone());
+            patch_message(&differ, wire_id, old_html, new_html, selector_dictionary, None)
+        } else {
+            patch_message(&self.html_differ, wire_id, old_html, new_html, selector_dictionary, None)
+        }
+    }
+
+    /// Get the subprotocol negotiated for a connection
+    #[napi]
+    pub fn get_connection_subprotocol(&self, connection_id: String) -> Option<String> {
+        self.connections.get_subprotocol(&connection_id)
+    }
+
+    /// Start listening on a TCP port for WebSocket upgrades (ws://host:port/livets-ws)
+    /// Pass port 0 to bind an ephemeral port, then read it back with `port()`.
+    /// Set `options.paths` to restrict which request paths are upgraded
+    #[napi]
+    pub fn listen(&mut self, host: String, port: u16, options: Option<ListenOptions>) -> napi::Result<()> {
+        let addr = format!("{}:{}", host, port);
+        let options = options.unwrap_or_default();
+        let rt = self.runtime().clone();
+        let connections = self.connections.clone();
+        let mut handshake_config = self.handshake_config.clone();
+        if let Some(paths) = options.paths.clone() {
+            handshake_config.paths = paths;
+        }
+        let handshake_config = Arc::new(handshake_config);
+        let shutdown = self.shutdown.clone();
+        let handler_map = self.event_handler.clone();
+        let flag_callback = self.flag_callback.clone();
+        let ping_timeout = self.ping_timeout;
+        let accept_errors = self.accept_errors.clone();
+
+        // Bind up front so address errors reach the caller instead of panicking the task
+        let listener = rt
+            .block_on(bind_listener(&addr, &options))
+            .map_err(|e| napi::Error::from_reason(format!("Failed to bind {}: {}", addr, e)))?;
+        self.local_port = listener.local_addr().ok().map(|a| a.port());
+        self.shutdown.insert("stop", false);
+
+        let handle = rt.spawn(async move {
+            loop {
+                if shutdown.get("stop").map(|e| *e.value()).unwrap_or(false) {
+                    tracing::info!("Shutting down WS broker listener");
+                    break;
+                }
+
+                let (stream, _addr) = match listener.accept().await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        accept_errors.fetch_add(1, Ordering::Relaxed);
+                        tracing::error!("accept error: {}", e);
+                        continue;
+                    }
+                };
+
+                let handler_clone = handler_map.get("handler").map(|e| e.value().clone());
+                let flag_callback_clone = flag_callback.get("callback").map(|e| e.value().clone());
+                tokio::spawn(handle_connection(
+                    stream,
+                    connections.clone(),
+                    handshake_config.clone(),
+                    handler_clone,
+                    flag_callback_clone,
+                    ping_timeout,
+                ));
+            }
+        });
+
+        self.listener_task = Some(handle);
+        Ok(())
+    }
+
+    /// Liveness/readiness probe as JSON `{listening, connections, accept_errors, bytes_sent, uptime_ms, dropped_events}`
+    /// `listening` turns false if the listener task has died, so orchestrators can restart the broker
+    #[napi]
+    pub fn health(&self) -> napi::Result<String> {
+        serde_json::to_string(&self.health_report())
+            .map_err(|e| napi::Error::from_reason(format!("Health serialization failed: {}", e)))
+    }
+
+    fn runtime(&self) -> &Arc<Runtime> {
+        self.rt.as_ref().expect("broker runtime is only released on drop")
+    }
+
+    fn health_report(&self) -> BrokerHealth {
+        BrokerHealth {
+            listening: self
+                .listener_task
+                .as_ref()
+                .map(|handle| !handle.is_finished())
+                .unwrap_or(false),
+            connections: self.connections.connection_count(),
+            accept_errors: self.accept_errors.load(Ordering::Relaxed),
+            bytes_sent: self.connections.total_bytes_sent(),
+            uptime_ms: self.started_at.elapsed().as_millis() as u64,
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get the port the broker is listening on, if any
+    #[napi]
+    pub fn port(&self) -> Option<u32> {
+        self.local_port.map(u32::from)
+    }
+
+    /// Stop the listener and close all connections
+    #[napi]
+    pub fn stop(&mut self) -> napi::Result<()> {
+        self.shutdown.insert("stop", true);
+        if let Some(handle) = self.listener_task.take() {
+            // The loop is usually parked in accept(); abort so the socket is released now
+            handle.abort();
+            self.runtime().block_on(async move {
+                let _ = handle.await;
+            });
+        }
+        self.local_port = None;
+        Ok(())
+    }
+
+    /// Get the messages sent to a connection after sequence number `seq`, for resuming after a brief disconnect
+    /// Returns null when they are no longer buffered and the client needs a full resync. The
+    /// broker answers clients' own `"r|seq"` replay requests with this itself
+    #[napi]
+    pub fn replay_since(&self, connection_id: String, seq: i64) -> napi::Result<Option<Vec<String>>> {
+        self.connections
+            .replay_since(&connection_id, seq.max(0) as u64)
+            .map_err(napi::Error::from)
+    }
+
+    /// Get the payload bytes written to a connection so far
+    #[napi]
+    pub fn get_connection_bytes_sent(&self, connection_id: String) -> Option<i64> {
+        self.connections
+            .bytes_sent(&connection_id)
+            .map(|bytes| bytes as i64)
+    }
+
+    /// Get a connection's activity as JSON: `connected_at`, `last_ping` and `last_inbound`
+    /// (milliseconds ago, `last_inbound` null until the client sends something),
+    /// `component_count` and `bytes_sent`
+    #[napi]
+    pub fn get_connection_info(&self, connection_id: String) -> napi::Result<Option<String>> {
+        self.connections
+            .connection_info(&connection_id)
+            .map(|info| serde_json::to_string(&info))
+            .transpose()
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// List every connection's activity as a JSON array (see `get_connection_info`)
+    #[napi]
+    pub fn list_connections(&self) -> napi::Result<String> {
+        serde_json::to_string(&self.connections.list_connections())
+            .map_err(|e| napi::Error::from_reason(e.to_string()))
+    }
+
+    /// Send a JSON-stringified message to a specific connection
+    #[napi]
+    pub fn send_to_connection(&self, connection_id: String, message: String) -> napi::Result<()> {
+        self.runtime().block_on(async {
+            self.connections
+                .send_to_connection(&connection_id, &message)
+                .await
+                .map_err(napi::Error::from)
+        })
+    }
+
+    /// Close a connection with a WebSocket close code (1000 or 3000-4999) and reason
+    /// The client receives the Close frame and a `Closed` event with cause `server_request` follows
+    #[napi]
+    pub fn close_connection(&self, connection_id: String, code: u16, reason: String) -> napi::Result<()> {
+        if code != 1000 && !(3000..=4999).contains(&code) {
+            return Err(napi::Error::from_reason(format!("Invalid close code {}: use 1000 or 3000-4999", code)));
+        }
+        // Control frame payloads are capped at 125 bytes, two of which hold the code
+        if reason.len() > 123 {
+            return Err(napi::Error::from_reason("Close reason exceeds 123 bytes".to_string()));
+        }
+
+        self.connections
+            .request_close(&connection_id, code, &reason)
+            .map_err(napi::Error::from)
+    }
+
+    /// Send a message to a connection, ahead of already queued messages when `high` is set
+    /// Use for control messages (forced reconnects, errors) that must not wait behind patch updates
+    #[napi]
+    pub fn send_to_connection_priority(&self, connection_id: String, message: String, high: bool) -> napi::Result<()> {
+        self.runtime().block_on(async {
+            self.connections
+                .send_to_connection_priority(&connection_id, &message, high)
+                .await
+                .map_err(napi::Error::from)
+        })
+    }
+
+    /// Send the same message to several connections in one call
+    /// Returns a JSON report `{sent: [ids], failed: [{connection_id, error}]}`
+    #[napi]
+    pub fn send_to_connections(&self, connection_ids: Vec<String>, message: String) -> napi::Result<String> {
+        let report = self.runtime().block_on(async {
+            self.connections
+                .send_to_connections(&connection_ids, &message)
+                .await
+        });
+        serde_json::to_string(&report)
+            .map_err(|e| napi::Error::from_reason(format!("Report serialization failed: {}", e)))
+    }
+
+    /// Register a component to a connection (for targeted broadcasts)
+    #[napi]
+    pub fn register_component(&self, component_id: String, connection_id: String) -> napi::Result<()> {
+        self.connections
+            .register_component(component_id, connection_id)
+            .map_err(napi::Error::from)
+    }
+
+    /// Cache the HTML a component's clients currently show, e.g. after its initial render
+    #[napi]
+    pub fn cache_component_html(&self, component_id: String, html: String) {
+        self.component_cache.set_html(&component_id, html);
+    }
+
+    /// Diff a component against its cached HTML and send the patch to all its connections
+    ///
+    /// Caching, diffing, message building and the broadcast all happen in Rust, so no
+    /// message crosses FFI. The message is addressed by the component's id prefix. Nothing
+    /// is sent when the HTML is unchanged. The component must have been cached first.
+    /// Connections with feature flags get the format `render_for_connection` would build
+    #[napi]
+    pub fn update_component(&self, component_id: String, new_html: String) -> napi::Result<()> {
+        let Some(old_html) = self.component_cache.get_html(&component_id) else {
+            return Err(LiveTSError::ComponentNotFound(format!("no cached HTML for {}", component_id)).into());
+        };
+        if old_html == new_html {
+            return Ok(());
+        }
+
+        let wire_id = short_id(&component_id).to_string();
+        let message = patch_message(&self.html_differ, wire_id.clone(), &old_html, &new_html, false, None)?;
+        self.component_cache.set_html(&component_id, new_html.clone());
+        // Connections with feature flags get the message format their flags select
+        self.runtime().block_on(self.connections.broadcast_to_component_by_flags(&component_id, &message, |flags| {
+            self.flagged_patch_message(flags, wire_id.clone(), &old_html, &new_html)
+        }))
+    }
+
+    /// Snapshot component registrations as JSON (`{component_id: [connection_id]}`)
+    /// Lets the JS layer re-sync after a hot reload without dropping connections
+    #[napi]
+    pub fn export_registrations(&self) -> napi::Result<String> {
+        serde_json::to_string(&self.connections.export_registrations())
+            .map_err(|e| napi::Error::from_reason(format!("Registration export failed: {}", e)))
+    }
+
+    /// Get the `top_n` components with the most connections as a JSON array of
+    /// `{component_id, connection_count}`, busiest first
+    #[napi]
+    pub fn get_component_stats(&self, top_n: u32) -> napi::Result<String> {
+        serde_json::to_string(&self.connections.component_stats(top_n as usize))
+            .map_err(|e| napi::Error::from_reason(format!("Component stats serialization failed: {}", e)))
+    }
+
+    /// Restore component registrations from `export_registrations` output
+    /// Returns the number of registrations restored; closed connections are skipped
+    #[napi]
+    pub fn import_registrations(&self, json: String) -> napi::Result<u32> {
+        let registrations: std::collections::HashMap<String, Vec<String>> = serde_json::from_str(&json)
+            .map_err(|e| napi::Error::from_reason(format!("Invalid registrations: {}", e)))?;
+        Ok(self.connections.import_registrations(registrations) as u32)
+    }
+
+    /// Unregister a component from a connection
+    #[napi]
+    pub fn unregister_component(&self, component_id: String, connection_id: String) -> napi::Result<()> {
+        self.connections
+            .unregister_component(&component_id, &connection_id)
+            .map_err(napi::Error::from)
+    }
+
+    /// Forward a pub/sub channel's messages to a connection on behalf of one of its components
+    /// The subscription ends when the component's last connection closes
+    #[napi]
+    pub fn subscribe_channel(&self, connection_id: String, component_id: String, channel: String) -> napi::Result<()> {
+        self.runtime().block_on(async {
+            self.connections
+                .subscribe_channel(&connection_id, &component_id, &channel)
+                .await
+                .map_err(napi::Error::from)
+        })
+    }
+
+    /// Stop forwarding a channel to a component
+    #[napi]
+    pub fn unsubscribe_channel(&self, component_id: String, channel: String) -> napi::Result<()> {
+        self.runtime()
+            .block_on(self.pubsub.unsubscribe(&channel, &component_id))
+            .map_err(napi::Error::from)
+    }
+
+    /// Publish to many channels in one call, e.g. one simulation tick's output
+    /// Channels nobody subscribed to are skipped. Returns the number of receivers reached
+    #[napi]
+    pub fn broadcast_many(&self, messages: Vec<ChannelMessage>) -> napi::Result<u32> {
+        let messages = messages.into_iter().map(|entry| (entry.channel, entry.message)).collect();
+        self.runtime()
+            .block_on(self.pubsub.broadcast_many(messages))
+            .map(|delivered| delivered as u32)
+            .map_err(napi::Error::from)
+    }
+}
+
+impl Drop for LiveTSWebSocketBroker {
+    /// Releases the listener and runtime when the broker is collected without `stop()`
+    fn drop(&mut self) {
+        self.shutdown.insert("stop", true);
+        if let Some(handle) = self.listener_task.take() {
+            handle.abort();
+        }
+
+        // Connection tasks are dropped with the runtime, closing their sockets
+        if let Some(rt) = self.rt.take().and_then(|rt| Arc::try_unwrap(rt).ok()) {
+            if tokio::runtime::Handle::try_current().is_ok() {
+                // Blocking on shutdown inside another runtime would panic
+                rt.shutdown_background();
+            } else {
+                rt.shutdown_timeout(std::time::Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+/// Sent in answer to a replay request the buffer no longer reaches back for; the client
+/// then reports its components' HTML as on a fresh connection
+const REPLAY_UNAVAILABLE: &str = r#"{"t":"r"}"#;
+
+/// The sequence number in a client's `"r|seq"` replay request, sent after reconnecting
+/// with the last `q` it saw
+fn replay_request(text: &str) -> Option<u64> {
+    text.strip_prefix("\"r|")?.strip_suffix('"')?.parse().ok()
+}
+
+/// Receives the next outbound message, draining the high-priority queue first
+/// Returns `None` once the normal queue is closed, which ends the connection
+async fn next_outbound(
+    high: &mut UnboundedReceiver<String>,
+    normal: &mut UnboundedReceiver<String>,
+) -> Option<String> {
+    tokio::select! {
+        biased;
+        Some(msg) = high.recv() => Some(msg),
+        msg = normal.recv() => msg,
+    }
+}
+
+/// Limits for incoming messages
+///
+/// tungstenite reassembles fragmented messages (continuation frames) before they are
+/// yielded, so handlers always see the complete payload up to `max_message_size`.
+fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(64 << 20),
+        max_frame_size: Some(16 << 20),
+        ..Default::default()
+    }
+}
+
+/// Removes a connection and emits its `Closed` event when its task ends
+///
+/// Runs on drop so a panic in the connection task still cleans up, reported as
+/// `InternalError`. Relies on panics unwinding, which the release profile keeps
+struct ConnectionGuard {
+    connection_id: String,
+    connections: Arc<connection::ConnectionManager>,
+    handler: Option<EventSink>,
+    /// How the connection ended, set when the task finishes normally
+    close_reason: Option<(CloseCause, Option<CloseFrame<'static>>)>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let _ = self.connections.remove_connection(&self.connection_id);
+        let evt = match (&self.close_reason, std::thread::panicking()) {
+            (Some((cause, frame)), _) => closed_event(&self.connection_id, *cause, frame.as_ref()),
+            (None, true) => {
+                tracing::error!("connection task panicked: {}", self.connection_id);
+                BrokerEvent::Closed {
+                    connection_id: self.connection_id.clone(),
+                    cause: CloseCause::InternalError,
+                    code: None,
+                    reason: Some("internal error".to_string()),
+                }
+            }
+            // Cancelled with the runtime; nobody is left to hear about it
+            (None, false) => return,
+        };
+        if let Some(sink) = &self.handler {
+            match serde_json::to_string(&evt) {
+                Ok(json) => {
+                    tracing::info!("🔌 Sending Closed event: {}", json);
+                    // Drop can't wait for queue room, so a task does unless the runtime is gone
+                    match tokio::runtime::Handle::try_current() {
+                        Ok(runtime) => {
+                            let sink = sink.clone();
+                            runtime.spawn(async move {
+                                let status = sink.emit(json).await;
+                                if status != napi::Status::Ok {
+                                    tracing::error!("❌ Failed to call JS handler for Closed: {:?}", status);
+                                }
+                            });
+                        }
+                        Err(_) => {
+                            let status = sink.try_emit(json);
+                            if status != napi::Status::Ok {
+                                tracing::error!("❌ Failed to call JS handler for Closed: {:?}", status);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("❌ Failed to serialize Closed event: {:?}", e);
+                }
+            }
+        }
+        tracing::info!("WS removed: {}", self.connection_id);
+    }
+}
+
+/// Message that makes a connection task panic, to exercise `ConnectionGuard`
+#[cfg(test)]
+const PANIC_TEST_MESSAGE: &str = "__livets_panic__";
+
+async fn handle_connection(
+    stream: tokio::net::TcpStream,
+    connections: Arc<connection::ConnectionManager>,
+    handshake_config: Arc<HandshakeConfig>,
+    handler: Option<EventSink>,
+    flag_callback: Option<ThreadsafeFunction<String>>,
+    ping_timeout: Option<std::time::Duration>,
+) {
+    let mut subprotocol: Option<String> = None;
+    let mut presented_token: Option<String> = None;
+    // Held for the whole connection, so it's freed only after the guard removes it
+    let mut permit: Option<connection::ConnectionPermit> = None;
+    let negotiate = |request: &Request, mut response: Response| -> std::result::Result<Response, ErrorResponse> {
+        if !handshake_config.accepts_path(request.uri().path()) {
+            let mut error = ErrorResponse::new(Some(format!("No WebSocket endpoint at {}", request.uri().path())));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            return Err(error);
+        }
+
+        permit = connections.try_admit();
+        if permit.is_none() {
+            let mut error = ErrorResponse::new(Some("Server full".to_string()));
+            *error.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Err(error);
+        }
+
+        presented_token = handshake::reconnect_token(
+            request.uri().query(),
+            request
+                .headers()
+                .get(handshake::RECONNECT_HEADER)
+                .and_then(|value| value.to_str().ok()),
+        );
+
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok());
+
+        match handshake_config.negotiate_subprotocol(offered) {
+            Ok(Some(protocol)) => {
+                if let Ok(value) = HeaderValue::from_str(&protocol) {
+                    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+                    subprotocol = Some(protocol);
+                }
+                Ok(response)
+            }
+            Ok(None) => Ok(response),
+            Err(reason) => {
+                let mut error = ErrorResponse::new(Some(reason));
+                *error.status_mut() = StatusCode::BAD_REQUEST;
+                Err(error)
+            }
+        }
+    };
+
+    let ws_stream = match accept_hdr_async_with_config(stream, negotiate, Some(websocket_config())).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            tracing::error!("websocket accept error: {}", e);
+            return;
+        }
+    };
+
+    let (mut write, mut read) = ws_stream.split();
+
+    // channel to receive outbound messages destined for this client
+    let (tx, mut rx): (tokio::sync::mpsc::UnboundedSender<String>, UnboundedReceiver<String>) = unbounded_channel();
+    let (priority_tx, mut priority_rx): (tokio::sync::mpsc::UnboundedSender<String>, UnboundedReceiver<String>) =
+        unbounded_channel();
+    let (close_tx, mut close_rx) = unbounded_channel::<(u16, String)>();
+
+    // assign a session id
+    let connection_id = Uuid::new_v4().to_string();
+    let close_reason: (CloseCause, Option<CloseFrame<'static>>);
+
+    // register in connection manager and attach sender
+    if let Err(e) = connections.add_connection(connection_id.clone()) {
+        tracing::error!("Failed to add connection: {}", e);
+        return;
+    }
+    let mut guard = ConnectionGuard {
+        connection_id: connection_id.clone(),
+        connections: connections.clone(),
+        handler: handler.clone(),
+        close_reason: None,
+    };
+    let _ = connections.attach_sender(&connection_id, tx);
+    let _ = connections.attach_priority_sender(&connection_id, priority_tx);
+    let _ = connections.attach_close_sender(&connection_id, close_tx);
+    let _ = connections.set_subprotocol(&connection_id, subprotocol.clone());
+
+    tracing::info!("WS connected: {} (subprotocol: {:?})", connection_id, subprotocol);
+
+    // An unknown or expired token just starts a fresh session
+    let resumed = match presented_token.map(|token| connections.resume_session(&token, &connection_id)) {
+        Some(Ok(Some(components))) => {
+            tracing::info!("WS resumed session: {} ({} components)", connection_id, components.len());
+            true
+        }
+        _ => false,
+    };
+    let reconnect_token = connections.issue_reconnect_token(&connection_id).ok().flatten();
+
+    if let Some(callback) = &flag_callback {
+        match js_connection_flags(callback, &connection_id).await {
+            Ok(flags) => {
+                let _ = connections.set_connection_flags(&connection_id, flags);
+            }
+            Err(e) => tracing::warn!("Flag callback failed for {}: {}", connection_id, e),
+        }
+    }
+
+    if let Some(sink) = &handler {
+        let evt = BrokerEvent::Connected {
+            connection_id: connection_id.clone(),
+            subprotocol,
+            reconnect_token,
+            resumed,
+            flags: connections.get_connection_flags(&connection_id).unwrap_or_default(),
+        };
+        match serde_json::to_string(&evt) {
+            Ok(json) => {
+                let status = sink.emit(json).await;
+                if status != napi::Status::Ok {
+                    tracing::error!("❌ Failed to call JS handler for Connected: {:?}", status);
+                }
+            }
+            Err(e) => {
+                tracing::error!("❌ Failed to serialize Connected event: {:?}", e);
+            }
+        }
+    }
+
+    // With a ping timeout, tick often enough to notice a silent client soon after it expires
+    let tick = ping_timeout
+        .map(|timeout| (timeout / 4).max(std::time::Duration::from_millis(1)))
+        .unwrap_or(std::time::Duration::from_secs(25));
+    let mut interval = tokio::time::interval(tick);
+    let mut last_inbound_ping = connections.clock().now_ms();
+
+    loop {
+        tokio::select! {
+            // Server-requested close, sent as a Close frame rather than a text message
+            Some((code, reason)) = close_rx.recv() => {
+                let frame = CloseFrame { code: code.into(), reason: reason.into() };
+                if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Close(Some(frame.clone()))).await {
+                    tracing::warn!("close send error ({}): {}", connection_id, e);
+                }
+                close_reason = (CloseCause::ServerRequest, Some(frame));
+                break;
+            }
+            // Outgoing from application to client
+            maybe_msg = next_outbound(&mut priority_rx, &mut rx) => {
+                match maybe_msg {
+                    Some(msg) => {
+                        let len = msg.len();
+                        if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(msg)).await {
+                            tracing::warn!("write error ({}): {}", connection_id, e);
+                            close_reason = (CloseCause::WriteError, None);
+                            break;
+                        }
+                        connections.record_bytes_sent(&connection_id, len);
+                    },
+                    None => {
+                        tracing::debug!("sender closed for {}", connection_id);
+                        close_reason = (CloseCause::ServerClose, None);
+                        break;
+                    }
+                }
+            }
+            // Incoming from client
+            incoming = read.next() => {
+                match incoming {
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Text(text))) => {
+                        let _ = connections.update_inbound(&connection_id);
+                        #[cfg(test)]
+                        if text == PANIC_TEST_MESSAGE {
+                            panic!("test panic in connection {}", connection_id);
+                        }
+                        if text == "\"p\"" {
+                            let _ = connections.update_ping(&connection_id);
+                            last_inbound_ping = connections.clock().now_ms();
+                            if ping_timeout.is_some() {
+                                let pong = "\"P\"".to_string();
+                                if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(pong)).await {
+                                    tracing::warn!("pong send error ({}): {}", connection_id, e);
+                                } else {
+                                    connections.record_bytes_sent(&connection_id, 3);
+                                }
+                            }
+                        }
+                        // Replay requests are answered here, the JS layer never sees them
+                        if let Some(seq) = replay_request(&text) {
+                            let replies = match connections.replay_since(&connection_id, seq) {
+                                Ok(Some(messages)) => messages,
+                                _ => vec![REPLAY_UNAVAILABLE.to_string()],
+                            };
+                            for reply in replies {
+                                let len = reply.len();
+                                if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Text(reply)).await {
+                                    tracing::warn!("replay send error ({}): {}", connection_id, e);
+                                    break;
+                                }
+                                connections.record_bytes_sent(&connection_id, len);
+                            }
+                            continue;
+                        }
+                        if let Some(sink) = &handler {
+                            let evt = BrokerEvent::Message { connection_id: connection_id.clone(), data: text };
+                            match serde_json::to_string(&evt) {
+                                Ok(json) => {
+                                    let status = sink.emit(json).await;
+                                    if status != napi::Status::Ok {
+                                        tracing::error!("❌ Failed to call JS handler for Message: {:?}", status);
+                                    }
+                                }
+                                Err(e) => {
+                                    tracing::error!("❌ Failed to serialize Message event: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(_bin))) => {
+                        // ignore binary for now
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Ping(payload))) => {
+                        let _ = connections.update_inbound(&connection_id);
+                        let _ = connections.update_ping(&connection_id);
+                        let len = payload.len();
+                        if let Err(e) = write.send(tokio_tungstenite::tungstenite::Message::Pong(payload)).await {
+                            tracing::warn!("pong send error: {}", e);
+                        } else {
+                            connections.record_bytes_sent(&connection_id, len);
+                        }
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Pong(_))) => {
+                        // no-op
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Frame(frame))) => {
+                        // Fragmented messages arrive reassembled as Text/Binary; a raw frame should
+                        // never surface from a read, but if it does treat its payload as a message
+                        tracing::warn!("unexpected raw frame from {}", connection_id);
+                        if let (Some(sink), Ok(text)) = (&handler, String::from_utf8(frame.into_data())) {
+                            let evt = BrokerEvent::Message { connection_id: connection_id.clone(), data: text };
+                            if let Ok(json) = serde_json::to_string(&evt) {
+                                sink.emit(json).await;
+                            }
+                        }
+                    }
+                    Some(Ok(tokio_tungstenite::tungstenite::Message::Close(frame))) => {
+                        tracing::info!("client requested close: {} ({:?})", connection_id, frame);
+                        close_reason = (CloseCause::ClientClose, frame);
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!("read error ({}): {}", connection_id, e);
+                        close_reason = (CloseCause::ReadError, None);
+                        break;
+                    }
+                    None => {
+                        tracing::info!("client disconnected: {}", connection_id);
+                        close_reason = (CloseCause::ClientDisconnect, None);
+                        break;
+                    }
+                }
+            }
+            // Heartbeat
+            _ = interval.tick() => {
+                if ping_timeout.is_some_and(|timeout| {
+                    connections.clock().now_ms().saturating_sub(last_inbound_ping) > timeout.as_millis() as u64
+                }) {
+                    tracing::info!("ping timeout: {}", connection_id);
+                    let frame = CloseFrame { code: 4000u16.into(), reason: "ping timeout".into() };
+                    let _ = write.send(tokio_tungstenite::tungstenite::Message::Close(Some(frame.clone()))).await;
+                    close_reason = (CloseCause::Timeout, Some(frame));
+                    break;
+                }
+            }
+        }
+    }
+
+    guard.close_reason = Some(close_reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestClient = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+    /// A broker listening on a free port, with a runtime for the test's WebSocket clients
+    struct TestBroker {
+        broker: LiveTSWebSocketBroker,
+        events: UnboundedReceiver<String>,
+        client_rt: Runtime,
+        url: String,
+    }
+
+    impl TestBroker {
+        /// Starts `broker` with its events going to `next_event`, unless it already has an event sender
+        fn start(mut broker: LiveTSWebSocketBroker) -> Self {
+            let (events_tx, events) = unbounded_channel();
+            if !broker.event_handler.contains_key("handler") {
+                broker.set_event_sender(events_tx);
+            }
+            broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+            let url = format!("ws://127.0.0.1:{}/livets-ws", broker.port().unwrap());
+            Self { broker, events, client_rt: Runtime::new().unwrap(), url }
+        }
+
+        fn connect(&self) -> TestClient {
+            let (ws, _) = self.client_rt.block_on(tokio_tungstenite::connect_async(&self.url)).unwrap();
+            ws
+        }
+
+        fn next_event(&mut self) -> BrokerEvent {
+            let json = self
+                .client_rt
+                .block_on(tokio::time::timeout(std::time::Duration::from_secs(5), self.events.recv()))
+                .unwrap()
+                .unwrap();
+            serde_json::from_str(&json).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_render_if_changed_skips_identical_content() {
+        let engine = LiveTSEngine::new();
+        assert!(engine.render_if_changed("counter".to_string(), "<div id=\"count\">5</div>".to_string()).is_err());
+
+        engine.cache_component_html("counter".to_string(), "<div id=\"count\">5</div>".to_string());
+        let unchanged = engine.render_if_changed("counter".to_string(), "<div id=\"count\">5</div>".to_string()).unwrap();
+        assert_eq!(unchanged, None);
+
+        let stats: serde_json::Value = serde_json::from_str(&engine.get_render_stats()).unwrap();
+        assert_eq!((stats["diffs_run"].as_u64(), stats["diffs_skipped"].as_u64()), (Some(0), Some(1)));
+
+        let message = engine.render_if_changed("counter".to_string(), "<div id=\"count\">6</div>".to_string()).unwrap();
+        assert!(message.unwrap().contains("t|#count|6"));
+        assert_eq!(engine.get_cached_html("counter".to_string()).as_deref(), Some("<div id=\"count\">6</div>"));
+        let stats: serde_json::Value = serde_json::from_str(&engine.get_render_stats()).unwrap();
+        assert_eq!(stats["diffs_run"].as_u64(), Some(1));
+    }
+
+    #[test]
+    fn test_build_multi_component_message() {
+        let engine = LiveTSEngine::new();
+        let message = engine
+            .build_multi_component_message(vec![
+                ComponentPatches {
+                    component_id: "counter-1234567890".to_string(),
+                    patches: vec!["t|#count|6".to_string(), "a|#count|class|text-red-600".to_string()],
+                },
+                ComponentPatches {
+                    component_id: "todo".to_string(),
+                    patches: vec![r#"e|[data-key="3"]|<li data-key="3">Done</li>"#.to_string()],
+                },
+            ])
+            .unwrap();
+
+        let parsed: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(parsed["t"], "pm");
+        let updates = parsed["u"].as_array().unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0]["c"], "counter-");
+        assert_eq!(
+            updates[0]["d"],
+            serde_json::json!(["t|#count|6", "a|#count|class|text-red-600"])
+        );
+        assert_eq!(updates[1]["c"], "todo");
+        assert_eq!(
+            updates[1]["d"],
+            serde_json::json!([r#"e|[data-key="3"]|<li data-key="3">Done</li>"#])
+        );
+    }
+
+    #[test]
+    fn test_build_remove_component_message() {
+        let engine = LiveTSEngine::new();
+        let component_id = "chat-room-42".to_string();
+        engine.cache_component_html(component_id.clone(), "<div>hi</div>".to_string());
+
+        let message = engine.build_remove_component_message(component_id.clone()).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(parsed["t"], "p");
+        assert_eq!(parsed["c"], "chat-roo");
+        assert_eq!(parsed["d"], serde_json::json!([r#"x|[data-livets-id="chat-room-42"]"#]));
+
+        assert_eq!(engine.get_cached_html(component_id), None);
+    }
+
+    #[test]
+    fn test_render_component_objects() {
+        let engine = LiveTSEngine::new();
+        let patches = engine
+            .render_component_objects(
+                "comp1".to_string(),
+                r#"<div id="count" class="text-4xl">5</div>"#.to_string(),
+                r#"<div id="count" class="text-4xl">6</div>"#.to_string(),
+            )
+            .unwrap();
+
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].kind, "UpdateText");
+        assert_eq!(patches[0].selector.as_deref(), Some("#count"));
+        assert_eq!(patches[0].text.as_deref(), Some("6"));
+        assert_eq!(patches[0].html, None);
+    }
+
+    #[test]
+    fn test_js_dom_patch_insert_position() {
+        let patch = JsDomPatch::from(DomPatch::InsertElement {
+            parent: "#list".to_string(),
+            position: InsertPosition::BeforeEnd,
+            html: "<li>new</li>".to_string(),
+        });
+
+        assert_eq!(patch.kind, "InsertElement");
+        assert_eq!(patch.parent.as_deref(), Some("#list"));
+        assert_eq!(patch.position.as_deref(), Some("BeforeEnd"));
+        assert_eq!(patch.selector, None);
+    }
+
+    #[test]
+    fn test_closed_event_reports_client_close_code() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        let frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "navigated away".into(),
+        };
+        let evt = closed_event("conn-1", CloseCause::ClientClose, Some(&frame));
+        let json: serde_json::Value = serde_json::to_value(&evt).unwrap();
+
+        assert_eq!(json["type"], "Closed");
+        assert_eq!(json["connection_id"], "conn-1");
+        assert_eq!(json["cause"], "client_close");
+        assert_eq!(json["code"], 1001);
+        assert_eq!(json["reason"], "navigated away");
+    }
+
+    #[test]
+    fn test_closed_event_without_frame() {
+        let evt = closed_event("conn-1", CloseCause::ReadError, None);
+        let json: serde_json::Value = serde_json::to_value(&evt).unwrap();
+
+        assert_eq!(json["cause"], "read_error");
+        assert!(json["code"].is_null());
+        assert!(json["reason"].is_null());
+    }
+
+    #[test]
+    fn test_clear_cache_forces_full_render() {
+        let engine = LiveTSEngine::new();
+        let old_html = r#"<div data-livets-id="comp1"><span id="count">1</span></div>"#;
+        let new_html = r#"<div data-livets-id="comp1"><span id="count">2</span></div>"#;
+        engine.cache_component_html("comp1".to_string(), old_html.to_string());
+        engine.cache_component_html("comp2".to_string(), old_html.to_string());
+        assert_eq!(engine.cache_size(), 2);
+        engine
+            .render_component_message("comp1".to_string(), old_html.to_string(), new_html.to_string())
+            .unwrap();
+
+        engine.clear_cache();
+        assert_eq!(engine.cache_size(), 0);
+
+        let cached = engine.get_cached_html("comp1".to_string()).unwrap_or_default();
+        let message = engine
+            .render_component_message("comp1".to_string(), cached, new_html.to_string())
+            .unwrap();
+        assert!(message.contains("\"h|[data-livets-root]|"));
+        // Versions restart along with the cached HTML
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(message["b"], 0);
+    }
+
+    #[test]
+    fn test_listen_rebinds_port_after_stop() {
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        let options = ListenOptions {
+            backlog: Some(16),
+            reuse_address: Some(true),
+            paths: None,
+        };
+        broker.listen("127.0.0.1".to_string(), 0, Some(options.clone())).unwrap();
+        let port = broker.port().unwrap() as u16;
+        assert!(port > 0);
+
+        broker.stop().unwrap();
+        assert_eq!(broker.port(), None);
+
+        broker.listen("127.0.0.1".to_string(), port, Some(options)).unwrap();
+        assert_eq!(broker.port(), Some(port as u32));
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_health_tracks_listener_state() {
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        let health = broker.health_report();
+        assert!(!health.listening);
+        assert_eq!(health.connections, 0);
+        assert_eq!(health.accept_errors, 0);
+
+        broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+        assert!(broker.health_report().listening);
+
+        // A dead listener task must be reported as not listening
+        if let Some(handle) = &broker.listener_task {
+            handle.abort();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!broker.health_report().listening);
+
+        broker.stop().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&broker.health().unwrap()).unwrap();
+        assert_eq!(json["listening"], false);
+        assert_eq!(json["bytes_sent"], 0);
+        assert!(json["uptime_ms"].is_u64());
+    }
+
+    #[test]
+    fn test_drop_releases_port() {
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+        let port = broker.port().unwrap() as u16;
+
+        drop(broker);
+
+        let rebound = std::net::TcpListener::bind(("127.0.0.1", port));
+        assert!(rebound.is_ok(), "port {} still held after drop", port);
+    }
+
+    #[test]
+    fn test_drop_after_stop() {
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+        broker.stop().unwrap();
+        drop(broker);
+    }
+
+    #[tokio::test]
+    async fn test_priority_message_written_before_queued_normals() {
+        let (tx, mut rx) = unbounded_channel::<String>();
+        let (priority_tx, mut priority_rx) = unbounded_channel::<String>();
+
+        for i in 0..10 {
+            tx.send(format!("normal-{}", i)).unwrap();
+        }
+        // Writer is already busy with the first queued message when the urgent one arrives
+        assert_eq!(next_outbound(&mut priority_rx, &mut rx).await.as_deref(), Some("normal-0"));
+        priority_tx.send("reconnect".to_string()).unwrap();
+        drop(tx);
+
+        let mut written = Vec::new();
+        while let Some(msg) = next_outbound(&mut priority_rx, &mut rx).await {
+            written.push(msg);
+        }
+
+        assert_eq!(written.len(), 10);
+        assert_eq!(written[0], "reconnect");
+        let expected: Vec<String> = (1..10).map(|i| format!("normal-{}", i)).collect();
+        assert_eq!(&written[1..], expected.as_slice());
+    }
+
+    #[test]
+    fn test_fragmented_message_reaches_handler() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+        use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut test = TestBroker::start(LiveTSWebSocketBroker::new(None).unwrap());
+        let payload = "x".repeat(200_000);
+        let (first, rest) = payload.split_at(70_000);
+        let mut ws = test.connect();
+        test.client_rt.block_on(async {
+            ws.send(Message::Frame(Frame::message(first.as_bytes().to_vec(), OpCode::Data(Data::Text), false)))
+                .await
+                .unwrap();
+            ws.send(Message::Frame(Frame::message(rest.as_bytes().to_vec(), OpCode::Data(Data::Continue), true)))
+                .await
+                .unwrap();
+        });
+
+        let received = loop {
+            if let BrokerEvent::Message { data, .. } = test.next_event() {
+                break data;
+            }
+        };
+        assert_eq!(received.len(), payload.len());
+        assert_eq!(received, payload);
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_new_broker_leaves_global_subscriber_alone() {
+        let broker = LiveTSWebSocketBroker::new(None).unwrap();
+        assert!(!tracing::dispatcher::has_been_set());
+        drop(broker);
+    }
+
+    #[test]
+    fn test_try_parse_event_branches() {
+        let engine = LiveTSEngine::new();
+        let result = |raw: &str| -> serde_json::Value { serde_json::from_str(&engine.try_parse_event(raw.to_string())).unwrap() };
+
+        let ok = result(r#""e|abc12345|increment||0|button""#);
+        assert_eq!(ok["ok"], true);
+        assert_eq!(ok["event"]["component_id"], "abc12345");
+        assert_eq!(ok["event"]["event_name"], "increment");
+
+        let ping = result("\"p\"");
+        assert_eq!(ping["ok"], false);
+        assert_eq!(ping["kind"], "ping");
+
+        let invalid = result("not an event");
+        assert_eq!(invalid["ok"], false);
+        assert_eq!(invalid["kind"], "invalid");
+        assert_eq!(invalid["code"], "E_INVALID_INPUT");
+
+        // Parses fine but the component id is too short to be valid
+        let validation = result(r#""e|abc|increment||0|button""#);
+        assert_eq!(validation["ok"], false);
+        assert_eq!(validation["kind"], "validation");
+        assert!(validation["message"].as_str().unwrap().contains("too short"));
+    }
+
+    #[test]
+    fn test_classify_message() {
+        let engine = LiveTSEngine::new();
+        let kind = |raw: &str| engine.classify_message(raw.to_string());
+
+        assert_eq!(kind("\"p\""), MessageKind::Ping);
+        assert_eq!(kind(r#"{"type":"ping"}"#), MessageKind::Ping);
+        assert_eq!(kind(r#""e|abc12345|increment||0|button""#), MessageKind::CompactEvent);
+        assert_eq!(kind(r#"{"type":"event","componentId":"abc12345","eventName":"go"}"#), MessageKind::JsonEvent);
+        assert_eq!(kind(r#"{"type":"subscribe","channel":"news"}"#), MessageKind::Control);
+        assert_eq!(kind(r#""c|init|abc12345|<p>5</p>""#), MessageKind::Init);
+        assert_eq!(kind("hello"), MessageKind::Unknown);
+        assert_eq!(kind(""), MessageKind::Unknown);
+
+        assert!(engine.is_ping_message("\"p\"".to_string()));
+        assert!(!engine.is_ping_message("\"p\" ".to_string()));
+        // Only the compact ping is a ping here; classify_message also knows the JSON form
+        assert!(!engine.is_ping_message(r#"{"type":"ping"}"#.to_string()));
+    }
+
+    #[test]
+    fn test_process_events_isolates_failures() {
+        let engine = LiveTSEngine::new();
+        let batch = vec![
+            r#""e|abc12345|increment||0|button""#.to_string(),
+            "\"p\"".to_string(),
+            "not an event".to_string(),
+            r#""e|abc12345|decrement||0|button""#.to_string(),
+        ];
+
+        let results: serde_json::Value = serde_json::from_str(&engine.process_events(batch)).unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0]["event"]["event_name"], "increment");
+        assert_eq!(results[1]["kind"], "ping");
+        assert_eq!(results[2]["kind"], "invalid");
+        assert_eq!(results[3]["ok"], true);
+        assert_eq!(results[3]["event"]["event_name"], "decrement");
+
+        assert_eq!(engine.process_events(Vec::new()), "[]");
+    }
+
+    #[test]
+    fn test_render_component_message_with_empty_html() {
+        let engine = LiveTSEngine::new();
+        let render = |old: &str, new: &str| -> serde_json::Value {
+            serde_json::from_str(&engine.render_component_message("counter1".to_string(), old.to_string(), new.to_string()).unwrap()).unwrap()
+        };
+
+        // Rendering nothing clears the root
+        let cleared = render(r#"<div id="count">5</div>"#, "");
+        assert_eq!(cleared["c"], "counter1");
+        assert_eq!(cleared["d"], serde_json::json!(["h|[data-livets-root]|"]));
+
+        // First render into an empty root
+        let first = render("", r#"<div id="count" class="a">5</div>"#);
+        assert_eq!(first["d"], serde_json::json!([r#"h|[data-livets-root]|<div id="count" class="a">5</div>"#]));
+
+        let nothing = render("", "");
+        assert_eq!(nothing["d"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_render_message_flags_full_replace() {
+        let engine = LiveTSEngine::new();
+        let render = |old: &str, new: &str| -> serde_json::Value {
+            serde_json::from_str(&engine.render_component_message("counter1".to_string(), old.to_string(), new.to_string()).unwrap()).unwrap()
+        };
+
+        let full = render("<section>Loading...</section>", "<table><tr><th>Name</th></tr></table>");
+        assert_eq!(full["d"].as_array().unwrap().len(), 1);
+        assert_eq!(full["f"], 1);
+
+        let granular = render(r#"<div id="count">5</div>"#, r#"<div id="count">6</div>"#);
+        assert_eq!(granular["d"], serde_json::json!(["t|#count|6"]));
+        assert!(granular.get("f").is_none());
+    }
+
+    #[test]
+    fn test_selector_dictionary_lists_each_selector_once() {
+        let mut engine = LiveTSEngine::new();
+        engine.set_selector_dictionary(true);
+
+        let message = engine
+            .render_component_message(
+                "counter1".to_string(),
+                r#"<div id="counter" class="a">5</div>"#.to_string(),
+                r#"<div id="counter" class="b">6</div>"#.to_string(),
+            )
+            .unwrap();
+        assert_eq!(message.matches("#counter").count(), 1);
+
+        let parsed: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(parsed["sel"], serde_json::json!(["#counter"]));
+        let mut patches: Vec<&str> = parsed["d"].as_array().unwrap().iter().map(|p| p.as_str().unwrap()).collect();
+        patches.sort();
+        assert_eq!(patches, vec!["a|0|class|b", "t|0|6"]);
+    }
+
+    #[test]
+    fn test_short_id_respects_char_boundaries() {
+        assert_eq!(short_id("abc"), "abc");
+        assert_eq!(short_id("abcdefghij"), "abcdefgh");
+        assert_eq!(short_id("ääääääääää"), "ääääääää");
+    }
+
+    #[test]
+    fn test_opaque_component_ids() {
+        let mut engine = LiveTSEngine::new();
+        let component_id = "3f2b9c1e-7d4a-4e55-9a0b-6c1d2e3f4a5b".to_string();
+        engine.set_opaque_component_ids(true);
+
+        let message = engine
+            .render_component_message(component_id.clone(), r#"<p id="n">1</p>"#.to_string(), r#"<p id="n">2</p>"#.to_string())
+            .unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(parsed["c"], "c1");
+        assert!(!message.contains("3f2b9c1e"));
+
+        // Inbound events naming the token are routed to the real component
+        let event = engine.parse_event_message(r#""e|c1|increment||0|button""#.to_string()).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&event).unwrap();
+        assert_eq!(event["component_id"], component_id.as_str());
+        assert_eq!(engine.resolve_component_token("c1".to_string()), Some(component_id.clone()));
+
+        // Removing the component targets its token in the DOM and retires it
+        let removal: serde_json::Value =
+            serde_json::from_str(&engine.build_remove_component_message(component_id.clone()).unwrap()).unwrap();
+        assert_eq!(removal["c"], "c1");
+        assert_eq!(removal["d"], serde_json::json!([r#"x|[data-livets-id="c1"]"#]));
+        assert_eq!(engine.resolve_component_token("c1".to_string()), None);
+
+        engine.set_opaque_component_ids(false);
+        assert_eq!(engine.component_token(component_id), "3f2b9c1e");
+    }
+
+    #[test]
+    fn test_close_connection_with_custom_code() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut test = TestBroker::start(LiveTSWebSocketBroker::new(None).unwrap());
+        let mut ws = test.connect();
+        let BrokerEvent::Connected { connection_id, .. } = test.next_event() else {
+            panic!("expected Connected event");
+        };
+
+        assert!(test.broker.close_connection(connection_id.clone(), 1006, String::new()).is_err());
+        test.broker
+            .close_connection(connection_id.clone(), 4001, "session expired".to_string())
+            .unwrap();
+
+        let frame = test.client_rt.block_on(async {
+            loop {
+                match tokio::time::timeout(std::time::Duration::from_secs(5), ws.next()).await.unwrap() {
+                    Some(Ok(Message::Close(frame))) => break frame.unwrap(),
+                    Some(Ok(_)) => continue,
+                    other => panic!("expected Close frame, got {:?}", other),
+                }
+            }
+        });
+        assert_eq!(u16::from(frame.code), 4001);
+        assert_eq!(frame.reason, "session expired");
+
+        match test.next_event() {
+            BrokerEvent::Closed { connection_id: closed_id, cause, code, reason } => {
+                assert_eq!(closed_id, connection_id);
+                assert_eq!(cause, CloseCause::ServerRequest);
+                assert_eq!(code, Some(4001));
+                assert_eq!(reason.as_deref(), Some("session expired"));
+            }
+            other => panic!("expected Closed event, got {:?}", other),
+        }
+        assert!(test.broker.get_connection_info(connection_id).unwrap().is_none());
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_panicking_connection_is_cleaned_up() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut test = TestBroker::start(LiveTSWebSocketBroker::new(None).unwrap());
+        let mut ws = test.connect();
+        let BrokerEvent::Connected { connection_id, .. } = test.next_event() else {
+            panic!("expected Connected event");
+        };
+
+        test.client_rt
+            .block_on(ws.send(Message::Text(PANIC_TEST_MESSAGE.to_string())))
+            .unwrap();
+        match test.next_event() {
+            BrokerEvent::Closed { connection_id: closed_id, cause, code, reason } => {
+                assert_eq!(closed_id, connection_id);
+                assert_eq!(cause, CloseCause::InternalError);
+                assert_eq!(code, None);
+                assert_eq!(reason.as_deref(), Some("internal error"));
+            }
+            other => panic!("expected Closed event, got {:?}", other),
+        }
+        assert!(test.broker.get_connection_info(connection_id).unwrap().is_none());
+        assert!(serde_json::from_str::<serde_json::Value>(&test.broker.health().unwrap()).unwrap()["listening"]
+            .as_bool()
+            .unwrap());
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_render_messages_chain_base_and_sequence_versions() {
+        let mut engine = LiveTSEngine::new();
+        let render = |component_id: &str, old: &str, new: &str| -> napi::Result<serde_json::Value> {
+            let message = engine.render_component_message(component_id.to_string(), old.to_string(), new.to_string())?;
+            Ok(serde_json::from_str(&message).unwrap())
+        };
+
+        let first = render("counter1", r#"<p id="n">1</p>"#, r#"<p id="n">2</p>"#).unwrap();
+        assert_eq!((first["b"].as_u64(), first["s"].as_u64()), (Some(0), Some(1)));
+        let second = render("counter1", r#"<p id="n">2</p>"#, r#"<p id="n">3</p>"#).unwrap();
+        assert_eq!((second["b"].clone(), second["s"].clone()), (first["s"].clone(), serde_json::json!(2)));
+        let other = render("counter2", r#"<p id="n">1</p>"#, r#"<p id="n">2</p>"#).unwrap();
+        assert_eq!((other["b"].as_u64(), other["s"].as_u64()), (Some(0), Some(1)));
+
+        // A failed diff sends nothing, so the version stays put
+        engine.html_differ = HtmlDiffer::with_config(DifferConfig { strict: true, ..Default::default() });
+        let render = |old: &str, new: &str| {
+            engine.render_component_message("counter1".to_string(), old.to_string(), new.to_string())
+        };
+        assert!(render(r#"<p id="n">3</p>"#, "<p><b>4</p>").is_err());
+        let third = render(r#"<p id="n">3</p>"#, r#"<p id="n">4</p>"#).unwrap();
+        let third: serde_json::Value = serde_json::from_str(&third).unwrap();
+        assert_eq!((third["b"].as_u64(), third["s"].as_u64()), (Some(2), Some(3)));
+
+        let preview: serde_json::Value = serde_json::from_str(
+            &engine.preview_render("counter1".to_string(), r#"<p id="n">4</p>"#.to_string(), r#"<p id="n">5</p>"#.to_string()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(preview["b"], 3);
+        engine.build_remove_component_message("counter1".to_string()).unwrap();
+        let fresh: serde_json::Value = serde_json::from_str(
+            &engine.render_component_message("counter1".to_string(), String::new(), r#"<p id="n">1</p>"#.to_string()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(fresh["b"], 0);
+    }
+
+    #[test]
+    fn test_preview_render_leaves_state_untouched() {
+        let mut engine = LiveTSEngine::new();
+        engine.set_opaque_component_ids(true);
+        engine.cache_component_html("counter-1".to_string(), r#"<p id="n">1</p>"#.to_string());
+
+        let preview = engine
+            .preview_render("counter-1".to_string(), r#"<p id="n">1</p>"#.to_string(), r#"<p id="n">2</p>"#.to_string())
+            .unwrap();
+        let preview: serde_json::Value = serde_json::from_str(&preview).unwrap();
+        assert_eq!(preview["d"], serde_json::json!(["t|#n|2"]));
+
+        assert_eq!(engine.get_cached_html("counter-1".to_string()), Some(r#"<p id="n">1</p>"#.to_string()));
+        assert_eq!(engine.cache_size(), 1);
+        assert_eq!(engine.resolve_component_token("c1".to_string()), None);
+        let stats: serde_json::Value = serde_json::from_str(&engine.get_render_stats()).unwrap();
+        assert_eq!(stats["diffs_run"].as_u64(), Some(0));
+    }
+
+    #[test]
+    fn test_broker_with_worker_thread_count() {
+        assert!(LiveTSWebSocketBroker::new(Some(0)).is_err());
+
+        let mut test = TestBroker::start(LiveTSWebSocketBroker::new(Some(2)).unwrap());
+        let _ws = test.connect();
+        assert!(matches!(test.next_event(), BrokerEvent::Connected { .. }));
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_parse_event_object_matches_json() {
+        let engine = LiveTSEngine::new();
+        engine.cache_component_html("comp-1".to_string(), "<p>hi</p>".to_string());
+        let raw = r#""e|comp-1|input|hello|1|input""#.to_string();
+
+        let object = engine.parse_event_object(raw.clone()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&engine.parse_event_and_get_cache(raw).unwrap()).unwrap();
+        assert_eq!(object.component_id, json["component_id"]);
+        assert_eq!(object.event_name, json["event_name"]);
+        assert_eq!(object.event_type, json["event_data"]["event_type"]);
+        assert_eq!(object.tag_name, json["event_data"]["target"]["tag_name"]);
+        assert_eq!(object.value.as_deref(), json["event_data"]["target"]["value"].as_str());
+        assert_eq!(object.checked, json["event_data"]["target"]["checked"].as_bool());
+        assert_eq!(object.old_html, "<p>hi</p>");
+
+        assert!(engine.parse_event_object("garbage".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_listen_rejects_unknown_paths() {
+        use tokio_tungstenite::tungstenite::Error as WsError;
+
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        let options = ListenOptions {
+            paths: Some(vec!["/livets-ws".to_string()]),
+            ..ListenOptions::default()
+        };
+        broker.listen("127.0.0.1".to_string(), 0, Some(options)).unwrap();
+        let port = broker.port().unwrap();
+
+        let client_rt = Runtime::new().unwrap();
+        client_rt.block_on(async {
+            let accepted = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/livets-ws?v=2", port)).await;
+            assert!(accepted.is_ok());
+
+            let rejected = tokio_tungstenite::connect_async(format!("ws://127.0.0.1:{}/other-ws", port)).await;
+            assert!(matches!(rejected, Err(WsError::Http(response)) if response.status() == StatusCode::NOT_FOUND));
+        });
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_queue_render_coalesces_rapid_renders() {
+        let mut engine = LiveTSEngine::new();
+        let count = |n: u32| format!(r#"<div id="count">{}</div>"#, n);
+
+        // Without a window every render is sent
+        let message = engine.queue_render("counter1".to_string(), count(0), count(1)).unwrap();
+        assert!(message.unwrap().contains("t|#count|1"));
+
+        engine.set_render_coalesce_window(20);
+        for n in 1..4 {
+            assert_eq!(engine.queue_render("counter1".to_string(), count(n), count(n + 1)).unwrap(), None);
+        }
+        assert!(engine.flush_renders().unwrap().is_empty());
+
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        let messages = engine.flush_renders().unwrap();
+        assert_eq!(messages.len(), 1);
+        let message: serde_json::Value = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(message["d"], serde_json::json!(["t|#count|4"]));
+        assert!(engine.flush_renders().unwrap().is_empty());
+
+        // Renders that end where they started send nothing
+        engine.queue_render("counter1".to_string(), count(4), count(5)).unwrap();
+        engine.queue_render("counter1".to_string(), count(5), count(4)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        assert!(engine.flush_renders().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ping_timeout_disconnects_silent_client() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        broker.set_ping_timeout(200);
+        let mut test = TestBroker::start(broker);
+        let mut ws = test.connect();
+        assert!(matches!(test.next_event(), BrokerEvent::Connected { .. }));
+
+        // A ping is answered with a pong and keeps the connection alive
+        let pong = test.client_rt.block_on(async {
+            ws.send(Message::Text("\"p\"".to_string())).await.unwrap();
+            tokio::time::timeout(std::time::Duration::from_secs(5), ws.next()).await.unwrap().unwrap().unwrap()
+        });
+        assert_eq!(pong, Message::Text("\"P\"".to_string()));
+
+        // Then the client goes silent
+        loop {
+            if let BrokerEvent::Closed { cause, code, .. } = test.next_event() {
+                assert_eq!(cause, CloseCause::Timeout);
+                assert_eq!(code, Some(4000));
+                break;
+            }
+        }
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_diff_debug_json() {
+        let engine = LiveTSEngine::new();
+        let json = engine
+            .diff_debug(r#"<p id="n">1</p>"#.to_string(), r#"<p id="n">2</p>"#.to_string())
+            .unwrap();
+        let annotated: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(annotated[0]["patch"]["type"], "UpdateText");
+        assert_eq!(annotated[0]["info"]["strategy"], "id");
+        assert_eq!(annotated[0]["info"]["tag"], "p");
+    }
+
+    #[test]
+    fn test_stalled_event_handler_drops_are_counted() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let broker = LiveTSWebSocketBroker::new(None).unwrap();
+        // Room for the Connected event only, and nobody ever reads it
+        let (events_tx, _stalled_rx) = tokio::sync::mpsc::channel(1);
+        broker.set_bounded_event_sender(events_tx);
+        let mut test = TestBroker::start(broker);
+
+        let mut ws = test.connect();
+        test.client_rt.block_on(async {
+            for n in 0..3 {
+                ws.send(Message::Text(format!(r#""e|abc12345|tick|{}|0|button""#, n))).await.unwrap();
+            }
+        });
+
+        let dropped = || test.broker.health_report().dropped_events;
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        while dropped() < 3 && Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(dropped(), 3);
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_blocking_event_queue_drops_after_timeout() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+        broker.set_event_queue(1, true, Some(50));
+        // Room for the Connected event only; each later event waits 50ms, then is dropped
+        let (events_tx, _stalled_rx) = tokio::sync::mpsc::channel(1);
+        broker.set_bounded_event_sender(events_tx);
+        let mut test = TestBroker::start(broker);
+
+        let mut ws = test.connect();
+        test.client_rt.block_on(async {
+            for n in 0..3 {
+                ws.send(Message::Text(format!(r#""e|abc12345|tick|{}|0|button""#, n))).await.unwrap();
+            }
+        });
+
+        let dropped = || test.broker.health_report().dropped_events;
+        let deadline = Instant::now() + std::time::Duration::from_secs(5);
+        while dropped() < 3 && Instant::now() < deadline {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(dropped(), 3);
+        test.broker.stop().unwrap();
+    }
+
+    #[test]
+    fn test_init_message_sets_first_event_baseline() {
+        let engine = LiveTSEngine::new();
+        let server_html = r#"<div data-livets-id="abc12345"><span id="count" class="big">5</span><button>+</button></div>"#;
+
+        let component_id = engine
+            .apply_init_message(format!("\"c|init|abc12345|{}\"", server_html))
+            .unwrap();
+        assert_eq!(component_id, "abc12345");
+
+        let event: serde_json::Value = serde_json::from_str(
+            &engine
+                .parse_event_and_get_cache(r#""e|abc12345|increment||0|button""#.to_string())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(event["old_html"], server_html);
+
+        let message: serde_json::Value = serde_json::from_str(
+            &engine
+                .process_response_and_generate_message(
+                    component_id,
+                    event["old_html"].as_str().unwrap().to_string(),
+                    server_html.replace(">5<", ">6<"),
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(message["d"], serde_json::json!(["t|#count|6"]));
+        assert!(message.get("f").is_none());
+
+        assert!(engine.apply_init_message(r#""c|init|abc12345""#.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_render_for_connection_respects_flags() {
+        let broker = LiveTSWebSocketBroker::new(Some(1)).unwrap();
+        broker.set_flag_rollouts(std::collections::HashMap::from([(FLAG_COMBINED_PATCHES.to_string(), 0)]));
+        let (legacy, beta) = ("conn-legacy".to_string(), "conn-beta".to_string());
+        broker.connections.add_connection(legacy.clone()).unwrap();
+        broker.connections.add_connection(beta.clone()).unwrap();
+        assert_eq!(broker.get_connection_flags(legacy.clone()), Some(Vec::new()));
+        broker
+            .set_connection_flags(beta.clone(), vec![FLAG_COMBINED_PATCHES.to_string()])
+            .unwrap();
+
+        let old_html = r#"<div id="count" class="text-green-600">5</div>"#.to_string();
+        let new_html = r#"<div id="count" class="text-red-600">6</div>"#.to_string();
+        let render = |connection_id: &String| {
+            let message = broker
+                .render_for_connection(connection_id.clone(), "counter-1".to_string(), old_html.clone(), new_html.clone())
+                .unwrap();
+            serde_json::from_str::<serde_json::Value>(&message).unwrap()["d"].clone()
+        };
+
+        assert_eq!(render(&legacy), serde_json::json!(["a|#count|class|text-red-600", "t|#count|6"]));
+        assert_eq!(render(&beta), serde_json::json!([r##"u|#count|{"class":"text-red-600"}|6"##]));
+        assert!(broker
+            .render_for_connection("missing".to_string(), "counter-1".to_string(), old_html.clone(), new_html.clone())
+            .is_err());
+    }
+
+    #[test]
+    fn test_update_component_sends_each_connection_its_flagged_format() {
+        let broker = LiveTSWebSocketBroker::new(Some(1)).unwrap();
+        let mut receivers = Vec::new();
+        for connection_id in ["conn-legacy", "conn-beta"] {
+            let (tx, rx) = unbounded_channel();
+            broker.connections.add_connection(connection_id.to_string()).unwrap();
+            broker.connections.attach_sender(&connection_id.to_string(), tx).unwrap();
+            broker.register_component("counter-1".to_string(), connection_id.to_string()).unwrap();
+            receivers.push(rx);
+        }
+        broker
+            .set_connection_flags("conn-beta".to_string(), vec![FLAG_COMBINED_PATCHES.to_string()])
+            .unwrap();
+
+        broker.cache_component_html("counter-1".to_string(), r#"<div id="count" class="text-green-600">5</div>"#.to_string());
+        broker
+            .update_component("counter-1".to_string(), r#"<div id="count" class="text-red-600">6</div>"#.to_string())
+            .unwrap();
+
+        let patches: Vec<serde_json::Value> = receivers
+            .iter_mut()
+            .map(|rx| serde_json::from_str::<serde_json::Value>(&rx.try_recv().unwrap()).unwrap()["d"].clone())
+            .collect();
+        assert_eq!(patches[0], serde_json::json!(["a|#count|class|text-red-600", "t|#count|6"]));
+        assert_eq!(patches[1], serde_json::json!([r##"u|#count|{"class":"text-red-600"}|6"##]));
+    }
+
+    #[test]
+    fn test_broker_broadcast_many_reaches_subscribed_connections() {
+        let broker = LiveTSWebSocketBroker::new(Some(1)).unwrap();
+        let connection_id = "conn-1".to_string();
+        let (tx, mut rx) = unbounded_channel();
+        broker.connections.add_connection(connection_id.clone()).unwrap();
+        broker.connections.attach_sender(&connection_id, tx).unwrap();
+        broker.register_component("ticker".to_string(), connection_id.clone()).unwrap();
+        broker
+            .subscribe_channel(connection_id.clone(), "ticker".to_string(), "prices".to_string())
+            .unwrap();
+
+        let delivered = broker
+            .broadcast_many(vec![
+                ChannelMessage { channel: "prices".to_string(), message: "tick".to_string() },
+                ChannelMessage { channel: "nobody".to_string(), message: "lost".to_string() },
+            ])
+            .unwrap();
+        assert_eq!(delivered, 1);
+        let received = broker
+            .runtime()
+            .block_on(tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()))
+            .unwrap();
+        assert_eq!(received.as_deref(), Some("tick"));
+    }
+}
@@ -3,120 +3,255 @@
 //! This module provides high-performance caching for component HTML and state,
 //! eliminating the need to transfer HTML back and forth across FFI boundaries.
 
+use crate::clock::{Clock, SystemClock};
+use crate::differ::ParsedDocument;
 use crate::types::*;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Cache of component HTML and parsed documents
+pub type ComponentCache = Cache<CachedComponent>;
+
+/// How often LFU access counts are halved, so entries that were hot once don't stay forever
+const LFU_DECAY_INTERVAL_MS: u64 = 60_000;
+
+/// Which entry the cache drops when it grows past its maximum size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Least recently written or touched
+    #[default]
+    Lru,
+    /// Least frequently read, written or touched (counts decay over time)
+    Lfu,
+    /// First inserted
+    Fifo,
+}
+
+/// A cached value with the time it was last written or touched
+#[derive(Debug, Clone)]
+struct CacheEntry<V> {
+    value: V,
+    last_updated: u64,
+    inserted_at: u64,
+    access_count: u64,
+}
 
-/// High-performance component cache with built-in eviction
-pub struct ComponentCache {
-    /// Cache for component HTML (component_id -> html)
-    html_cache: DashMap<ComponentId, CachedComponent>,
+/// High-performance keyed cache with built-in eviction
+pub struct Cache<V> {
+    /// Cached values (key -> entry)
+    entries: DashMap<String, CacheEntry<V>>,
     /// Maximum cache size before eviction
     max_size: usize,
+    policy: EvictionPolicy,
+    /// When LFU access counts were last halved
+    last_decay: AtomicU64,
+    clock: Arc<dyn Clock>,
+}
+
+/// FNV-1a hash of HTML, fed incrementally as the HTML is built
+///
+/// Not collision resistant; only used to spot renders that didn't change anything
+#[derive(Debug, Clone, Copy)]
+pub struct ContentHasher(u64);
+
+impl ContentHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    pub fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    /// Feed the next chunk of HTML
+    pub fn update(&mut self, chunk: &str) {
+        for byte in chunk.bytes() {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hash of a complete HTML string; equal to feeding it to a `ContentHasher` in any chunks
+pub fn content_hash(html: &str) -> u64 {
+    let mut hasher = ContentHasher::new();
+    hasher.update(html);
+    hasher.finish()
 }
 
-impl ComponentCache {
+impl<V: Clone> Cache<V> {
     pub fn new(max_size: usize) -> Self {
+        Self::with_policy(max_size, EvictionPolicy::default())
+    }
+
+    pub fn with_policy(max_size: usize, policy: EvictionPolicy) -> Self {
+        Self::with_clock(max_size, policy, Arc::new(SystemClock::new()))
+    }
+
+    /// Creates a cache reading time from `clock`, e.g. a `MockClock` in tests
+    pub fn with_clock(max_size: usize, policy: EvictionPolicy, clock: Arc<dyn Clock>) -> Self {
         Self {
-            html_cache: DashMap::new(),
+            entries: DashMap::new(),
             max_size,
+            policy,
+            last_decay: AtomicU64::new(clock.now_ms()),
+            clock,
         }
     }
 
-    /// Store component HTML in cache
-    pub fn set_html(&self, component_id: &str, html: String) {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
-
-        let cached_component = CachedComponent {
-            component_id: component_id.to_string(),
-            current_html: html,
-            last_updated: timestamp,
-        };
+    /// The policy used to pick entries for eviction
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
 
-        self.html_cache
-            .insert(component_id.to_string(), cached_component);
+    /// Store a value in the cache
+    pub fn insert(&self, key: &str, value: V) {
+        let now = self.clock.now_ms();
+        match self.entries.entry(key.to_string()) {
+            Entry::Occupied(mut occupied) => {
+                let entry = occupied.get_mut();
+                entry.value = value;
+                entry.last_updated = now;
+                entry.access_count += 1;
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(CacheEntry {
+                    value,
+                    last_updated: now,
+                    inserted_at: now,
+                    access_count: 1,
+                });
+            }
+        }
 
-        // Evict oldest entries if cache is too large
-        if self.html_cache.len() > self.max_size {
-            self.evict_oldest();
+        // Evict entries if cache is too large
+        if self.entries.len() > self.max_size {
+            self.evict(key);
         }
     }
 
-    /// Get component HTML from cache
-    pub fn get_html(&self, component_id: &str) -> Option<String> {
-        self.html_cache
-            .get(component_id)
-            .map(|entry| entry.current_html.clone())
+    /// Get a value from the cache
+    pub fn get(&self, key: &str) -> Option<V> {
+        self.read(key, |value| value.clone())
     }
 
-    /// Check if component exists in cache
-    pub fn has_component(&self, component_id: &str) -> bool {
-        self.html_cache.contains_key(component_id)
+    /// Read part of an entry, counting the access for LFU
+    fn read<T>(&self, key: &str, f: impl FnOnce(&V) -> T) -> Option<T> {
+        if self.policy == EvictionPolicy::Lfu {
+            let mut entry = self.entries.get_mut(key)?;
+            entry.access_count += 1;
+            Some(f(&entry.value))
+        } else {
+            self.entries.get(key).map(|entry| f(&entry.value))
+        }
     }
 
-    /// Remove component from cache
-    pub fn remove_component(&self, component_id: &str) -> Option<CachedComponent> {
-        self.html_cache.remove(component_id).map(|(_, v)| v)
+    /// Check if a key exists in the cache
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Remove a value from the cache
+    pub fn remove(&self, key: &str) -> Option<V> {
+        self.entries.remove(key).map(|(_, entry)| entry.value)
     }
 
     /// Get cache statistics
     pub fn stats(&self) -> CacheStats {
         CacheStats {
-            size: self.html_cache.len(),
+            size: self.entries.len(),
             max_size: self.max_size,
             hit_ratio: 0.0, // TODO: Implement hit tracking
         }
     }
 
-    /// Clear all cached components
+    /// Clear all cached values
     pub fn clear(&self) {
-        self.html_cache.clear();
+        self.entries.clear();
+    }
+
+    /// Get the number of cached values
+    pub fn len(&self) -> usize {
+        self.entries.len()
     }
 
-    /// Evict oldest cache entries to maintain size limit
-    fn evict_oldest(&self) {
-        let mut oldest_key = None;
-        let mut oldest_time = u64::MAX;
+    /// Check if the cache holds no values
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 
-        // Find the oldest entry
-        for entry in self.html_cache.iter() {
-            if entry.last_updated < oldest_time {
-                oldest_time = entry.last_updated;
-                oldest_key = Some(entry.component_id.clone());
-            }
+    /// Evict one entry per the cache's policy, never the one just inserted
+    fn evict(&self, inserted_key: &str) {
+        if self.policy == EvictionPolicy::Lfu {
+            self.decay_access_counts();
         }
 
-        // Remove the oldest entry
-        if let Some(key) = oldest_key {
-            self.html_cache.remove(&key);
+        // Ties on the policy's rank go to the least recently updated entry
+        let victim = self
+            .entries
+            .iter()
+            .filter(|entry| entry.key() != inserted_key)
+            .min_by_key(|entry| {
+                let rank = match self.policy {
+                    EvictionPolicy::Lru => entry.last_updated,
+                    EvictionPolicy::Lfu => entry.access_count,
+                    EvictionPolicy::Fifo => entry.inserted_at,
+                };
+                (rank, entry.last_updated)
+            })
+            .map(|entry| entry.key().clone());
+
+        if let Some(key) = victim {
+            self.entries.remove(&key);
         }
     }
 
-    /// Update component's last access time
-    pub fn touch_component(&self, component_id: &str) {
-        if let Some(mut entry) = self.html_cache.get_mut(component_id) {
-            entry.last_updated = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as u64;
+    /// Halve every access count once per decay interval
+    fn decay_access_counts(&self) {
+        let now = self.clock.now_ms();
+        let last = self.last_decay.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < LFU_DECAY_INTERVAL_MS
+            || self
+                .last_decay
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        for mut entry in self.entries.iter_mut() {
+            entry.access_count /= 2;
         }
     }
 
-    /// Get components that haven't been accessed for a given time (in milliseconds)
-    pub fn get_stale_components(&self, max_age_ms: u64) -> Vec<String> {
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
+    /// Update an entry's last access time
+    pub fn touch(&self, key: &str) {
+        if let Some(mut entry) = self.entries.get_mut(key) {
+            entry.last_updated = self.clock.now_ms();
+            entry.access_count += 1;
+        }
+    }
 
-        self.html_cache
+    /// Get keys that haven't been accessed for a given time (in milliseconds)
+    pub fn get_stale_keys(&self, max_age_ms: u64) -> Vec<String> {
+        let current_time = self.clock.now_ms();
+
+        self.entries
             .iter()
             .filter_map(|entry| {
-                if current_time - entry.last_updated > max_age_ms {
-                    Some(entry.component_id.clone())
+                if current_time.saturating_sub(entry.last_updated) > max_age_ms {
+                    Some(entry.key().clone())
                 } else {
                     None
                 }
@@ -124,19 +259,100 @@ impl ComponentCache {
             .collect()
     }
 
-    /// Clean up stale components
+    /// Clean up stale entries
     pub fn cleanup_stale(&self, max_age_ms: u64) -> usize {
-        let stale_components = self.get_stale_components(max_age_ms);
-        let count = stale_components.len();
+        let stale_keys = self.get_stale_keys(max_age_ms);
+        let count = stale_keys.len();
 
-        for component_id in stale_components {
-            self.html_cache.remove(&component_id);
+        for key in stale_keys {
+            self.entries.remove(&key);
         }
 
         count
     }
 }
 
+impl Cache<CachedComponent> {
+    /// Store component HTML in cache
+    pub fn set_html(&self, component_id: &str, html: String) {
+        self.insert_component(component_id, html, None);
+    }
+
+    /// Store a parsed document so the next diff can skip re-parsing the old HTML
+    pub fn set_parsed(&self, component_id: &str, document: ParsedDocument) {
+        self.insert_component(component_id, document.html().to_string(), Some(Arc::new(document)));
+    }
+
+    /// Get the component's parsed document, if one was stored with its HTML
+    pub fn get_parsed(&self, component_id: &str) -> Option<Arc<ParsedDocument>> {
+        self.read(component_id, |component| component.parsed.clone())
+            .flatten()
+    }
+
+    fn insert_component(&self, component_id: &str, html: String, parsed: Option<Arc<ParsedDocument>>) {
+        let access_count = self
+            .entries
+            .get(component_id)
+            .map(|entry| entry.value.access_count.clone())
+            .unwrap_or_default();
+        let cached_component = CachedComponent {
+            component_id: component_id.to_string(),
+            content_hash: content_hash(&html),
+            current_html: html,
+            parsed,
+            last_updated: self.clock.now_ms(),
+            access_count,
+        };
+
+        self.insert(component_id, cached_component);
+    }
+
+    /// Get component HTML from cache
+    pub fn get_html(&self, component_id: &str) -> Option<String> {
+        self.read(component_id, |component| {
+            component.access_count.fetch_add(1, Ordering::Relaxed);
+            component.current_html.clone()
+        })
+    }
+
+    /// (component id, `get_html` reads) for every cached component, most read first
+    /// Counts start over when a component is removed
+    pub fn component_access_stats(&self) -> Vec<(String, u64)> {
+        let mut stats: Vec<(String, u64)> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value.access_count.load(Ordering::Relaxed)))
+            .collect();
+        stats.sort_by(|(a_id, a_count), (b_id, b_count)| b_count.cmp(a_count).then_with(|| a_id.cmp(b_id)));
+        stats
+    }
+
+    /// Get the `content_hash` of the component's cached HTML
+    pub fn get_hash(&self, component_id: &str) -> Option<u64> {
+        self.read(component_id, |component| component.content_hash)
+    }
+
+    /// Check if component exists in cache
+    pub fn has_component(&self, component_id: &str) -> bool {
+        self.contains(component_id)
+    }
+
+    /// Remove component from cache
+    pub fn remove_component(&self, component_id: &str) -> Option<CachedComponent> {
+        self.remove(component_id)
+    }
+
+    /// Update component's last access time
+    pub fn touch_component(&self, component_id: &str) {
+        self.touch(component_id);
+    }
+
+    /// Get components that haven't been accessed for a given time (in milliseconds)
+    pub fn get_stale_components(&self, max_age_ms: u64) -> Vec<String> {
+        self.get_stale_keys(max_age_ms)
+    }
+}
+
 /// Cache performance statistics
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct CacheStats {
@@ -145,15 +361,16 @@ pub struct CacheStats {
     pub hit_ratio: f64,
 }
 
-impl Default for ComponentCache {
+impl<V: Clone> Default for Cache<V> {
     fn default() -> Self {
-        Self::new(1000) // Default to 1000 cached components
+        Self::new(1000) // Default to 1000 cached entries
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_basic_cache_operations() {
@@ -178,18 +395,21 @@ mod tests {
 
     #[test]
     fn test_cache_eviction() {
-        let cache = ComponentCache::new(2);
+        let clock = Arc::new(MockClock::new(1_000));
+        let cache = ComponentCache::with_clock(2, EvictionPolicy::Lru, clock.clone());
 
         // Fill cache
         cache.set_html("comp1", "<div>1</div>".to_string());
+        clock.advance(1);
         cache.set_html("comp2", "<div>2</div>".to_string());
 
         // Adding third component should evict oldest
-        std::thread::sleep(std::time::Duration::from_millis(1));
+        clock.advance(1);
         cache.set_html("comp3", "<div>3</div>".to_string());
 
         // comp1 should be evicted (oldest)
-        assert_eq!(cache.html_cache.len(), 2);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(!cache.has_component("comp1"));
     }
 
     #[test]
@@ -209,7 +429,7 @@ mod tests {
         cache.set_html("comp1", "<div>1</div>".to_string());
 
         // Manually set old timestamp to simulate stale component
-        if let Some(mut entry) = cache.html_cache.get_mut("comp1") {
+        if let Some(mut entry) = cache.entries.get_mut("comp1") {
             entry.last_updated = 0; // Very old timestamp
         }
 
@@ -217,4 +437,159 @@ mod tests {
         assert_eq!(cleaned, 1);
         assert!(!cache.has_component("comp1"));
     }
+
+    #[test]
+    fn test_parsed_document_storage() {
+        let differ = crate::differ::HtmlDiffer::new();
+        let cache = ComponentCache::new(10);
+
+        cache.set_parsed("comp1", differ.parse("<p>1</p>"));
+        assert_eq!(cache.get_html("comp1"), Some("<p>1</p>".to_string()));
+        assert_eq!(cache.get_parsed("comp1").unwrap().html(), "<p>1</p>");
+
+        // Replacing the HTML drops the now stale parsed form
+        cache.set_html("comp1", "<p>2</p>".to_string());
+        assert!(cache.get_parsed("comp1").is_none());
+    }
+
+    #[test]
+    fn test_generic_cache_with_non_string_value() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Selectors {
+            ids: Vec<u32>,
+        }
+
+        let cache: Cache<Selectors> = Cache::new(2);
+        cache.insert("a", Selectors { ids: vec![1, 2] });
+        assert_eq!(cache.get("a"), Some(Selectors { ids: vec![1, 2] }));
+        assert!(cache.contains("a"));
+
+        // Eviction works the same as for HTML entries
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        cache.insert("b", Selectors { ids: vec![3] });
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        cache.insert("c", Selectors { ids: vec![] });
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains("a"));
+
+        assert_eq!(cache.remove("b"), Some(Selectors { ids: vec![3] }));
+        assert_eq!(cache.get("b"), None);
+    }
+
+    #[test]
+    fn test_lfu_retains_frequently_read_entry() {
+        let fill = |cache: &ComponentCache| {
+            cache.set_html("hot", "<div>hot</div>".to_string());
+            for _ in 0..5 {
+                cache.get_html("hot");
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            cache.set_html("cold", "<div>cold</div>".to_string());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            cache.set_html("new", "<div>new</div>".to_string());
+        };
+
+        // LRU drops the hot entry because it was written first
+        let lru = ComponentCache::new(2);
+        fill(&lru);
+        assert!(!lru.has_component("hot"));
+        assert!(lru.has_component("cold"));
+
+        let lfu = ComponentCache::with_policy(2, EvictionPolicy::Lfu);
+        fill(&lfu);
+        assert!(lfu.has_component("hot"));
+        assert!(!lfu.has_component("cold"));
+        assert!(lfu.has_component("new"));
+    }
+
+    #[test]
+    fn test_lfu_access_counts_decay() {
+        let cache: Cache<u32> = Cache::with_policy(10, EvictionPolicy::Lfu);
+        cache.insert("a", 1);
+        for _ in 0..7 {
+            cache.get("a");
+        }
+        assert_eq!(cache.entries.get("a").unwrap().access_count, 8);
+
+        // Pretend the decay interval has passed
+        cache.last_decay.store(0, Ordering::Relaxed);
+        cache.decay_access_counts();
+        assert_eq!(cache.entries.get("a").unwrap().access_count, 4);
+
+        // Not again until another interval has passed
+        cache.decay_access_counts();
+        assert_eq!(cache.entries.get("a").unwrap().access_count, 4);
+    }
+
+    #[test]
+    fn test_fifo_evicts_first_inserted_despite_touch() {
+        let cache: Cache<u32> = Cache::with_policy(2, EvictionPolicy::Fifo);
+        cache.insert("a", 1);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        cache.insert("b", 2);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        cache.touch("a");
+        cache.insert("c", 3);
+
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        assert!(cache.contains("c"));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut hasher = ContentHasher::new();
+        hasher.update("<div id=\"count\">");
+        hasher.update("5</div>");
+        assert_eq!(hasher.finish(), content_hash("<div id=\"count\">5</div>"));
+        assert_ne!(content_hash("<div>5</div>"), content_hash("<div>6</div>"));
+
+        let cache = ComponentCache::new(10);
+        assert_eq!(cache.get_hash("counter"), None);
+        cache.set_html("counter", "<div>5</div>".to_string());
+        assert_eq!(cache.get_hash("counter"), Some(content_hash("<div>5</div>")));
+    }
+
+    #[test]
+    fn test_stale_components_with_mock_clock() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let cache = ComponentCache::with_clock(10, EvictionPolicy::Lru, clock.clone());
+        cache.set_html("idle", "<div>1</div>".to_string());
+        cache.set_html("busy", "<div>2</div>".to_string());
+        assert!(cache.get_stale_components(50).is_empty());
+
+        clock.advance(40);
+        cache.touch_component("busy");
+        clock.advance(20);
+        assert_eq!(cache.get_stale_components(50), vec!["idle".to_string()]);
+
+        // Exactly at the limit isn't stale yet
+        clock.advance(30);
+        assert_eq!(cache.get_stale_components(50), vec!["idle".to_string()]);
+        clock.advance(1);
+        let mut stale = cache.get_stale_components(50);
+        stale.sort();
+        assert_eq!(stale, vec!["busy".to_string(), "idle".to_string()]);
+    }
+
+    #[test]
+    fn test_component_access_stats_ranking() {
+        let cache = ComponentCache::new(10);
+        cache.set_html("cold", "<p>1</p>".to_string());
+        cache.set_html("hot", "<p>1</p>".to_string());
+        for _ in 0..3 {
+            cache.get_html("hot");
+        }
+        cache.get_html("cold");
+        cache.get_html("missing");
+
+        // Touches and HTML updates keep the count
+        cache.touch_component("hot");
+        cache.set_html("hot", "<p>2</p>".to_string());
+        assert_eq!(cache.component_access_stats(), vec![("hot".to_string(), 3), ("cold".to_string(), 1)]);
+
+        cache.remove_component("hot");
+        cache.set_html("hot", "<p>3</p>".to_string());
+        assert_eq!(cache.component_access_stats(), vec![("cold".to_string(), 1), ("hot".to_string(), 0)]);
+    }
 }
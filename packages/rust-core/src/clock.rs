@@ -0,0 +1,68 @@
+//! Time source for TTL, staleness and heartbeat logic
+//!
+//! The cache and connection manager read time through a `Clock` so tests can advance
+//! a `MockClock` instead of sleeping.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time in milliseconds; only differences between readings matter
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+/// Monotonic clock: reads the wall time once at construction, then advances with an `Instant`,
+/// so a system clock adjustment can't make readings jump back or skip ahead
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    origin: Instant,
+    origin_ms: u64,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            origin_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        self.origin_ms + self.origin.elapsed().as_millis() as u64
+    }
+}
+
+/// Clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now_ms: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(start_ms: u64) -> Self {
+        Self { now_ms: AtomicU64::new(start_ms) }
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::Relaxed)
+    }
+}
@@ -1,24 +1,59 @@
 //! WebSocket connection management for LiveTS
 
+use crate::clock::{Clock, SystemClock};
+use crate::pubsub::PubSubSystem;
+use crate::store::{BroadcastBus, BusMessage, ComponentRegistry, LocalComponentRegistry};
 use crate::types::*;
 use dashmap::DashMap;
+use futures_util::future::join_all;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
-/// Information about a WebSocket connection
+/// Information about a WebSocket connection; times are milliseconds on the manager's clock
 #[derive(Debug, Clone)]
 pub struct Connection {
     pub component_ids: Vec<ComponentId>,
-    pub last_ping: std::time::Instant,
+    pub connected_at: u64,
+    pub last_ping: u64,
+    // When the client last sent anything, ping or otherwise
+    pub last_inbound: Option<u64>,
     // Outbound sender to write messages to this connection's websocket task
     pub sender: Option<UnboundedSender<String>>,
+    // High-priority outbound sender, drained by the writer before `sender`
+    pub priority_sender: Option<UnboundedSender<String>>,
+    // Asks the writer to send a Close frame (code, reason) and end the connection
+    pub close_sender: Option<UnboundedSender<(u16, String)>>,
+    // Subprotocol negotiated during the handshake, if any
+    pub subprotocol: Option<String>,
+    // Payload bytes successfully written to the socket
+    pub bytes_sent: Arc<AtomicU64>,
+    // Recently sent messages kept for replay after a brief disconnect
+    pub replay: ReplayBuffer,
+    // Token a later connection presents to resume this one's session
+    pub reconnect_token: Option<String>,
+    // Feature flags that select message formats for this connection, e.g. during a rollout
+    pub flags: BTreeSet<String>,
 }
 
 impl Connection {
-    pub fn new() -> Self {
+    pub fn new(now_ms: u64) -> Self {
         Self {
             component_ids: Vec::new(),
-            last_ping: std::time::Instant::now(),
+            connected_at: now_ms,
+            last_ping: now_ms,
+            last_inbound: None,
             sender: None,
+            priority_sender: None,
+            close_sender: None,
+            subprotocol: None,
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            replay: ReplayBuffer::new(DEFAULT_REPLAY_CAPACITY),
+            reconnect_token: None,
+            flags: BTreeSet::new(),
         }
     }
 
@@ -35,25 +70,366 @@ impl Connection {
     pub fn attach_sender(&mut self, sender: UnboundedSender<String>) {
         self.sender = Some(sender);
     }
+
+    pub fn attach_priority_sender(&mut self, sender: UnboundedSender<String>) {
+        self.priority_sender = Some(sender);
+    }
+
+    pub fn attach_close_sender(&mut self, sender: UnboundedSender<(u16, String)>) {
+        self.close_sender = Some(sender);
+    }
+}
+
+/// Messages kept per connection for replay unless configured otherwise
+pub const DEFAULT_REPLAY_CAPACITY: usize = 64;
+
+/// How long a closed connection's session can be resumed unless configured otherwise
+pub const DEFAULT_RECONNECT_TTL_MS: u64 = 30_000;
+
+/// Computes a new connection's feature flags from its id
+pub type FlagAssigner = dyn Fn(&ConnectionId) -> Vec<String> + Send + Sync;
+
+/// Assigner enabling each `(flag, percent)` for that share of connections
+///
+/// Connections are bucketed by a hash of the flag and connection id, so a connection's
+/// flags are stable and each flag's rollout is independent of the others
+pub fn rollout_flags(rollouts: Vec<(String, u8)>) -> Arc<FlagAssigner> {
+    Arc::new(move |conn_id: &ConnectionId| {
+        rollouts
+            .iter()
+            .filter(|(flag, percent)| {
+                let mut hasher = crate::cache::ContentHasher::new();
+                hasher.update(flag);
+                hasher.update(conn_id);
+                hasher.finish() % 100 < u64::from(*percent)
+            })
+            .map(|(flag, _)| flag.clone())
+            .collect()
+    })
+}
+
+/// What a closed connection leaves behind for `resume_session`
+#[derive(Debug, Clone)]
+struct ParkedSession {
+    component_ids: Vec<ComponentId>,
+    replay: ReplayBuffer,
+    expires_at: u64,
+}
+
+/// Stamps a JSON-object message with its sequence number as `q`, e.g. `{"q":7,"t":"p",...}`,
+/// so the client can ask for a replay after the last one it saw; other messages are unchanged
+pub fn with_sequence(data: &str, seq: u64) -> String {
+    let Some(fields) = data.strip_prefix('{').filter(|_| data.ends_with('}')) else {
+        return data.to_string();
+    };
+    if fields[..fields.len() - 1].trim().is_empty() {
+        format!("{{\"q\":{}}}", seq)
+    } else {
+        format!("{{\"q\":{},{}", seq, fields)
+    }
+}
+
+/// Ring buffer of the last messages sent to a connection
+///
+/// Sequence numbers start at 1 and count messages on the normal outbound queue in
+/// send order; high-priority control messages are neither numbered nor replayed.
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    last_seq: u64,
+    messages: VecDeque<(u64, String)>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            last_seq: 0,
+            messages: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Records a sent message, returning its sequence number
+    pub fn push(&mut self, data: &str) -> u64 {
+        self.last_seq += 1;
+        if self.capacity > 0 {
+            if self.messages.len() == self.capacity {
+                self.messages.pop_front();
+            }
+            self.messages.push_back((self.last_seq, data.to_string()));
+        }
+        self.last_seq
+    }
+
+    /// Gets the messages sent after `seq`
+    /// Returns `None` when they are no longer all buffered and the client needs a full resync
+    pub fn since(&self, seq: u64) -> Option<Vec<String>> {
+        if seq > self.last_seq {
+            return None;
+        }
+        if seq == self.last_seq {
+            return Some(Vec::new());
+        }
+
+        let oldest = self.messages.front().map(|(oldest, _)| *oldest)?;
+        if seq + 1 < oldest {
+            return None;
+        }
+
+        Some(
+            self.messages
+                .iter()
+                .filter(|(message_seq, _)| *message_seq > seq)
+                .map(|(_, data)| data.clone())
+                .collect(),
+        )
+    }
+
+    /// Gets the sequence number of the last message sent (0 before the first)
+    pub fn last_sequence(&self) -> u64 {
+        self.last_seq
+    }
+}
+
+/// One of the manager's connection slots, given back when dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    admitted: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.admitted.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 /// Manages WebSocket connections and component associations
 pub struct ConnectionManager {
+    instance_id: String,
     connections: DashMap<ConnectionId, Connection>,
-    component_to_connections: DashMap<ComponentId, Vec<ConnectionId>>,
+    component_to_connections: Arc<dyn ComponentRegistry>,
+    bus: Option<Arc<dyn BroadcastBus>>,
+    total_bytes_sent: AtomicU64,
+    replay_capacity: AtomicUsize,
+    clock: Arc<dyn Clock>,
+    /// Sessions of closed connections by reconnect token
+    parked_sessions: DashMap<String, ParkedSession>,
+    reconnect_ttl_ms: AtomicU64,
+    flag_assigner: RwLock<Option<Arc<FlagAssigner>>>,
+    /// Channels components subscribe to; a component leaving with its last connection is unsubscribed
+    pubsub: RwLock<Option<Arc<PubSubSystem>>>,
+    /// Most connections admitted at once (0 is unlimited)
+    max_connections: AtomicUsize,
+    admitted: Arc<AtomicUsize>,
 }
 
 impl ConnectionManager {
     pub fn new() -> Self {
+        Self::with_backend(Arc::new(LocalComponentRegistry::new()), None)
+    }
+
+    /// Creates a manager using a shared component registry and an optional cross-instance bus
+    pub fn with_backend(
+        registry: Arc<dyn ComponentRegistry>,
+        bus: Option<Arc<dyn BroadcastBus>>,
+    ) -> Self {
         Self {
+            instance_id: uuid::Uuid::new_v4().to_string(),
             connections: DashMap::new(),
-            component_to_connections: DashMap::new(),
+            component_to_connections: registry,
+            bus,
+            total_bytes_sent: AtomicU64::new(0),
+            replay_capacity: AtomicUsize::new(DEFAULT_REPLAY_CAPACITY),
+            clock: Arc::new(SystemClock::new()),
+            parked_sessions: DashMap::new(),
+            reconnect_ttl_ms: AtomicU64::new(DEFAULT_RECONNECT_TTL_MS),
+            flag_assigner: RwLock::new(None),
+            pubsub: RwLock::new(None),
+            max_connections: AtomicUsize::new(0),
+            admitted: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reads connection times from `clock` instead of the system clock, e.g. a `MockClock` in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Gets the clock connection times are read from
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Sets how many sent messages are kept per connection for replay (0 disables replay)
+    /// Applies to connections added afterwards
+    pub fn set_replay_capacity(&self, capacity: usize) {
+        self.replay_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Caps how many connections `try_admit` lets in at once (0, the default, is unlimited)
+    /// Connections already admitted stay when the cap is lowered below their count
+    pub fn set_max_connections(&self, max: usize) {
+        self.max_connections.store(max, Ordering::Relaxed);
+    }
+
+    /// Takes a connection slot, held until the permit is dropped; `None` when the
+    /// instance is full. The accept path asks before registering a connection
+    pub fn try_admit(&self) -> Option<ConnectionPermit> {
+        let max = self.max_connections.load(Ordering::Relaxed);
+        self.admitted
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |admitted| {
+                (max == 0 || admitted < max).then_some(admitted + 1)
+            })
+            .ok()?;
+        Some(ConnectionPermit { admitted: self.admitted.clone() })
+    }
+
+    /// Sets how long a closed connection's session stays resumable (0 stops issuing tokens)
+    pub fn set_reconnect_ttl(&self, ttl_ms: u64) {
+        self.reconnect_ttl_ms.store(ttl_ms, Ordering::Relaxed);
+    }
+
+    /// Issues the token a later connection presents to resume this one's session, replacing
+    /// any earlier one. Returns `None` when reconnection is disabled
+    pub fn issue_reconnect_token(&self, conn_id: &ConnectionId) -> Result<Option<String>> {
+        if self.reconnect_ttl_ms.load(Ordering::Relaxed) == 0 {
+            return Ok(None);
         }
+        let mut connection = self
+            .connections
+            .get_mut(conn_id)
+            .ok_or_else(|| LiveTSError::ConnectionNotFound(conn_id.clone()))?;
+        let token = uuid::Uuid::new_v4().to_string();
+        connection.reconnect_token = Some(token.clone());
+        Ok(Some(token))
+    }
+
+    /// Moves the component registrations and replay buffer of the closed connection that
+    /// issued `token` to `conn_id`. Tokens are single-use; returns the restored component
+    /// ids, or `None` when the token is unknown or expired
+    pub fn resume_session(&self, token: &str, conn_id: &ConnectionId) -> Result<Option<Vec<ComponentId>>> {
+        if !self.connections.contains_key(conn_id) {
+            return Err(LiveTSError::ConnectionNotFound(conn_id.clone()));
+        }
+        let Some((_, session)) = self.parked_sessions.remove(token) else {
+            return Ok(None);
+        };
+        if session.expires_at <= self.clock.now_ms() {
+            return Ok(None);
+        }
+
+        if let Some(mut connection) = self.connections.get_mut(conn_id) {
+            connection.replay = session.replay;
+            for component_id in &session.component_ids {
+                connection.add_component(component_id.clone());
+            }
+        }
+        for component_id in &session.component_ids {
+            self.component_to_connections.add(component_id, conn_id);
+        }
+        Ok(Some(session.component_ids))
+    }
+
+    /// Sets the callback that tags each new connection with feature flags (`None` stops tagging)
+    /// Applies to connections added afterwards
+    pub fn set_flag_assigner(&self, assigner: Option<Arc<FlagAssigner>>) {
+        if let Ok(mut current) = self.flag_assigner.write() {
+            *current = assigner;
+        }
+    }
+
+    /// Sets the pub/sub system `subscribe_channel` forwards from (`None` detaches it)
+    pub fn set_pubsub(&self, pubsub: Option<Arc<PubSubSystem>>) {
+        if let Ok(mut current) = self.pubsub.write() {
+            *current = pubsub;
+        }
+    }
+
+    /// Subscribes a component to a channel, forwarding its messages to the connection
+    /// The subscription ends when the component's last connection is removed
+    pub async fn subscribe_channel(
+        &self,
+        conn_id: &ConnectionId,
+        component_id: &ComponentId,
+        channel: &ChannelId,
+    ) -> Result<()> {
+        let pubsub = self
+            .pubsub
+            .read()
+            .ok()
+            .and_then(|pubsub| pubsub.clone())
+            .ok_or_else(|| LiveTSError::PubSubError("no pub/sub system attached".to_string()))?;
+        let sender = self
+            .connections
+            .get(conn_id)
+            .ok_or_else(|| LiveTSError::ConnectionNotFound(conn_id.clone()))?
+            .sender
+            .clone()
+            .ok_or_else(|| LiveTSError::WebSocketError("No sender attached to connection".into()))?;
+        pubsub.subscribe_forwarding(channel, component_id, sender).await
+    }
+
+    /// Gets a connection's feature flags, sorted
+    pub fn get_connection_flags(&self, conn_id: &ConnectionId) -> Option<Vec<String>> {
+        self.connections
+            .get(conn_id)
+            .map(|conn| conn.flags.iter().cloned().collect())
+    }
+
+    /// Replaces a connection's feature flags
+    pub fn set_connection_flags(&self, conn_id: &ConnectionId, flags: Vec<String>) -> Result<()> {
+        let mut connection = self
+            .connections
+            .get_mut(conn_id)
+            .ok_or_else(|| LiveTSError::ConnectionNotFound(conn_id.clone()))?;
+        connection.flags = flags.into_iter().collect();
+        Ok(())
+    }
+
+    /// Whether a connection has a feature flag
+    pub fn has_flag(&self, conn_id: &ConnectionId, flag: &str) -> bool {
+        self.connections
+            .get(conn_id)
+            .is_some_and(|conn| conn.flags.contains(flag))
+    }
+
+    /// Gets the id this instance uses when publishing on the bus
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Spawns a task delivering broadcasts published by other instances to local connections
+    /// Returns `None` when no bus is configured. Must be called from within a tokio runtime
+    pub fn spawn_bus_listener(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let mut receiver = self.bus.as_ref()?.subscribe();
+        let manager = self.clone();
+
+        Some(tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        if message.origin != manager.instance_id {
+                            manager.deliver_local(&message.component_id, &message.data).await;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Bus listener lagged, skipped {} messages", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }))
     }
 
     /// Adds a new WebSocket connection
     pub fn add_connection(&self, conn_id: ConnectionId) -> Result<()> {
-        let connection = Connection::new();
+        let mut connection = Connection::new(self.clock.now_ms());
+        connection.replay = ReplayBuffer::new(self.replay_capacity.load(Ordering::Relaxed));
+        let assigner = self.flag_assigner.read().ok().and_then(|assigner| assigner.clone());
+        if let Some(assigner) = assigner {
+            connection.flags = assigner(&conn_id).into_iter().collect();
+        }
         self.connections.insert(conn_id, connection);
         Ok(())
     }
@@ -68,19 +444,87 @@ impl ConnectionManager {
         }
     }
 
+    /// Attaches the high-priority outbound sender to an existing connection
+    pub fn attach_priority_sender(&self, conn_id: &ConnectionId, sender: UnboundedSender<String>) -> Result<()> {
+        if let Some(mut conn) = self.connections.get_mut(conn_id) {
+            conn.attach_priority_sender(sender);
+            Ok(())
+        } else {
+            Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
+        }
+    }
+
+    /// Attaches the sender used to request a server-side close
+    pub fn attach_close_sender(&self, conn_id: &ConnectionId, sender: UnboundedSender<(u16, String)>) -> Result<()> {
+        if let Some(mut conn) = self.connections.get_mut(conn_id) {
+            conn.attach_close_sender(sender);
+            Ok(())
+        } else {
+            Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
+        }
+    }
+
+    /// Asks a connection's writer to send a Close frame with `code` and `reason`, then disconnect
+    pub fn request_close(&self, conn_id: &ConnectionId, code: u16, reason: &str) -> Result<()> {
+        let conn = self
+            .connections
+            .get(conn_id)
+            .ok_or_else(|| LiveTSError::ConnectionNotFound(conn_id.clone()))?;
+        let sender = conn
+            .close_sender
+            .as_ref()
+            .ok_or_else(|| LiveTSError::WebSocketError("No close sender attached to connection".into()))?;
+        sender
+            .send((code, reason.to_string()))
+            .map_err(|e| LiveTSError::WebSocketError(format!("Close request failed: {}", e)))
+    }
+
+    /// Records the subprotocol negotiated for a connection
+    pub fn set_subprotocol(&self, conn_id: &ConnectionId, subprotocol: Option<String>) -> Result<()> {
+        if let Some(mut conn) = self.connections.get_mut(conn_id) {
+            conn.subprotocol = subprotocol;
+            Ok(())
+        } else {
+            Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
+        }
+    }
+
+    /// Gets the subprotocol negotiated for a connection
+    pub fn get_subprotocol(&self, conn_id: &ConnectionId) -> Option<String> {
+        self.connections
+            .get(conn_id)
+            .and_then(|conn| conn.subprotocol.clone())
+    }
+
     /// Removes a WebSocket connection and cleans up component associations
     pub fn remove_connection(&self, conn_id: &ConnectionId) -> Result<()> {
         if let Some((_, connection)) = self.connections.remove(conn_id) {
             // Clean up component associations
+            let pubsub = self.pubsub.read().ok().and_then(|pubsub| pubsub.clone());
             for component_id in &connection.component_ids {
-                if let Some(mut connections) = self.component_to_connections.get_mut(component_id) {
-                    connections.retain(|id| id != conn_id);
-                    if connections.is_empty() {
-                        drop(connections);
-                        self.component_to_connections.remove(component_id);
+                self.component_to_connections.remove(component_id, conn_id);
+                // Nobody is left to forward the component's channels to
+                if let Some(pubsub) = &pubsub {
+                    if self.component_to_connections.connections_for(component_id).is_empty() {
+                        pubsub.release_component(component_id);
                     }
                 }
             }
+
+            // Keep the session around for a client that reconnects with its token
+            let ttl_ms = self.reconnect_ttl_ms.load(Ordering::Relaxed);
+            if let (Some(token), true) = (connection.reconnect_token, ttl_ms > 0) {
+                let now = self.clock.now_ms();
+                self.parked_sessions.retain(|_, session| session.expires_at > now);
+                self.parked_sessions.insert(
+                    token,
+                    ParkedSession {
+                        component_ids: connection.component_ids,
+                        replay: connection.replay,
+                        expires_at: now + ttl_ms,
+                    },
+                );
+            }
         }
         Ok(())
     }
@@ -99,10 +543,7 @@ impl ConnectionManager {
         }
 
         // Add connection to component mapping
-        self.component_to_connections
-            .entry(component_id)
-            .or_insert_with(Vec::new)
-            .push(conn_id);
+        self.component_to_connections.add(&component_id, &conn_id);
 
         Ok(())
     }
@@ -119,44 +560,187 @@ impl ConnectionManager {
         }
 
         // Remove connection from component mapping
-        if let Some(mut connections) = self.component_to_connections.get_mut(component_id) {
-            connections.retain(|id| id != conn_id);
-            if connections.is_empty() {
-                drop(connections);
-                self.component_to_connections.remove(component_id);
+        self.component_to_connections.remove(component_id, conn_id);
+
+        Ok(())
+    }
+
+    /// Snapshots the component -> connection registrations
+    pub fn export_registrations(&self) -> HashMap<ComponentId, Vec<ConnectionId>> {
+        self.component_to_connections.entries().into_iter().collect()
+    }
+
+    /// Gets the components with the most connections, busiest first (ties by component id)
+    pub fn component_stats(&self, top_n: usize) -> Vec<ComponentStats> {
+        let mut stats: Vec<ComponentStats> = self
+            .component_to_connections
+            .entries()
+            .into_iter()
+            .map(|(component_id, conn_ids)| ComponentStats {
+                component_id,
+                connection_count: conn_ids.len(),
+            })
+            .collect();
+        stats.sort_by(|a, b| {
+            b.connection_count
+                .cmp(&a.connection_count)
+                .then_with(|| a.component_id.cmp(&b.component_id))
+        });
+        stats.truncate(top_n);
+        stats
+    }
+
+    /// Restores registrations from a snapshot, skipping connections that no longer exist
+    /// Returns the number of registrations restored
+    pub fn import_registrations(&self, registrations: HashMap<ComponentId, Vec<ConnectionId>>) -> usize {
+        let mut restored = 0;
+        for (component_id, conn_ids) in registrations {
+            for conn_id in conn_ids {
+                if self.register_component(component_id.clone(), conn_id).is_ok() {
+                    restored += 1;
+                }
             }
         }
+        restored
+    }
 
-        Ok(())
+    /// Removes every component registration while keeping the connections open
+    pub fn clear_registrations(&self) {
+        for mut entry in self.connections.iter_mut() {
+            let conn_id = entry.key().clone();
+            for component_id in entry.component_ids.drain(..) {
+                self.component_to_connections.remove(&component_id, &conn_id);
+            }
+        }
     }
 
     /// Broadcasts data to all connections associated with a component
+    /// With a bus configured, connections held by other instances receive it too
     pub async fn broadcast_to_component(
         &self,
         component_id: &ComponentId,
         data: &str,
     ) -> Result<()> {
-        if let Some(connections) = self.component_to_connections.get(component_id) {
-            for conn_id in connections.iter() {
-                if let Err(e) = self.send_to_connection(conn_id, data).await {
-                    tracing::warn!("Failed to send to connection {}: {}", conn_id, e);
+        self.deliver_local(component_id, data).await;
+
+        if let Some(bus) = &self.bus {
+            bus.publish(BusMessage {
+                origin: self.instance_id.clone(),
+                component_id: component_id.clone(),
+                data: data.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Like `broadcast_to_component`, but a local connection with feature flags gets
+    /// `render(flags)` instead of `data`, rendered once per distinct set of flags
+    ///
+    /// Connections held by other instances get `data`, since their flags aren't known here
+    pub async fn broadcast_to_component_by_flags<E, F>(
+        &self,
+        component_id: &ComponentId,
+        data: &str,
+        render: F,
+    ) -> std::result::Result<(), E>
+    where
+        E: From<LiveTSError>,
+        F: Fn(&[String]) -> std::result::Result<String, E>,
+    {
+        let mut rendered: HashMap<Vec<String>, String> = HashMap::new();
+        let mut messages: Vec<(ConnectionId, String)> = Vec::new();
+        for conn_id in self.local_connections(component_id) {
+            let flags = self.get_connection_flags(&conn_id).unwrap_or_default();
+            if flags.is_empty() {
+                messages.push((conn_id, data.to_string()));
+                continue;
+            }
+            let message = match rendered.get(&flags) {
+                Some(message) => message.clone(),
+                None => {
+                    let message = render(&flags)?;
+                    rendered.insert(flags, message.clone());
+                    message
                 }
+            };
+            messages.push((conn_id, message));
+        }
+
+        let results = join_all(messages.into_iter().map(|(conn_id, message)| async move {
+            let result = self.send_to_connection(&conn_id, &message).await;
+            (conn_id, result)
+        }))
+        .await;
+        for (conn_id, result) in results {
+            if let Err(e) = result {
+                tracing::warn!("Failed to send to connection {}: {}", conn_id, e);
             }
         }
+
+        if let Some(bus) = &self.bus {
+            bus.publish(BusMessage {
+                origin: self.instance_id.clone(),
+                component_id: component_id.clone(),
+                data: data.to_string(),
+            })?;
+        }
         Ok(())
     }
 
+    /// A component's connections held by this instance; the others are reached through the bus
+    fn local_connections(&self, component_id: &ComponentId) -> Vec<ConnectionId> {
+        self.component_to_connections
+            .connections_for(component_id)
+            .into_iter()
+            .filter(|conn_id| self.connections.contains_key(conn_id))
+            .collect()
+    }
+
+    /// Sends data to all of a component's connections held by this instance concurrently
+    ///
+    /// A failing or slow connection doesn't hold up the others. Messages to a single
+    /// connection stay in order, but there is no ordering guarantee across connections.
+    pub async fn broadcast(&self, component_id: &ComponentId, data: &str) -> SendReport {
+        let results = join_all(self.local_connections(component_id).into_iter().map(|conn_id| async move {
+            let result = self.send_to_connection(&conn_id, data).await;
+            (conn_id, result)
+        }))
+        .await;
+
+        let mut report = SendReport::default();
+        for (conn_id, result) in results {
+            match result {
+                Ok(()) => report.sent.push(conn_id),
+                Err(e) => report.failed.push(SendFailure {
+                    connection_id: conn_id,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        report
+    }
+
+    /// Sends data to the component's local connections, logging failures
+    async fn deliver_local(&self, component_id: &ComponentId, data: &str) {
+        let report = self.broadcast(component_id, data).await;
+        for failure in report.failed {
+            tracing::warn!("Failed to send to connection {}: {}", failure.connection_id, failure.error);
+        }
+    }
+
     /// Sends data to a specific connection
     pub async fn send_to_connection(
         &self,
         conn_id: &ConnectionId,
         data: &str,
     ) -> Result<()> {
-        if let Some(conn) = self.connections.get(conn_id) {
+        if let Some(mut conn) = self.connections.get_mut(conn_id) {
             if let Some(sender) = &conn.sender {
+                let data = with_sequence(data, conn.replay.last_sequence() + 1);
                 sender
-                    .send(data.to_string())
+                    .send(data.clone())
                     .map_err(|e| LiveTSError::WebSocketError(format!("Send failed: {}", e)))?;
+                conn.replay.push(&data);
                 Ok(())
             } else {
                 Err(LiveTSError::WebSocketError("No sender attached to connection".into()))
@@ -166,12 +750,86 @@ impl ConnectionManager {
         }
     }
 
+    /// Sends data to a specific connection, ahead of queued normal messages when `high` is set
+    pub async fn send_to_connection_priority(
+        &self,
+        conn_id: &ConnectionId,
+        data: &str,
+        high: bool,
+    ) -> Result<()> {
+        if !high {
+            return self.send_to_connection(conn_id, data).await;
+        }
+
+        if let Some(conn) = self.connections.get(conn_id) {
+            if let Some(sender) = &conn.priority_sender {
+                sender
+                    .send(data.to_string())
+                    .map_err(|e| LiveTSError::WebSocketError(format!("Send failed: {}", e)))?;
+                Ok(())
+            } else {
+                Err(LiveTSError::WebSocketError("No priority sender attached to connection".into()))
+            }
+        } else {
+            Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
+        }
+    }
+
+    /// Sends the same data to a list of connections, collecting per-connection outcomes
+    pub async fn send_to_connections(&self, conn_ids: &[ConnectionId], data: &str) -> SendReport {
+        let mut report = SendReport::default();
+        for conn_id in conn_ids {
+            match self.send_to_connection(conn_id, data).await {
+                Ok(()) => report.sent.push(conn_id.clone()),
+                Err(e) => report.failed.push(SendFailure {
+                    connection_id: conn_id.clone(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+        report
+    }
+
+    /// Records bytes written to a connection's socket
+    /// Counts message payloads as written; no compression extension is negotiated, so this is the wire payload size
+    pub fn record_bytes_sent(&self, conn_id: &ConnectionId, bytes: usize) {
+        if let Some(conn) = self.connections.get(conn_id) {
+            conn.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        self.total_bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Gets the bytes written to a connection so far
+    pub fn bytes_sent(&self, conn_id: &ConnectionId) -> Option<u64> {
+        self.connections
+            .get(conn_id)
+            .map(|conn| conn.bytes_sent.load(Ordering::Relaxed))
+    }
+
+    /// Gets the bytes written across all connections, including closed ones
+    pub fn total_bytes_sent(&self) -> u64 {
+        self.total_bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Gets the messages sent to a connection after sequence number `seq`
+    /// Returns `Ok(None)` when the buffer no longer reaches back that far and a full resync is needed
+    pub fn replay_since(&self, conn_id: &ConnectionId, seq: u64) -> Result<Option<Vec<String>>> {
+        self.connections
+            .get(conn_id)
+            .map(|conn| conn.replay.since(seq))
+            .ok_or_else(|| LiveTSError::ConnectionNotFound(conn_id.clone()))
+    }
+
+    /// Gets the sequence number of the last message sent to a connection
+    pub fn last_sequence(&self, conn_id: &ConnectionId) -> Option<u64> {
+        self.connections
+            .get(conn_id)
+            .map(|conn| conn.replay.last_sequence())
+    }
+
     /// Gets all connections for a component
     pub fn get_component_connections(&self, component_id: &ComponentId) -> Vec<ConnectionId> {
-        self.component_to_connections
-            .get(component_id)
-            .map(|connections| connections.clone())
-            .unwrap_or_default()
+        self.component_to_connections.connections_for(component_id)
     }
 
     /// Gets the number of active connections
@@ -181,18 +839,90 @@ impl ConnectionManager {
 
     /// Gets the number of registered components
     pub fn component_count(&self) -> usize {
-        self.component_to_connections.len()
+        self.component_to_connections.component_count()
     }
 
     /// Updates the last ping time for a connection
     pub fn update_ping(&self, conn_id: &ConnectionId) -> Result<()> {
         if let Some(mut connection) = self.connections.get_mut(conn_id) {
-            connection.last_ping = std::time::Instant::now();
+            connection.last_ping = self.clock.now_ms();
+            Ok(())
+        } else {
+            Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
+        }
+    }
+
+    /// Updates the time a connection last received anything from its client
+    pub fn update_inbound(&self, conn_id: &ConnectionId) -> Result<()> {
+        if let Some(mut connection) = self.connections.get_mut(conn_id) {
+            connection.last_inbound = Some(self.clock.now_ms());
             Ok(())
         } else {
             Err(LiveTSError::ConnectionNotFound(conn_id.clone()))
         }
     }
+
+    /// Gets a snapshot of one connection's activity
+    pub fn connection_info(&self, conn_id: &ConnectionId) -> Option<ConnectionInfo> {
+        self.connections
+            .get(conn_id)
+            .map(|conn| ConnectionInfo::new(conn_id, &conn, self.clock.now_ms()))
+    }
+
+    /// Gets a snapshot of every connection's activity
+    pub fn list_connections(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .iter()
+            .map(|entry| ConnectionInfo::new(entry.key(), entry.value(), self.clock.now_ms()))
+            .collect()
+    }
+}
+
+/// How many connections a component is registered on
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ComponentStats {
+    pub component_id: ComponentId,
+    pub connection_count: usize,
+}
+
+/// Activity snapshot of a connection; times are milliseconds ago
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ConnectionInfo {
+    pub connection_id: ConnectionId,
+    pub connected_at: u64,
+    pub last_ping: u64,
+    /// `None` until the client sends something
+    pub last_inbound: Option<u64>,
+    pub component_count: usize,
+    pub bytes_sent: u64,
+}
+
+impl ConnectionInfo {
+    fn new(conn_id: &ConnectionId, conn: &Connection, now_ms: u64) -> Self {
+        let ms_ago = |at: u64| now_ms.saturating_sub(at);
+        Self {
+            connection_id: conn_id.clone(),
+            connected_at: ms_ago(conn.connected_at),
+            last_ping: ms_ago(conn.last_ping),
+            last_inbound: conn.last_inbound.map(ms_ago),
+            component_count: conn.component_ids.len(),
+            bytes_sent: conn.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Outcome of sending one message to several connections
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SendReport {
+    pub sent: Vec<ConnectionId>,
+    pub failed: Vec<SendFailure>,
+}
+
+/// A connection that could not be sent to, with the reason
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SendFailure {
+    pub connection_id: ConnectionId,
+    pub error: String,
 }
 
 impl Default for ConnectionManager {
@@ -200,3 +930,430 @@ impl Default for ConnectionManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::store::InMemoryBus;
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_connection_on_other_instance() {
+        let registry: Arc<dyn ComponentRegistry> = Arc::new(LocalComponentRegistry::new());
+        let bus: Arc<dyn BroadcastBus> = Arc::new(InMemoryBus::new(16));
+        let instance_a = Arc::new(ConnectionManager::with_backend(registry.clone(), Some(bus.clone())));
+        let instance_b = Arc::new(ConnectionManager::with_backend(registry.clone(), Some(bus.clone())));
+        let listener_a = instance_a.spawn_bus_listener().unwrap();
+        let listener_b = instance_b.spawn_bus_listener().unwrap();
+
+        // Connection lives on instance B
+        let conn_b = "conn-b".to_string();
+        let (tx_b, mut rx_b) = unbounded_channel();
+        instance_b.add_connection(conn_b.clone()).unwrap();
+        instance_b.attach_sender(&conn_b, tx_b).unwrap();
+        instance_b.register_component("comp".to_string(), conn_b.clone()).unwrap();
+
+        // Shared registry makes it visible from instance A
+        assert_eq!(instance_a.get_component_connections(&"comp".to_string()), vec![conn_b.clone()]);
+
+        instance_a.broadcast_to_component(&"comp".to_string(), "hello").await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(1), rx_b.recv()).await.unwrap();
+        assert_eq!(received.as_deref(), Some("hello"));
+
+        // Instance B must not receive its own echo from the bus a second time
+        instance_b.broadcast_to_component(&"comp".to_string(), "again").await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), rx_b.recv()).await.unwrap();
+        assert_eq!(received.as_deref(), Some("again"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx_b.try_recv().is_err());
+
+        listener_a.abort();
+        listener_b.abort();
+    }
+
+    #[tokio::test]
+    async fn test_local_broadcast_without_bus() {
+        let manager = ConnectionManager::new();
+        let conn_id = "conn-1".to_string();
+        let (tx, mut rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+        manager.register_component("comp".to_string(), conn_id.clone()).unwrap();
+
+        manager.broadcast_to_component(&"comp".to_string(), "patch").await.unwrap();
+        assert_eq!(rx.recv().await.as_deref(), Some("patch"));
+
+        manager.remove_connection(&conn_id).unwrap();
+        assert_eq!(manager.component_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_remove_connection_ends_channel_forwarding() {
+        let manager = ConnectionManager::new();
+        let pubsub = Arc::new(PubSubSystem::new());
+        manager.set_pubsub(Some(pubsub.clone()));
+        let (conn_id, component, channel) = ("conn-1".to_string(), "ticker".to_string(), "prices".to_string());
+        let (tx, mut rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+        manager.register_component(component.clone(), conn_id.clone()).unwrap();
+
+        manager.subscribe_channel(&conn_id, &component, &channel).await.unwrap();
+        pubsub.broadcast(&channel, "tick".to_string()).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert_eq!(received.as_deref(), Some("tick"));
+        assert_eq!(pubsub.forwarding_task_count(), 1);
+
+        manager.remove_connection(&conn_id).unwrap();
+        assert!(!pubsub.is_subscribed(&channel, &component));
+        for _ in 0..100 {
+            if pubsub.forwarding_task_count() == 0 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pubsub.forwarding_task_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_connections_reports_failures() {
+        let manager = ConnectionManager::new();
+        let (tx1, mut rx1) = unbounded_channel();
+        let (tx2, mut rx2) = unbounded_channel();
+        manager.add_connection("conn-1".to_string()).unwrap();
+        manager.add_connection("conn-2".to_string()).unwrap();
+        manager.add_connection("conn-no-sender".to_string()).unwrap();
+        manager.attach_sender(&"conn-1".to_string(), tx1).unwrap();
+        manager.attach_sender(&"conn-2".to_string(), tx2).unwrap();
+
+        let ids = vec![
+            "conn-1".to_string(),
+            "missing".to_string(),
+            "conn-2".to_string(),
+            "conn-no-sender".to_string(),
+        ];
+        let report = manager.send_to_connections(&ids, "hello").await;
+
+        assert_eq!(report.sent, vec!["conn-1".to_string(), "conn-2".to_string()]);
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(report.failed[0].connection_id, "missing");
+        assert_eq!(report.failed[1].connection_id, "conn-no-sender");
+        assert_eq!(rx1.recv().await.as_deref(), Some("hello"));
+        assert_eq!(rx2.recv().await.as_deref(), Some("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_registrations() {
+        let manager = ConnectionManager::new();
+        let conn_id = "conn-1".to_string();
+        let component_id = "comp".to_string();
+        let (tx, mut rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+        manager.register_component(component_id.clone(), conn_id.clone()).unwrap();
+
+        let snapshot = manager.export_registrations();
+        assert_eq!(snapshot.get(&component_id), Some(&vec![conn_id.clone()]));
+
+        manager.clear_registrations();
+        assert_eq!(manager.component_count(), 0);
+        manager.broadcast_to_component(&component_id, "lost").await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // Registrations for connections that went away are skipped
+        let mut snapshot = snapshot;
+        snapshot.insert("other".to_string(), vec!["gone".to_string()]);
+        assert_eq!(manager.import_registrations(snapshot), 1);
+
+        manager.broadcast_to_component(&component_id, "restored").await.unwrap();
+        assert_eq!(rx.recv().await.as_deref(), Some("restored"));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_collects_results() {
+        let manager = ConnectionManager::new();
+        let component_id = "room".to_string();
+        let mut receivers = Vec::new();
+        for i in 0..3 {
+            let conn_id = format!("conn-{}", i);
+            let (tx, rx) = unbounded_channel();
+            manager.add_connection(conn_id.clone()).unwrap();
+            manager.attach_sender(&conn_id, tx).unwrap();
+            manager.register_component(component_id.clone(), conn_id).unwrap();
+            receivers.push(rx);
+        }
+        manager.add_connection("conn-no-sender".to_string()).unwrap();
+        manager.register_component(component_id.clone(), "conn-no-sender".to_string()).unwrap();
+
+        let report = manager.broadcast(&component_id, "tick").await;
+
+        assert_eq!(report.sent.len(), 3);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].connection_id, "conn-no-sender");
+        for rx in receivers.iter_mut() {
+            assert_eq!(rx.recv().await.as_deref(), Some("tick"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_to_connection_priority_uses_priority_queue() {
+        let manager = ConnectionManager::new();
+        let conn_id = "conn-1".to_string();
+        let (tx, mut rx) = unbounded_channel();
+        let (priority_tx, mut priority_rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+
+        // Without a priority queue high-priority sends fail rather than silently downgrade
+        assert!(manager.send_to_connection_priority(&conn_id, "urgent", true).await.is_err());
+
+        manager.attach_priority_sender(&conn_id, priority_tx).unwrap();
+        manager.send_to_connection_priority(&conn_id, "normal", false).await.unwrap();
+        manager.send_to_connection_priority(&conn_id, "urgent", true).await.unwrap();
+
+        assert_eq!(rx.recv().await.as_deref(), Some("normal"));
+        assert_eq!(priority_rx.recv().await.as_deref(), Some("urgent"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_bytes_sent_counters() {
+        let manager = ConnectionManager::new();
+        manager.add_connection("conn-1".to_string()).unwrap();
+        manager.add_connection("conn-2".to_string()).unwrap();
+
+        manager.record_bytes_sent(&"conn-1".to_string(), "t|#count|6".len());
+        manager.record_bytes_sent(&"conn-1".to_string(), 32);
+        manager.record_bytes_sent(&"conn-2".to_string(), 100);
+
+        assert_eq!(manager.bytes_sent(&"conn-1".to_string()), Some(42));
+        assert_eq!(manager.bytes_sent(&"conn-2".to_string()), Some(100));
+        assert_eq!(manager.bytes_sent(&"missing".to_string()), None);
+        assert_eq!(manager.total_bytes_sent(), 142);
+
+        // The total keeps counting traffic of connections that have closed
+        manager.remove_connection(&"conn-2".to_string()).unwrap();
+        assert_eq!(manager.bytes_sent(&"conn-2".to_string()), None);
+        assert_eq!(manager.total_bytes_sent(), 142);
+    }
+
+    #[tokio::test]
+    async fn test_replay_since_valid_sequence() {
+        let manager = ConnectionManager::new();
+        let conn_id = "conn-1".to_string();
+        let (tx, _rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+
+        for i in 1..=5 {
+            manager.send_to_connection(&conn_id, &format!("m{}", i)).await.unwrap();
+        }
+        assert_eq!(manager.last_sequence(&conn_id), Some(5));
+
+        let replayed = manager.replay_since(&conn_id, 3).unwrap();
+        assert_eq!(replayed, Some(vec!["m4".to_string(), "m5".to_string()]));
+        assert_eq!(manager.replay_since(&conn_id, 5).unwrap(), Some(Vec::new()));
+        assert!(manager.replay_since(&"missing".to_string(), 0).is_err());
+
+        // JSON objects carry their sequence number to the client, and are replayed with it
+        manager.send_to_connection(&conn_id, r#"{"t":"p","d":[]}"#).await.unwrap();
+        manager.send_to_connection(&conn_id, "{}").await.unwrap();
+        assert_eq!(
+            manager.replay_since(&conn_id, 5).unwrap(),
+            Some(vec![r#"{"q":6,"t":"p","d":[]}"#.to_string(), r#"{"q":7}"#.to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_since_expired_sequence() {
+        let manager = ConnectionManager::new();
+        manager.set_replay_capacity(3);
+        let conn_id = "conn-1".to_string();
+        let (tx, _rx) = unbounded_channel();
+        manager.add_connection(conn_id.clone()).unwrap();
+        manager.attach_sender(&conn_id, tx).unwrap();
+
+        for i in 1..=6 {
+            manager.send_to_connection(&conn_id, &format!("m{}", i)).await.unwrap();
+        }
+
+        // Only m4..m6 are buffered: seq 3 is the oldest point that can be resumed
+        assert_eq!(
+            manager.replay_since(&conn_id, 3).unwrap(),
+            Some(vec!["m4".to_string(), "m5".to_string(), "m6".to_string()])
+        );
+        assert_eq!(manager.replay_since(&conn_id, 2).unwrap(), None);
+        // A sequence the server never sent also forces a resync
+        assert_eq!(manager.replay_since(&conn_id, 9).unwrap(), None);
+    }
+
+    #[test]
+    fn test_connection_info_tracks_activity() {
+        let clock = Arc::new(MockClock::new(5_000));
+        let manager = ConnectionManager::new().with_clock(clock.clone());
+        let conn_id = "conn-1".to_string();
+        manager.add_connection(conn_id.clone()).unwrap();
+
+        let fresh = manager.connection_info(&conn_id).unwrap();
+        assert_eq!(fresh.last_inbound, None);
+        assert_eq!(fresh.component_count, 0);
+
+        clock.advance(20);
+        manager.register_component("counter-1".to_string(), conn_id.clone()).unwrap();
+        manager.record_bytes_sent(&conn_id, 42);
+        manager.update_inbound(&conn_id).unwrap();
+        clock.advance(5);
+
+        let info = manager.connection_info(&conn_id).unwrap();
+        assert_eq!(info.connected_at, 25);
+        assert_eq!(info.last_ping, 25);
+        assert_eq!(info.last_inbound, Some(5));
+
+        manager.update_ping(&conn_id).unwrap();
+        assert_eq!(manager.connection_info(&conn_id).unwrap().last_ping, 0);
+        assert_eq!(info.component_count, 1);
+        assert_eq!(info.bytes_sent, 42);
+
+        manager.add_connection("conn-2".to_string()).unwrap();
+        assert_eq!(manager.list_connections().len(), 2);
+        assert!(manager.connection_info(&"missing".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_request_close_reaches_close_channel() {
+        let manager = ConnectionManager::new();
+        let conn_id = "conn-1".to_string();
+        manager.add_connection(conn_id.clone()).unwrap();
+        assert!(manager.request_close(&conn_id, 4001, "session expired").is_err());
+
+        let (tx, mut rx) = unbounded_channel();
+        manager.attach_close_sender(&conn_id, tx).unwrap();
+        manager.request_close(&conn_id, 4001, "session expired").unwrap();
+        assert_eq!(rx.try_recv().unwrap(), (4001, "session expired".to_string()));
+
+        assert!(matches!(
+            manager.request_close(&"missing".to_string(), 4001, ""),
+            Err(LiveTSError::ConnectionNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_component_stats_ranking() {
+        let manager = ConnectionManager::new();
+        for conn in ["conn-1", "conn-2", "conn-3"] {
+            manager.add_connection(conn.to_string()).unwrap();
+        }
+        let registrations: [(&str, &[&str]); 4] = [
+            ("feed", &["conn-1", "conn-2", "conn-3"]),
+            ("chat", &["conn-1"]),
+            ("cart", &["conn-2", "conn-3"]),
+            ("about", &["conn-3"]),
+        ];
+        for (component, conns) in registrations {
+            for conn in conns {
+                manager.register_component(component.to_string(), conn.to_string()).unwrap();
+            }
+        }
+
+        let ranking: Vec<(String, usize)> = manager
+            .component_stats(10)
+            .into_iter()
+            .map(|stat| (stat.component_id, stat.connection_count))
+            .collect();
+        assert_eq!(
+            ranking,
+            vec![
+                ("feed".to_string(), 3),
+                ("cart".to_string(), 2),
+                ("about".to_string(), 1),
+                ("chat".to_string(), 1),
+            ]
+        );
+
+        let top = manager.component_stats(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[1].component_id, "cart");
+    }
+
+    #[test]
+    fn test_resume_session_with_reconnect_token() {
+        let clock = Arc::new(MockClock::new(0));
+        let manager = ConnectionManager::new().with_clock(clock.clone());
+        manager.set_reconnect_ttl(1_000);
+        let (old_id, new_id) = ("conn-old".to_string(), "conn-new".to_string());
+
+        manager.add_connection(old_id.clone()).unwrap();
+        let token = manager.issue_reconnect_token(&old_id).unwrap().unwrap();
+        manager.register_component("counter-1".to_string(), old_id.clone()).unwrap();
+        manager.connections.get_mut(&old_id).unwrap().replay.push("m1");
+        manager.remove_connection(&old_id).unwrap();
+        assert!(manager.get_component_connections(&"counter-1".to_string()).is_empty());
+
+        clock.advance(500);
+        manager.add_connection(new_id.clone()).unwrap();
+        let restored = manager.resume_session(&token, &new_id).unwrap();
+        assert_eq!(restored, Some(vec!["counter-1".to_string()]));
+        assert_eq!(manager.get_component_connections(&"counter-1".to_string()), vec![new_id.clone()]);
+        assert_eq!(manager.last_sequence(&new_id).unwrap(), 1);
+
+        // Single use
+        assert_eq!(manager.resume_session(&token, &new_id).unwrap(), None);
+
+        // Expired
+        let token = manager.issue_reconnect_token(&new_id).unwrap().unwrap();
+        manager.remove_connection(&new_id).unwrap();
+        clock.advance(1_000);
+        manager.add_connection(old_id.clone()).unwrap();
+        assert_eq!(manager.resume_session(&token, &old_id).unwrap(), None);
+
+        manager.set_reconnect_ttl(0);
+        assert_eq!(manager.issue_reconnect_token(&old_id).unwrap(), None);
+    }
+
+    #[test]
+    fn test_connection_flags_from_rollout() {
+        let manager = ConnectionManager::new();
+        manager.set_flag_assigner(Some(rollout_flags(vec![
+            ("combined_patches".to_string(), 50),
+            ("everyone".to_string(), 100),
+            ("nobody".to_string(), 0),
+        ])));
+
+        let ids: Vec<ConnectionId> = (0..100).map(|i| format!("conn-{}", i)).collect();
+        for id in &ids {
+            manager.add_connection(id.clone()).unwrap();
+        }
+        let flagged = ids.iter().filter(|id| manager.has_flag(id, "combined_patches")).count();
+        assert!(flagged > 10 && flagged < 90, "{} of 100 flagged", flagged);
+        assert!(ids.iter().all(|id| manager.has_flag(id, "everyone") && !manager.has_flag(id, "nobody")));
+
+        // Stable per connection id
+        let first = manager.get_connection_flags(&ids[0]).unwrap();
+        manager.remove_connection(&ids[0]).unwrap();
+        manager.add_connection(ids[0].clone()).unwrap();
+        assert_eq!(manager.get_connection_flags(&ids[0]).unwrap(), first);
+
+        manager
+            .set_connection_flags(&ids[1], vec!["beta".to_string(), "alpha".to_string()])
+            .unwrap();
+        assert_eq!(manager.get_connection_flags(&ids[1]).unwrap(), vec!["alpha", "beta"]);
+        assert!(manager.set_connection_flags(&"missing".to_string(), Vec::new()).is_err());
+        assert_eq!(manager.get_connection_flags(&"missing".to_string()), None);
+    }
+
+    #[test]
+    fn test_admission_respects_max_connections() {
+        let manager = ConnectionManager::new();
+        let unlimited: Vec<_> = (0..3).map(|_| manager.try_admit().unwrap()).collect();
+        drop(unlimited);
+
+        manager.set_max_connections(1);
+        let permit = manager.try_admit().expect("first connection fits");
+        assert!(manager.try_admit().is_none());
+        drop(permit);
+        assert!(manager.try_admit().is_some());
+    }
+}
@@ -1,99 +1,463 @@
 //! HTML diffing algorithm for efficient DOM updates
 
 use crate::types::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Attributes whose presence, not value, carries meaning
+const BOOLEAN_ATTRIBUTES: &[&str] = &[
+    "hidden", "open", "disabled", "checked", "selected", "readonly", "required",
+    "multiple", "autofocus", "inert", "novalidate", "formnovalidate", "allowfullscreen",
+    "autoplay", "controls", "loop", "muted", "playsinline", "reversed", "default",
+];
+
+/// Elements that never have a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source",
+    "track", "wbr",
+];
+
+/// Selector for the component root, targeted when the whole content is replaced
+const ROOT_SELECTOR: &str = "[data-livets-root]";
+
+/// Any tag: comment/doctype or optional '/' prefix, name, then the attribute string
+const TAG_PATTERN: &str = r#"<(!--[\s\S]*?--|![^>]*|/?)([a-zA-Z][\w-]*)?((?:[^>"']|"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')*)>"#;
+
+/// Which kinds of changes the differ is allowed to emit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffMode {
+    /// Text, attribute and structural patches, falling back to a full replace
+    #[default]
+    Full,
+    /// Only text updates; attribute and structural changes are ignored, so
+    /// DOM managed by client-side JS is never replaced
+    TextOnly,
+}
+
+/// How changed element content is sent to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentKind {
+    /// `UpdateText` for plain text, `ReplaceInnerHtml` when the new content has child elements
+    #[default]
+    Auto,
+    /// Always `UpdateText`; markup is shown literally
+    Text,
+    /// Always `ReplaceInnerHtml`
+    Html,
+}
+
+/// Differ settings
+#[derive(Debug, Clone, Default)]
+pub struct DifferConfig {
+    pub mode: DiffMode,
+    pub content: ContentKind,
+    /// Return `HtmlParsingError` for HTML the differ can't fully parse instead of
+    /// falling back to a full replace (useful in development to surface template bugs)
+    pub strict: bool,
+    /// Never emit patches carrying raw HTML (`ReplaceInnerHtml`, `ReplaceElement`,
+    /// `InsertElement`), so unescaped content can't reach the client's `innerHTML`.
+    /// Content changes become `UpdateText` (markup shows literally) and changes that
+    /// need raw HTML are dropped with a warning in `DiffStats`, leaving that part of
+    /// the DOM stale until the page reloads; structural templates shouldn't opt in
+    pub no_raw_html: bool,
+    /// Address shadow-DOM content: elements with a `part` attribute get a `[part~="name"]`
+    /// selector for each of their parts and the component root gets `:host`. The client
+    /// resolves these in the component's shadow root, since a document-level query can't
+    /// cross it
+    pub shadow_dom: bool,
+    /// Attributes compared by presence only, so `x`, `x=""` and `x="x"` are equal
+    /// (defaults to the HTML boolean attributes such as `disabled` and `checked`)
+    pub boolean_attributes: Option<Vec<String>>,
+    /// Fold an element's attribute and text patches into one `UpdateElement` when it has
+    /// more than one, so the selector is sent once (clients must understand the `u` op)
+    pub combine_element_patches: bool,
+    /// Class prefixes (e.g. `is-`, `has-`) the server owns. When set, only classes with one
+    /// of these prefixes are diffed, as `ToggleClass` patches, and any other class is left
+    /// to the client, e.g. utilities added by a JS library
+    pub managed_class_prefixes: Vec<String>,
+}
+
+/// Finds the elements the differ matches between two renders
+///
+/// Each element carries its text content and full source; the differ matches them by
+/// identity and position and patches what changed. An empty result means nothing could
+/// be matched, so the differ falls back to replacing the whole content
+pub trait ElementExtractor: Send + Sync {
+    fn extract(&self, html: &str) -> Vec<HtmlElement>;
+}
+
+/// The built-in extractor: leaf elements (`<tag>text</tag>`) and void elements, found with regexes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegexExtractor;
+
+impl ElementExtractor for RegexExtractor {
+    fn extract(&self, html: &str) -> Vec<HtmlElement> {
+        regex_elements(html).unwrap_or_default()
+    }
+}
 
 /// High-performance HTML diffing engine
-pub struct HtmlDiffer;
+pub struct HtmlDiffer {
+    config: DifferConfig,
+    extractor: Arc<dyn ElementExtractor>,
+}
 
 impl HtmlDiffer {
     pub fn new() -> Self {
-        Self
+        Self::with_config(DifferConfig::default())
+    }
+
+    pub fn with_config(config: DifferConfig) -> Self {
+        Self { config, extractor: Arc::new(RegexExtractor) }
+    }
+
+    /// Finds elements with `extractor` instead of the built-in `RegexExtractor`, e.g. a
+    /// full HTML5 parser. Keyed lists and identity lookups still read the HTML directly
+    pub fn with_extractor(mut self, extractor: Arc<dyn ElementExtractor>) -> Self {
+        self.extractor = extractor;
+        self
+    }
+
+    /// Gets the differ settings
+    pub fn config(&self) -> &DifferConfig {
+        &self.config
+    }
+
+    /// Gets the extractor elements are found with
+    pub fn extractor(&self) -> &Arc<dyn ElementExtractor> {
+        &self.extractor
     }
 
     /// Compares two HTML strings and generates minimal patch operations
     pub fn diff(&self, old_html: &str, new_html: &str) -> Result<Vec<DomPatch>> {
-        let mut patches = Vec::new();
+        self.diff_parsed(&self.parse(old_html), &self.parse(new_html))
+    }
+
+    /// Parses HTML once so it can be diffed against many versions without re-parsing
+    pub fn parse(&self, html: &str) -> ParsedDocument {
+        let mut elements = self.parse_elements(html);
+        let duplicate_ts_selectors =
+            elements.as_mut().map(|elements| index_duplicate_ts_selectors(elements)).unwrap_or_default();
+        ParsedDocument {
+            html: html.to_string(),
+            elements,
+            keyed_lists: self.parse_keyed_lists(html),
+            component_id: self.extract_component_id(html),
+            duplicate_ts_selectors,
+        }
+    }
+
+    /// Compares two pre-parsed documents and generates minimal patch operations
+    pub fn diff_parsed(&self, old: &ParsedDocument, new: &ParsedDocument) -> Result<Vec<DomPatch>> {
+        self.diff_parsed_with_stats(old, new).map(|(patches, _)| patches)
+    }
+
+    /// Diffs `new_html` against each candidate old HTML and keeps the smallest patch set,
+    /// e.g. when it's unclear which version a reconnecting client last saw
+    ///
+    /// Patch sets are compared by their compact size. A candidate only wins if it beats a full
+    /// replace of the root; when none does (or none diffs), the full replace is returned with
+    /// index `candidates.len()`. Ties go to the earlier candidate
+    pub fn diff_best_of(&self, candidates: &[&str], new_html: &str) -> (usize, Vec<DomPatch>) {
+        let full_replace = vec![DomPatch::ReplaceInnerHtml {
+            selector: ROOT_SELECTOR.to_string(),
+            html: new_html.to_string(),
+        }];
+        let size = |patches: &[DomPatch]| -> usize {
+            self.patches_to_compact(patches.to_vec()).iter().map(String::len).sum()
+        };
+
+        let new = self.parse(new_html);
+        let mut best = (candidates.len(), size(&full_replace), full_replace);
+        for (index, candidate) in candidates.iter().enumerate() {
+            let Ok(patches) = self.diff_parsed(&self.parse(candidate), &new) else { continue };
+            let patches_size = size(&patches);
+            if patches_size < best.1 {
+                best = (index, patches_size, patches);
+            }
+        }
+        (best.0, best.2)
+    }
+
+    /// Like `diff`, also reporting what the differ noticed while generating the patches
+    pub fn diff_with_stats(&self, old_html: &str, new_html: &str) -> Result<(Vec<DomPatch>, DiffStats)> {
+        self.diff_parsed_with_stats(&self.parse(old_html), &self.parse(new_html))
+    }
+
+    /// Diffs HTML that grew by appending, e.g. streamed SSR output, given that the first
+    /// `unchanged_prefix_len` bytes of both versions are identical
+    ///
+    /// The prefix is only scanned for the elements still open where it ends, never parsed
+    /// or diffed. Complete elements appended at the end of the root or of an element with
+    /// `data-ts-sel`, `id` or `data-key` become a single `InsertElement`; any other change,
+    /// or a hint that doesn't hold, falls back to `diff`.
+    pub fn diff_appended(&self, old_html: &str, new_html: &str, unchanged_prefix_len: usize) -> Result<Vec<DomPatch>> {
+        match self.appended_patch(old_html, new_html, unchanged_prefix_len) {
+            Some(patch) => Ok(vec![patch]),
+            None => self.diff(old_html, new_html),
+        }
+    }
+
+    /// The `InsertElement` for HTML appended after `prefix_len` bytes, if that's all that changed
+    fn appended_patch(&self, old_html: &str, new_html: &str, prefix_len: usize) -> Option<DomPatch> {
+        if self.config.mode == DiffMode::TextOnly || self.config.no_raw_html {
+            return None;
+        }
+        let prefix = old_html.get(..prefix_len)?;
+        if !new_html.starts_with(prefix) || !(prefix.is_empty() || prefix.ends_with('>')) {
+            return None;
+        }
+
+        // The rest of the old HTML must close the new HTML too, leaving the appended
+        // region in between
+        let old_tail = &old_html[prefix_len..];
+        let appended = new_html[prefix_len..].strip_suffix(old_tail)?;
+        if !appended.trim_start().starts_with('<') || check_well_formed(appended).is_err() {
+            return None;
+        }
+
+        // Elements open at the end of the prefix, innermost last, as (tag name, attribute string)
+        let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+        let mut open: Vec<(String, &str)> = Vec::new();
+        for capture in tag_regex.captures_iter(prefix) {
+            let kind = capture.get(1).map_or("", |m| m.as_str());
+            let (Some(name), false) = (capture.get(2), kind.starts_with('!')) else {
+                continue;
+            };
+            let tag_name = name.as_str().to_lowercase();
+            if kind == "/" {
+                if let Some(position) = open.iter().rposition(|(open_tag, _)| *open_tag == tag_name) {
+                    open.truncate(position);
+                }
+            } else if !capture[3].trim_end().ends_with('/') && !VOID_ELEMENTS.contains(&tag_name.as_str()) {
+                open.push((tag_name, capture.get(3).map_or("", |m| m.as_str())));
+            }
+        }
+
+        // Only an append at the very end of its parent has an unambiguous position
+        let parent = match open.last() {
+            None if old_tail.trim().is_empty() => ROOT_SELECTOR.to_string(),
+            Some((tag_name, attributes)) => {
+                let closing = format!("</{}", tag_name);
+                let closes_parent = old_tail
+                    .trim_start()
+                    .get(..closing.len())
+                    .is_some_and(|head| head.eq_ignore_ascii_case(&closing));
+                let element =
+                    HtmlElement::new(tag_name.clone(), parse_attributes(attributes), String::new(), String::new());
+                if !closes_parent || (element.ts_selector.is_empty() && element.id.is_empty() && element.key.is_empty()) {
+                    return None;
+                }
+                self.build_element_selector("", &element)
+            }
+            None => return None,
+        };
+
+        Some(DomPatch::InsertElement {
+            parent,
+            position: InsertPosition::BeforeEnd,
+            html: appended.to_string(),
+        })
+    }
+
+    fn diff_parsed_with_stats(&self, old: &ParsedDocument, new: &ParsedDocument) -> Result<(Vec<DomPatch>, DiffStats)> {
+        let mut patches: Vec<DomPatch> = Vec::new();
+        let mut stats = DiffStats::default();
+
+        for (label, document) in [("old", old), ("new", new)] {
+            for (value, count) in &document.duplicate_ts_selectors {
+                stats.warnings.push(format!(
+                    "data-ts-sel {} is shared by {} elements in the {} HTML; later ones are addressed as {}~1, {}~2, ...",
+                    value, count, label, value, value
+                ));
+            }
+        }
+
+        if self.config.strict {
+            for (label, document) in [("old", old), ("new", new)] {
+                if let Err(reason) = check_well_formed(&document.html) {
+                    return Err(LiveTSError::HtmlParsingError(format!("{} HTML: {}", label, reason)));
+                }
+            }
+        }
+
+        // Comments, a regenerated component id and how boolean attributes are spelled aren't
+        // visible, so they alone never need a patch
+        if self.normalize_boolean_attributes(&without_comments_and_ids(&old.html))
+            == self.normalize_boolean_attributes(&without_comments_and_ids(&new.html))
+        {
+            return Ok((patches, stats));
+        }
+
+        // Keyed children are reconciled structurally, alongside the element diff
+        let keyed_patches = if self.config.mode == DiffMode::TextOnly {
+            Vec::new()
+        } else {
+            self.diff_keyed_lists(old, new)
+        };
 
         // Strategy 1: Intelligent element-by-element comparison
-        if let Some(smart_patches) = self.smart_element_diff(old_html, new_html) {
+        if let Some(smart_patches) = self.smart_element_diff(old, new, &mut stats) {
             patches.extend(smart_patches);
-            return Ok(patches);
+            patches.extend(keyed_patches);
+        } else if !keyed_patches.is_empty() {
+            patches.extend(keyed_patches);
+        } else if self.config.strict
+            && old.html.trim() != new.html.trim()
+            && [old, new].iter().any(|doc| doc.elements.is_none() && !doc.html.trim().is_empty())
+        {
+            // An empty render is unambiguous; only unparseable HTML is refused
+            return Err(LiveTSError::HtmlParsingError(
+                "no diffable elements found; refusing to fall back to a full replace".to_string(),
+            ));
+        } else if self.config.mode != DiffMode::TextOnly
+            && old.html.trim() != new.html.trim()
+            && self.compared_classes(&self.normalize_boolean_attributes(&old.html))
+                != self.compared_classes(&self.normalize_boolean_attributes(&new.html))
+        {
+            // Strategy 2: Fallback to full replacement if no intelligent diff found
+            patches.push(DomPatch::ReplaceInnerHtml {
+                selector: ROOT_SELECTOR.to_string(),
+                html: new.html.clone(),
+            });
         }
 
-        // Strategy 2: Fallback to full replacement if no intelligent diff found
-        if old_html.trim() != new_html.trim() {
-            patches.push(DomPatch::ReplaceInnerHtml {
-                selector: "[data-livets-root]".to_string(),
-                html: new_html.to_string(),
+        // A moved `selected` is sent as the `<select>`'s value, which also updates a select
+        // the user has already changed, where the attribute no longer would. The element
+        // diff leaves out the `selected` patches of those selects' options
+        if self.config.mode != DiffMode::TextOnly && !Self::is_full_replace(&patches) {
+            patches.extend(self.diff_select_values(&old.html, &new.html).into_iter().map(|(_, patch)| patch));
+        }
+
+        if self.config.no_raw_html {
+            patches.retain(|patch| {
+                let raw = matches!(
+                    patch,
+                    DomPatch::ReplaceInnerHtml { .. }
+                        | DomPatch::ReplaceElement { .. }
+                        | DomPatch::InsertElement { .. }
+                        | DomPatch::InsertBefore { .. }
+                );
+                if raw {
+                    stats.warnings.push(format!("dropped raw HTML patch (no_raw_html): {:?}", patch));
+                }
+                !raw
             });
         }
 
-        Ok(patches)
+        let before = patches.len();
+        let patches = merge_patches(patches);
+        if patches.len() < before {
+            stats.warnings.push(format!("merged {} redundant patches", before - patches.len()));
+        }
+        let patches = if self.config.combine_element_patches {
+            combine_element_patches(patches)
+        } else {
+            patches
+        };
+
+        stats.patch_count = patches.len();
+        Ok((patches, stats))
     }
 
     /// Smart diffing that handles any HTML elements and CSS classes generically
-    fn smart_element_diff(&self, old_html: &str, new_html: &str) -> Option<Vec<DomPatch>> {
-        let mut patches = Vec::new();
+    fn smart_element_diff(&self, old: &ParsedDocument, new: &ParsedDocument, stats: &mut DiffStats) -> Option<Vec<DomPatch>> {
+        let old_elements = old.elements.as_ref()?;
+        let new_elements = new.elements.as_ref()?;
 
-        // Parse both HTML strings to extract elements
-        let old_elements = self.parse_elements(old_html)?;
-        let new_elements = self.parse_elements(new_html)?;
-        
+        // Single-element components (a badge, a counter) have only one possible pairing
+        if let ([old_elem], [new_elem]) = (old_elements.as_slice(), new_elements.as_slice()) {
+            return self.single_element_diff(old, new, old_elem, new_elem);
+        }
+        self.matched_element_diff(old, new, stats)
+    }
 
+    /// `matched_element_diff` for one element on each side, without scoring candidates or
+    /// disambiguating selectors; pairs the elements exactly when `find_matching_element` would
+    fn single_element_diff(
+        &self,
+        old: &ParsedDocument,
+        new: &ParsedDocument,
+        old_elem: &HtmlElement,
+        new_elem: &HtmlElement,
+    ) -> Option<Vec<DomPatch>> {
+        let same_node = if !old_elem.match_key.is_empty() {
+            new_elem.match_key == old_elem.match_key
+        } else if !old_elem.key.is_empty() {
+            new_elem.key == old_elem.key
+        } else {
+            (!old_elem.ts_selector.is_empty() && new_elem.ts_selector == old_elem.ts_selector)
+                || (!old_elem.id.is_empty() && new_elem.id == old_elem.id)
+                || (new_elem.tag_name == old_elem.tag_name && new_elem.cell == old_elem.cell)
+        };
 
-        // Find the component ID for targeted selectors
-        let component_id = self.extract_component_id(new_html);
-        let base_selector = if let Some(id) = component_id {
-            format!("[data-livets-id=\"{}\"]", id)
+        let base_selector = self.base_selector(old, new);
+        let patches = if same_node {
+            self.diff_element(&base_selector, old_elem, new_elem)
+        } else if self.config.mode != DiffMode::TextOnly {
+            match self.find_identified_element(&new.html, old_elem) {
+                Some(identified) => self.diff_element(&base_selector, old_elem, &identified),
+                None => Vec::new(),
+            }
         } else {
-            "[data-livets-root]".to_string()
+            Vec::new()
         };
 
+        if patches.is_empty() {
+            None
+        } else {
+            Some(patches.into_iter().map(|patch| self.optimize_patch(patch)).collect())
+        }
+    }
+
+    /// Pairs each old element with its best match among the new ones and diffs the pairs
+    fn matched_element_diff(&self, old: &ParsedDocument, new: &ParsedDocument, stats: &mut DiffStats) -> Option<Vec<DomPatch>> {
+        // Patches paired with the index of the old element they target
+        let mut patches: Vec<(usize, DomPatch)> = Vec::new();
+
+        let old_elements = old.elements.as_ref()?;
+        let new_elements = new.elements.as_ref()?;
+
+        let base_selector = self.base_selector(old, new);
+
         // Process all elements generically
-        for old_elem in &old_elements {
+        for (index, old_elem) in old_elements.iter().enumerate() {
             // Find the best matching element in new_elements
-            if let Some(new_elem) = self.find_matching_element(old_elem, &new_elements) {
-                let text_changed = old_elem.text_content != new_elem.text_content;
-                let classes_changed = old_elem.classes != new_elem.classes;
-                
-                if text_changed && classes_changed {
-                    // Both changed: generate both patches
-                    let selector = self.build_element_selector(&base_selector, &old_elem);
-                    
-                    // Update classes first
-                    patches.push(DomPatch::SetAttribute {
-                        selector: selector.clone(),
-                        attr: "class".to_string(),
-                        value: new_elem.classes.clone(),
-                    });
-                    
-                    // Then update text
-                    patches.push(DomPatch::UpdateText {
-                        selector,
-                        text: new_elem.text_content.clone(),
-                    });
-                }
-                else if classes_changed {
-                    // Only class changed
-                    let selector = self.build_element_selector(&base_selector, &old_elem);
-                    patches.push(DomPatch::SetAttribute {
-                        selector,
-                        attr: "class".to_string(),
-                        value: new_elem.classes.clone(),
-                    });
-                }
-                else if text_changed {
-                    // Only text changed
-                    let selector = self.build_element_selector(&base_selector, &old_elem);
-                    patches.push(DomPatch::UpdateText {
-                        selector,
-                        text: new_elem.text_content.clone(),
-                    });
+            if let Some(matched) = self.find_matching_element(old_elem, new_elements) {
+                for patch in self.diff_element(&base_selector, old_elem, matched.element) {
+                    patches.push((index, patch));
                 }
+            } else if self.config.mode != DiffMode::TextOnly {
+                // The same element may have gained child elements, so it's no longer a leaf
+                if let Some(new_elem) = self.find_identified_element(&new.html, old_elem) {
+                    for patch in self.diff_element(&base_selector, old_elem, &new_elem) {
+                        patches.push((index, patch));
+                    }
+                }
+            }
+        }
+
+        // Selects whose selected option moved get a value patch instead, see `diff_parsed_with_stats`
+        if self.config.mode != DiffMode::TextOnly {
+            let changed_selects: Vec<std::ops::Range<usize>> =
+                self.diff_select_values(&old.html, &new.html).into_iter().map(|(range, _)| range).collect();
+            if !changed_selects.is_empty() {
+                patches.retain(|(index, patch)| {
+                    let selection = matches!(
+                        patch,
+                        DomPatch::SetAttribute { attr, .. } | DomPatch::RemoveAttribute { attr, .. } if attr == "selected"
+                    );
+                    let in_changed_select = old_elements[*index]
+                        .offset
+                        .is_some_and(|offset| changed_selects.iter().any(|range| range.contains(&offset)));
+                    !(selection && in_changed_select)
+                });
             }
         }
 
         if patches.is_empty() { None } else { 
+            let patches = self.disambiguate_selectors(&old.html, old_elements, patches, stats);
+
             // Convert to compact format
             let compact_patches: Vec<DomPatch> = patches.into_iter().map(|patch| {
                 self.optimize_patch(patch)
@@ -102,32 +466,516 @@ impl HtmlDiffer {
         }
     }
 
+    /// `SetProperty` value patches for the single-choice `<select>`s whose selected option
+    /// changed, each with the select's source range in `old_html`; selects are paired by
+    /// position and skipped if their count changed
+    fn diff_select_values(&self, old_html: &str, new_html: &str) -> Vec<(std::ops::Range<usize>, DomPatch)> {
+        let (old_values, new_values) = (self.select_values(old_html), self.select_values(new_html));
+        if old_values.len() != new_values.len() {
+            return Vec::new();
+        }
+        old_values
+            .into_iter()
+            .zip(new_values)
+            .filter(|((old_selector, old_value, _), (new_selector, new_value, _))| {
+                old_selector == new_selector && old_value != new_value
+            })
+            .map(|((_, _, range), (selector, value, _))| {
+                let patch = DomPatch::SetProperty {
+                    selector: self.optimize_selector(selector),
+                    property: "value".to_string(),
+                    value,
+                };
+                (range, patch)
+            })
+            .collect()
+    }
+
+    /// (selector, value, source range) of each `<select>` without `multiple`, in document order
+    ///
+    /// The value is the last `selected` option's, else the first option's, as the browser
+    /// picks it; an option without a `value` attribute has its trimmed text as its value
+    fn select_values(&self, html: &str) -> Vec<(String, String, std::ops::Range<usize>)> {
+        if !html.contains("<select") && !html.contains("<SELECT") {
+            return Vec::new();
+        }
+        let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+        let mut values = Vec::new();
+        // (selector, first option's value, selected option's value, start) of the open select
+        let mut open: Option<(String, Option<String>, Option<String>, usize)> = None;
+
+        for capture in tag_regex.captures_iter(html) {
+            let kind = capture.get(1).map_or("", |m| m.as_str());
+            let tag_name = capture.get(2).map_or(String::new(), |m| m.as_str().to_lowercase());
+            let attributes = parse_attributes(capture.get(3).map_or("", |m| m.as_str()));
+            let has = |name: &str| attributes.iter().any(|(attr, _)| attr == name);
+
+            match (kind, tag_name.as_str()) {
+                ("", "select") if has("multiple") => open = None,
+                ("", "select") => {
+                    let name = attributes.iter().find(|(attr, _)| attr == "name").map(|(_, value)| value.clone());
+                    let element = HtmlElement::new(tag_name.clone(), attributes.clone(), String::new(), String::new());
+                    let selector = match self.build_element_selector("", &element) {
+                        // A bare tag can't tell selects apart, their names can
+                        selector if selector == "select" && name.is_some() => {
+                            format!("select[name=\"{}\"]", css_string(&name.unwrap_or_default()))
+                        }
+                        selector => selector,
+                    };
+                    open = Some((selector, None, None, capture.get(0).map_or(0, |m| m.start())));
+                }
+                ("", "option") => {
+                    if let Some((_, first, selected, _)) = open.as_mut() {
+                        let value = match attributes.iter().find(|(attr, _)| attr == "value") {
+                            Some((_, value)) => value.clone(),
+                            None => {
+                                let text = &html[capture.get(0).map_or(0, |m| m.end())..];
+                                decode_entities(text.split('<').next().unwrap_or_default().trim())
+                            }
+                        };
+                        if has("selected") {
+                            *selected = Some(value.clone());
+                        }
+                        first.get_or_insert(value);
+                    }
+                }
+                ("/", "select") => {
+                    if let Some((selector, first, selected, start)) = open.take() {
+                        let end = capture.get(0).map_or(start, |m| m.end());
+                        values.push((selector, selected.or(first).unwrap_or_default(), start..end));
+                    }
+                }
+                _ => {}
+            }
+        }
+        values
+    }
+
+    /// Selector scoping a component's patches; the client still has the old component id
+    fn base_selector(&self, old: &ParsedDocument, new: &ParsedDocument) -> String {
+        match old.component_id.as_ref().or(new.component_id.as_ref()) {
+            Some(id) => format!("[data-livets-id=\"{}\"]", id),
+            None => ROOT_SELECTOR.to_string(),
+        }
+    }
+
+    /// The elements the differ matches on, as it sees them in `html`, for tests and tooling
+    /// (e.g. checking that every dynamic element of a template carries `data-ts-sel`)
+    pub fn parse_elements_public(&self, html: &str) -> Vec<PublicHtmlElement> {
+        self.parse_elements(html)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|element| PublicHtmlElement {
+                tag_name: element.tag_name,
+                classes: element.classes,
+                id: element.id,
+                ts_selector: element.ts_selector,
+                text_content: element.text_content,
+            })
+            .collect()
+    }
+
+    /// Element-level patches, each with how its element was matched, for a devtools overlay
+    ///
+    /// Covers the patches the element matcher produces before selectors are disambiguated
+    /// and patches merged; keyed-list moves and the full-replace fallback aren't annotated
+    pub fn diff_debug(&self, old_html: &str, new_html: &str) -> Vec<(DomPatch, MatchInfo)> {
+        let (old, new) = (self.parse(old_html), self.parse(new_html));
+        let (Some(old_elements), Some(new_elements)) = (&old.elements, &new.elements) else {
+            return Vec::new();
+        };
+        let base_selector = self.base_selector(&old, &new);
+        let mut annotated = Vec::new();
+
+        for (old_index, old_elem) in old_elements.iter().enumerate() {
+            let (new_elem, info) = match self.find_matching_element(old_elem, new_elements) {
+                Some(matched) => (
+                    matched.element.clone(),
+                    MatchInfo {
+                        strategy: matched.strategy,
+                        score: matched.score,
+                        tag: matched.element.tag_name.clone(),
+                        old_index,
+                        new_index: Some(matched.index),
+                    },
+                ),
+                None if self.config.mode == DiffMode::TextOnly => continue,
+                None => match self.find_identified_element(&new.html, old_elem) {
+                    Some(element) => {
+                        let tag = element.tag_name.clone();
+                        let info = MatchInfo { strategy: MatchStrategy::Identity, score: 0, tag, old_index, new_index: None };
+                        (element, info)
+                    }
+                    None => continue,
+                },
+            };
+            for patch in self.diff_element(&base_selector, old_elem, &new_elem) {
+                annotated.push((self.optimize_patch(patch), info.clone()));
+            }
+        }
+        annotated
+    }
+
+    /// Generates the patches turning one matched element into its new version
+    fn diff_element(&self, base_selector: &str, old_elem: &HtmlElement, new_elem: &HtmlElement) -> Vec<DomPatch> {
+        let mut patches = Vec::new();
+
+        if self.config.mode == DiffMode::TextOnly {
+            // A tag change is structural, so only same-tag text edits are applied
+            if old_elem.tag_name == new_elem.tag_name && old_elem.text_content != new_elem.text_content {
+                patches.push(DomPatch::UpdateText {
+                    selector: self.build_element_selector(base_selector, old_elem),
+                    text: decode_entities(&new_elem.text_content),
+                });
+            }
+            return patches;
+        }
+
+        // Same logical node rendered with a different tag: swap just this node
+        if old_elem.tag_name != new_elem.tag_name {
+            patches.push(DomPatch::ReplaceElement {
+                selector: self.build_element_selector(base_selector, old_elem),
+                html: new_elem.outer_html.clone(),
+            });
+            return patches;
+        }
+
+        let text_changed = old_elem.text_content != new_elem.text_content;
+        let attribute_changes = self.diff_attributes(old_elem, new_elem, is_component_root(base_selector, old_elem));
+        let class_toggles = self.diff_managed_classes(old_elem, new_elem);
+
+        if !text_changed && attribute_changes.is_empty() && class_toggles.is_empty() {
+            return patches;
+        }
+
+        let selector = self.build_element_selector(base_selector, old_elem);
+
+        // Attributes first
+        for (attr, value) in attribute_changes {
+            patches.push(match value {
+                Some(value) => DomPatch::SetAttribute {
+                    selector: selector.clone(),
+                    attr,
+                    value,
+                },
+                None => DomPatch::RemoveAttribute {
+                    selector: selector.clone(),
+                    attr,
+                },
+            });
+        }
+        for (class, add) in class_toggles {
+            patches.push(DomPatch::ToggleClass { selector: selector.clone(), class, add });
+        }
+
+        // Then update text
+        if text_changed {
+            patches.push(self.content_patch(selector, &new_elem.text_content));
+        }
+
+        patches
+    }
+
+    /// Patch setting an element's content as text or HTML, per `DifferConfig::content`
+    fn content_patch(&self, selector: String, content: &str) -> DomPatch {
+        let is_markup = match self.config.content {
+            _ if self.config.no_raw_html => false,
+            ContentKind::Auto => regex::Regex::new(r"<[a-zA-Z]").unwrap().is_match(content),
+            ContentKind::Text => false,
+            ContentKind::Html => true,
+        };
+
+        if is_markup {
+            DomPatch::ReplaceInnerHtml {
+                selector,
+                html: content.to_string(),
+            }
+        } else {
+            DomPatch::UpdateText {
+                selector,
+                text: decode_entities(content),
+            }
+        }
+    }
+
+    /// Finds the element with the same identity (match hint, key, ts selector or id) as
+    /// `target` anywhere in `html`, with its inner HTML as the text content
+    fn find_identified_element(&self, html: &str, target: &HtmlElement) -> Option<HtmlElement> {
+        let identity = [
+            ("data-ts-match", &target.match_key),
+            ("data-key", &target.key),
+            ("data-ts-sel", &target.ts_selector),
+            ("id", &target.id),
+        ]
+        .into_iter()
+        .find(|(_, value)| !value.is_empty())?;
+
+        /// (attributes, start, content start) of the matching opening tag
+        type OpenTag = (Vec<(String, String)>, usize, usize);
+
+        let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+        let mut open: Option<OpenTag> = None;
+        let mut depth = 0;
+
+        for capture in tag_regex.captures_iter(html) {
+            let prefix = capture.get(1).map_or("", |m| m.as_str());
+            let Some(name) = capture.get(2).filter(|_| !prefix.starts_with('!')) else {
+                continue;
+            };
+            if !name.as_str().eq_ignore_ascii_case(&target.tag_name) {
+                continue;
+            }
+            let whole = capture.get(0)?;
+            let self_closing = capture[3].trim_end().ends_with('/');
+
+            match &open {
+                None if prefix.is_empty() && !self_closing => {
+                    let attributes = parse_attributes(&capture[3]);
+                    if attributes.iter().any(|(attr, value)| attr == identity.0 && value == identity.1) {
+                        open = Some((attributes, whole.start(), whole.end()));
+                        depth = 1;
+                    }
+                }
+                None => {}
+                Some(_) if prefix == "/" => depth -= 1,
+                Some(_) if !self_closing => depth += 1,
+                Some(_) => {}
+            }
+
+            if depth == 0 {
+                if let Some((attributes, start, content_start)) = open {
+                    return Some(HtmlElement::new(
+                        target.tag_name.clone(),
+                        attributes,
+                        html[content_start..whole.start()].trim().to_string(),
+                        html[start..whole.end()].to_string(),
+                    ));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Post-pass making sure patches for different source elements never share a selector
+    ///
+    /// Colliding selectors are narrowed by an attribute whose value is unique within the
+    /// collision, otherwise by `:nth-of-type` with the element's position among its
+    /// same-tag siblings in `old_html`. Every collision is recorded as a warning in `stats`.
+    fn disambiguate_selectors(
+        &self,
+        old_html: &str,
+        old_elements: &[HtmlElement],
+        patches: Vec<(usize, DomPatch)>,
+        stats: &mut DiffStats,
+    ) -> Vec<DomPatch> {
+        let mut targets: Vec<(String, Vec<usize>)> = Vec::new();
+        for (index, patch) in &patches {
+            let Some(selector) = patch_selector(patch) else { continue };
+            match targets.iter_mut().find(|(existing, _)| existing == selector) {
+                Some((_, indices)) if !indices.contains(index) => indices.push(*index),
+                Some(_) => {}
+                None => targets.push((selector.to_string(), vec![*index])),
+            }
+        }
+
+        let mut replacements: std::collections::HashMap<usize, String> = std::collections::HashMap::new();
+        for (selector, indices) in targets.iter().filter(|(_, indices)| indices.len() > 1) {
+            // data-ts-sel values are sent bare, so they can't be narrowed with CSS
+            if indices.iter().any(|&index| !old_elements[index].ts_selector.is_empty()) {
+                stats.warnings.push(format!(
+                    "selector {} targets {} different elements",
+                    selector,
+                    indices.len()
+                ));
+                continue;
+            }
+
+            for &index in indices {
+                let element = &old_elements[index];
+                let unique_attribute = element.attributes.iter().find(|(name, value)| {
+                    !value.is_empty()
+                        && name != "class"
+                        && name != "style"
+                        && indices.iter().filter(|&&other| other != index).all(|&other| {
+                            !old_elements[other].attributes.iter().any(|(n, v)| n == name && v == value)
+                        })
+                });
+
+                let narrowed = match unique_attribute {
+                    Some((name, value)) => format!("{}[{}=\"{}\"]", selector, name, css_string(value)),
+                    None => match element.offset.and_then(|offset| nth_of_type(old_html, offset)) {
+                        Some(position) => format!("{}:nth-of-type({})", selector, position),
+                        None => continue,
+                    },
+                };
+                replacements.insert(index, narrowed);
+            }
+
+            let message = if indices.iter().all(|index| replacements.contains_key(index)) {
+                "and was disambiguated"
+            } else {
+                "and could not be fully disambiguated"
+            };
+            stats.warnings.push(format!(
+                "selector {} targeted {} different elements {}",
+                selector,
+                indices.len(),
+                message
+            ));
+        }
+
+        patches
+            .into_iter()
+            .map(|(index, mut patch)| {
+                if let (Some(narrowed), Some(selector)) = (replacements.get(&index), patch_selector_mut(&mut patch)) {
+                    *selector = narrowed.clone();
+                }
+                patch
+            })
+            .collect()
+    }
+
+    /// Three-way attribute comparison, returning (attr, new value) or (attr, None) to remove
+    ///
+    /// Added and changed attributes come first (class ahead of the rest), then removals.
+    /// Boolean attributes only compare presence and are always set to an empty value.
+    /// A component root's `data-livets-id` is regenerated per render, so it's ignored there.
+    fn diff_attributes(&self, old_elem: &HtmlElement, new_elem: &HtmlElement, root: bool) -> Vec<(String, Option<String>)> {
+        let mut changes: Vec<(String, Option<String>)> = Vec::new();
+
+        let managed_classes = !self.config.managed_class_prefixes.is_empty();
+        let skipped = |attr: &str| (root && attr == "data-livets-id") || (attr == "class" && managed_classes);
+        for (attr, value) in &new_elem.attributes {
+            if skipped(attr) || changes.iter().any(|(seen, _)| seen == attr) {
+                continue;
+            }
+            let is_boolean = self.is_boolean_attribute(attr);
+            let changed = match old_elem.attribute(attr) {
+                None => true,
+                Some(_) if is_boolean => false,
+                // Class order doesn't affect rendering, so only a different set counts
+                Some(old_value) if attr == "class" => !same_class_set(old_value, value),
+                Some(old_value) => old_value != value,
+            };
+            if changed {
+                let value = if is_boolean { String::new() } else { value.clone() };
+                changes.push((attr.clone(), Some(value)));
+            }
+        }
+        changes.sort_by_key(|(attr, _)| attr != "class");
+
+        for (attr, _) in &old_elem.attributes {
+            if !skipped(attr) && !new_elem.has_attribute(attr) && !changes.iter().any(|(seen, _)| seen == attr) {
+                changes.push((attr.clone(), None));
+            }
+        }
+
+        changes
+    }
+
+    /// (class, added) for each managed class the element gained or lost, removals first
+    fn diff_managed_classes(&self, old_elem: &HtmlElement, new_elem: &HtmlElement) -> Vec<(String, bool)> {
+        let managed = |element: &HtmlElement| -> Vec<String> {
+            element
+                .classes
+                .split_whitespace()
+                .filter(|class| self.is_managed_class(class))
+                .map(str::to_string)
+                .collect()
+        };
+        let (old_classes, new_classes) = (managed(old_elem), managed(new_elem));
+        let removed = old_classes.iter().filter(|class| !new_classes.contains(class)).map(|class| (class.clone(), false));
+        let added = new_classes.iter().filter(|class| !old_classes.contains(class)).map(|class| (class.clone(), true));
+        removed.chain(added).collect()
+    }
+
+    fn is_managed_class(&self, class: &str) -> bool {
+        self.config.managed_class_prefixes.iter().any(|prefix| class.starts_with(prefix.as_str()))
+    }
+
+    /// The HTML with class lists sorted, and narrowed to the managed classes when only those
+    /// are diffed, so documents differing in nothing the differ patches compare equal
+    fn compared_classes<'a>(&self, html: &'a str) -> std::borrow::Cow<'a, str> {
+        if self.config.managed_class_prefixes.is_empty() {
+            return sorted_classes(html);
+        }
+        let class_regex = regex::Regex::new(r#"(\sclass=")([^"]*)""#).unwrap();
+        class_regex.replace_all(html, |capture: &regex::Captures| {
+            let mut classes: Vec<&str> = capture[2].split_whitespace().filter(|class| self.is_managed_class(class)).collect();
+            classes.sort_unstable();
+            format!("{}{}\"", &capture[1], classes.join(" "))
+        })
+    }
+
+    fn is_boolean_attribute(&self, attr: &str) -> bool {
+        match &self.config.boolean_attributes {
+            Some(names) => names.iter().any(|name| name.eq_ignore_ascii_case(attr)),
+            None => BOOLEAN_ATTRIBUTES.iter().any(|name| name.eq_ignore_ascii_case(attr)),
+        }
+    }
+
+    /// Rewrites `x=""` and `x="x"` to a bare `x` for boolean attributes, inside tags only
+    fn normalize_boolean_attributes<'a>(&self, html: &'a str) -> std::borrow::Cow<'a, str> {
+        let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+        let attr_regex =
+            regex::Regex::new(r#"(\s)([a-zA-Z][\w:-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'=<>`]+))"#).unwrap();
+        tag_regex.replace_all(html, |tag: &regex::Captures| {
+            let attributes = attr_regex.replace_all(&tag[3], |attr: &regex::Captures| {
+                let name = &attr[2];
+                let value = attr.get(3).or(attr.get(4)).or(attr.get(5)).map_or("", |m| m.as_str());
+                if self.is_boolean_attribute(name) && (value.is_empty() || value.eq_ignore_ascii_case(name)) {
+                    format!("{}{}", &attr[1], name)
+                } else {
+                    attr[0].to_string()
+                }
+            });
+            format!("<{}{}{}>", &tag[1], tag.get(2).map_or("", |m| m.as_str()), attributes)
+        })
+    }
+
     /// Find the best matching element based on tag name and context
-    fn find_matching_element<'a>(&self, target: &HtmlElement, candidates: &'a [HtmlElement]) -> Option<&'a HtmlElement> {
+    fn find_matching_element<'a>(&self, target: &HtmlElement, candidates: &'a [HtmlElement]) -> Option<ElementMatch<'a>> {
+        let found = |strategy: MatchStrategy, matches: &dyn Fn(&HtmlElement) -> bool| {
+            candidates
+                .iter()
+                .enumerate()
+                .find(|(_, candidate)| matches(candidate))
+                .map(|(index, element)| ElementMatch { index, element, strategy, score: 0 })
+        };
+
+        // Priority 0: Explicit matching hint from the template is authoritative,
+        // so a hinted element with no counterpart is treated as gone
+        if !target.match_key.is_empty() {
+            return found(MatchStrategy::Hint, &|candidate| candidate.match_key == target.match_key);
+        }
+
+        // Priority 0b: data-key identifies the node regardless of its tag name
+        if !target.key.is_empty() {
+            return found(MatchStrategy::Key, &|candidate| candidate.key == target.key);
+        }
+
         // Priority 1: Exact ts_selector match (most reliable)
         if !target.ts_selector.is_empty() {
-            for candidate in candidates {
-                if candidate.ts_selector == target.ts_selector {
-                    return Some(candidate);
-                }
+            if let Some(matched) = found(MatchStrategy::TsSel, &|candidate| candidate.ts_selector == target.ts_selector) {
+                return Some(matched);
             }
         }
         
         // Priority 2: Exact ID match (very reliable)
         if !target.id.is_empty() {
-            for candidate in candidates {
-                if candidate.id == target.id {
-                    return Some(candidate);
-                }
+            if let Some(matched) = found(MatchStrategy::Id, &|candidate| candidate.id == target.id) {
+                return Some(matched);
             }
         }
         
-        // Priority 3: Score-based matching for elements without unique identifiers
+        // Priority 3: Score-based matching for elements without unique identifiers,
+        // with table content only matched within the same cell of the same row
         let mut best_match = None;
         let mut best_score = 0;
         
-        for candidate in candidates {
-            if candidate.tag_name == target.tag_name {
+        for (index, candidate) in candidates.iter().enumerate() {
+            if candidate.tag_name == target.tag_name && candidate.cell == target.cell {
                 let mut score = 1; // Base score for same tag
                 
                 // Boost score for similar class patterns (e.g., both have "text-4xl")
@@ -142,7 +990,7 @@ impl HtmlDiffer {
                 
                 // Boost for common class patterns (any shared significant class)
                 for target_class in &target_classes {
-                    if target_class.len() > 3 && candidate_classes.contains(target_class) {
+                    if target_class.chars().count() > 3 && candidate_classes.contains(target_class) {
                         score += 2;
                     }
                 }
@@ -154,14 +1002,14 @@ impl HtmlDiffer {
                         score += 3;
                     }
                     // Both have similar length text
-                    else if target.text_content.len() == candidate.text_content.len() {
+                    else if target.text_content.chars().count() == candidate.text_content.chars().count() {
                         score += 1;
                     }
                 }
                 
                 if score > best_score {
                     best_score = score;
-                    best_match = Some(candidate);
+                    best_match = Some(ElementMatch { index, element: candidate, strategy: MatchStrategy::Score, score });
                 }
             }
         }
@@ -169,71 +1017,218 @@ impl HtmlDiffer {
         best_match
     }
 
-        /// Parse HTML to extract all meaningful elements
+    /// Parse HTML to extract all meaningful elements
     fn parse_elements(&self, html: &str) -> Option<Vec<HtmlElement>> {
-        let mut elements = Vec::new();
-        
-        // Regex to match any element with content: <tag attributes>content</tag>
-        let element_regex = regex::Regex::new(r#"<(\w+)([^>]*)>([^<]*)</(\w+)>"#).unwrap();
-        
-        for capture in element_regex.captures_iter(html) {
-            let open_tag = capture.get(1)?.as_str().to_string();
-            let attributes = capture.get(2)?.as_str();
-            let text_content = capture.get(3)?.as_str().trim().to_string();
-            let close_tag = capture.get(4)?.as_str();
-            
-            // Only process if opening and closing tags match
-            if open_tag == close_tag {
-                // Extract class attribute
-                let class_regex = regex::Regex::new(r#"class="([^"]*)""#).unwrap();
-                let classes = class_regex.captures(attributes)
-                    .map(|m| m.get(1).unwrap().as_str().to_string())
-                    .unwrap_or_default();
-                
-                // Extract id attribute
-                let id_regex = regex::Regex::new(r#"id="([^"]*)""#).unwrap();
-                let id = id_regex.captures(attributes)
-                    .map(|m| m.get(1).unwrap().as_str().to_string())
-                    .unwrap_or_default();
-                
-                // Extract data-ts-selector attribute
-                let ts_selector_regex = regex::Regex::new(r#"data-ts-sel="([^"]*)""#).unwrap();
-                let ts_selector = ts_selector_regex.captures(attributes)
-                    .map(|m| m.get(1).unwrap().as_str().to_string())
-                    .unwrap_or_default();
-                
-                elements.push(HtmlElement {
-                    tag_name: open_tag,
-                    classes,
-                    text_content,
-                    id,
-                    ts_selector,
-                });
-            }
-        }
-        
+        let elements = self.extractor.extract(html);
         if elements.is_empty() { None } else { Some(elements) }
     }
 
-    /// Extract component ID from HTML
-    fn extract_component_id(&self, html: &str) -> Option<String> {
-        let id_regex = regex::Regex::new(r#"data-livets-id="([^"]+)""#).ok()?;
-        let capture = id_regex.captures(html)?;
-        Some(capture.get(1)?.as_str().to_string())
-    }
+    /// Find elements whose element children all carry `data-key`, with those children in order
+    fn parse_keyed_lists(&self, html: &str) -> Vec<KeyedList> {
+        struct OpenElement {
+            tag_name: String,
+            attributes: Vec<(String, String)>,
+            start: usize,
+            keyed_children: Vec<(String, String)>,
+            has_unkeyed_children: bool,
+        }
 
-    /// Build a specific CSS selector for an element
-    fn build_element_selector(&self, _base_selector: &str, element: &HtmlElement) -> String {
-        // Strategy 1: Use data-ts-selector if available (most precise and framework-native)
-        if !element.ts_selector.is_empty() {
-            // Return compact selector format for WebSocket transmission
-            return element.ts_selector.clone();
+        fn close(
+            differ: &HtmlDiffer,
+            element: OpenElement,
+            outer_html: &str,
+            stack: &mut [OpenElement],
+            lists: &mut Vec<KeyedList>,
+        ) {
+            let key = element
+                .attributes
+                .iter()
+                .find(|(name, _)| name == "data-key")
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default();
+            if let Some(parent) = stack.last_mut() {
+                if key.is_empty() {
+                    parent.has_unkeyed_children = true;
+                } else {
+                    parent.keyed_children.push((key, outer_html.to_string()));
+                }
+            }
+
+            if !element.keyed_children.is_empty() && !element.has_unkeyed_children {
+                let parent = HtmlElement::new(element.tag_name, element.attributes, String::new(), String::new());
+                lists.push(KeyedList {
+                    parent_selector: differ.build_element_selector("", &parent),
+                    children: element.keyed_children,
+                });
+            }
         }
-        
+
+        if !html.contains("data-key") {
+            return Vec::new();
+        }
+
+        let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+        let mut stack: Vec<OpenElement> = Vec::new();
+        let mut lists = Vec::new();
+
+        for capture in tag_regex.captures_iter(html) {
+            let prefix = capture.get(1).map_or("", |m| m.as_str());
+            let (Some(name), false) = (capture.get(2), prefix.starts_with('!')) else {
+                continue;
+            };
+            let tag_name = name.as_str().to_lowercase();
+            let whole = capture.get(0).unwrap();
+
+            if prefix == "/" {
+                // Elements left open inside this one are implicitly closed with it
+                let Some(position) = stack.iter().rposition(|open| open.tag_name == tag_name) else {
+                    continue;
+                };
+                while stack.len() > position {
+                    let element = stack.pop().unwrap();
+                    let outer_html = &html[element.start..whole.end()];
+                    close(self, element, outer_html, &mut stack, &mut lists);
+                }
+                continue;
+            }
+
+            let element = OpenElement {
+                attributes: parse_attributes(&capture[3]),
+                start: whole.start(),
+                keyed_children: Vec::new(),
+                has_unkeyed_children: false,
+                tag_name,
+            };
+            if capture[3].trim_end().ends_with('/') || VOID_ELEMENTS.contains(&element.tag_name.as_str()) {
+                close(self, element, whole.as_str(), &mut stack, &mut lists);
+            } else {
+                stack.push(element);
+            }
+        }
+
+        lists
+    }
+
+    /// Reconcile keyed lists present in both documents with a longest common subsequence
+    ///
+    /// Children in the LCS stay where they are (their content is diffed like any other
+    /// element); everything else is removed and/or inserted at its new position, so an
+    /// insertion in the middle of a list costs one patch instead of touching every row.
+    fn diff_keyed_lists(&self, old: &ParsedDocument, new: &ParsedDocument) -> Vec<DomPatch> {
+        let mut patches = Vec::new();
+
+        for new_list in &new.keyed_lists {
+            let Some(old_list) = old
+                .keyed_lists
+                .iter()
+                .find(|list| list.parent_selector == new_list.parent_selector)
+            else {
+                continue;
+            };
+
+            let old_keys: Vec<&str> = old_list.children.iter().map(|(key, _)| key.as_str()).collect();
+            let new_keys: Vec<&str> = new_list.children.iter().map(|(key, _)| key.as_str()).collect();
+            let common = longest_common_subsequence(&old_keys, &new_keys);
+            let kept_old: std::collections::HashSet<usize> = common.iter().map(|(old_index, _)| *old_index).collect();
+            let kept_new: std::collections::HashSet<usize> = common.iter().map(|(_, new_index)| *new_index).collect();
+            let child_selector = |key: &str| format!("{} > [data-key=\"{}\"]", new_list.parent_selector, css_string(key));
+
+            // Removals first, so later positions only refer to children that stay
+            for (index, key) in old_keys.iter().enumerate() {
+                if !kept_old.contains(&index) && !new_keys.contains(key) {
+                    patches.push(DomPatch::RemoveElement { selector: child_selector(key) });
+                }
+            }
+
+            // Insertions and moves in new order: a child first in the list goes to the top, one
+            // followed by a kept child goes right before it, and the tail follows the sibling
+            // before it (already in place, since patches are applied in order). Children that
+            // are in both lists but out of order are moved, so their DOM state survives
+            for (index, (key, html)) in new_list.children.iter().enumerate() {
+                if kept_new.contains(&index) {
+                    continue;
+                }
+                let next_kept = (index + 1..new_keys.len()).find(|later| kept_new.contains(later));
+                let previous = index.checked_sub(1).map(|previous| new_keys[previous]);
+                if old_keys.contains(&key.as_str()) {
+                    let (target, position) = match (previous, next_kept) {
+                        (Some(_), Some(next)) => (child_selector(new_keys[next]), InsertPosition::BeforeBegin),
+                        (Some(previous), None) => (child_selector(previous), InsertPosition::AfterEnd),
+                        (None, _) => (new_list.parent_selector.clone(), InsertPosition::AfterBegin),
+                    };
+                    patches.push(DomPatch::MoveElement { selector: child_selector(key), target, position });
+                    continue;
+                }
+                patches.push(match (previous, next_kept) {
+                    (Some(_), Some(next)) => DomPatch::InsertBefore {
+                        reference_selector: child_selector(new_keys[next]),
+                        html: html.clone(),
+                    },
+                    (Some(previous), None) => DomPatch::InsertElement {
+                        parent: child_selector(previous),
+                        position: InsertPosition::AfterEnd,
+                        html: html.clone(),
+                    },
+                    (None, _) => DomPatch::InsertElement {
+                        parent: new_list.parent_selector.clone(),
+                        position: InsertPosition::AfterBegin,
+                        html: html.clone(),
+                    },
+                });
+            }
+        }
+
+        patches
+    }
+
+    /// Extract component ID from HTML
+    fn extract_component_id(&self, html: &str) -> Option<String> {
+        let id_regex =
+            regex::Regex::new(r#"data-livets-id\s*=\s*(?:"([^"]+)"|'([^']+)'|([^\s"'=<>`]+))"#).ok()?;
+        let capture = id_regex.captures(html)?;
+        let value = capture.get(1).or_else(|| capture.get(2)).or_else(|| capture.get(3))?;
+        Some(value.as_str().to_string())
+    }
+
+    /// Build a specific CSS selector for an element
+    fn build_element_selector(&self, base_selector: &str, element: &HtmlElement) -> String {
+        // Strategy 0: Shadow-DOM parts and the host, resolved by the client at the shadow root
+        if self.config.shadow_dom {
+            if is_component_root(base_selector, element) {
+                return ":host".to_string();
+            }
+            let parts: String = element
+                .attribute("part")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|part| format!("[part~=\"{}\"]", css_string(part)))
+                .collect();
+            if !parts.is_empty() {
+                return parts;
+            }
+        }
+
+        // Strategy 1: Use data-ts-selector if available (most precise and framework-native)
+        if !element.ts_selector.is_empty() {
+            // Return compact selector format for WebSocket transmission
+            return element.ts_selector.clone();
+        }
+        
         // Strategy 2: Use ID if available (most stable and specific)
         if !element.id.is_empty() {
             return format!("#{}", element.id);
         }
+
+        // Strategy 2b: Use data-key, which stays stable even when the tag changes
+        if !element.key.is_empty() {
+            return format!("[data-key=\"{}\"]", css_string(&element.key));
+        }
+
+        // Strategy 2c: Address table content through its row and cell, never by shared classes
+        if let Some(cell) = &element.cell {
+            let cell_selector = cell.selector();
+            return if cell.is_element { cell_selector } else { format!("{} {}", cell_selector, element.tag_name) };
+        }
         
         // Strategy 3: Use distinguishing classes for elements without framework selectors
         if !element.classes.is_empty() {
@@ -258,7 +1253,7 @@ impl HtmlDiffer {
         }
         
         // Strategy 4: Use text content as additional specificity for short text
-        if !element.text_content.is_empty() && element.text_content.len() <= 10 {
+        if !element.text_content.is_empty() && element.text_content.chars().count() <= 10 {
             return format!("{}:contains('{}')", 
                 element.tag_name,
                 element.text_content.replace("'", "\\'")
@@ -309,7 +1304,14 @@ impl HtmlDiffer {
 
     /// Converts a DomPatch directly to ultra-compact string format
     /// Format: "op|selector|data"
-    /// Operations: t=UpdateText, a=SetAttribute, r=RemoveAttribute, h=ReplaceInnerHtml, e=ReplaceElement
+    /// Operations: t=UpdateText, a=SetAttribute, r=RemoveAttribute, h=ReplaceInnerHtml, e=ReplaceElement,
+    /// x=RemoveElement, i=InsertElement, b=InsertBefore, m=MoveElement (`m|selector|bb/ab/be/ae|target`),
+    /// u=UpdateElement (`u|selector|{"attr":"value"}|text`, the text field omitted when unchanged;
+    /// `|` inside the JSON is escaped as `\u007c`), p=SetProperty (`p|selector|property|value`),
+    /// k=ToggleClass (`k|selector|class|1` adds the class, `|0` removes it),
+    /// q=ClientCommand (`q|command|{"arg":"value"}`, no selector)
+    /// The last field runs to the end of the string, so text, HTML and attribute values
+    /// (e.g. data URIs) are sent as-is even when they contain `|`
     fn patch_to_compact(&self, patch: DomPatch) -> String {
         match patch {
             DomPatch::UpdateText { selector, text } => {
@@ -332,10 +1334,62 @@ impl HtmlDiffer {
                 let compact_selector = self.optimize_selector(selector);
                 format!("e|{}|{}", compact_selector, html)
             }
+            DomPatch::RemoveElement { selector } => {
+                let compact_selector = self.optimize_selector(selector);
+                format!("x|{}", compact_selector)
+            }
+            DomPatch::InsertElement { parent, position, html } => {
+                let compact_selector = self.optimize_selector(parent);
+                format!("i|{}|{}|{}", compact_selector, compact_position(&position), html)
+            }
+            DomPatch::MoveElement { selector, target, position } => {
+                let compact_selector = self.optimize_selector(selector);
+                format!("m|{}|{}|{}", compact_selector, compact_position(&position), self.optimize_selector(target))
+            }
+            DomPatch::InsertBefore { reference_selector, html } => {
+                let compact_selector = self.optimize_selector(reference_selector);
+                format!("b|{}|{}", compact_selector, html)
+            }
+            DomPatch::UpdateElement { selector, attrs, text } => {
+                let compact_selector = self.optimize_selector(selector);
+                let attrs: std::collections::BTreeMap<_, _> = attrs.into_iter().collect();
+                let attrs = serde_json::to_string(&attrs).unwrap_or_default().replace('|', "\\u007c");
+                match text {
+                    Some(text) => format!("u|{}|{}|{}", compact_selector, attrs, text),
+                    None => format!("u|{}|{}", compact_selector, attrs),
+                }
+            }
+            DomPatch::SetProperty { selector, property, value } => {
+                let compact_selector = self.optimize_selector(selector);
+                format!("p|{}|{}|{}", compact_selector, property, value)
+            }
+            DomPatch::ToggleClass { selector, class, add } => {
+                let compact_selector = self.optimize_selector(selector);
+                format!("k|{}|{}|{}", compact_selector, class, if add { 1 } else { 0 })
+            }
+            DomPatch::ClientCommand { command, args } => format!("q|{}|{}", command, args),
             _ => String::new(), // Fallback for unknown patch types
         }
     }
 
+    /// Parses a compact `q|command|args` string back into a `ClientCommand` patch
+    pub fn compact_to_command(compact: &str) -> Result<DomPatch> {
+        let mut parts = compact.splitn(3, '|');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("q"), Some(command), Some(args)) if !command.is_empty() => {
+                let args = serde_json::from_str(args)
+                    .map_err(|e| LiveTSError::InvalidInput(format!("command args JSON: {}", e)))?;
+                Ok(DomPatch::ClientCommand { command: command.to_string(), args })
+            }
+            _ => Err(LiveTSError::InvalidInput(format!("not a compact command: {}", compact))),
+        }
+    }
+
+    /// Whether the patches are a single replacement of the whole root's content
+    pub fn is_full_replace(patches: &[DomPatch]) -> bool {
+        matches!(patches, [DomPatch::ReplaceInnerHtml { selector, .. }] if selector == ROOT_SELECTOR)
+    }
+
     /// Converts a vector of DomPatches to compact string format
     pub fn patches_to_compact(&self, patches: Vec<DomPatch>) -> Vec<String> {
         patches.into_iter()
@@ -343,291 +1397,1108 @@ impl HtmlDiffer {
             .collect()
     }
 
+    /// Converts patches to compact strings that reference selectors by index
+    ///
+    /// Returns `(selectors, patches)`: each distinct selector appears once in `selectors`,
+    /// and the patch's selector field holds its decimal index, e.g. `a|0|class|big`.
+    pub fn patches_to_compact_with_dictionary(&self, patches: Vec<DomPatch>) -> (Vec<String>, Vec<String>) {
+        let mut selectors: Vec<String> = Vec::new();
+        let compact = patches
+            .into_iter()
+            .map(|mut patch| {
+                if let Some(selector) = patch_selector_mut(&mut patch) {
+                    let optimized = self.optimize_selector(std::mem::take(selector));
+                    let index = match selectors.iter().position(|known| *known == optimized) {
+                        Some(index) => index,
+                        None => {
+                            selectors.push(optimized);
+                            selectors.len() - 1
+                        }
+                    };
+                    *selector = index.to_string();
+                }
+                self.patch_to_compact(patch)
+            })
+            .collect();
+        (selectors, compact)
+    }
+
     /// Convert full CSS selector to compact format for WebSocket transmission
     fn optimize_selector(&self, selector: String) -> String {
         // If it's already a data-ts-selector, extract just the value
-        if selector.starts_with("[data-ts-sel=\"") && selector.ends_with("\"]") {
-            // Extract: [data-ts-sel="abc123.0"] -> abc123.0
-            return selector[19..selector.len()-2].to_string();
+        // Extract: [data-ts-sel="abc123.0"] -> abc123.0
+        if let Some(value) = selector
+            .strip_prefix("[data-ts-sel=\"")
+            .and_then(|rest| rest.strip_suffix("\"]"))
+        {
+            return value.to_string();
         }
         // Return as-is for other selectors
         selector
     }
 }
 
-/// Represents a parsed HTML element
+/// Children of one parent element that all carry `data-key`
 #[derive(Debug, Clone)]
-struct HtmlElement {
-    tag_name: String,
-    classes: String,
-    text_content: String,
-    id: String,
-    ts_selector: String,
+struct KeyedList {
+    parent_selector: String,
+    /// (key, outer HTML) in document order
+    children: Vec<(String, String)>,
 }
 
-impl Default for HtmlDiffer {
-    fn default() -> Self {
-        Self::new()
+/// Index pairs (into `old`, into `new`) of a longest common subsequence of two key lists
+fn longest_common_subsequence(old: &[&str], new: &[&str]) -> Vec<(usize, usize)> {
+    // lengths[i][j] = LCS length of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
     }
+
+    let mut pairs = Vec::with_capacity(lengths[0][0]);
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Checks that every non-void tag is closed in the right order
+fn check_well_formed(html: &str) -> std::result::Result<(), String> {
+    let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
 
-    #[test]
-    fn test_text_content_change() {
-        let differ = HtmlDiffer::new();
-        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html = r#"<div class="text-green-600 font-bold text-4xl">6</div>"#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        assert_eq!(patches.len(), 1);
-        
-        if let DomPatch::UpdateText { selector: _, text } = &patches[0] {
-            assert_eq!(text, "6");
-        } else {
-            panic!("Expected UpdateText patch");
+    let mut open: Vec<String> = Vec::new();
+    for capture in tag_regex.captures_iter(html) {
+        let prefix = capture.get(1).map_or("", |m| m.as_str());
+        if prefix.starts_with('!') {
+            continue; // comment or doctype
+        }
+        let Some(name) = capture.get(2).map(|m| m.as_str().to_lowercase()) else {
+            continue; // a bare '<' in text
+        };
+        let self_closing = capture[3].trim_end().ends_with('/');
+
+        if prefix == "/" {
+            match open.pop() {
+                Some(expected) if expected == name => {}
+                Some(expected) => return Err(format!("found </{}> while <{}> is still open", name, expected)),
+                None => return Err(format!("found </{}> without a matching opening tag", name)),
+            }
+        } else if !self_closing && !VOID_ELEMENTS.contains(&name.as_str()) {
+            open.push(name);
         }
     }
 
-    #[test]
-    fn test_class_change() {
-        let differ = HtmlDiffer::new();
-        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html = r#"<div class="text-red-600 font-bold text-4xl">5</div>"#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        
+    match open.pop() {
+        Some(unclosed) => Err(format!("<{}> is never closed", unclosed)),
+        None => Ok(()),
+    }
+}
 
+/// Finds the leaf elements (`<tag>text</tag>`) and void elements of `html`, in document order
+fn regex_elements(html: &str) -> Option<Vec<HtmlElement>> {
+    // Markup inside comments isn't rendered; blanking keeps the offsets `table_cells` uses
+    let html = &blank_comments(html);
+
+    // Regex to match any element with content: <tag attributes>content</tag>
+    // Quoted attribute values may contain '>' and backslash-escaped quotes
+    let element_regex = regex::Regex::new(
+        r#"<(\w+)((?:[^>"']|"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')*)>([^<]*)</(\w+)>"#,
+    )
+    .unwrap();
+    
+    let cells = if html.contains("<tr") || html.contains("<TR") {
+        table_cells(html)
+    } else {
+        HashMap::new()
+    };
+
+    // (start offset, element), so void elements can be put back in document order
+    let mut found: Vec<(usize, HtmlElement)> = Vec::new();
+
+    for capture in element_regex.captures_iter(html) {
+        let open_tag = capture.get(1)?.as_str().to_string();
+        let attributes = capture.get(2)?.as_str();
+        let text_content = capture.get(3)?.as_str().trim().to_string();
+        let close_tag = capture.get(4)?.as_str();
         
-        assert_eq!(patches.len(), 1);
-        
-        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
-            assert_eq!(attr, "class");
-            assert_eq!(value, "text-red-600 font-bold text-4xl");
-        } else {
-            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+        // Only process if opening and closing tags match
+        if open_tag == close_tag {
+            // Double-quoted, single-quoted and unquoted values are all accepted
+            let mut element = HtmlElement::new(
+                open_tag,
+                parse_attributes(attributes),
+                text_content,
+                capture.get(0)?.as_str().to_string(),
+            );
+            element.cell = cells.get(&capture.get(0)?.start()).cloned();
+            element.offset = Some(capture.get(0)?.start());
+            found.push((capture.get(0)?.start(), element));
         }
     }
 
-    #[test]
-    fn test_both_text_and_class_change() {
-        let differ = HtmlDiffer::new();
-        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html = r#"<div class="text-red-600 font-bold text-4xl">-3</div>"#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        
-        // Should generate 2 patches: one for class change, one for text change
-        assert_eq!(patches.len(), 2);
-        
-        // First patch should be class change
-        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
-            assert_eq!(attr, "class");
-            assert_eq!(value, "text-red-600 font-bold text-4xl");
-        } else {
-            panic!("Expected SetAttribute patch for classes, got {:?}", patches[0]);
+    // Void elements (`<img>`, `<input>`) have no content but their attributes still change
+    let void_regex = regex::Regex::new(&format!(
+        r#"(?i)<({})\b((?:[^>"']|"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')*)>"#,
+        VOID_ELEMENTS.join("|")
+    ))
+    .unwrap();
+    for capture in void_regex.captures_iter(html) {
+        let start = capture.get(0)?.start();
+        let attributes = capture.get(2)?.as_str().trim_end().trim_end_matches('/');
+        let mut element = HtmlElement::new(
+            capture.get(1)?.as_str().to_lowercase(),
+            parse_attributes(attributes),
+            String::new(),
+            capture.get(0)?.as_str().to_string(),
+        );
+        element.cell = cells.get(&start).cloned();
+        element.offset = Some(start);
+        found.push((start, element));
+    }
+    found.sort_by_key(|(start, _)| *start);
+    let elements: Vec<HtmlElement> = found.into_iter().map(|(_, element)| element).collect();
+
+    if elements.is_empty() { None } else { Some(elements) }
+}
+
+/// Escapes a value for use inside a double-quoted CSS attribute selector
+fn css_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Position of the element starting at `offset` among its same-tag siblings, counted from 1
+fn nth_of_type(html: &str, offset: usize) -> Option<usize> {
+    let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+    // (tag name, nth-of-type counters for its children) of each open element, below the top level
+    let mut stack: Vec<(String, HashMap<String, usize>)> = vec![(String::new(), HashMap::new())];
+
+    for capture in tag_regex.captures_iter(html) {
+        let start = capture.get(0)?.start();
+        if start > offset {
+            break;
         }
-        
-        // Second patch should be text change
-        if let DomPatch::UpdateText { text, .. } = &patches[1] {
-            assert_eq!(text, "-3");
-        } else {
-            panic!("Expected UpdateText patch for text, got {:?}", patches[1]);
+        let prefix = capture.get(1).map_or("", |m| m.as_str());
+        let Some(name) = capture.get(2).filter(|_| !prefix.starts_with('!')) else {
+            continue;
+        };
+        let tag_name = name.as_str().to_lowercase();
+
+        if prefix == "/" {
+            if let Some(position) = stack.iter().rposition(|(open, _)| *open == tag_name) {
+                stack.truncate(position.max(1));
+            }
+            continue;
+        }
+
+        let (_, child_counts) = stack.last_mut()?;
+        let count = child_counts.entry(tag_name.clone()).or_insert(0);
+        *count += 1;
+        if start == offset {
+            return Some(*count);
+        }
+
+        let self_closing = capture[3].trim_end().ends_with('/');
+        if !self_closing && !VOID_ELEMENTS.contains(&tag_name.as_str()) {
+            stack.push((tag_name, HashMap::new()));
         }
     }
 
-    #[test]
-    fn test_generic_elements_without_text_4xl() {
-        let differ = HtmlDiffer::new();
-        
-        // Test with completely different classes (no text-4xl)
-        let old_html = r#"<span class="status error-state">Failed</span>"#;
-        let new_html = r#"<span class="status success-state">Success</span>"#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        
-        // Should generate 2 patches for both text and class changes
-        assert_eq!(patches.len(), 2);
-        
-        // First should be class change
-        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
-            assert_eq!(attr, "class");
-            assert_eq!(value, "status success-state");
-        } else {
-            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+    None
+}
+
+/// Maps the start offset of every tag inside a table cell to that cell's position
+fn table_cells(html: &str) -> HashMap<usize, CellPosition> {
+    struct OpenElement {
+        tag_name: String,
+        /// nth-of-type counters for the element's children
+        child_counts: HashMap<String, usize>,
+        /// Selector of the table the element is or is inside of
+        table: Option<String>,
+        row: Option<RowPosition>,
+        cell: Option<CellPosition>,
+    }
+
+    let tag_regex = regex::Regex::new(TAG_PATTERN).unwrap();
+    let mut stack: Vec<OpenElement> = Vec::new();
+    // nth-of-type counters for the top-level elements
+    let mut root_counts: HashMap<String, usize> = HashMap::new();
+    let mut cells = HashMap::new();
+
+    for capture in tag_regex.captures_iter(html) {
+        let prefix = capture.get(1).map_or("", |m| m.as_str());
+        let Some(name) = capture.get(2).filter(|_| !prefix.starts_with('!')) else {
+            continue;
+        };
+        let tag_name = name.as_str().to_lowercase();
+
+        if prefix == "/" {
+            if let Some(position) = stack.iter().rposition(|open| open.tag_name == tag_name) {
+                stack.truncate(position);
+            }
+            continue;
         }
-        
-        // Second should be text change
-        if let DomPatch::UpdateText { text, .. } = &patches[1] {
-            assert_eq!(text, "Success");
+
+        let counts = match stack.last_mut() {
+            Some(parent) => &mut parent.child_counts,
+            None => &mut root_counts,
+        };
+        let count = counts.entry(tag_name.clone()).or_insert(0);
+        *count += 1;
+        let nth = *count;
+        let parent = stack.last();
+
+        let table = if tag_name == "table" {
+            let attributes = parse_attributes(&capture[3]);
+            let attribute = |name: &str| {
+                attributes.iter().find(|(attr, value)| attr == name && !value.is_empty()).map(|(_, value)| value)
+            };
+            Some(match (attribute("data-ts-sel"), attribute("id")) {
+                (Some(ts_selector), _) => format!("[data-ts-sel=\"{}\"]", css_string(ts_selector)),
+                (None, Some(id)) => format!("#{}", id),
+                (None, None) => format!("table:nth-of-type({})", nth),
+            })
         } else {
-            panic!("Expected UpdateText patch, got {:?}", patches[1]);
+            parent.and_then(|parent| parent.table.clone())
+        };
+
+        let row = (tag_name == "tr").then(|| {
+            let attributes = parse_attributes(&capture[3]);
+            RowPosition {
+                table: table.clone().unwrap_or_default(),
+                key: attributes
+                    .iter()
+                    .find(|(attr, _)| attr == "data-key")
+                    .map(|(_, value)| value.clone())
+                    .filter(|key| !key.is_empty()),
+                section: parent.map_or_else(String::new, |parent| parent.tag_name.clone()),
+                nth,
+            }
+        });
+        let cell = match parent.and_then(|parent| parent.row.clone()) {
+            Some(row) if tag_name == "td" || tag_name == "th" => Some(CellPosition {
+                row,
+                tag_name: tag_name.clone(),
+                nth,
+                is_element: true,
+            }),
+            _ => parent.and_then(|parent| parent.cell.clone()).map(|cell| CellPosition { is_element: false, ..cell }),
+        };
+
+        if let Some(cell) = &cell {
+            cells.insert(capture.get(0).unwrap().start(), cell.clone());
+        }
+
+        let self_closing = capture[3].trim_end().ends_with('/');
+        if !self_closing && !VOID_ELEMENTS.contains(&tag_name.as_str()) {
+            stack.push(OpenElement {
+                tag_name,
+                child_counts: HashMap::new(),
+                table,
+                row,
+                cell,
+            });
         }
     }
 
-    #[test]
-    fn test_button_class_change() {
-        let differ = HtmlDiffer::new();
-        
-        // Test with button elements
-        let old_html = r#"<button class="btn btn-primary disabled">Submit</button>"#;
-        let new_html = r#"<button class="btn btn-primary enabled">Submit</button>"#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        
-        // Should generate 1 patch for class change only (text unchanged)
-        assert_eq!(patches.len(), 1);
-        
-        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
-            assert_eq!(attr, "class");
-            assert_eq!(value, "btn btn-primary enabled");
+    cells
+}
+
+/// Parse an opening tag's attribute string into (name, value) pairs in document order
+/// Attributes without a value (e.g. `hidden`) get an empty value. Quoted values are
+/// read up to the matching unescaped quote and returned with entities decoded
+fn parse_attributes(attributes: &str) -> Vec<(String, String)> {
+    let chars: Vec<char> = attributes.chars().collect();
+    let is_name_end = |c: char| c.is_whitespace() || matches!(c, '=' | '"' | '\'' | '<' | '>' | '/');
+    let mut parsed = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        // Skip separators and stray characters between attributes
+        if is_name_end(chars[i]) {
+            i += 1;
+            continue;
+        }
+
+        let name_start = i;
+        while i < chars.len() && !is_name_end(chars[i]) {
+            i += 1;
+        }
+        let name = chars[name_start..i].iter().collect::<String>().to_lowercase();
+
+        let mut j = i;
+        while j < chars.len() && chars[j].is_whitespace() {
+            j += 1;
+        }
+        if j >= chars.len() || chars[j] != '=' {
+            parsed.push((name, String::new()));
+            continue;
+        }
+        j += 1;
+        while j < chars.len() && chars[j].is_whitespace() {
+            j += 1;
+        }
+
+        let mut value = String::new();
+        if j < chars.len() && (chars[j] == '"' || chars[j] == '\'') {
+            let quote = chars[j];
+            j += 1;
+            while j < chars.len() && chars[j] != quote {
+                // Templates escaping quotes JS-style (\") must not end the value early
+                if chars[j] == '\\' && chars.get(j + 1) == Some(&quote) {
+                    value.push(quote);
+                    j += 2;
+                    continue;
+                }
+                value.push(chars[j]);
+                j += 1;
+            }
+            j += 1; // closing quote
         } else {
-            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+            while j < chars.len() && !chars[j].is_whitespace() && chars[j] != '>' {
+                value.push(chars[j]);
+                j += 1;
+            }
         }
+
+        parsed.push((name, decode_entities(&value)));
+        i = j;
     }
 
-    #[test]
-    fn test_multiple_buttons_with_similar_classes() {
-        let differ = HtmlDiffer::new();
-        
-        // Test with multiple buttons that share CSS classes (like counter buttons)
-        let old_html = r#"
-            <button class="px-4 py-2 bg-red-500 text-white">-1</button>
-            <button class="px-4 py-2 bg-blue-500 text-white">+1</button>
-        "#;
-        let new_html = r#"
-            <button class="px-4 py-2 bg-red-500 text-white">-4</button>
-            <button class="px-4 py-2 bg-blue-500 text-white">+4</button>
-        "#;
-        
-        let patches = differ.diff(old_html, new_html).unwrap();
-        
-        // Should generate 2 patches: one for each button's text change
-        assert_eq!(patches.len(), 2);
-        
-        // Find the patches for each button
-        let mut decrement_patch = None;
-        let mut increment_patch = None;
-        
-        for patch in &patches {
-            if let DomPatch::UpdateText { text, selector } = patch {
-                if text == "-4" {
-                    decrement_patch = Some((selector, text));
-                } else if text == "+4" {
-                    increment_patch = Some((selector, text));
+    parsed
+}
+
+/// What the differ noticed while generating a set of patches
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct DiffStats {
+    pub patch_count: usize,
+    /// Problems that may make the patches apply to the wrong elements or leave DOM stale
+    pub warnings: Vec<String>,
+}
+
+/// How an old element was paired with its new version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchStrategy {
+    /// Same `data-ts-match` hint
+    Hint,
+    /// Same `data-key`
+    Key,
+    /// Same `data-ts-sel`
+    TsSel,
+    /// Same `id`
+    Id,
+    /// Best similarity score among same-tag elements
+    Score,
+    /// Found by id in the new HTML after it stopped being a leaf element
+    Identity,
+}
+
+/// Which elements a `diff_debug` patch came from and why they were paired
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MatchInfo {
+    pub strategy: MatchStrategy,
+    /// Similarity score, only non-zero for `MatchStrategy::Score`
+    pub score: u32,
+    /// Tag of the matched new element
+    pub tag: String,
+    /// Position among the old document's elements
+    pub old_index: usize,
+    /// Position among the new document's elements (none for an `Identity` match)
+    pub new_index: Option<usize>,
+}
+
+/// A parsed element as returned by `parse_elements_public`; empty strings for missing attributes
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PublicHtmlElement {
+    pub tag_name: String,
+    pub classes: String,
+    pub id: String,
+    pub ts_selector: String,
+    /// Trimmed text of an element without child elements
+    pub text_content: String,
+}
+
+/// A candidate chosen by `find_matching_element`
+struct ElementMatch<'a> {
+    index: usize,
+    element: &'a HtmlElement,
+    strategy: MatchStrategy,
+    score: u32,
+}
+
+/// What part of an element a patch writes, for spotting patches that overwrite each other
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PatchSlot {
+    /// The whole element (replace or remove)
+    Element,
+    /// Content as HTML
+    Html,
+    /// Content as text
+    Text,
+    Attribute(String),
+    Property(String),
+    /// One class in the class list
+    Class(String),
+}
+
+fn patch_slot(patch: &DomPatch) -> Option<PatchSlot> {
+    match patch {
+        DomPatch::ReplaceElement { .. } | DomPatch::RemoveElement { .. } => Some(PatchSlot::Element),
+        DomPatch::ReplaceInnerHtml { .. } => Some(PatchSlot::Html),
+        DomPatch::UpdateText { .. } | DomPatch::ReplaceText { .. } => Some(PatchSlot::Text),
+        DomPatch::SetAttribute { attr, .. } | DomPatch::RemoveAttribute { attr, .. } => {
+            Some(PatchSlot::Attribute(attr.clone()))
+        }
+        DomPatch::SetProperty { property, .. } => Some(PatchSlot::Property(property.clone())),
+        DomPatch::ToggleClass { class, .. } => Some(PatchSlot::Class(class.clone())),
+        DomPatch::InsertElement { .. }
+        | DomPatch::InsertBefore { .. }
+        | DomPatch::MoveElement { .. }
+        | DomPatch::UpdateElement { .. }
+        | DomPatch::ClientCommand { .. } => None,
+    }
+}
+
+/// Drops patches whose effect a later or more comprehensive patch on the same selector overwrites
+///
+/// The last patch per selector and slot wins; a whole-element patch supersedes everything
+/// else on its selector, and an HTML content patch supersedes text content patches.
+/// Survivors keep their relative order.
+fn merge_patches(patches: Vec<DomPatch>) -> Vec<DomPatch> {
+    let mut last: HashMap<(&str, PatchSlot), usize> = HashMap::new();
+    for (index, patch) in patches.iter().enumerate() {
+        if let (Some(selector), Some(slot)) = (patch_selector(patch), patch_slot(patch)) {
+            last.insert((selector, slot), index);
+        }
+    }
+
+    let keep: Vec<bool> = patches
+        .iter()
+        .enumerate()
+        .map(|(index, patch)| {
+            let (Some(selector), Some(slot)) = (patch_selector(patch), patch_slot(patch)) else {
+                return true;
+            };
+            let winner = |slot: PatchSlot| last.get(&(selector, slot)).copied();
+            match (winner(PatchSlot::Element), slot) {
+                (Some(element), _) => element == index,
+                (None, PatchSlot::Text) if winner(PatchSlot::Html).is_some() => false,
+                (None, slot) => winner(slot) == Some(index),
+            }
+        })
+        .collect();
+
+    patches
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(patch, keep)| keep.then_some(patch))
+        .collect()
+}
+
+/// Folds the `SetAttribute` and `UpdateText` patches of each selector that has more than
+/// one into a single `UpdateElement`, placed where the first of them was
+fn combine_element_patches(patches: Vec<DomPatch>) -> Vec<DomPatch> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for patch in &patches {
+        if let DomPatch::SetAttribute { selector, .. } | DomPatch::UpdateText { selector, .. } = patch {
+            *counts.entry(selector.clone()).or_default() += 1;
+        }
+    }
+
+    let mut combined: Vec<DomPatch> = Vec::with_capacity(patches.len());
+    let mut slots: HashMap<String, usize> = HashMap::new();
+    for patch in patches {
+        let selector = match &patch {
+            DomPatch::SetAttribute { selector, .. } | DomPatch::UpdateText { selector, .. }
+                if counts[selector] > 1 =>
+            {
+                selector.clone()
+            }
+            _ => {
+                combined.push(patch);
+                continue;
+            }
+        };
+        let slot = *slots.entry(selector.clone()).or_insert_with(|| {
+            combined.push(DomPatch::UpdateElement { selector, attrs: HashMap::new(), text: None });
+            combined.len() - 1
+        });
+        if let DomPatch::UpdateElement { attrs, text, .. } = &mut combined[slot] {
+            match patch {
+                DomPatch::SetAttribute { attr, value, .. } => {
+                    attrs.insert(attr, value);
                 }
+                DomPatch::UpdateText { text: new_text, .. } => *text = Some(new_text),
+                _ => {}
             }
         }
-        
-        assert!(decrement_patch.is_some(), "Should find decrement button patch");
-        assert!(increment_patch.is_some(), "Should find increment button patch");
-        
-        // Selectors should be different to target different buttons
-        let (dec_selector, _) = decrement_patch.unwrap();
-        let (inc_selector, _) = increment_patch.unwrap();
-        assert_ne!(dec_selector, inc_selector, "Selectors should be different for different buttons");
     }
+    combined
+}
+
+/// Replaces each comment with as many spaces as it was long
+fn blank_comments(html: &str) -> std::borrow::Cow<'_, str> {
+    let comment_regex = regex::Regex::new(r"<!--[\s\S]*?-->").unwrap();
+    comment_regex.replace_all(html, |capture: &regex::Captures| " ".repeat(capture[0].len()))
+}
+
+/// The HTML without comments and with the root's `data-livets-id` emptied, for comparing what's
+/// visible; nested components keep their ids, since a changed one is a different component
+fn without_comments_and_ids(html: &str) -> String {
+    let comment_regex = regex::Regex::new(r"<!--[\s\S]*?-->").unwrap();
+    let id_regex = regex::Regex::new(r#"(data-livets-id\s*=\s*)(?:"[^"]*"|'[^']*'|[^\s"'=<>`]+)"#).unwrap();
+    let html = comment_regex.replace_all(html, "");
+    id_regex.replace(&html, "${1}\"\"").into_owned()
+}
+
+/// Whether `element` is the component root `base_selector` scopes patches to
+fn is_component_root(base_selector: &str, element: &HtmlElement) -> bool {
+    element
+        .attribute("data-livets-id")
+        .is_some_and(|id| base_selector == format!("[data-livets-id=\"{}\"]", id))
+}
+
+/// Whether two class attribute values hold the same classes, in any order
+fn same_class_set(old: &str, new: &str) -> bool {
+    fn classes(value: &str) -> std::collections::HashSet<&str> {
+        value.split_whitespace().collect()
+    }
+    classes(old) == classes(new)
+}
+
+/// The HTML with every double-quoted class list sorted, so documents differing only in class order compare equal
+fn sorted_classes(html: &str) -> std::borrow::Cow<'_, str> {
+    let class_regex = regex::Regex::new(r#"(\sclass=")([^"]*)""#).unwrap();
+    class_regex.replace_all(html, |capture: &regex::Captures| {
+        let mut classes: Vec<&str> = capture[2].split_whitespace().collect();
+        classes.sort_unstable();
+        format!("{}{}\"", &capture[1], classes.join(" "))
+    })
+}
+
+/// Compact code for an insert or move position
+fn compact_position(position: &InsertPosition) -> &'static str {
+    match position {
+        InsertPosition::BeforeBegin => "bb",
+        InsertPosition::AfterBegin => "ab",
+        InsertPosition::BeforeEnd => "be",
+        InsertPosition::AfterEnd => "ae",
+    }
+}
+
+/// Gets the selector a patch targets, if it has one
+fn patch_selector(patch: &DomPatch) -> Option<&str> {
+    match patch {
+        DomPatch::ReplaceText { selector, .. }
+        | DomPatch::UpdateText { selector, .. }
+        | DomPatch::SetAttribute { selector, .. }
+        | DomPatch::RemoveAttribute { selector, .. }
+        | DomPatch::ReplaceElement { selector, .. }
+        | DomPatch::RemoveElement { selector }
+        | DomPatch::ReplaceInnerHtml { selector, .. }
+        | DomPatch::MoveElement { selector, .. }
+        | DomPatch::UpdateElement { selector, .. }
+        | DomPatch::SetProperty { selector, .. }
+        | DomPatch::ToggleClass { selector, .. } => Some(selector),
+        DomPatch::InsertBefore { reference_selector, .. } => Some(reference_selector),
+        DomPatch::InsertElement { .. } | DomPatch::ClientCommand { .. } => None,
+    }
+}
+
+fn patch_selector_mut(patch: &mut DomPatch) -> Option<&mut String> {
+    match patch {
+        DomPatch::ReplaceText { selector, .. }
+        | DomPatch::UpdateText { selector, .. }
+        | DomPatch::SetAttribute { selector, .. }
+        | DomPatch::RemoveAttribute { selector, .. }
+        | DomPatch::ReplaceElement { selector, .. }
+        | DomPatch::RemoveElement { selector }
+        | DomPatch::ReplaceInnerHtml { selector, .. }
+        | DomPatch::MoveElement { selector, .. }
+        | DomPatch::UpdateElement { selector, .. }
+        | DomPatch::SetProperty { selector, .. }
+        | DomPatch::ToggleClass { selector, .. } => Some(selector),
+        DomPatch::InsertBefore { reference_selector, .. } => Some(reference_selector),
+        DomPatch::InsertElement { .. } | DomPatch::ClientCommand { .. } => None,
+    }
+}
+
+/// HTML parsed into the element list the differ works on
+#[derive(Debug, Clone)]
+pub struct ParsedDocument {
+    html: String,
+    /// `None` when no diffable elements were found
+    elements: Option<Vec<HtmlElement>>,
+    keyed_lists: Vec<KeyedList>,
+    component_id: Option<String>,
+    /// `data-ts-sel` values carried by more than one element, with how many carry each
+    duplicate_ts_selectors: Vec<(String, usize)>,
+}
+
+impl ParsedDocument {
+    /// Gets the source HTML the document was parsed from
+    pub fn html(&self) -> &str {
+        &self.html
+    }
+}
+
+/// Makes repeated `data-ts-sel` values unique by suffixing the nth repeat (in document order)
+/// with `~n`, which the client resolves to the nth element carrying the bare value
+///
+/// Returns each repeated value with how many elements carry it.
+fn index_duplicate_ts_selectors(elements: &mut [HtmlElement]) -> Vec<(String, usize)> {
+    let mut seen: Vec<(String, usize)> = Vec::new();
+    for element in elements.iter_mut().filter(|element| !element.ts_selector.is_empty()) {
+        match seen.iter_mut().find(|(value, _)| *value == element.ts_selector) {
+            Some((value, count)) => {
+                element.ts_selector = format!("{}~{}", value, count);
+                *count += 1;
+            }
+            None => seen.push((element.ts_selector.clone(), 1)),
+        }
+    }
+    seen.retain(|(_, count)| *count > 1);
+    seen
+}
+
+/// Decodes character references in an attribute value or text (`&quot;`, `&#39;`, `&#x22;`, ...)
+/// Unknown or malformed references are kept as written
+fn decode_entities(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let reference = rest.find(';').map(|end| (&rest[1..end], end));
+        let character = reference.and_then(|(name, _)| match name {
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let code = if let Some(hex) = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    u32::from_str_radix(hex, 16).ok()
+                } else {
+                    name.strip_prefix('#').and_then(|dec| dec.parse::<u32>().ok())
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+
+        match (character, reference) {
+            (Some(c), Some((_, end))) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Represents a parsed HTML element
+#[derive(Debug, Clone)]
+pub struct HtmlElement {
+    tag_name: String,
+    classes: String,
+    text_content: String,
+    id: String,
+    ts_selector: String,
+    /// Matching hint from `data-ts-match`, independent of the selector
+    match_key: String,
+    /// Identity from `data-key`, matched independently of the tag name
+    key: String,
+    /// The element's full source, used when it has to be replaced wholesale
+    outer_html: String,
+    attributes: Vec<(String, String)>,
+    /// The table cell the element is or is inside of
+    cell: Option<CellPosition>,
+    /// Where the element starts in the HTML, when the extractor knows
+    offset: Option<usize>,
+}
+
+/// A table row, identified by `data-key` when it has one, else by its position
+#[derive(Debug, Clone)]
+struct RowPosition {
+    /// Selector of the row's table: its `data-ts-sel`, id or position; empty outside a table
+    table: String,
+    key: Option<String>,
+    /// Tag of the row's parent (`tbody`, `thead`, `tfoot` or `table`)
+    section: String,
+    /// nth-of-type among the section's rows
+    nth: usize,
+}
+
+impl PartialEq for RowPosition {
+    fn eq(&self, other: &Self) -> bool {
+        self.table == other.table
+            && match (&self.key, &other.key) {
+                (Some(key), Some(other_key)) => key == other_key,
+                _ => self.section == other.section && self.nth == other.nth,
+            }
+    }
+}
+
+/// A `td`/`th` within its row
+#[derive(Debug, Clone)]
+struct CellPosition {
+    row: RowPosition,
+    tag_name: String,
+    /// nth-of-type among the row's cells
+    nth: usize,
+    /// Whether the element is the cell itself rather than something inside it
+    is_element: bool,
+}
+
+impl PartialEq for CellPosition {
+    fn eq(&self, other: &Self) -> bool {
+        self.row == other.row && self.tag_name == other.tag_name && self.nth == other.nth
+    }
+}
+
+impl CellPosition {
+    fn selector(&self) -> String {
+        let row = match &self.row.key {
+            Some(key) => format!("tr[data-key=\"{}\"]", css_string(key)),
+            None => format!("tr:nth-of-type({})", self.row.nth),
+        };
+        let section = match (self.row.table.is_empty(), self.row.section.as_str()) {
+            (_, "") => String::new(),
+            (false, "table") => format!("{} > ", self.row.table),
+            (false, section) => format!("{} > {} > ", self.row.table, section),
+            (true, section) => format!("{} > ", section),
+        };
+        format!("{}{} > {}:nth-of-type({})", section, row, self.tag_name, self.nth)
+    }
+}
+
+impl HtmlElement {
+    /// `attributes` as (lowercase name, value) pairs in document order; `outer_html` is the
+    /// element's full source, used when it has to be replaced wholesale
+    pub fn new(tag_name: String, attributes: Vec<(String, String)>, text_content: String, outer_html: String) -> Self {
+        let attribute_value = |name: &str| {
+            attributes
+                .iter()
+                .find(|(attr, _)| attr == name)
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        };
+
+        Self {
+            tag_name,
+            classes: attribute_value("class"),
+            text_content,
+            id: attribute_value("id"),
+            ts_selector: attribute_value("data-ts-sel"),
+            match_key: attribute_value("data-ts-match"),
+            key: attribute_value("data-key"),
+            outer_html,
+            attributes,
+            cell: None,
+            offset: None,
+        }
+    }
+
+    fn has_attribute(&self, name: &str) -> bool {
+        self.attributes.iter().any(|(attr, _)| attr == name)
+    }
+
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Default for HtmlDiffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn test_counter_with_number_change_and_class_change() {
+    fn test_text_content_change() {
         let differ = HtmlDiffer::new();
+        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html = r#"<div class="text-green-600 font-bold text-4xl">6</div>"#;
         
-        // Test simulating the counter scenario: number changes, class changes, buttons change
-        let old_html = r#"
-            <div class="text-center mb-6">
-                <div class="text-green-600 font-bold text-4xl">5</div>
-                <p class="text-gray-600 mt-2">Current count</p>
-            </div>
-            <div class="flex gap-3 justify-center mb-6">
-                <button class="px-4 py-2 bg-red-500 text-white rounded hover:bg-red-600 transition-colors">-1</button>
-                <button class="px-4 py-2 bg-blue-500 text-white rounded hover:bg-blue-600 transition-colors">+1</button>
-            </div>
-        "#;
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1);
         
-        let new_html = r#"
-            <div class="text-center mb-6">
-                <div class="text-red-600 font-bold text-4xl">-42</div>
-                <p class="text-gray-600 mt-2">Current count</p>
-            </div>
-            <div class="flex gap-3 justify-center mb-6">
-                <button class="px-4 py-2 bg-red-500 text-white rounded hover:bg-red-600 transition-colors">-1</button>
-                <button class="px-4 py-2 bg-blue-500 text-white rounded hover:bg-blue-600 transition-colors">+1</button>
-            </div>
-        "#;
+        if let DomPatch::UpdateText { selector: _, text } = &patches[0] {
+            assert_eq!(text, "6");
+        } else {
+            panic!("Expected UpdateText patch");
+        }
+    }
+
+    #[test]
+    fn test_class_change() {
+        let differ = HtmlDiffer::new();
+        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html = r#"<div class="text-red-600 font-bold text-4xl">5</div>"#;
         
         let patches = differ.diff(old_html, new_html).unwrap();
         
-        // Should generate patches for both the counter display (class + text) changes
-        // Buttons should remain unchanged in this scenario
-        assert!(patches.len() >= 2, "Should generate at least 2 patches for counter display changes");
+
         
-        // Check that we have class and text patches for the counter display
-        let mut has_class_patch = false;
-        let mut has_text_patch = false;
+        assert_eq!(patches.len(), 1);
         
-        for patch in &patches {
-            match patch {
-                DomPatch::SetAttribute { attr, value, .. } if attr == "class" => {
-                    if value.contains("text-red-600") {
-                        has_class_patch = true;
-                    }
-                }
-                DomPatch::UpdateText { text, .. } => {
-                    if text == "-42" {
-                        has_text_patch = true;
-                    }
-                }
-                _ => {}
-            }
+        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
+            assert_eq!(attr, "class");
+            assert_eq!(value, "text-red-600 font-bold text-4xl");
+        } else {
+            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
         }
-        
-        assert!(has_class_patch, "Should have class change patch for color change");
-        assert!(has_text_patch, "Should have text change patch for number change");
     }
 
     #[test]
-    fn test_random_button_edge_cases() {
+    fn test_both_text_and_class_change() {
         let differ = HtmlDiffer::new();
-        
-        // Case 1: Same count, different sign (should generate class change only)
         let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html = r#"<div class="text-red-600 font-bold text-4xl">-5</div>"#;
+        let new_html = r#"<div class="text-red-600 font-bold text-4xl">-3</div>"#;
         
         let patches = differ.diff(old_html, new_html).unwrap();
-        assert_eq!(patches.len(), 2, "Should generate both class and text patches for 5 -> -5");
-        
-        // Case 2: Same absolute value, no sign change (should generate text change only)
-        let old_html2 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html2 = r#"<div class="text-green-600 font-bold text-4xl">7</div>"#;
         
-        let patches2 = differ.diff(old_html2, new_html2).unwrap();
-        assert_eq!(patches2.len(), 1, "Should generate only text patch for 5 -> 7 (same sign)");
+        // Should generate 2 patches: one for class change, one for text change
+        assert_eq!(patches.len(), 2);
         
-        // Case 3: Exact same value (should generate no patches)
-        let old_html3 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html3 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        // First patch should be class change
+        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
+            assert_eq!(attr, "class");
+            assert_eq!(value, "text-red-600 font-bold text-4xl");
+        } else {
+            panic!("Expected SetAttribute patch for classes, got {:?}", patches[0]);
+        }
         
-        let patches3 = differ.diff(old_html3, new_html3).unwrap();
-        assert_eq!(patches3.len(), 0, "Should generate no patches for identical content");
+        // Second patch should be text change
+        if let DomPatch::UpdateText { text, .. } = &patches[1] {
+            assert_eq!(text, "-3");
+        } else {
+            panic!("Expected UpdateText patch for text, got {:?}", patches[1]);
+        }
     }
 
     #[test]
-    fn test_id_based_selectors() {
+    fn test_generic_elements_without_text_4xl() {
         let differ = HtmlDiffer::new();
         
-        // Test with elements that have IDs - should use stable ID selectors
-        let old_html = r#"<div id="counter-display" class="text-green-600 font-bold text-4xl">5</div>"#;
-        let new_html = r#"<div id="counter-display" class="text-red-600 font-bold text-4xl">-42</div>"#;
+        // Test with completely different classes (no text-4xl)
+        let old_html = r#"<span class="status error-state">Failed</span>"#;
+        let new_html = r#"<span class="status success-state">Success</span>"#;
         
         let patches = differ.diff(old_html, new_html).unwrap();
         
-        // Should generate 2 patches: class change and text change
+        // Should generate 2 patches for both text and class changes
         assert_eq!(patches.len(), 2);
         
-        // Both patches should use the stable ID selector
-        for patch in &patches {
+        // First should be class change
+        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
+            assert_eq!(attr, "class");
+            assert_eq!(value, "status success-state");
+        } else {
+            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+        }
+        
+        // Second should be text change
+        if let DomPatch::UpdateText { text, .. } = &patches[1] {
+            assert_eq!(text, "Success");
+        } else {
+            panic!("Expected UpdateText patch, got {:?}", patches[1]);
+        }
+    }
+
+    #[test]
+    fn test_button_class_change() {
+        let differ = HtmlDiffer::new();
+        
+        // Test with button elements
+        let old_html = r#"<button class="btn btn-primary disabled">Submit</button>"#;
+        let new_html = r#"<button class="btn btn-primary enabled">Submit</button>"#;
+        
+        let patches = differ.diff(old_html, new_html).unwrap();
+        
+        // Should generate 1 patch for class change only (text unchanged)
+        assert_eq!(patches.len(), 1);
+        
+        if let DomPatch::SetAttribute { attr, value, .. } = &patches[0] {
+            assert_eq!(attr, "class");
+            assert_eq!(value, "btn btn-primary enabled");
+        } else {
+            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+        }
+    }
+
+    #[test]
+    fn test_multiple_buttons_with_similar_classes() {
+        let differ = HtmlDiffer::new();
+        
+        // Test with multiple buttons that share CSS classes (like counter buttons)
+        let old_html = r#"
+            <button class="px-4 py-2 bg-red-500 text-white">-1</button>
+            <button class="px-4 py-2 bg-blue-500 text-white">+1</button>
+        "#;
+        let new_html = r#"
+            <button class="px-4 py-2 bg-red-500 text-white">-4</button>
+            <button class="px-4 py-2 bg-blue-500 text-white">+4</button>
+        "#;
+        
+        let patches = differ.diff(old_html, new_html).unwrap();
+        
+        // Should generate 2 patches: one for each button's text change
+        assert_eq!(patches.len(), 2);
+        
+        // Find the patches for each button
+        let mut decrement_patch = None;
+        let mut increment_patch = None;
+        
+        for patch in &patches {
+            if let DomPatch::UpdateText { text, selector } = patch {
+                if text == "-4" {
+                    decrement_patch = Some((selector, text));
+                } else if text == "+4" {
+                    increment_patch = Some((selector, text));
+                }
+            }
+        }
+        
+        assert!(decrement_patch.is_some(), "Should find decrement button patch");
+        assert!(increment_patch.is_some(), "Should find increment button patch");
+        
+        // Selectors should be different to target different buttons
+        let (dec_selector, _) = decrement_patch.unwrap();
+        let (inc_selector, _) = increment_patch.unwrap();
+        assert_ne!(dec_selector, inc_selector, "Selectors should be different for different buttons");
+    }
+
+    #[test]
+    fn test_counter_with_number_change_and_class_change() {
+        let differ = HtmlDiffer::new();
+        
+        // Test simulating the counter scenario: number changes, class changes, buttons change
+        let old_html = r#"
+            <div class="text-center mb-6">
+                <div class="text-green-600 font-bold text-4xl">5</div>
+                <p class="text-gray-600 mt-2">Current count</p>
+            </div>
+            <div class="flex gap-3 justify-center mb-6">
+                <button class="px-4 py-2 bg-red-500 text-white rounded hover:bg-red-600 transition-colors">-1</button>
+                <button class="px-4 py-2 bg-blue-500 text-white rounded hover:bg-blue-600 transition-colors">+1</button>
+            </div>
+        "#;
+        
+        let new_html = r#"
+            <div class="text-center mb-6">
+                <div class="text-red-600 font-bold text-4xl">-42</div>
+                <p class="text-gray-600 mt-2">Current count</p>
+            </div>
+            <div class="flex gap-3 justify-center mb-6">
+                <button class="px-4 py-2 bg-red-500 text-white rounded hover:bg-red-600 transition-colors">-1</button>
+                <button class="px-4 py-2 bg-blue-500 text-white rounded hover:bg-blue-600 transition-colors">+1</button>
+            </div>
+        "#;
+        
+        let patches = differ.diff(old_html, new_html).unwrap();
+        
+        // Should generate patches for both the counter display (class + text) changes
+        // Buttons should remain unchanged in this scenario
+        assert!(patches.len() >= 2, "Should generate at least 2 patches for counter display changes");
+        
+        // Check that we have class and text patches for the counter display
+        let mut has_class_patch = false;
+        let mut has_text_patch = false;
+        
+        for patch in &patches {
+            match patch {
+                DomPatch::SetAttribute { attr, value, .. } if attr == "class" => {
+                    if value.contains("text-red-600") {
+                        has_class_patch = true;
+                    }
+                }
+                DomPatch::UpdateText { text, .. } => {
+                    if text == "-42" {
+                        has_text_patch = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        
+        assert!(has_class_patch, "Should have class change patch for color change");
+        assert!(has_text_patch, "Should have text change patch for number change");
+    }
+
+    #[test]
+    fn test_random_button_edge_cases() {
+        let differ = HtmlDiffer::new();
+        
+        // Case 1: Same count, different sign (should generate class change only)
+        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html = r#"<div class="text-red-600 font-bold text-4xl">-5</div>"#;
+        
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 2, "Should generate both class and text patches for 5 -> -5");
+        
+        // Case 2: Same absolute value, no sign change (should generate text change only)
+        let old_html2 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html2 = r#"<div class="text-green-600 font-bold text-4xl">7</div>"#;
+        
+        let patches2 = differ.diff(old_html2, new_html2).unwrap();
+        assert_eq!(patches2.len(), 1, "Should generate only text patch for 5 -> 7 (same sign)");
+        
+        // Case 3: Exact same value (should generate no patches)
+        let old_html3 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html3 = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        
+        let patches3 = differ.diff(old_html3, new_html3).unwrap();
+        assert_eq!(patches3.len(), 0, "Should generate no patches for identical content");
+    }
+
+    #[test]
+    fn test_id_based_selectors() {
+        let differ = HtmlDiffer::new();
+        
+        // Test with elements that have IDs - should use stable ID selectors
+        let old_html = r#"<div id="counter-display" class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html = r#"<div id="counter-display" class="text-red-600 font-bold text-4xl">-42</div>"#;
+        
+        let patches = differ.diff(old_html, new_html).unwrap();
+        
+        // Should generate 2 patches: class change and text change
+        assert_eq!(patches.len(), 2);
+        
+        // Both patches should use the stable ID selector
+        for patch in &patches {
             match patch {
                 DomPatch::SetAttribute { selector, .. } => {
                     assert_eq!(selector, "#counter-display", "Should use ID selector for class change");
@@ -639,4 +2510,1151 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_details_open_toggle() {
+        let differ = HtmlDiffer::new();
+        let closed = r#"<details id="faq">More info</details>"#;
+        let open = r#"<details id="faq" open>More info</details>"#;
+
+        let patches = differ.diff(closed, open).unwrap();
+        assert_eq!(patches.len(), 1);
+        if let DomPatch::SetAttribute { selector, attr, value } = &patches[0] {
+            assert_eq!(selector, "#faq");
+            assert_eq!(attr, "open");
+            assert_eq!(value, "");
+        } else {
+            panic!("Expected SetAttribute patch, got {:?}", patches[0]);
+        }
+
+        let patches = differ.diff(open, closed).unwrap();
+        assert_eq!(patches.len(), 1);
+        if let DomPatch::RemoveAttribute { selector, attr } = &patches[0] {
+            assert_eq!(selector, "#faq");
+            assert_eq!(attr, "open");
+        } else {
+            panic!("Expected RemoveAttribute patch, got {:?}", patches[0]);
+        }
+    }
+
+    #[test]
+    fn test_hidden_attribute_toggle() {
+        let differ = HtmlDiffer::new();
+        let visible = r#"<p class="notice text-sm">Saved</p>"#;
+        let hidden = r#"<p class="notice text-sm" hidden>Saved</p>"#;
+
+        let patches = differ.diff(visible, hidden).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::SetAttribute { attr, value, .. } if attr == "hidden" && value.is_empty()
+        ));
+
+        let patches = differ.diff(hidden, visible).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::RemoveAttribute { attr, .. } if attr == "hidden"
+        ));
+    }
+
+    #[test]
+    fn test_class_change_with_each_quote_style() {
+        let differ = HtmlDiffer::new();
+        let cases = [
+            (r#"<span class="badge muted">3</span>"#, r#"<span class="badge active">3</span>"#),
+            (r#"<span class='badge muted'>3</span>"#, r#"<span class='badge active'>3</span>"#),
+            (r#"<span class=muted>3</span>"#, r#"<span class=active>3</span>"#),
+        ];
+
+        for (old_html, new_html) in cases {
+            let patches = differ.diff(old_html, new_html).unwrap();
+            assert_eq!(patches.len(), 1, "quote style in {}", old_html);
+            match &patches[0] {
+                DomPatch::SetAttribute { attr, value, .. } => {
+                    assert_eq!(attr, "class");
+                    assert!(value.ends_with("active"), "got {}", value);
+                }
+                other => panic!("Expected SetAttribute patch, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_single_quoted_id_selector() {
+        let differ = HtmlDiffer::new();
+        let old_html = r#"<div id='total' class='text-lg'>10</div>"#;
+        let new_html = r#"<div id='total' class='text-lg'>12</div>"#;
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1);
+        if let DomPatch::UpdateText { selector, text } = &patches[0] {
+            assert_eq!(selector, "#total");
+            assert_eq!(text, "12");
+        } else {
+            panic!("Expected UpdateText patch, got {:?}", patches[0]);
+        }
+    }
+
+    #[test]
+    fn test_match_hint_overrides_heuristic() {
+        let differ = HtmlDiffer::new();
+        // Without hints, the first old badge scores higher against the second new
+        // badge (shared "info" class) than against its real counterpart
+        let old_html = r#"
+            <span data-ts-match="alpha" class="badge info">Alpha</span>
+            <span data-ts-match="beta" class="badge warn">Beta</span>
+        "#;
+        let new_html = r#"
+            <span data-ts-match="alpha" class="badge error">Omega</span>
+            <span data-ts-match="beta" class="badge info">Beta</span>
+        "#;
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 3, "got {:?}", patches);
+
+        let alpha: Vec<&DomPatch> = patches
+            .iter()
+            .filter(|p| matches!(p, DomPatch::SetAttribute { selector, .. } | DomPatch::UpdateText { selector, .. } if selector == ".badge.info"))
+            .collect();
+        assert_eq!(alpha.len(), 2);
+        assert!(matches!(alpha[0], DomPatch::SetAttribute { value, .. } if value == "badge error"));
+        assert!(matches!(alpha[1], DomPatch::UpdateText { text, .. } if text == "Omega"));
+
+        assert!(patches.iter().any(|p| matches!(
+            p,
+            DomPatch::SetAttribute { selector, value, .. } if selector == ".badge.warn" && value == "badge info"
+        )));
+    }
+
+    #[test]
+    fn test_tag_swap_replaces_single_element() {
+        let differ = HtmlDiffer::new();
+        let old_html = r#"
+            <p class="hint">Page 2 of 3</p>
+            <a data-key="next" class="btn" href="/page/3">Next</a>
+        "#;
+        let new_html = r#"
+            <p class="hint">Page 3 of 3</p>
+            <button data-key="next" class="btn" disabled>Next</button>
+        "#;
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        let replacements: Vec<&DomPatch> = patches
+            .iter()
+            .filter(|p| matches!(p, DomPatch::ReplaceElement { .. }))
+            .collect();
+
+        assert_eq!(replacements.len(), 1, "got {:?}", patches);
+        if let DomPatch::ReplaceElement { selector, html } = replacements[0] {
+            assert_eq!(selector, "[data-key=\"next\"]");
+            assert_eq!(html, r#"<button data-key="next" class="btn" disabled>Next</button>"#);
+        }
+        assert!(!patches.iter().any(|p| matches!(p, DomPatch::ReplaceInnerHtml { .. })));
+    }
+
+    #[test]
+    fn test_attribute_with_backslash_escaped_quotes() {
+        let differ = HtmlDiffer::new();
+        // The quoted "class=" inside the title must not be taken for the real class
+        let old_html = r#"<div title="She said \"class=\" here" class="note muted">Hi</div>"#;
+        let new_html = r#"<div title="She said \"class=\" here" class="note active">Hi</div>"#;
+
+        let attributes = parse_attributes(r#" title="She said \"class=\" here" class="note muted""#);
+        assert_eq!(attributes[0], ("title".to_string(), r#"She said "class=" here"#.to_string()));
+        assert_eq!(attributes[1], ("class".to_string(), "note muted".to_string()));
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::SetAttribute { attr, value, .. } if attr == "class" && value == "note active"
+        ));
+    }
+
+    #[test]
+    fn test_attribute_with_entity_encoded_quotes() {
+        let differ = HtmlDiffer::new();
+        let attributes = parse_attributes(r#" data-json="{&quot;a&quot;:1}" id='x&#39;y' title="a &amp b""#);
+        assert_eq!(attributes[0], ("data-json".to_string(), r#"{"a":1}"#.to_string()));
+        assert_eq!(attributes[1], ("id".to_string(), "x'y".to_string()));
+        // A bare ampersand isn't a reference and is kept as written
+        assert_eq!(attributes[2], ("title".to_string(), "a &amp b".to_string()));
+
+        let old_html = r#"<span id="cfg" data-json="{&quot;a&quot;:1}" title="x > y">1</span>"#;
+        let new_html = r#"<span id="cfg" data-json="{&quot;a&quot;:1}" title="x > y">2</span>"#;
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        if let DomPatch::UpdateText { selector, text } = &patches[0] {
+            assert_eq!(selector, "#cfg");
+            assert_eq!(text, "2");
+        } else {
+            panic!("Expected UpdateText patch, got {:?}", patches[0]);
+        }
+    }
+
+    #[test]
+    fn test_diff_parsed_reuses_old_document() {
+        let differ = HtmlDiffer::new();
+        let old = differ.parse(r#"<div id="count" class="text-green-600">5</div>"#);
+
+        let text_only = differ.parse(r#"<div id="count" class="text-green-600">6</div>"#);
+        let patches = differ.diff_parsed(&old, &text_only).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::UpdateText { text, .. } if text == "6"));
+
+        let class_only = differ.parse(r#"<div id="count" class="text-red-600">5</div>"#);
+        let patches = differ.diff_parsed(&old, &class_only).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::SetAttribute { value, .. } if value == "text-red-600"));
+
+        // The string API is a wrapper over the parsed one
+        assert_eq!(
+            format!("{:?}", differ.diff(old.html(), class_only.html()).unwrap()),
+            format!("{:?}", patches)
+        );
+    }
+
+    #[test]
+    fn test_text_only_mode_ignores_structure() {
+        let differ = HtmlDiffer::with_config(DifferConfig { mode: DiffMode::TextOnly, ..Default::default() });
+
+        // Attribute, boolean and tag changes are all ignored
+        let old_html = r#"<div id="status" class="ok">Ready</div><a data-key="next" href="/2">Next</a>"#;
+        let new_html = r#"<div id="status" class="warn" hidden>Ready</div><button data-key="next">Next</button>"#;
+        assert!(differ.diff(old_html, new_html).unwrap().is_empty());
+
+        // Unparseable structure never falls back to a full replace
+        assert!(differ.diff("<ul></ul>", "<ul><li>").unwrap().is_empty());
+
+        let patches = differ
+            .diff(
+                r#"<div id="status" class="ok">Ready</div>"#,
+                r#"<div id="status" class="warn">Saving</div>"#,
+            )
+            .unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, text } if selector == "#status" && text == "Saving"
+        ));
+    }
+
+    #[test]
+    fn test_colliding_selectors_are_disambiguated() {
+        let differ = HtmlDiffer::new();
+        let old_html = r#"<ol><li class="step">•</li><li class="step">•</li></ol>"#;
+        let new_html = r#"<ol><li class="step done">•</li><li class="step done">•</li></ol>"#;
+
+        let (patches, stats) = differ.diff_with_stats(old_html, new_html).unwrap();
+        let selectors: Vec<&str> = patches.iter().filter_map(patch_selector).collect();
+        assert_eq!(selectors, vec![".step:nth-of-type(1)", ".step:nth-of-type(2)"]);
+        assert_eq!(stats.patch_count, 2);
+
+        // Positions count every same-tag sibling, not only the colliding ones
+        let old_html = r#"<ol><li>Start</li><li class="step">•</li><li class="step">•</li></ol>"#;
+        let new_html = r#"<ol><li>Start</li><li class="step done">•</li><li class="step done">•</li></ol>"#;
+        let (patches, stats) = differ.diff_with_stats(old_html, new_html).unwrap();
+        let selectors: Vec<&str> = patches.iter().filter_map(patch_selector).collect();
+        assert_eq!(selectors, vec![".step:nth-of-type(2)", ".step:nth-of-type(3)"]);
+        assert_eq!(stats.warnings.len(), 1);
+        assert!(stats.warnings[0].contains(".step"));
+
+        // A distinguishing attribute is preferred over position
+        let old_html = r#"<a class="tab" href="/a">Go</a><a class="tab" href="/b">Go</a>"#;
+        let new_html = r#"<a class="tab on" href="/a">Go</a><a class="tab on" href="/b">Go</a>"#;
+        let patches = differ.diff(old_html, new_html).unwrap();
+        let selectors: Vec<&str> = patches.iter().filter_map(patch_selector).collect();
+        assert_eq!(selectors, vec![r#".tab[href="/a"]"#, r#".tab[href="/b"]"#]);
+
+        // Distinct selectors are left alone
+        let (_, stats) = differ
+            .diff_with_stats(r#"<p id="a">1</p><p id="b">1</p>"#, r#"<p id="a">2</p><p id="b">2</p>"#)
+            .unwrap();
+        assert!(stats.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_malformed_html() {
+        let old_html = r#"<div><span class="n">1</span></div>"#;
+        let new_html = r#"<div><span class="n">2</div>"#;
+
+        let strict = HtmlDiffer::with_config(DifferConfig { strict: true, ..Default::default() });
+        match strict.diff(old_html, new_html) {
+            Err(LiveTSError::HtmlParsingError(reason)) => assert!(reason.contains("new HTML"), "got {}", reason),
+            other => panic!("Expected HtmlParsingError, got {:?}", other),
+        }
+
+        // Well-formed input without diffable elements can't be diffed precisely either
+        assert!(strict.diff("<ul></ul>", "<ul><li><b>x</b> y</li></ul>").is_err());
+
+        // Void elements, self-closing tags and comments don't need closing tags
+        assert!(check_well_formed(r#"<!-- <b> --><p>1<br>2</p><img src="x"/><input name=q>"#).is_ok());
+        assert!(strict.diff(r#"<p id="a">1</p><hr>"#, r#"<p id="a">2</p><hr>"#).is_ok());
+
+        let lenient = HtmlDiffer::new();
+        let patches = lenient.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::ReplaceInnerHtml { .. }));
+    }
+
+    fn keyed_list(keys: &[&str]) -> String {
+        let rows: String = keys
+            .iter()
+            .map(|key| format!(r#"<li data-key="{}">Item {}</li>"#, key, key))
+            .collect();
+        format!(r#"<ul id="todos">{}</ul>"#, rows)
+    }
+
+    #[test]
+    fn test_keyed_insert_at_top() {
+        let differ = HtmlDiffer::new();
+        let old_html = keyed_list(&["a", "b", "c", "d", "e"]);
+        let new_html = keyed_list(&["x", "a", "b", "c", "d", "e"]);
+
+        let patches = differ.diff(&old_html, &new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        match &patches[0] {
+            DomPatch::InsertElement { parent, position, html } => {
+                assert_eq!(parent, "#todos");
+                assert!(matches!(position, InsertPosition::AfterBegin));
+                assert_eq!(html, r#"<li data-key="x">Item x</li>"#);
+            }
+            other => panic!("Expected InsertElement patch, got {:?}", other),
+        }
+        assert!(!patches.iter().any(|p| matches!(p, DomPatch::UpdateText { .. })));
+    }
+
+    #[test]
+    fn test_keyed_insert_remove_and_move() {
+        let differ = HtmlDiffer::new();
+
+        // Insertion in the middle goes before the next sibling
+        let patches = differ.diff(&keyed_list(&["a", "b", "c"]), &keyed_list(&["a", "b", "n", "c"])).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::InsertBefore { reference_selector, .. } if reference_selector == r#"#todos > [data-key="c"]"#
+        ));
+
+        // Appending anchors on the previous sibling
+        let patches = differ.diff(&keyed_list(&["a", "b"]), &keyed_list(&["a", "b", "n"])).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::InsertElement { parent, position: InsertPosition::AfterEnd, .. }
+                if parent == r#"#todos > [data-key="b"]"#
+        ));
+
+        // Removal
+        let patches = differ.diff(&keyed_list(&["a", "b", "c"]), &keyed_list(&["a", "c"])).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::RemoveElement { selector } if selector == r#"#todos > [data-key="b"]"#
+        ));
+
+        // Moving the last row to the top only touches that row
+        let patches = differ.diff(&keyed_list(&["a", "b", "c"]), &keyed_list(&["c", "a", "b"])).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::MoveElement { selector, target, position: InsertPosition::AfterBegin }
+                if selector.ends_with(r#"[data-key="c"]"#) && target == "#todos"
+        ));
+    }
+
+    #[test]
+    fn test_empty_documents() {
+        let differ = HtmlDiffer::new();
+        let html = r#"<div id="count">5</div>"#;
+
+        let patches = differ.diff(html, "").unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::ReplaceInnerHtml { html, .. } if html.is_empty()));
+
+        let patches = differ.diff("", html).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::ReplaceInnerHtml { html: new, .. } if new == html));
+
+        assert!(differ.diff("", "").unwrap().is_empty());
+        assert!(differ.diff("  \n", "").unwrap().is_empty());
+
+        // Strict mode still allows rendering nothing
+        let strict = HtmlDiffer::with_config(DifferConfig { strict: true, ..Default::default() });
+        assert_eq!(strict.diff(html, "").unwrap().len(), 1);
+        assert!(strict.diff("", "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_attributes_added_removed_and_changed() {
+        let differ = HtmlDiffer::new();
+        let old_html = r#"<p id="note" class="muted" title="Old">Hi</p>"#;
+        let new_html = r#"<p id="note" class="bold" data-x="1">Hi</p>"#;
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 3, "got {:?}", patches);
+        assert!(patches.iter().any(|p| matches!(
+            p,
+            DomPatch::SetAttribute { selector, attr, value } if selector == "#note" && attr == "class" && value == "bold"
+        )));
+        assert!(patches.iter().any(|p| matches!(
+            p,
+            DomPatch::SetAttribute { attr, value, .. } if attr == "data-x" && value == "1"
+        )));
+        assert!(patches.iter().any(|p| matches!(
+            p,
+            DomPatch::RemoveAttribute { attr, .. } if attr == "title"
+        )));
+
+        // Unchanged attributes in a different order produce nothing
+        let reordered = r#"<p title="Old" class="muted" id="note">Hi</p>"#;
+        assert!(differ.diff(old_html, reordered).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_text_and_markup_content_patches() {
+        let differ = HtmlDiffer::new();
+
+        let patches = differ.diff(r#"<p id="bio">Hello</p>"#, r#"<p id="bio">Hi &amp; bye</p>"#).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, text } if selector == "#bio" && text == "Hi & bye"
+        ));
+
+        // The paragraph gained a child element, so its content is HTML now
+        let patches = differ
+            .diff(r#"<p id="bio">Hello</p>"#, r#"<p id="bio">Hello <b>world</b></p>"#)
+            .unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::ReplaceInnerHtml { selector, html } if selector == "#bio" && html == "Hello <b>world</b>"
+        ));
+
+        // Forcing a kind overrides the detection
+        let html_only = HtmlDiffer::with_config(DifferConfig { content: ContentKind::Html, ..Default::default() });
+        let patches = html_only.diff(r#"<p id="bio">Hello</p>"#, r#"<p id="bio">Bye</p>"#).unwrap();
+        assert!(matches!(&patches[0], DomPatch::ReplaceInnerHtml { html, .. } if html == "Bye"));
+
+        let text_only = HtmlDiffer::with_config(DifferConfig { content: ContentKind::Text, ..Default::default() });
+        let patches = text_only
+            .diff(r#"<p id="bio">Hello</p>"#, r#"<p id="bio">Hello <b>world</b></p>"#)
+            .unwrap();
+        assert!(matches!(&patches[0], DomPatch::UpdateText { text, .. } if text == "Hello <b>world</b>"));
+    }
+
+    #[test]
+    fn test_no_raw_html_mode() {
+        let differ = HtmlDiffer::with_config(DifferConfig { no_raw_html: true, ..Default::default() });
+        let is_raw = |patch: &DomPatch| {
+            matches!(
+                patch,
+                DomPatch::ReplaceInnerHtml { .. } | DomPatch::ReplaceElement { .. } | DomPatch::InsertElement { .. }
+            )
+        };
+
+        // Content that gained markup is sent as text
+        let (patches, _) = differ
+            .diff_with_stats(r#"<p id="bio">Hi</p>"#, r#"<p id="bio">Hi <b onmouseover="steal()">there</b></p>"#)
+            .unwrap();
+        assert_eq!(patches.len(), 1);
+        assert!(matches!(&patches[0], DomPatch::UpdateText { text, .. } if text.contains("<b onmouseover")));
+
+        // Tag swaps, keyed inserts and full replaces are dropped with a warning
+        let cases = [
+            (r#"<a data-key="next" class="btn">Next</a>"#, r#"<button data-key="next" class="btn">Next</button>"#),
+            (r#"<ul id="l"><li data-key="a">A</li></ul>"#, r#"<ul id="l"><li data-key="b">B</li><li data-key="a">A</li></ul>"#),
+            ("<ul></ul>", "<ul><li><b>x</b> y</li></ul>"),
+        ];
+        for (old_html, new_html) in cases {
+            let (patches, stats) = differ.diff_with_stats(old_html, new_html).unwrap();
+            assert!(!patches.iter().any(is_raw), "got {:?}", patches);
+            assert!(stats.warnings.iter().any(|w| w.contains("no_raw_html")));
+        }
+
+        // Text and attribute changes are unaffected
+        let patches = differ
+            .diff(r#"<div id="n" class="a">1</div>"#, r#"<div id="n" class="b">2</div>"#)
+            .unwrap();
+        assert_eq!(patches.len(), 2);
+    }
+
+    fn table(rows: &[[&str; 3]], keyed: bool) -> String {
+        let rows: String = rows
+            .iter()
+            .enumerate()
+            .map(|(index, cells)| {
+                let key = if keyed { format!(r#" data-key="r{}""#, index + 1) } else { String::new() };
+                let cells: String = cells.iter().map(|cell| format!(r#"<td class="cell">{}</td>"#, cell)).collect();
+                format!("<tr{}>{}</tr>", key, cells)
+            })
+            .collect();
+        format!("<table><tbody>{}</tbody></table>", rows)
+    }
+
+    #[test]
+    fn test_table_cell_changes_stay_in_their_row() {
+        let differ = HtmlDiffer::new();
+        let old_rows = [["1", "2", "3"], ["4", "5", "6"], ["7", "8", "9"]];
+        let new_rows = [["1", "2", "3"], ["4", "50", "6"], ["7", "8", "9"]];
+
+        let patches = differ.diff(&table(&old_rows, false), &table(&new_rows, false)).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, text }
+                if selector == "table:nth-of-type(1) > tbody > tr:nth-of-type(2) > td:nth-of-type(2)" && text == "50"
+        ));
+
+        // Keyed rows are addressed by key
+        let patches = differ.diff(&table(&old_rows, true), &table(&new_rows, true)).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, .. }
+                if selector == r#"table:nth-of-type(1) > tbody > tr[data-key="r2"] > td:nth-of-type(2)"#
+        ));
+
+        // The same cell in another table is a different cell
+        let two_tables = |first: &[[&str; 3]], second: &[[&str; 3]]| {
+            format!("<div>{}{}</div>", table(first, false), table(second, false))
+        };
+        let patches = differ.diff(&two_tables(&old_rows, &old_rows), &two_tables(&old_rows, &new_rows)).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, text }
+                if selector == "table:nth-of-type(2) > tbody > tr:nth-of-type(2) > td:nth-of-type(2)" && text == "50"
+        ));
+    }
+
+    #[test]
+    fn test_multibyte_text_matching() {
+        let differ = HtmlDiffer::new();
+
+        // "José" and "Josè" have the same number of characters; "Maria" only the same byte length
+        let old_html = r#"<p class="name">José</p>"#;
+        let new_html = r#"<p class="name">Maria</p><p class="name">Josè</p>"#;
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(&patches[0], DomPatch::UpdateText { text, .. } if text == "Josè"));
+
+        let patches = differ.diff("<b>ñandú</b>", "<b>ñandú 🐦</b>").unwrap();
+        assert!(matches!(&patches[0], DomPatch::UpdateText { selector, .. } if selector == "b:contains('ñandú')"));
+
+        assert_eq!(differ.optimize_selector(r#"[data-ts-sel="é"]"#.to_string()), "é");
+        assert_eq!(differ.optimize_selector(r#"[data-ts-sel="ab"]"#.to_string()), "ab");
+    }
+
+    #[test]
+    fn test_merge_redundant_patches() {
+        let text = |selector: &str, text: &str| DomPatch::UpdateText { selector: selector.to_string(), text: text.to_string() };
+        let class = |selector: &str, value: &str| DomPatch::SetAttribute {
+            selector: selector.to_string(),
+            attr: "class".to_string(),
+            value: value.to_string(),
+        };
+
+        let merged = merge_patches(vec![
+            text("#a", "1"),
+            class("#a", "old"),
+            text("#b", "x"),
+            text("#a", "2"),
+            class("#a", "new"),
+            DomPatch::RemoveAttribute { selector: "#a".to_string(), attr: "title".to_string() },
+        ]);
+        assert_eq!(merged.len(), 4, "got {:?}", merged);
+        assert!(matches!(&merged[0], DomPatch::UpdateText { selector, text } if selector == "#b" && text == "x"));
+        assert!(matches!(&merged[1], DomPatch::UpdateText { text, .. } if text == "2"));
+        assert!(matches!(&merged[2], DomPatch::SetAttribute { value, .. } if value == "new"));
+        assert!(matches!(&merged[3], DomPatch::RemoveAttribute { .. }));
+
+        // HTML content beats text content, and replacing the element beats everything
+        let merged = merge_patches(vec![
+            DomPatch::ReplaceInnerHtml { selector: "#a".to_string(), html: "<b>1</b>".to_string() },
+            text("#a", "2"),
+            text("#b", "3"),
+            class("#b", "on"),
+            DomPatch::ReplaceElement { selector: "#b".to_string(), html: "<i>4</i>".to_string() },
+        ]);
+        assert_eq!(merged.len(), 2, "got {:?}", merged);
+        assert!(matches!(&merged[0], DomPatch::ReplaceInnerHtml { .. }));
+        assert!(matches!(&merged[1], DomPatch::ReplaceElement { .. }));
+
+        // Inserts are never merged
+        let insert = || DomPatch::InsertElement {
+            parent: "#list".to_string(),
+            position: InsertPosition::BeforeEnd,
+            html: "<li>x</li>".to_string(),
+        };
+        assert_eq!(merge_patches(vec![insert(), insert()]).len(), 2);
+    }
+
+    #[test]
+    fn test_keyed_middle_insertions_compact() {
+        let differ = HtmlDiffer::new();
+
+        // Consecutive new rows all go before the same kept sibling, so they land in order
+        let patches = differ
+            .diff(&keyed_list(&["a", "b", "c"]), &keyed_list(&["a", "m", "n", "b", "c"]))
+            .unwrap();
+        let compact = differ.patches_to_compact(patches);
+        assert_eq!(
+            compact,
+            vec![
+                r#"b|#todos > [data-key="b"]|<li data-key="m">Item m</li>"#.to_string(),
+                r#"b|#todos > [data-key="b"]|<li data-key="n">Item n</li>"#.to_string(),
+            ]
+        );
+
+        let compact = differ.patches_to_compact(vec![DomPatch::InsertElement {
+            parent: "#todos".to_string(),
+            position: InsertPosition::AfterBegin,
+            html: "<li>x</li>".to_string(),
+        }]);
+        assert_eq!(compact, vec!["i|#todos|ab|<li>x</li>".to_string()]);
+
+        // A keyed append follows the last kept sibling
+        let patches = differ.diff(&keyed_list(&["a", "b"]), &keyed_list(&["a", "b", "c"])).unwrap();
+        assert_eq!(
+            differ.patches_to_compact(patches),
+            vec![r#"i|#todos > [data-key="b"]|ae|<li data-key="c">Item c</li>"#.to_string()]
+        );
+
+        // Keys are escaped inside the attribute selector
+        let patches = differ.diff(&keyed_list(&["a", r"x\y", "b"]), &keyed_list(&["a", "b"])).unwrap();
+        assert_eq!(
+            patches,
+            vec![DomPatch::RemoveElement { selector: r#"#todos > [data-key="x\\y"]"#.to_string() }]
+        );
+
+        // The reference selector goes through the dictionary like any other selector
+        let (selectors, compact) = differ.patches_to_compact_with_dictionary(vec![DomPatch::InsertBefore {
+            reference_selector: r#"[data-ts-sel="c1.2"]"#.to_string(),
+            html: "<li>y</li>".to_string(),
+        }]);
+        assert_eq!(selectors, vec!["c1.2".to_string()]);
+        assert_eq!(compact, vec!["b|0|<li>y</li>".to_string()]);
+    }
+
+    #[test]
+    fn test_data_uri_attribute_values() {
+        let differ = HtmlDiffer::new();
+        let payload = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==".repeat(40);
+        let card = |src: &str, name: &str| {
+            format!(
+                r#"<div id="card"><img id="avatar" src="data:image/png;base64,{}" alt="A"><p id="name">{}</p></div>"#,
+                src, name
+            )
+        };
+
+        // An unchanged giant src is never re-sent, even when the image itself changes
+        let patches = differ.diff(&card(&payload, "Ann"), &card(&payload, "Bob")).unwrap();
+        assert_eq!(differ.patches_to_compact(patches), vec!["t|#name|Bob".to_string()]);
+        let with_alt = card(&payload, "Ann").replace(r#"alt="A""#, r#"alt="B""#);
+        let patches = differ.diff(&card(&payload, "Ann"), &with_alt).unwrap();
+        assert_eq!(differ.patches_to_compact(patches), vec!["a|#avatar|alt|B".to_string()]);
+        assert!(differ.diff(&card(&payload, "Ann"), &card(&payload, "Ann")).unwrap().is_empty());
+
+        // A changed one goes out whole, '|' and all, as the last field
+        let changed = format!("{}/+;x|y", payload);
+        let patches = differ.diff(&card(&payload, "Ann"), &card(&changed, "Ann")).unwrap();
+        assert_eq!(
+            differ.patches_to_compact(patches),
+            vec![format!("a|#avatar|src|data:image/png;base64,{}", changed)]
+        );
+    }
+
+    #[test]
+    fn test_class_order_is_ignored() {
+        let differ = HtmlDiffer::new();
+
+        let patches = differ
+            .diff(r#"<div id="box" class="a b  c">Hi</div>"#, r#"<div id="box" class="c a b">Hi</div>"#)
+            .unwrap();
+        assert!(patches.is_empty(), "got {:?}", patches);
+
+        // Also on a wrapper the element diff can't see, instead of falling back to a full replace
+        let patches = differ
+            .diff(r#"<div class="x y"><p id="a">1</p><p>2</p></div>"#, r#"<div class="y x"><p id="a">1</p><p>2</p></div>"#)
+            .unwrap();
+        assert!(patches.is_empty(), "got {:?}", patches);
+
+        // A real change sends the new string as written
+        let patches = differ
+            .diff(r#"<div id="box" class="a b">Hi</div>"#, r#"<div id="box" class="b d a">Hi</div>"#)
+            .unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::SetAttribute { attr, value, .. } if attr == "class" && value == "b d a"
+        ));
+    }
+
+    #[test]
+    fn test_ignores_comment_and_component_id_changes() {
+        let differ = HtmlDiffer::new();
+
+        let patches = differ
+            .diff(
+                r#"<div data-livets-id="a1b2"><p id="n">1</p><p>x</p></div>"#,
+                r#"<div data-livets-id="c3d4"><p id="n">1</p><p>x</p></div>"#,
+            )
+            .unwrap();
+        assert!(patches.is_empty(), "got {:?}", patches);
+
+        let patches = differ
+            .diff(r#"<p id="n">1</p><!-- render 1 --><p>x</p>"#, r#"<p id="n">1</p><!-- render 2: <b>y</b> --><p>x</p>"#)
+            .unwrap();
+        assert!(patches.is_empty(), "got {:?}", patches);
+
+        // A real change alongside a new id only patches the change
+        let patches = differ
+            .diff(r#"<div data-livets-id="a1b2"><p id="n">1</p></div>"#, r#"<div data-livets-id="c3d4"><p id="n">2</p></div>"#)
+            .unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(&patches[0], DomPatch::UpdateText { text, .. } if text == "2"));
+    }
+
+    #[test]
+    fn test_nested_component_id_change_is_patched() {
+        let differ = HtmlDiffer::new();
+
+        let patches = differ
+            .diff(
+                r#"<div data-livets-id="a1b2"><p id="n">1</p><span data-livets-id="e5f6">x</span></div>"#,
+                r#"<div data-livets-id="c3d4"><p id="n">1</p><span data-livets-id="g7h8">x</span></div>"#,
+            )
+            .unwrap();
+        assert!(
+            patches.iter().any(|patch| matches!(
+                patch,
+                DomPatch::SetAttribute { attr, value, .. } if attr == "data-livets-id" && value == "g7h8"
+            )),
+            "got {:?}",
+            patches
+        );
+    }
+
+    #[test]
+    fn test_diff_debug_reports_match_strategy() {
+        let differ = HtmlDiffer::new();
+        let annotated = differ.diff_debug(
+            r#"<p id="n">1</p><span class="big count">5</span>"#,
+            r#"<p id="n">2</p><span class="big count">6</span>"#,
+        );
+        assert_eq!(annotated.len(), 2, "got {:?}", annotated);
+
+        let (patch, info) = &annotated[0];
+        assert!(matches!(patch, DomPatch::UpdateText { selector, text } if selector == "#n" && text == "2"));
+        assert_eq!(info.strategy, MatchStrategy::Id);
+        assert_eq!((info.score, info.tag.as_str(), info.old_index, info.new_index), (0, "p", 0, Some(0)));
+
+        let (patch, info) = &annotated[1];
+        assert!(matches!(patch, DomPatch::UpdateText { text, .. } if text == "6"));
+        assert_eq!(info.strategy, MatchStrategy::Score);
+        assert!(info.score > 1);
+        assert_eq!((info.tag.as_str(), info.new_index), ("span", Some(1)));
+
+        let json = serde_json::to_value(info).unwrap();
+        assert_eq!(json["strategy"], "score");
+    }
+
+    #[test]
+    fn test_shadow_dom_part_selectors() {
+        // The client applies these at the component's shadow root, not the document
+        let old_html = r#"<span part="label badge">Old</span>"#;
+        let new_html = r#"<span part="label badge">New</span>"#;
+
+        let shadow = HtmlDiffer::with_config(DifferConfig { shadow_dom: true, ..Default::default() });
+        let patches = shadow.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::UpdateText { selector, text } if selector == r#"[part~="label"][part~="badge"]"# && text == "New"
+        ));
+
+        let patches = HtmlDiffer::new().diff(old_html, new_html).unwrap();
+        assert!(matches!(&patches[0], DomPatch::UpdateText { selector, .. } if !selector.contains("part")));
+
+        // Only the component's own root is the host, not a nested component inside it
+        let patches = shadow
+            .diff(r#"<div data-livets-id="a1b2" title="a">x</div>"#, r#"<div data-livets-id="a1b2" title="b">x</div>"#)
+            .unwrap();
+        assert!(matches!(&patches[..], [DomPatch::SetAttribute { selector, .. }] if selector == ":host"), "got {:?}", patches);
+        let patches = shadow
+            .diff(
+                r#"<div data-livets-id="a1b2"><p data-livets-id="e5f6" title="a">x</p></div>"#,
+                r#"<div data-livets-id="a1b2"><p data-livets-id="e5f6" title="b">x</p></div>"#,
+            )
+            .unwrap();
+        assert!(matches!(&patches[..], [DomPatch::SetAttribute { selector, .. }] if selector != ":host"), "got {:?}", patches);
+    }
+
+    #[test]
+    fn test_boolean_attribute_spellings_are_equal() {
+        let differ = HtmlDiffer::new();
+        let spellings = [
+            r#"<form id="f"><button id="save" disabled="">Save</button><input id="agree" type="checkbox" checked></form>"#,
+            r#"<form id="f"><button id="save" disabled>Save</button><input id="agree" type="checkbox" checked="checked"></form>"#,
+            r#"<form id="f"><button id="save" disabled="disabled">Save</button><input id="agree" type="checkbox" checked=""></form>"#,
+        ];
+        for old_html in spellings {
+            for new_html in spellings {
+                let patches = differ.diff(old_html, new_html).unwrap();
+                assert!(patches.is_empty(), "{} -> {} gave {:?}", old_html, new_html, patches);
+            }
+        }
+
+        // Removing the attribute is still a change
+        let patches = differ
+            .diff(r#"<button id="save" disabled="disabled">Save</button>"#, r#"<button id="save">Save</button>"#)
+            .unwrap();
+        assert!(matches!(&patches[..], [DomPatch::RemoveAttribute { attr, .. }] if attr == "disabled"));
+
+        // A custom list replaces the defaults
+        let custom = HtmlDiffer::with_config(DifferConfig {
+            boolean_attributes: Some(vec!["busy".to_string()]),
+            ..Default::default()
+        });
+        assert!(custom.diff(r#"<div id="s" busy="">x</div>"#, r#"<div id="s" busy="busy">x</div>"#).unwrap().is_empty());
+        let patches = custom
+            .diff(r#"<button id="save" disabled="">Save</button>"#, r#"<button id="save" disabled="disabled">Save</button>"#)
+            .unwrap();
+        assert!(matches!(&patches[..], [DomPatch::SetAttribute { attr, value, .. }] if attr == "disabled" && value == "disabled"));
+    }
+
+    #[test]
+    fn test_keyed_swap_with_identical_content_moves() {
+        let differ = HtmlDiffer::new();
+        let card = |key: &str| format!(r#"<div class="card" data-key="{}"><h3>Same</h3></div>"#, key);
+        let old_html = format!(r#"<section id="cards">{}{}</section>"#, card("x"), card("y"));
+        let new_html = format!(r#"<section id="cards">{}{}</section>"#, card("y"), card("x"));
+
+        let patches = differ.diff(&old_html, &new_html).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::MoveElement { selector, target, position: InsertPosition::AfterEnd }
+                if selector == r#"#cards > [data-key="x"]"# && target == r#"#cards > [data-key="y"]"#
+        ));
+        assert_eq!(
+            differ.patches_to_compact(patches),
+            vec![r#"m|#cards > [data-key="x"]|ae|#cards > [data-key="y"]"#.to_string()]
+        );
+
+        // A row moved into the middle goes before the next row that stayed
+        let patches = differ.diff(&keyed_list(&["a", "b", "c", "d"]), &keyed_list(&["b", "c", "a", "d"])).unwrap();
+        assert_eq!(patches.len(), 1, "got {:?}", patches);
+        assert!(matches!(
+            &patches[0],
+            DomPatch::MoveElement { selector, target, position: InsertPosition::BeforeBegin }
+                if selector.ends_with(r#"[data-key="a"]"#) && target.ends_with(r#"[data-key="d"]"#)
+        ));
+    }
+
+    #[test]
+    fn test_combined_class_and_text_patch() {
+        let old_html = r#"<div class="text-green-600 font-bold text-4xl">5</div>"#;
+        let new_html = r#"<div class="text-red-600 font-bold text-4xl">-3</div>"#;
+        let differ = HtmlDiffer::with_config(DifferConfig {
+            combine_element_patches: true,
+            ..Default::default()
+        });
+
+        let patches = differ.diff(old_html, new_html).unwrap();
+        assert_eq!(patches.len(), 1);
+        match &patches[0] {
+            DomPatch::UpdateElement { attrs, text, .. } => {
+                assert_eq!(attrs.len(), 1);
+                assert_eq!(attrs["class"], "text-red-600 font-bold text-4xl");
+                assert_eq!(text.as_deref(), Some("-3"));
+            }
+            other => panic!("Expected UpdateElement, got {:?}", other),
+        }
+
+        let compact = differ.patches_to_compact(patches);
+        assert!(compact[0].starts_with("u|"));
+        assert!(compact[0].ends_with(r#"|{"class":"text-red-600 font-bold text-4xl"}|-3"#));
+
+        // A lone change stays a plain patch
+        let patches = differ.diff(old_html, r#"<div class="text-green-600 font-bold text-4xl">6</div>"#).unwrap();
+        assert!(matches!(&patches[..], [DomPatch::UpdateText { text, .. }] if text == "6"));
+    }
+
+    #[test]
+    fn test_diff_appended_log_line() {
+        let differ = HtmlDiffer::new();
+        let lines: String = (0..100).map(|i| format!("<li>line {}</li>", i)).collect();
+        let old_html = format!(r#"<div class="log-viewer"><ul id="log">{}</ul></div>"#, lines);
+        let new_html = format!(r#"<div class="log-viewer"><ul id="log">{}<li>line 100</li></ul></div>"#, lines);
+        let prefix_len = old_html.len() - "</ul></div>".len();
+
+        let patches = differ.diff_appended(&old_html, &new_html, prefix_len).unwrap();
+        assert_eq!(patches.len(), 1);
+        match &patches[0] {
+            DomPatch::InsertElement { parent, position: InsertPosition::BeforeEnd, html } => {
+                assert_eq!(parent, "#log");
+                assert_eq!(html, "<li>line 100</li>");
+            }
+            other => panic!("Expected InsertElement, got {:?}", other),
+        }
+
+        // A hint that doesn't hold falls back to a full diff
+        let edited = new_html.replace("line 3<", "line three<");
+        let fallback = differ.diff_appended(&old_html, &edited, prefix_len).unwrap();
+        let full = differ.diff(&old_html, &edited).unwrap();
+        assert_eq!(format!("{:?}", fallback), format!("{:?}", full));
+    }
+
+    #[test]
+    fn test_parse_elements_public() {
+        let differ = HtmlDiffer::new();
+        let template = r#"<div data-livets-id="c1">
+            <h2 class="title">Todos</h2>
+            <span id="count" data-ts-sel="c1.0">3</span>
+            <input type="text" data-ts-sel="c1.1" value="">
+            <button class="btn primary" ts-on:click="add">Add</button>
+        </div>"#;
+
+        let elements = differ.parse_elements_public(template);
+        let tags: Vec<&str> = elements.iter().map(|element| element.tag_name.as_str()).collect();
+        assert_eq!(tags, ["h2", "span", "input", "button"]);
+        assert_eq!(
+            elements[1],
+            PublicHtmlElement {
+                tag_name: "span".to_string(),
+                classes: String::new(),
+                id: "count".to_string(),
+                ts_selector: "c1.0".to_string(),
+                text_content: "3".to_string(),
+            }
+        );
+        assert_eq!(elements[3].classes, "btn primary");
+
+        // The kind of template check this enables: the inputs and counters are addressable
+        let untracked: Vec<&str> = elements
+            .iter()
+            .filter(|element| matches!(element.tag_name.as_str(), "span" | "input") && element.ts_selector.is_empty())
+            .map(|element| element.tag_name.as_str())
+            .collect();
+        assert!(untracked.is_empty());
+        assert!(differ.parse_elements_public("plain text").is_empty());
+    }
+
+    #[test]
+    fn test_moving_select_selection_sets_its_value() {
+        let differ = HtmlDiffer::new();
+        let render = |selected: usize| {
+            let options: String = ["s", "m", "l"]
+                .iter()
+                .enumerate()
+                .map(|(index, size)| {
+                    let attr = if index == selected { " selected" } else { "" };
+                    format!("<option value=\"{}\"{}>{}</option>", size, attr, size.to_uppercase())
+                })
+                .collect();
+            format!("<div><select id=\"size\" name=\"size\">{}</select></div>", options)
+        };
+        let set_value = |value: &str| {
+            vec![DomPatch::SetProperty {
+                selector: "#size".to_string(),
+                property: "value".to_string(),
+                value: value.to_string(),
+            }]
+        };
+
+        assert_eq!(differ.diff(&render(0), &render(2)).unwrap(), set_value("l"));
+        assert_eq!(differ.diff(&render(2), &render(1)).unwrap(), set_value("m"));
+        assert!(differ.diff(&render(1), &render(1)).unwrap().is_empty());
+
+        // Without a `selected` option the first one is shown, and text stands in for a value
+        let plain = "<select name=\"n\"><option>One</option><option>Two</option></select>";
+        let picked = "<select name=\"n\"><option>One</option><option selected>Two</option></select>";
+        assert_eq!(
+            differ.diff(plain, picked).unwrap(),
+            vec![DomPatch::SetProperty {
+                selector: "select[name=\"n\"]".to_string(),
+                property: "value".to_string(),
+                value: "Two".to_string(),
+            }]
+        );
+        // Quotes in the name are escaped inside the attribute selector
+        let quoted = plain.replace("name=\"n\"", r#"name='items["a"]'"#);
+        let quoted_picked = picked.replace("name=\"n\"", r#"name='items["a"]'"#);
+        assert_eq!(
+            differ.diff(&quoted, &quoted_picked).unwrap(),
+            vec![DomPatch::SetProperty {
+                selector: r#"select[name="items[\"a\"]"]"#.to_string(),
+                property: "value".to_string(),
+                value: "Two".to_string(),
+            }]
+        );
+        assert_eq!(
+            differ.patch_to_compact(set_value("m").remove(0)),
+            "p|#size|value|m"
+        );
+
+        // A multiple select next to it has no single value, so its options keep their patches
+        let both = |size: usize, tag: &str| {
+            let tags = format!(
+                "<select name=\"tags\" multiple><option value=\"new\"{}>New</option><option value=\"sale\">Sale</option></select>",
+                tag
+            );
+            format!("{}{}", render(size), tags)
+        };
+        let patches = differ.diff(&both(0, ""), &both(2, " selected")).unwrap();
+        assert!(patches.contains(&set_value("l").remove(0)), "got {:?}", patches);
+        assert!(
+            patches.iter().any(|patch| matches!(
+                patch,
+                DomPatch::SetAttribute { attr, .. } if attr == "selected"
+            )),
+            "got {:?}",
+            patches
+        );
+        assert_eq!(patches.len(), 2, "got {:?}", patches);
+    }
+
+    #[test]
+    fn test_custom_element_extractor_is_used() {
+        /// Reads `name=value` lines as `<span id="name">value</span>` elements
+        struct LineExtractor;
+
+        impl ElementExtractor for LineExtractor {
+            fn extract(&self, html: &str) -> Vec<HtmlElement> {
+                html.lines()
+                    .filter_map(|line| line.split_once('='))
+                    .map(|(name, value)| {
+                        let attributes = vec![("id".to_string(), name.trim().to_string())];
+                        HtmlElement::new("span".to_string(), attributes, value.trim().to_string(), String::new())
+                    })
+                    .collect()
+            }
+        }
+
+        let old = "count=1\nlabel=Clicks";
+        let new = "count=2\nlabel=Clicks";
+        assert!(HtmlDiffer::is_full_replace(&HtmlDiffer::new().diff(old, new).unwrap()));
+
+        let differ = HtmlDiffer::new().with_extractor(Arc::new(LineExtractor));
+        assert_eq!(
+            differ.diff(old, new).unwrap(),
+            vec![DomPatch::UpdateText { selector: "#count".to_string(), text: "2".to_string() }]
+        );
+        assert_eq!(differ.parse_elements_public(old).len(), 2);
+    }
+
+    #[test]
+    fn test_managed_class_prefixes_leave_other_classes_alone() {
+        let differ = HtmlDiffer::with_config(DifferConfig {
+            managed_class_prefixes: vec!["is-".to_string(), "has-".to_string()],
+            ..Default::default()
+        });
+        let old = r#"<button id="save" class="btn has-icon">Save</button>"#;
+        let new = r#"<button id="save" class="btn shadow-lg is-active">Save</button>"#;
+
+        // shadow-lg belongs to the client, so it's neither added nor part of a class rewrite
+        assert_eq!(
+            differ.diff(old, new).unwrap(),
+            vec![
+                DomPatch::ToggleClass { selector: "#save".to_string(), class: "has-icon".to_string(), add: false },
+                DomPatch::ToggleClass { selector: "#save".to_string(), class: "is-active".to_string(), add: true },
+            ]
+        );
+        assert!(differ.diff(old, r#"<button id="save" class="shadow-lg has-icon">Save</button>"#).unwrap().is_empty());
+        assert_eq!(
+            differ.patches_to_compact(differ.diff(new, old).unwrap()),
+            vec!["k|#save|is-active|0", "k|#save|has-icon|1"]
+        );
+
+        // Without prefixes the whole class attribute is diffed
+        assert!(matches!(
+            HtmlDiffer::new().diff(old, new).unwrap().as_slice(),
+            [DomPatch::SetAttribute { attr, .. }] if attr == "class"
+        ));
+    }
+
+    #[test]
+    fn test_single_element_fast_path_matches_general_path() {
+        let differ = HtmlDiffer::new();
+        let cases = [
+            (r#"<span class="badge">4</span>"#, r#"<span class="badge is-new">5</span>"#),
+            (r#"<div data-livets-id="c1"><b id="count">1</b></div>"#, r#"<div data-livets-id="c1"><b id="count">2</b></div>"#),
+            (r#"<p data-ts-sel="c1.0">a</p>"#, r#"<h2 data-ts-sel="c1.0">b</h2>"#),
+            (r#"<li data-key="7">x</li>"#, r#"<li data-key="8">y</li>"#),
+            (r#"<span>1</span>"#, r#"<em>1</em>"#),
+            (r#"<input id="q" value="a">"#, r#"<input id="q" value="b" disabled>"#),
+            (r#"<span>same</span>"#, r#"<span>same</span>"#),
+        ];
+
+        for (old_html, new_html) in cases {
+            let (old, new) = (differ.parse(old_html), differ.parse(new_html));
+            assert_eq!(old.elements.as_ref().map(Vec::len), Some(1), "{}", old_html);
+            let fast = differ.smart_element_diff(&old, &new, &mut DiffStats::default());
+            let general = differ.matched_element_diff(&old, &new, &mut DiffStats::default());
+            assert_eq!(fast, general, "{} -> {}", old_html, new_html);
+        }
+    }
+
+    #[test]
+    fn test_focus_command_round_trips_through_compact() {
+        let differ = HtmlDiffer::new();
+        let focus = DomPatch::ClientCommand {
+            command: "focus".to_string(),
+            args: serde_json::json!({ "selector": "#q|x" }),
+        };
+        let compact = differ.patches_to_compact(vec![focus.clone()]);
+        assert_eq!(compact, vec![r##"q|focus|{"selector":"#q|x"}"##.to_string()]);
+        assert_eq!(HtmlDiffer::compact_to_command(&compact[0]).unwrap(), focus);
+
+        // Commands have no selector, so the dictionary leaves them alone
+        let (selectors, compact) = differ.patches_to_compact_with_dictionary(vec![focus]);
+        assert!(selectors.is_empty());
+        assert!(compact[0].starts_with("q|focus|"));
+
+        assert!(HtmlDiffer::compact_to_command("q|focus|{oops").is_err());
+        assert!(HtmlDiffer::compact_to_command("t|#q|hello").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_ts_selectors_are_indexed_and_reported() {
+        let differ = HtmlDiffer::new();
+        let old = r#"<div data-ts-sel="c1.0">first</div><div data-ts-sel="c1.0">second</div>"#;
+        let new = r#"<div data-ts-sel="c1.0">first</div><div data-ts-sel="c1.0">changed</div>"#;
+
+        let (patches, stats) = differ.diff_with_stats(old, new).unwrap();
+        let selectors: Vec<_> = patches.iter().filter_map(patch_selector).collect();
+        assert_eq!(selectors, vec!["c1.0~1"], "{:?}", patches);
+        assert_eq!(stats.warnings.len(), 2, "{:?}", stats.warnings);
+        assert!(stats.warnings.iter().all(|w| w.contains("data-ts-sel c1.0 is shared by 2 elements")));
+
+        // Unique values are left alone and not reported
+        let (_, stats) = differ.diff_with_stats(r#"<b data-ts-sel="c1.0">1</b>"#, r#"<b data-ts-sel="c1.0">2</b>"#).unwrap();
+        assert!(stats.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_diff_best_of_picks_the_closest_candidate() {
+        let differ = HtmlDiffer::new();
+        let new_html = r#"<ul><li id="a">Apples</li><li id="b">Bananas: 12</li><li id="c">Cherries</li></ul>"#;
+        let candidates = [
+            r#"<ul><li id="a">Apricots</li><li id="b">Blueberries: 3</li><li id="c">Currants</li></ul>"#,
+            r#"<ul><li id="a">Apples</li><li id="b">Bananas: 11</li><li id="c">Cherries</li></ul>"#,
+            r#"<section><h1>Loading...</h1></section>"#,
+        ];
+
+        let (index, patches) = differ.diff_best_of(&candidates, new_html);
+        assert_eq!(index, 1);
+        assert_eq!(patches, differ.diff(candidates[1], new_html).unwrap());
+        assert_eq!(patches.len(), 1);
+
+        // Nothing close enough: a full replace, flagged by an out-of-range index
+        let (index, patches) = differ.diff_best_of(&[candidates[2]], new_html);
+        assert_eq!(index, 1);
+        assert!(HtmlDiffer::is_full_replace(&patches));
+        assert_eq!(differ.diff_best_of(&[], new_html).0, 0);
+    }
+}
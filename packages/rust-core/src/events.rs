@@ -2,33 +2,96 @@
 
 use crate::types::*;
 use dashmap::DashMap;
-use std::sync::Arc;
+use futures_util::future::BoxFuture;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 /// Handler function type for processing client events
 pub type EventHandler = dyn Fn(ClientEvent) -> Result<()> + Send + Sync;
 
+/// Handler that responds to an event with the component's new HTML
+pub type RenderHandler = dyn Fn(ClientEvent) -> BoxFuture<'static, Result<String>> + Send + Sync;
+
+/// A component's handler: fire-and-forget or request/response
+enum RegisteredHandler {
+    Notify(Box<EventHandler>),
+    Render(Box<RenderHandler>),
+}
+
+/// Key used to coalesce events: (component id, event name)
+type CoalesceKey = (ComponentId, String);
+
 /// Routes client events to appropriate handlers
+///
+/// Concurrency model: `route_event` never blocks the calling task. A render handler's
+/// future is awaited in place, so events for different components (or several events
+/// for one component) can be in flight at once; handlers must tolerate that. A handler
+/// that hands events to a single thread, such as Node's main thread, runs them one at a
+/// time in arrival order, so its responses come back in the order the events were routed.
 pub struct EventRouter {
-    handlers: DashMap<ComponentId, Arc<Box<EventHandler>>>,
+    handlers: DashMap<ComponentId, Arc<RegisteredHandler>>,
+    /// Receives events for components without a handler of their own
+    default_handler: RwLock<Option<Arc<RegisteredHandler>>>,
+    /// Debounce window in milliseconds (0 disables coalescing)
+    coalesce_window_ms: AtomicU64,
+    /// Latest pending event per key, tagged with the generation that queued it
+    pending: DashMap<CoalesceKey, (u64, ClientEvent)>,
+    next_generation: AtomicU64,
 }
 
 impl EventRouter {
     pub fn new() -> Self {
         Self {
             handlers: DashMap::new(),
+            default_handler: RwLock::new(None),
+            coalesce_window_ms: AtomicU64::new(0),
+            pending: DashMap::new(),
+            next_generation: AtomicU64::new(0),
         }
     }
 
+    /// Sets the window used to coalesce rapid-fire events (0 disables coalescing)
+    ///
+    /// Events with the same component id and event name arriving within the
+    /// window of each other collapse into the latest one (trailing debounce).
+    pub fn set_coalesce_window(&self, window_ms: u64) {
+        self.coalesce_window_ms.store(window_ms, Ordering::Relaxed);
+    }
+
     /// Registers an event handler for a component
     pub fn register_handler(
         &self,
         component_id: ComponentId,
         handler: Box<EventHandler>,
     ) -> Result<()> {
-        self.handlers.insert(component_id, Arc::new(handler));
+        self.handlers
+            .insert(component_id, Arc::new(RegisteredHandler::Notify(handler)));
         Ok(())
     }
 
+    /// Registers a handler that answers each event with the component's new HTML
+    pub fn register_render_handler(
+        &self,
+        component_id: ComponentId,
+        handler: Box<RenderHandler>,
+    ) -> Result<()> {
+        self.handlers
+            .insert(component_id, Arc::new(RegisteredHandler::Render(handler)));
+        Ok(())
+    }
+
+    /// Registers a catch-all handler for events whose component has no handler
+    /// With one set, `route_event` dispatches there instead of returning `ComponentNotFound`
+    pub fn set_default_handler(&self, handler: Box<EventHandler>) {
+        *self.default_handler.write().unwrap() = Some(Arc::new(RegisteredHandler::Notify(handler)));
+    }
+
+    /// Removes the catch-all handler
+    pub fn clear_default_handler(&self) {
+        *self.default_handler.write().unwrap() = None;
+    }
+
     /// Removes an event handler for a component
     pub fn unregister_handler(&self, component_id: &ComponentId) -> Result<()> {
         self.handlers.remove(component_id);
@@ -36,23 +99,56 @@ impl EventRouter {
     }
 
     /// Routes a client event to the appropriate handler
-    pub async fn route_event(&self, component_id: &ComponentId, event: ClientEvent) -> Result<()> {
-        if let Some(_handler) = self.handlers.get(component_id) {
-            // In a real implementation, this would be called via FFI to the TypeScript layer
-            // For now, we'll just log the event
-            tracing::info!(
-                "Routing event '{}' for component '{}': {:?}",
-                event.event_name,
-                component_id,
-                event.payload
-            );
-            
-            // TODO: Implement actual event handler invocation
-            // This will require calling back into the Node.js/TypeScript layer
-            
-            Ok(())
-        } else {
-            Err(LiveTSError::ComponentNotFound(component_id.clone()))
+    ///
+    /// Returns the new HTML produced by a render handler, or `None` for
+    /// fire-and-forget handlers. With a coalesce window set, the event is held for
+    /// the window and only dispatched if no newer event with the same key arrived
+    /// meanwhile; superseded events resolve to `Ok(None)` without reaching the handler.
+    pub async fn route_event(&self, component_id: &ComponentId, event: ClientEvent) -> Result<Option<String>> {
+        if !self.handlers.contains_key(component_id) && self.default_handler.read().unwrap().is_none() {
+            return Err(LiveTSError::ComponentNotFound(component_id.clone()));
+        }
+
+        let window_ms = self.coalesce_window_ms.load(Ordering::Relaxed);
+        if window_ms == 0 {
+            return self.dispatch(component_id, event).await;
+        }
+
+        let key = (component_id.clone(), event.event_name.clone());
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        self.pending.insert(key.clone(), (generation, event));
+
+        tokio::time::sleep(Duration::from_millis(window_ms)).await;
+
+        match self.pending.remove_if(&key, |_, (queued, _)| *queued == generation) {
+            Some((_, (_, latest))) => self.dispatch(component_id, latest).await,
+            None => {
+                tracing::debug!("Coalesced event '{}' for component '{}'", key.1, component_id);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Invokes the component's handler (or the default handler) with the event
+    async fn dispatch(&self, component_id: &ComponentId, event: ClientEvent) -> Result<Option<String>> {
+        // Clone the handler out so the map isn't locked while it runs
+        let handler = self
+            .handlers
+            .get(component_id)
+            .map(|entry| entry.value().clone())
+            .or_else(|| self.default_handler.read().unwrap().clone())
+            .ok_or_else(|| LiveTSError::ComponentNotFound(component_id.clone()))?;
+
+        tracing::info!(
+            "Routing event '{}' for component '{}': {:?}",
+            event.event_name,
+            component_id,
+            event.payload
+        );
+
+        match &*handler {
+            RegisteredHandler::Notify(handler) => handler(event).map(|_| None),
+            RegisteredHandler::Render(handler) => handler(event).await.map(Some),
         }
     }
 
@@ -92,7 +188,7 @@ impl EventRouter {
     }
 
     /// Processes a batch of events
-    pub async fn process_event_batch(&self, events: Vec<ClientEvent>) -> Result<Vec<Result<()>>> {
+    pub async fn process_event_batch(&self, events: Vec<ClientEvent>) -> Result<Vec<Result<Option<String>>>> {
         let mut results = Vec::new();
         
         for event in events {
@@ -128,6 +224,7 @@ impl Default for EventRouter {
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::sync::Mutex;
 
     #[tokio::test]
     async fn test_event_validation() {
@@ -169,4 +266,103 @@ mod tests {
         let result = router.route_event(&"nonexistent".to_string(), event).await;
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_coalesces_rapid_input_events() {
+        let router = EventRouter::new();
+        let component_id = "search-1".to_string();
+        let dispatched = Arc::new(Mutex::new(Vec::new()));
+        let sink = dispatched.clone();
+
+        router
+            .register_handler(
+                component_id.clone(),
+                Box::new(move |event: ClientEvent| {
+                    let value = event.payload["value"].as_str().unwrap_or_default().to_string();
+                    sink.lock().unwrap().push(value);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        router.set_coalesce_window(20);
+
+        let input = |value: &str| ClientEvent {
+            event_type: "input".to_string(),
+            event_name: "search".to_string(),
+            component_id: "search-1".to_string(),
+            payload: json!({ "value": value }),
+            target: None,
+        };
+
+        let (first, second, third) = tokio::join!(
+            router.route_event(&component_id, input("h")),
+            router.route_event(&component_id, input("he")),
+            router.route_event(&component_id, input("hey")),
+        );
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+
+        assert_eq!(*dispatched.lock().unwrap(), vec!["hey".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_render_handler_returns_new_html() {
+        let router = EventRouter::new();
+        let component_id = "counter-1".to_string();
+
+        // Stands in for a JS handler answering through the threadsafe function
+        router
+            .register_render_handler(
+                component_id.clone(),
+                Box::new(|event: ClientEvent| -> BoxFuture<'static, Result<String>> {
+                    Box::pin(async move {
+                        tokio::task::yield_now().await;
+                        match event.event_name.as_str() {
+                            "increment" => Ok("<div id=\"count\">6</div>".to_string()),
+                            other => Err(LiveTSError::EventRoutingError(format!("no handler for {}", other))),
+                        }
+                    })
+                }),
+            )
+            .unwrap();
+
+        let event = |name: &str| ClientEvent {
+            event_type: "click".to_string(),
+            event_name: name.to_string(),
+            component_id: "counter-1".to_string(),
+            payload: json!({}),
+            target: None,
+        };
+
+        let html = router.route_event(&component_id, event("increment")).await.unwrap();
+        assert_eq!(html.as_deref(), Some("<div id=\"count\">6</div>"));
+
+        let result = router.route_event(&component_id, event("explode")).await;
+        assert!(matches!(result, Err(LiveTSError::EventRoutingError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_default_handler_receives_unhandled_events() {
+        let router = EventRouter::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        router.set_default_handler(Box::new(move |event: ClientEvent| {
+            sink.lock().unwrap().push(event.component_id);
+            Ok(())
+        }));
+
+        let event = ClientEvent {
+            event_type: "click".to_string(),
+            event_name: "increment".to_string(),
+            component_id: "lazy-1".to_string(),
+            payload: json!({}),
+            target: None,
+        };
+        let result = router.route_event(&"lazy-1".to_string(), event.clone()).await;
+        assert!(matches!(result, Ok(None)));
+        assert_eq!(*seen.lock().unwrap(), vec!["lazy-1".to_string()]);
+
+        router.clear_default_handler();
+        let result = router.route_event(&"lazy-1".to_string(), event).await;
+        assert!(matches!(result, Err(LiveTSError::ComponentNotFound(_))));
+    }
+}
@@ -0,0 +1,147 @@
+//! WebSocket handshake negotiation for the broker
+//!
+//! This module decides, from the client's upgrade request, which path and
+//! subprotocol (if any) a connection speaks and whether the upgrade should be accepted.
+
+/// Header a reconnecting client may send its reconnect token in, instead of `?reconnect=`
+pub const RECONNECT_HEADER: &str = "x-livets-reconnect";
+
+/// Gets the reconnect token from an upgrade request's query string or `RECONNECT_HEADER`
+/// The query parameter wins when both are present
+pub fn reconnect_token(query: Option<&str>, header: Option<&str>) -> Option<String> {
+    query
+        .and_then(|query| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("reconnect="))
+        })
+        .or(header)
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+/// Handshake settings applied to every incoming upgrade request
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeConfig {
+    /// Supported subprotocols in server preference order
+    pub subprotocols: Vec<String>,
+    /// Reject clients that don't offer any supported subprotocol
+    pub require_subprotocol: bool,
+    /// Request paths accepted for upgrades (empty accepts any path)
+    pub paths: Vec<String>,
+}
+
+impl HandshakeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether an upgrade to `path` (without the query string) should be accepted
+    pub fn accepts_path(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|accepted| accepted == path)
+    }
+
+    /// Picks the subprotocol for a connection from the client's `Sec-WebSocket-Protocol` header
+    /// Returns `Ok(None)` when no subprotocol is negotiated and `Err` when the upgrade must be rejected
+    pub fn negotiate_subprotocol(&self, offered: Option<&str>) -> std::result::Result<Option<String>, String> {
+        let offered: Vec<&str> = offered
+            .map(|header| {
+                header
+                    .split(',')
+                    .map(|protocol| protocol.trim())
+                    .filter(|protocol| !protocol.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Server preference wins when the client offers several supported protocols
+        let selected = self
+            .subprotocols
+            .iter()
+            .find(|supported| offered.contains(&supported.as_str()))
+            .cloned();
+
+        if selected.is_none() && self.require_subprotocol {
+            return Err(format!(
+                "No acceptable subprotocol offered (supported: {})",
+                self.subprotocols.join(", ")
+            ));
+        }
+
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(required: bool) -> HandshakeConfig {
+        HandshakeConfig {
+            subprotocols: vec!["livets.v2".to_string(), "livets.binary".to_string()],
+            require_subprotocol: required,
+            paths: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_selects_supported_subprotocol() {
+        let config = config(false);
+
+        assert_eq!(
+            config.negotiate_subprotocol(Some("livets.binary")),
+            Ok(Some("livets.binary".to_string()))
+        );
+
+        // Server preference order decides between multiple supported offers
+        assert_eq!(
+            config.negotiate_subprotocol(Some("chat, livets.binary, livets.v2")),
+            Ok(Some("livets.v2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_no_subprotocol_when_optional() {
+        let config = config(false);
+
+        assert_eq!(config.negotiate_subprotocol(None), Ok(None));
+        assert_eq!(config.negotiate_subprotocol(Some("chat")), Ok(None));
+        assert_eq!(HandshakeConfig::new().negotiate_subprotocol(Some("livets.v2")), Ok(None));
+    }
+
+    #[test]
+    fn test_rejects_when_required() {
+        let config = config(true);
+
+        assert!(config.negotiate_subprotocol(None).is_err());
+        assert!(config.negotiate_subprotocol(Some("chat, mqtt")).is_err());
+        assert_eq!(
+            config.negotiate_subprotocol(Some("mqtt, livets.v2")),
+            Ok(Some("livets.v2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_accepts_configured_paths() {
+        assert!(HandshakeConfig::new().accepts_path("/anything"));
+
+        let config = HandshakeConfig {
+            paths: vec!["/livets-ws".to_string(), "/admin-ws".to_string()],
+            ..HandshakeConfig::new()
+        };
+        assert!(config.accepts_path("/livets-ws"));
+        assert!(config.accepts_path("/admin-ws"));
+        assert!(!config.accepts_path("/"));
+        assert!(!config.accepts_path("/livets-ws/extra"));
+    }
+
+    #[test]
+    fn test_reconnect_token_from_query_or_header() {
+        assert_eq!(reconnect_token(Some("v=2&reconnect=abc"), None), Some("abc".to_string()));
+        assert_eq!(reconnect_token(None, Some("def")), Some("def".to_string()));
+        assert_eq!(reconnect_token(Some("reconnect=abc"), Some("def")), Some("abc".to_string()));
+        assert_eq!(reconnect_token(Some("reconnect="), None), None);
+        assert_eq!(reconnect_token(Some("v=2"), None), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Diffing of structured JSON state, for components that sync data instead of HTML
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// One JSON Patch (RFC 6902) operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JsonPatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// Generates minimal JSON Patch operations between two JSON values
+///
+/// Objects are diffed key by key and arrays element by element after trimming their
+/// common prefix and suffix, so an insertion in the middle of a list is a single `add`.
+/// Anything else that changed, including a change of type, is a `replace`.
+#[derive(Debug, Clone, Default)]
+pub struct JsonDiffer;
+
+impl JsonDiffer {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Compares two JSON values; applying the operations in order turns `old` into `new`
+    pub fn diff(&self, old: &Value, new: &Value) -> Vec<JsonPatchOp> {
+        let mut ops = Vec::new();
+        self.diff_value("", old, new, &mut ops);
+        ops
+    }
+
+    fn diff_value(&self, path: &str, old: &Value, new: &Value, ops: &mut Vec<JsonPatchOp>) {
+        match (old, new) {
+            _ if old == new => {}
+            (Value::Object(old_map), Value::Object(new_map)) => {
+                for (key, old_value) in old_map {
+                    let child = child_path(path, key);
+                    match new_map.get(key) {
+                        Some(new_value) => self.diff_value(&child, old_value, new_value, ops),
+                        None => ops.push(JsonPatchOp::Remove { path: child }),
+                    }
+                }
+                for (key, new_value) in new_map {
+                    if !old_map.contains_key(key) {
+                        ops.push(JsonPatchOp::Add { path: child_path(path, key), value: new_value.clone() });
+                    }
+                }
+            }
+            (Value::Array(old_items), Value::Array(new_items)) => self.diff_array(path, old_items, new_items, ops),
+            _ => ops.push(JsonPatchOp::Replace { path: path.to_string(), value: new.clone() }),
+        }
+    }
+
+    /// Diffs the elements between the arrays' common prefix and suffix pairwise, then adds
+    /// or removes the difference in length at the end of that region
+    fn diff_array(&self, path: &str, old: &[Value], new: &[Value], ops: &mut Vec<JsonPatchOp>) {
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let old_middle = &old[prefix..old.len() - suffix];
+        let new_middle = &new[prefix..new.len() - suffix];
+
+        for (offset, (old_item, new_item)) in old_middle.iter().zip(new_middle).enumerate() {
+            self.diff_value(&child_path(path, &(prefix + offset).to_string()), old_item, new_item, ops);
+        }
+        let common = old_middle.len().min(new_middle.len());
+        for (offset, new_item) in new_middle.iter().enumerate().skip(common) {
+            ops.push(JsonPatchOp::Add {
+                path: child_path(path, &(prefix + offset).to_string()),
+                value: new_item.clone(),
+            });
+        }
+        // From the back, so earlier indices stay valid
+        for offset in (common..old_middle.len()).rev() {
+            ops.push(JsonPatchOp::Remove { path: child_path(path, &(prefix + offset).to_string()) });
+        }
+    }
+}
+
+/// Appends a reference token to a JSON Pointer, escaping `~` and `/`
+fn child_path(path: &str, token: &str) -> String {
+    format!("{}/{}", path, token.replace('~', "~0").replace('/', "~1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_nested_object_changes() {
+        let old = json!({"user": {"name": "Ada", "tags": {"admin": true}, "age": 36}, "theme": "dark"});
+        let new = json!({"user": {"name": "Ada", "tags": {"admin": false}, "email": "ada@example.com"}, "theme": 1});
+
+        let ops = JsonDiffer::new().diff(&old, &new);
+        assert_eq!(
+            ops,
+            vec![
+                JsonPatchOp::Replace { path: "/theme".to_string(), value: json!(1) },
+                JsonPatchOp::Remove { path: "/user/age".to_string() },
+                JsonPatchOp::Replace { path: "/user/tags/admin".to_string(), value: json!(false) },
+                JsonPatchOp::Add { path: "/user/email".to_string(), value: json!("ada@example.com") },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_value(&ops[1]).unwrap(),
+            json!({"op": "remove", "path": "/user/age"})
+        );
+        assert!(JsonDiffer::new().diff(&old, &old).is_empty());
+        assert_eq!(
+            JsonDiffer::new().diff(&json!([1]), &json!({"a/b": 1})),
+            vec![JsonPatchOp::Replace { path: String::new(), value: json!({"a/b": 1}) }]
+        );
+        assert_eq!(
+            JsonDiffer::new().diff(&json!({"a/b": 1}), &json!({"a/b": 2})),
+            vec![JsonPatchOp::Replace { path: "/a~1b".to_string(), value: json!(2) }]
+        );
+    }
+
+    #[test]
+    fn test_array_element_insertion_and_removal() {
+        let differ = JsonDiffer::new();
+        let old = json!({"todos": [{"id": 1}, {"id": 2}, {"id": 3}]});
+        let new = json!({"todos": [{"id": 1}, {"id": 9}, {"id": 2}, {"id": 3}]});
+
+        assert_eq!(
+            differ.diff(&old, &new),
+            vec![JsonPatchOp::Add { path: "/todos/1".to_string(), value: json!({"id": 9}) }]
+        );
+        assert_eq!(differ.diff(&new, &old), vec![JsonPatchOp::Remove { path: "/todos/1".to_string() }]);
+
+        // Changed elements are diffed in place, extra ones removed from the back
+        assert_eq!(
+            differ.diff(&json!([1, {"done": false}, 3, 4]), &json!([1, {"done": true}])),
+            vec![
+                JsonPatchOp::Replace { path: "/1/done".to_string(), value: json!(true) },
+                JsonPatchOp::Remove { path: "/3".to_string() },
+                JsonPatchOp::Remove { path: "/2".to_string() },
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Forwarding of the core's `tracing` events to an embedder's logger
+//!
+//! `SinkLayer` turns each event into a `LogRecord` and hands it to a callback, so logs
+//! can flow into the host application's logging pipeline instead of stdout.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// One log event: `{"level":"INFO","target":"livets_core::bindings","message":...,"fields":{...}}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogRecord {
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// The event's structured fields other than the message
+    pub fields: Map<String, Value>,
+}
+
+/// Receives every log record at or above the sink's level
+pub type LogSinkFn = dyn Fn(&LogRecord) + Send + Sync;
+
+/// `tracing` layer delivering events up to `max_level` (e.g. `INFO` includes `WARN`) to a callback
+///
+/// More verbose events are disabled for the whole subscriber the layer is part of, so
+/// their fields are never recorded
+pub struct SinkLayer {
+    sink: Arc<LogSinkFn>,
+    max_level: Level,
+}
+
+impl SinkLayer {
+    pub fn new(sink: Arc<LogSinkFn>, max_level: Level) -> Self {
+        Self { sink, max_level }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SinkLayer {
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.max_level))
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+        (self.sink)(&LogRecord {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        });
+    }
+}
+
+/// Collects an event's message and fields, keeping numbers and booleans typed
+#[derive(Default)]
+struct RecordVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl Visit for RecordVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.insert(field.name().to_string(), Value::from(value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.insert(field.name().to_string(), Value::from(format!("{:?}", value)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_events_reach_the_sink_at_its_level() {
+        let records: Arc<Mutex<Vec<LogRecord>>> = Arc::new(Mutex::new(Vec::new()));
+        let collected = records.clone();
+        let layer = SinkLayer::new(
+            Arc::new(move |record: &LogRecord| collected.lock().unwrap().push(record.clone())),
+            Level::INFO,
+        );
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info!(connection_id = "c1", bytes = 42u64, urgent = true, "sent {} patches", 3);
+            tracing::debug!("below the sink's level");
+            tracing::warn!("slow client");
+        });
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].level, "INFO");
+        assert_eq!(records[0].target, module_path!());
+        assert_eq!(records[0].message, "sent 3 patches");
+        assert_eq!(
+            Value::Object(records[0].fields.clone()),
+            serde_json::json!({"connection_id": "c1", "bytes": 42, "urgent": true})
+        );
+        assert_eq!((records[1].level.as_str(), records[1].message.as_str()), ("WARN", "slow client"));
+    }
+}
@@ -6,6 +6,12 @@
 use crate::types::*;
 use serde_json;
 
+/// Maximum accepted length of an event name, in characters
+pub const MAX_EVENT_NAME_LEN: usize = 64;
+
+/// Form controls whose empty value is meaningful (a cleared field), not absent
+const VALUE_TAGS: &[&str] = &["input", "textarea", "select"];
+
 /// High-performance event parser that handles multiple formats
 pub struct EventParser;
 
@@ -35,6 +41,9 @@ impl EventParser {
         match parse_result {
             EventParseResult::Compact(compact_event) => {
                 let event_name = compact_event.event_name.clone();
+                let has_value = VALUE_TAGS
+                    .iter()
+                    .any(|tag| compact_event.tag_name.eq_ignore_ascii_case(tag));
                 Ok(ParsedEvent {
                     component_id: compact_event.component_id,
                     event_name: event_name.clone(),
@@ -42,8 +51,12 @@ impl EventParser {
                         event_type: event_name,
                         target: EventTarget {
                             tag_name: compact_event.tag_name,
-                            attributes: std::collections::HashMap::new(),
-                            value: if compact_event.value.is_empty() {
+                            attributes: compact_event
+                                .data
+                                .map(|(key, value)| (format!("data-{}", key), value))
+                                .into_iter()
+                                .collect(),
+                            value: if compact_event.value.is_empty() && !has_value {
                                 None
                             } else {
                                 Some(compact_event.value)
@@ -65,8 +78,8 @@ impl EventParser {
         }
     }
 
-    /// Parse ultra-compact event format: "e|shortId|eventName|value|checked|tagName"
-    /// Example: "e|abc123|increment||false|button"
+    /// Parse ultra-compact event format: "e|shortId|eventName|value|checked|tagName[|dataKey:dataVal]"
+    /// Example: "e|abc123|increment||false|button" or "e|abc123|remove||0|button|id:42"
     fn parse_compact_event(&self, raw_message: &str) -> EventParseResult {
         // Remove quotes and split by delimiter
         let content = if raw_message.starts_with('"') && raw_message.ends_with('"') {
@@ -77,9 +90,9 @@ impl EventParser {
 
         let parts: Vec<&str> = content.split('|').collect();
 
-        if parts.len() != 6 || parts[0] != "e" {
+        if !(parts.len() == 6 || parts.len() == 7) || parts[0] != "e" {
             return EventParseResult::Invalid(format!(
-                "Invalid compact event format. Expected 6 parts, got {}: {:?}",
+                "Invalid compact event format. Expected 6 or 7 parts, got {}: {:?}",
                 parts.len(),
                 parts
             ));
@@ -90,6 +103,18 @@ impl EventParser {
         let value = parts[3].to_string();
         let checked = parts[4] == "1" || parts[4].to_lowercase() == "true";
         let tag_name = parts[5].to_string();
+        let data = match parts.get(6) {
+            None => None,
+            Some(field) => match field.split_once(':') {
+                Some((key, value)) if !key.is_empty() => Some((key.to_string(), value.to_string())),
+                _ => {
+                    return EventParseResult::Invalid(format!(
+                        "Invalid compact event data field, expected dataKey:dataVal: {:?}",
+                        field
+                    ))
+                }
+            },
+        };
 
         EventParseResult::Compact(CompactEvent {
             component_id,
@@ -97,6 +122,7 @@ impl EventParser {
             value,
             checked,
             tag_name,
+            data,
         })
     }
 
@@ -152,6 +178,27 @@ impl EventParser {
         })
     }
 
+    /// Parse a client's init message: `"c|init|componentId|html"`
+    ///
+    /// Sent on connect with the HTML the client already shows, so the first event can be
+    /// diffed against it. The HTML runs to the end of the message and may contain `|`
+    pub fn parse_init_message(&self, raw_message: &str) -> Result<(String, String)> {
+        let content = raw_message
+            .strip_prefix('"')
+            .and_then(|content| content.strip_suffix('"'))
+            .unwrap_or(raw_message);
+        let Some(rest) = content.strip_prefix("c|init|") else {
+            return Err(LiveTSError::InvalidInput(format!("Not an init message: {}", raw_message)));
+        };
+        match rest.split_once('|') {
+            Some((component_id, html)) if !component_id.is_empty() => Ok((component_id.to_string(), html.to_string())),
+            _ => Err(LiveTSError::InvalidInput(format!(
+                "Invalid init message, expected c|init|componentId|html: {}",
+                raw_message
+            ))),
+        }
+    }
+
     /// Validate that a parsed event is complete and valid
     pub fn validate_event(&self, event: &ParsedEvent) -> Result<()> {
         if event.component_id.is_empty() {
@@ -166,6 +213,21 @@ impl EventParser {
             ));
         }
 
+        if event.event_name.chars().count() > MAX_EVENT_NAME_LEN {
+            return Err(LiveTSError::InvalidInput(format!(
+                "Event name exceeds {} characters",
+                MAX_EVENT_NAME_LEN
+            )));
+        }
+
+        // Same charset rule as EventRouter::validate_event, so anything parsed here can be routed
+        if !event.event_name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            return Err(LiveTSError::InvalidInput(format!(
+                "Event name contains invalid characters: {}",
+                event.event_name
+            )));
+        }
+
         // Validate component ID format (should be UUID-like)
         if event.component_id.len() < 8 {
             return Err(LiveTSError::InvalidInput(
@@ -226,6 +288,42 @@ mod tests {
         assert_eq!(result.event_data.target.tag_name, "input");
     }
 
+    #[test]
+    fn test_parse_compact_event_empty_value() {
+        let parser = EventParser::new();
+
+        // A cleared text field reports an empty value rather than none
+        let cleared = parser.parse_message(r#""e|xyz98765|input||0|input""#).unwrap();
+        assert_eq!(cleared.event_data.target.value, Some(String::new()));
+
+        let cleared = parser.parse_message(r#""e|xyz98765|input||0|TEXTAREA""#).unwrap();
+        assert_eq!(cleared.event_data.target.value, Some(String::new()));
+
+        // A value doesn't apply to a button click
+        let click = parser.parse_message(r#""e|xyz98765|save||0|button""#).unwrap();
+        assert_eq!(click.event_data.target.value, None);
+    }
+
+    #[test]
+    fn test_parse_compact_event_data_field() {
+        let parser = EventParser::new();
+
+        let remove = parser.parse_message(r#""e|abc12345|remove||0|button|id:42""#).unwrap();
+        assert_eq!(remove.event_data.target.tag_name, "button");
+        assert_eq!(remove.event_data.target.attributes.get("data-id").map(String::as_str), Some("42"));
+
+        // The value may itself contain ':'
+        let link = parser.parse_message(r#""e|abc12345|open||0|a|href-key:https://x.io""#).unwrap();
+        assert_eq!(link.event_data.target.attributes.get("data-href-key").map(String::as_str), Some("https://x.io"));
+
+        // Six-field events still parse, with no attributes
+        let plain = parser.parse_message(r#""e|abc12345|increment||0|button""#).unwrap();
+        assert!(plain.event_data.target.attributes.is_empty());
+
+        assert!(parser.parse_message(r#""e|abc12345|remove||0|button|42""#).is_err());
+        assert!(parser.parse_message(r#""e|abc12345|remove||0|button|id:1|extra""#).is_err());
+    }
+
     #[test]
     fn test_parse_json_event() {
         let parser = EventParser::new();
@@ -274,10 +372,58 @@ mod tests {
         assert!(parser.validate_event(&invalid_event).is_err());
     }
 
+    fn event_named(event_name: &str) -> ParsedEvent {
+        ParsedEvent {
+            component_id: "valid-uuid-123".to_string(),
+            event_name: event_name.to_string(),
+            event_data: EventData {
+                event_type: "click".to_string(),
+                target: EventTarget::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_validate_event_name_length() {
+        let parser = EventParser::new();
+
+        assert!(parser.validate_event(&event_named(&"a".repeat(MAX_EVENT_NAME_LEN))).is_ok());
+
+        let result = parser.validate_event(&event_named(&"a".repeat(MAX_EVENT_NAME_LEN + 1)));
+        assert!(result.unwrap_err().to_string().contains("exceeds"));
+
+        // The limit counts characters, not UTF-8 bytes
+        assert!(parser.validate_event(&event_named(&"é".repeat(MAX_EVENT_NAME_LEN))).is_ok());
+    }
+
+    #[test]
+    fn test_validate_event_name_charset() {
+        let parser = EventParser::new();
+
+        assert!(parser.validate_event(&event_named("toggle_item-2")).is_ok());
+
+        let result = parser.validate_event(&event_named("add item"));
+        assert!(result.unwrap_err().to_string().contains("invalid characters"));
+    }
+
     #[test]
     fn test_ping_message() {
         let parser = EventParser::new();
         let result = parser.parse_message("\"p\"");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_init_message() {
+        let parser = EventParser::new();
+        let (component_id, html) = parser
+            .parse_init_message(r#""c|init|abc12345|<div data-livets-id="abc12345"><p title="a|b">5</p></div>""#)
+            .unwrap();
+        assert_eq!(component_id, "abc12345");
+        assert_eq!(html, r#"<div data-livets-id="abc12345"><p title="a|b">5</p></div>"#);
+
+        assert!(parser.parse_init_message(r#""c|init|abc12345""#).is_err());
+        assert!(parser.parse_init_message(r#""c|init||<p>5</p>""#).is_err());
+        assert!(parser.parse_init_message(r#""e|abc12345|increment||0|button""#).is_err());
+    }
 }
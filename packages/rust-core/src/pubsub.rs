@@ -1,9 +1,16 @@
 //! High-performance pub/sub messaging system for real-time communication between components
 
+use crate::clock::{Clock, SystemClock};
 use crate::types::*;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use serde::Serialize;
 use std::collections::HashSet;
-use tokio::sync::broadcast;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
 
 /// Pub/Sub system for real-time messaging between components
 pub struct PubSubSystem {
@@ -11,6 +18,47 @@ pub struct PubSubSystem {
     component_channels: DashMap<ComponentId, HashSet<ChannelId>>,
     // Broadcast channels for real-time messaging
     channels: DashMap<ChannelId, broadcast::Sender<String>>,
+    // Last message broadcast on each channel, for subscribers that arrive later
+    retained: Arc<DashMap<ChannelId, String>>,
+    // Channels `broadcast_create` opened that nobody has listened to yet
+    unclaimed: DashSet<ChannelId>,
+    // Rate limits set with `set_channel_throttle`
+    throttles: Arc<DashMap<ChannelId, ChannelThrottle>>,
+    // Forwarding tasks of `subscribe_forwarding` subscriptions
+    forwarders: DashMap<(ChannelId, ComponentId), SubscriptionGuard>,
+    live_forwarders: Arc<AtomicUsize>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Aborts a subscription's forwarding task when dropped
+#[derive(Debug)]
+struct SubscriptionGuard {
+    task: JoinHandle<()>,
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Counts a forwarding task as live until its future is dropped, by finishing or being aborted
+struct LiveTask(Arc<AtomicUsize>);
+
+impl Drop for LiveTask {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Rate limit state of a throttled channel
+#[derive(Debug)]
+struct ChannelThrottle {
+    min_interval_ms: u64,
+    last_sent_ms: Option<u64>,
+    /// Latest message held back until the interval has passed
+    pending: Option<String>,
+    flush_scheduled: bool,
 }
 
 impl PubSubSystem {
@@ -19,11 +67,23 @@ impl PubSubSystem {
             subscribers: DashMap::new(),
             component_channels: DashMap::new(),
             channels: DashMap::new(),
+            retained: Arc::new(DashMap::new()),
+            unclaimed: DashSet::new(),
+            throttles: Arc::new(DashMap::new()),
+            forwarders: DashMap::new(),
+            live_forwarders: Arc::new(AtomicUsize::new(0)),
+            clock: Arc::new(SystemClock::new()),
         }
     }
 
+    /// Reads throttle times from `clock` instead of the system clock, e.g. a `MockClock` in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     /// Subscribes a component to a channel
-    pub async fn subscribe(&mut self, channel: &ChannelId, component_id: &ComponentId) -> Result<()> {
+    pub async fn subscribe(&self, channel: &ChannelId, component_id: &ComponentId) -> Result<()> {
         // Add component to channel subscribers
         self.subscribers
             .entry(channel.clone())
@@ -36,18 +96,59 @@ impl PubSubSystem {
             .or_insert_with(HashSet::new)
             .insert(channel.clone());
 
-        // Create broadcast channel if it doesn't exist
-        if !self.channels.contains_key(channel) {
-            let (tx, _) = broadcast::channel(1000); // Buffer size of 1000 messages
-            self.channels.insert(channel.clone(), tx);
-        }
+        self.ensure_channel(channel);
+        self.unclaimed.remove(channel);
 
         tracing::debug!("Component {} subscribed to channel {}", component_id, channel);
         Ok(())
     }
 
+    /// Subscribes a component and forwards the channel's messages to `sink`, e.g. the
+    /// sender of the connection showing the component
+    ///
+    /// The forwarding task runs until the component unsubscribes (or is unsubscribed from
+    /// everything when its connection closes), the channel goes away or `sink` is closed.
+    /// Subscribing again replaces the previous task
+    pub async fn subscribe_forwarding(
+        &self,
+        channel: &ChannelId,
+        component_id: &ComponentId,
+        sink: UnboundedSender<String>,
+    ) -> Result<()> {
+        self.subscribe(channel, component_id).await?;
+        let mut receiver = self
+            .create_receiver(channel)
+            .ok_or_else(|| LiveTSError::PubSubError(format!("channel {} is gone", channel)))?;
+
+        self.live_forwarders.fetch_add(1, Ordering::Relaxed);
+        let live = LiveTask(self.live_forwarders.clone());
+        let task = tokio::spawn(async move {
+            let _live = live;
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        if sink.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => tracing::warn!("subscriber lagged, {} messages skipped", skipped),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        self.forwarders.insert((channel.clone(), component_id.clone()), SubscriptionGuard { task });
+        Ok(())
+    }
+
+    /// Number of forwarding tasks that haven't finished or been cancelled yet
+    pub fn forwarding_task_count(&self) -> usize {
+        self.live_forwarders.load(Ordering::Relaxed)
+    }
+
     /// Unsubscribes a component from a channel
-    pub async fn unsubscribe(&mut self, channel: &ChannelId, component_id: &ComponentId) -> Result<()> {
+    pub async fn unsubscribe(&self, channel: &ChannelId, component_id: &ComponentId) -> Result<()> {
+        self.forwarders.remove(&(channel.clone(), component_id.clone()));
+
         // Remove component from channel subscribers
         if let Some(mut subscribers) = self.subscribers.get_mut(channel) {
             subscribers.remove(component_id);
@@ -56,7 +157,7 @@ impl PubSubSystem {
             if subscribers.is_empty() {
                 drop(subscribers);
                 self.subscribers.remove(channel);
-                self.channels.remove(channel);
+                self.remove_channel(channel);
             }
         }
 
@@ -76,9 +177,17 @@ impl PubSubSystem {
     }
 
     /// Unsubscribes a component from all channels (cleanup)
-    pub async fn unsubscribe_all(&mut self, component_id: &ComponentId) -> Result<()> {
+    pub async fn unsubscribe_all(&self, component_id: &ComponentId) -> Result<()> {
+        self.release_component(component_id);
+        Ok(())
+    }
+
+    /// `unsubscribe_all` for callers that can't await, e.g. a connection being removed
+    /// Drops the component's forwarding tasks along with its subscriptions
+    pub fn release_component(&self, component_id: &ComponentId) {
         if let Some((_, channels)) = self.component_channels.remove(component_id) {
             for channel in channels {
+                self.forwarders.remove(&(channel.clone(), component_id.clone()));
                 if let Some(mut subscribers) = self.subscribers.get_mut(&channel) {
                     subscribers.remove(component_id);
 
@@ -86,30 +195,183 @@ impl PubSubSystem {
                     if subscribers.is_empty() {
                         drop(subscribers);
                         self.subscribers.remove(&channel);
-                        self.channels.remove(&channel);
+                        self.remove_channel(&channel);
                     }
                 }
             }
         }
 
         tracing::debug!("Component {} unsubscribed from all channels", component_id);
-        Ok(())
+    }
+
+    /// Creates the broadcast channel if it doesn't exist
+    fn ensure_channel(&self, channel: &ChannelId) {
+        self.channels.entry(channel.clone()).or_insert_with(|| {
+            let (tx, _) = broadcast::channel(1000); // Buffer size of 1000 messages
+            tx
+        });
+    }
+
+    fn remove_channel(&self, channel: &ChannelId) {
+        self.channels.remove(channel);
+        self.retained.remove(channel);
+        self.unclaimed.remove(channel);
+        // The throttle setting outlives the channel, but nobody is left to flush to
+        if let Some(mut throttle) = self.throttles.get_mut(channel) {
+            throttle.pending = None;
+        }
+    }
+
+    /// Removes channels nobody listens to: no receivers left (e.g. every subscriber's task
+    /// crashed without unsubscribing) and no subscribed components
+    ///
+    /// Their retained messages go with them. Channels `broadcast_create` opened are kept until
+    /// someone has listened, so a late subscriber still finds the retained message.
+    /// Returns the number of channels removed
+    pub fn cleanup_empty_channels(&self) -> usize {
+        let orphaned: Vec<ChannelId> = self
+            .channels
+            .iter()
+            .filter(|entry| entry.value().receiver_count() == 0 && !self.unclaimed.contains(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut removed = 0;
+        for channel in orphaned {
+            // Re-checked under the entry lock, in case someone subscribed since the scan
+            let gone = self.channels.remove_if(&channel, |_, sender| {
+                sender.receiver_count() == 0
+                    && !self.subscribers.contains_key(&channel)
+                    && !self.unclaimed.contains(&channel)
+            });
+            if gone.is_some() {
+                self.remove_channel(&channel);
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            tracing::debug!("Removed {} empty channels", removed);
+        }
+        removed
+    }
+
+    /// Spawns a task running `cleanup_empty_channels` every `every`
+    /// The task stops once the system is dropped. Must be called from within a tokio runtime
+    pub fn spawn_channel_janitor(self: &Arc<Self>, every: Duration) -> JoinHandle<()> {
+        let system = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(system) = system.upgrade() else { break };
+                system.cleanup_empty_channels();
+            }
+        })
+    }
+
+    /// Limits a channel to one delivered message per `min_interval_ms` (0 removes the limit)
+    ///
+    /// Broadcasts within the interval are coalesced: only the latest is delivered, once
+    /// the interval has passed. For high-frequency channels such as telemetry. Removing
+    /// the limit delivers the message it was holding back right away
+    pub fn set_channel_throttle(&self, channel: &ChannelId, min_interval_ms: u64) {
+        if min_interval_ms == 0 {
+            let pending = self.throttles.remove(channel).and_then(|(_, throttle)| throttle.pending);
+            if let (Some(message), Some(sender)) = (pending, self.channels.get(channel)) {
+                self.retained.insert(channel.clone(), message.clone());
+                let _ = sender.send(message);
+            }
+            return;
+        }
+        self.throttles
+            .entry(channel.clone())
+            .and_modify(|throttle| throttle.min_interval_ms = min_interval_ms)
+            .or_insert_with(|| ChannelThrottle {
+                min_interval_ms,
+                last_sent_ms: None,
+                pending: None,
+                flush_scheduled: false,
+            });
+    }
+
+    /// Sends a message now, or holds it as the channel's latest when throttled
+    /// Returns the number of receivers reached, `None` when the message was held back
+    fn send_or_hold(&self, channel: &ChannelId, sender: &broadcast::Sender<String>, message: String) -> Option<usize> {
+        if let Some(mut throttle) = self.throttles.get_mut(channel) {
+            let now = self.clock.now_ms();
+            let due = throttle.last_sent_ms.map(|sent| sent + throttle.min_interval_ms);
+            if let Some(due) = due.filter(|due| *due > now) {
+                throttle.pending = Some(message);
+                if !throttle.flush_scheduled {
+                    throttle.flush_scheduled = true;
+                    self.schedule_flush(channel.clone(), sender.clone(), Duration::from_millis(due - now));
+                }
+                return None;
+            }
+            throttle.last_sent_ms = Some(now);
+            throttle.pending = None;
+        }
+
+        self.retained.insert(channel.clone(), message.clone());
+        Some(sender.send(message).unwrap_or(0))
+    }
+
+    /// Delivers a throttled channel's pending message once its interval has passed
+    fn schedule_flush(&self, channel: ChannelId, sender: broadcast::Sender<String>, wait: Duration) {
+        let throttles = self.throttles.clone();
+        let retained = self.retained.clone();
+        let clock = self.clock.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            let pending = throttles.get_mut(&channel).and_then(|mut throttle| {
+                throttle.flush_scheduled = false;
+                let pending = throttle.pending.take();
+                if pending.is_some() {
+                    throttle.last_sent_ms = Some(clock.now_ms());
+                }
+                pending
+            });
+            if let Some(message) = pending {
+                retained.insert(channel, message.clone());
+                let _ = sender.send(message);
+            }
+        });
+    }
+
+    /// Broadcasts a message, creating the channel first if nobody has subscribed yet
+    /// The message is retained, so a later subscriber can still read it with `retained_message`
+    pub async fn broadcast_create(&self, channel: &ChannelId, message: String) -> Result<()> {
+        if !self.channels.contains_key(channel) {
+            self.unclaimed.insert(channel.clone());
+        }
+        self.ensure_channel(channel);
+        self.broadcast(channel, message).await
+    }
+
+    /// Gets the last message broadcast on a channel
+    pub fn retained_message(&self, channel: &ChannelId) -> Option<String> {
+        self.retained.get(channel).map(|message| message.clone())
     }
 
     /// Broadcasts a message to all subscribers of a channel
+    /// On a throttled channel the message may be coalesced, see `set_channel_throttle`
     pub async fn broadcast(&self, channel: &ChannelId, message: String) -> Result<()> {
         if let Some(sender) = self.channels.get(channel) {
-            match sender.send(message.clone()) {
-                Ok(subscriber_count) => {
+            match self.send_or_hold(channel, &sender, message) {
+                Some(0) => {
+                    // No active receivers
+                    tracing::debug!("No active receivers for channel {}", channel);
+                }
+                Some(subscriber_count) => {
                     tracing::debug!(
                         "Broadcasted message to {} subscribers on channel {}",
                         subscriber_count,
                         channel
                     );
                 }
-                Err(_) => {
-                    // No active receivers
-                    tracing::debug!("No active receivers for channel {}", channel);
+                None => {
+                    tracing::trace!("Throttled message on channel {}", channel);
                 }
             }
         } else {
@@ -119,6 +381,27 @@ impl PubSubSystem {
         Ok(())
     }
 
+    /// Broadcasts a batch of messages, e.g. one simulation tick's output, in a single pass
+    ///
+    /// Messages to channels that don't exist are skipped and throttled channels coalesce,
+    /// as with `broadcast`. Returns the total number of receivers reached right away
+    pub async fn broadcast_many(&self, messages: Vec<(ChannelId, String)>) -> Result<usize> {
+        let mut delivered = 0;
+        let mut missing = 0;
+        for (channel, message) in messages {
+            let Some(sender) = self.channels.get(&channel) else {
+                missing += 1;
+                continue;
+            };
+            delivered += self.send_or_hold(&channel, &sender, message).unwrap_or(0);
+        }
+        if missing > 0 {
+            tracing::warn!("Skipped {} batched broadcasts to non-existent channels", missing);
+        }
+
+        Ok(delivered)
+    }
+
     /// Gets all subscribers for a channel
     pub fn get_subscribers(&self, channel: &ChannelId) -> Vec<ComponentId> {
         self.subscribers
@@ -157,12 +440,45 @@ impl PubSubSystem {
             .unwrap_or(false)
     }
 
+    /// Lists every channel as JSON `[{"channel","subscribers","buffered_messages"}, ...]`, sorted by name
+    /// `buffered_messages` counts the retained message a late subscriber would read (0 or 1)
+    pub fn list_channels(&self) -> String {
+        let mut channels: Vec<ChannelInfo> = self
+            .channels
+            .iter()
+            .map(|entry| ChannelInfo {
+                channel: entry.key().clone(),
+                subscribers: self.subscribers.get(entry.key()).map(|s| s.len()).unwrap_or(0),
+                buffered_messages: usize::from(self.retained.contains_key(entry.key())),
+            })
+            .collect();
+        channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+        serde_json::to_string(&channels).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    /// Lists the component ids subscribed to a channel as a sorted JSON array
+    pub fn inspect_channel(&self, channel: &ChannelId) -> String {
+        let mut subscribers = self.get_subscribers(channel);
+        subscribers.sort();
+        serde_json::to_string(&subscribers).unwrap_or_else(|_| "[]".to_string())
+    }
+
     /// Creates a new receiver for a channel (for listening to messages)
     pub fn create_receiver(&self, channel: &ChannelId) -> Option<broadcast::Receiver<String>> {
-        self.channels.get(channel).map(|sender| sender.subscribe())
+        let receiver = self.channels.get(channel).map(|sender| sender.subscribe())?;
+        self.unclaimed.remove(channel);
+        Some(receiver)
     }
 }
 
+/// One entry of `list_channels`
+#[derive(Debug, Clone, Serialize)]
+struct ChannelInfo {
+    channel: ChannelId,
+    subscribers: usize,
+    buffered_messages: usize,
+}
+
 /// Statistics about the pub/sub system
 #[derive(Debug, Clone)]
 pub struct PubSubStats {
@@ -180,10 +496,11 @@ impl Default for PubSubSystem {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[tokio::test]
     async fn test_subscribe_and_broadcast() {
-        let mut pubsub = PubSubSystem::new();
+        let pubsub = PubSubSystem::new();
         let channel = "test-channel".to_string();
         let component = "test-component".to_string();
 
@@ -203,7 +520,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_unsubscribe() {
-        let mut pubsub = PubSubSystem::new();
+        let pubsub = PubSubSystem::new();
         let channel = "test-channel".to_string();
         let component = "test-component".to_string();
 
@@ -217,7 +534,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_unsubscribe_all() {
-        let mut pubsub = PubSubSystem::new();
+        let pubsub = PubSubSystem::new();
         let component = "test-component".to_string();
 
         // Subscribe to multiple channels
@@ -230,4 +547,180 @@ mod tests {
         assert_eq!(pubsub.get_component_channels(&component).len(), 0);
         assert_eq!(pubsub.get_stats().active_components, 0);
     }
+
+    #[tokio::test]
+    async fn test_broadcast_create_before_subscribe() {
+        let pubsub = PubSubSystem::new();
+        let channel = "prices".to_string();
+        let component = "ticker".to_string();
+
+        // Plain broadcast to a channel nobody created is dropped
+        pubsub.broadcast(&channel, "99".to_string()).await.unwrap();
+        assert!(!pubsub.channel_exists(&channel));
+        assert_eq!(pubsub.retained_message(&channel), None);
+
+        pubsub.broadcast_create(&channel, "100".to_string()).await.unwrap();
+        assert!(pubsub.channel_exists(&channel));
+
+        // A late subscriber reads the retained value, then gets live messages
+        pubsub.subscribe(&channel, &component).await.unwrap();
+        assert_eq!(pubsub.retained_message(&channel), Some("100".to_string()));
+        let mut receiver = pubsub.create_receiver(&channel).unwrap();
+        pubsub.broadcast(&channel, "101".to_string()).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "101");
+        assert_eq!(pubsub.retained_message(&channel), Some("101".to_string()));
+
+        // Retained values go away with the channel
+        pubsub.unsubscribe(&channel, &component).await.unwrap();
+        assert_eq!(pubsub.retained_message(&channel), None);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_inspect_channels() {
+        let pubsub = PubSubSystem::new();
+        let (news, scores) = ("news".to_string(), "scores".to_string());
+        pubsub.subscribe(&scores, &"board".to_string()).await.unwrap();
+        pubsub.subscribe(&news, &"ticker".to_string()).await.unwrap();
+        pubsub.subscribe(&news, &"feed".to_string()).await.unwrap();
+        pubsub.broadcast(&news, "hello".to_string()).await.unwrap();
+        pubsub.broadcast_create(&"alerts".to_string(), "fire".to_string()).await.unwrap();
+
+        let listing: serde_json::Value = serde_json::from_str(&pubsub.list_channels()).unwrap();
+        assert_eq!(
+            listing,
+            serde_json::json!([
+                { "channel": "alerts", "subscribers": 0, "buffered_messages": 1 },
+                { "channel": "news", "subscribers": 2, "buffered_messages": 1 },
+                { "channel": "scores", "subscribers": 1, "buffered_messages": 0 },
+            ])
+        );
+
+        assert_eq!(pubsub.inspect_channel(&news), r#"["feed","ticker"]"#);
+        assert_eq!(pubsub.inspect_channel(&"missing".to_string()), "[]");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_many() {
+        let pubsub = PubSubSystem::new();
+        let channels: Vec<ChannelId> = (0..3).map(|i| format!("room-{}", i)).collect();
+        let mut receivers = Vec::new();
+        for channel in &channels {
+            pubsub.subscribe(channel, &"sim".to_string()).await.unwrap();
+            receivers.push(pubsub.create_receiver(channel).unwrap());
+        }
+
+        let mut batch: Vec<(ChannelId, String)> = channels
+            .iter()
+            .map(|channel| (channel.clone(), format!("tick for {}", channel)))
+            .collect();
+        batch.push(("nowhere".to_string(), "dropped".to_string()));
+        assert_eq!(pubsub.broadcast_many(batch).await.unwrap(), 3);
+
+        for (channel, receiver) in channels.iter().zip(receivers.iter_mut()) {
+            assert_eq!(receiver.recv().await.unwrap(), format!("tick for {}", channel));
+            assert_eq!(pubsub.retained_message(channel), Some(format!("tick for {}", channel)));
+        }
+        assert!(!pubsub.channel_exists(&"nowhere".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_channel_throttle_coalesces_to_latest() {
+        let clock = Arc::new(MockClock::new(1_000));
+        let pubsub = PubSubSystem::new().with_clock(clock.clone());
+        let channel = "telemetry".to_string();
+        pubsub.subscribe(&channel, &"gauge".to_string()).await.unwrap();
+        pubsub.set_channel_throttle(&channel, 50);
+        let mut receiver = pubsub.create_receiver(&channel).unwrap();
+
+        for i in 0..10 {
+            pubsub.broadcast(&channel, format!("reading {}", i)).await.unwrap();
+        }
+        // The first goes out at once, the rest are held back as the latest
+        assert_eq!(receiver.try_recv().unwrap(), "reading 0");
+        assert!(receiver.try_recv().is_err());
+
+        // Once the interval has passed, the next broadcast replaces the held one
+        clock.advance(50);
+        pubsub.broadcast(&channel, "reading 10".to_string()).await.unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), "reading 10");
+        assert_eq!(pubsub.retained_message(&channel), Some("reading 10".to_string()));
+
+        // Removing the throttle delivers what it was holding
+        pubsub.broadcast(&channel, "reading 11".to_string()).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        pubsub.set_channel_throttle(&channel, 0);
+        assert_eq!(receiver.try_recv().unwrap(), "reading 11");
+        assert_eq!(pubsub.retained_message(&channel), Some("reading 11".to_string()));
+
+        pubsub.broadcast(&channel, "reading 12".to_string()).await.unwrap();
+        pubsub.broadcast(&channel, "reading 13".to_string()).await.unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), "reading 12");
+        assert_eq!(receiver.try_recv().unwrap(), "reading 13");
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_cancels_forwarding_tasks() {
+        let pubsub = PubSubSystem::new();
+        let channel = "presence".to_string();
+        // Keeps the channel open, so only the guards can stop the tasks
+        pubsub.subscribe(&channel, &"sidebar".to_string()).await.unwrap();
+
+        let (sink, mut received) = tokio::sync::mpsc::unbounded_channel();
+        for round in 0..200 {
+            let component = format!("avatar-{}", round % 3);
+            pubsub.subscribe_forwarding(&channel, &component, sink.clone()).await.unwrap();
+            pubsub.unsubscribe(&channel, &component).await.unwrap();
+        }
+        let component = "avatar-live".to_string();
+        pubsub.subscribe_forwarding(&channel, &component, sink.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pubsub.forwarding_task_count(), 1);
+
+        pubsub.broadcast(&channel, "online".to_string()).await.unwrap();
+        assert_eq!(received.recv().await.unwrap(), "online");
+
+        pubsub.unsubscribe_all(&component).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pubsub.forwarding_task_count(), 0);
+        assert!(pubsub.channel_exists(&channel));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_removes_channels_without_receivers() {
+        let pubsub = PubSubSystem::new();
+        let orphan = "crashed".to_string();
+        pubsub.broadcast_create(&orphan, "last words".to_string()).await.unwrap();
+        let receiver = pubsub.create_receiver(&orphan).unwrap();
+        let subscribed = "chat".to_string();
+        pubsub.subscribe(&subscribed, &"room".to_string()).await.unwrap();
+
+        // A live receiver keeps the channel
+        assert_eq!(pubsub.cleanup_empty_channels(), 0);
+        drop(receiver);
+
+        // Subscribed components keep theirs even without receivers
+        assert_eq!(pubsub.cleanup_empty_channels(), 1);
+        assert!(!pubsub.channel_exists(&orphan));
+        assert_eq!(pubsub.retained_message(&orphan), None);
+        assert!(pubsub.channel_exists(&subscribed));
+
+        let pubsub = Arc::new(pubsub);
+        pubsub.broadcast_create(&orphan, "again".to_string()).await.unwrap();
+        drop(pubsub.create_receiver(&orphan));
+        let janitor = pubsub.spawn_channel_janitor(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pubsub.channel_exists(&orphan));
+        janitor.abort();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_keeps_retained_channels_until_someone_listens() {
+        let pubsub = PubSubSystem::new();
+        let channel = "announcements".to_string();
+        pubsub.broadcast_create(&channel, "welcome".to_string()).await.unwrap();
+
+        assert_eq!(pubsub.cleanup_empty_channels(), 0);
+        pubsub.subscribe(&channel, &"banner".to_string()).await.unwrap();
+        assert_eq!(pubsub.retained_message(&channel), Some("welcome".to_string()));
+    }
 }
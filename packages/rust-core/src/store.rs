@@ -0,0 +1,138 @@
+//! Pluggable backends for sharing connection state across broker instances
+//!
+//! A single broker keeps everything in process. To run several brokers behind a
+//! load balancer, the component -> connection mapping can be moved into a shared
+//! store and component broadcasts fanned out over a cross-instance bus (e.g. Redis
+//! pub/sub), so a broadcast on one instance reaches connections held by another.
+
+use crate::types::*;
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+
+/// Storage for the component -> connection mapping
+///
+/// Implementations must be cheap to call from synchronous code; remote stores
+/// are expected to keep a local view and sync it in the background.
+pub trait ComponentRegistry: Send + Sync {
+    /// Associates a connection with a component
+    fn add(&self, component_id: &ComponentId, conn_id: &ConnectionId);
+
+    /// Removes a connection from a component, dropping the component once empty
+    fn remove(&self, component_id: &ComponentId, conn_id: &ConnectionId);
+
+    /// Gets all connections (on any instance) registered for a component
+    fn connections_for(&self, component_id: &ComponentId) -> Vec<ConnectionId>;
+
+    /// Gets the number of components with at least one connection
+    fn component_count(&self) -> usize;
+
+    /// Gets a snapshot of every component and its connections
+    fn entries(&self) -> Vec<(ComponentId, Vec<ConnectionId>)>;
+}
+
+/// Default in-process registry backed by a `DashMap`
+pub struct LocalComponentRegistry {
+    component_to_connections: DashMap<ComponentId, Vec<ConnectionId>>,
+}
+
+impl LocalComponentRegistry {
+    pub fn new() -> Self {
+        Self {
+            component_to_connections: DashMap::new(),
+        }
+    }
+}
+
+impl ComponentRegistry for LocalComponentRegistry {
+    fn add(&self, component_id: &ComponentId, conn_id: &ConnectionId) {
+        let mut connections = self
+            .component_to_connections
+            .entry(component_id.clone())
+            .or_default();
+        if !connections.contains(conn_id) {
+            connections.push(conn_id.clone());
+        }
+    }
+
+    fn remove(&self, component_id: &ComponentId, conn_id: &ConnectionId) {
+        if let Some(mut connections) = self.component_to_connections.get_mut(component_id) {
+            connections.retain(|id| id != conn_id);
+            if connections.is_empty() {
+                drop(connections);
+                self.component_to_connections.remove(component_id);
+            }
+        }
+    }
+
+    fn connections_for(&self, component_id: &ComponentId) -> Vec<ConnectionId> {
+        self.component_to_connections
+            .get(component_id)
+            .map(|connections| connections.clone())
+            .unwrap_or_default()
+    }
+
+    fn component_count(&self) -> usize {
+        self.component_to_connections.len()
+    }
+
+    fn entries(&self) -> Vec<(ComponentId, Vec<ConnectionId>)> {
+        self.component_to_connections
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+}
+
+impl Default for LocalComponentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A component broadcast travelling between broker instances
+#[derive(Debug, Clone)]
+pub struct BusMessage {
+    /// Instance that published the message (used to skip our own echoes)
+    pub origin: String,
+    pub component_id: ComponentId,
+    pub data: String,
+}
+
+/// Cross-instance channel used to fan component broadcasts out to other brokers
+pub trait BroadcastBus: Send + Sync {
+    /// Publishes a message to every instance, including the sender
+    fn publish(&self, message: BusMessage) -> Result<()>;
+
+    /// Creates a receiver for messages published by any instance
+    fn subscribe(&self) -> broadcast::Receiver<BusMessage>;
+}
+
+/// In-process bus, useful for tests and for several brokers sharing one process
+pub struct InMemoryBus {
+    sender: broadcast::Sender<BusMessage>,
+}
+
+impl InMemoryBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+}
+
+impl BroadcastBus for InMemoryBus {
+    fn publish(&self, message: BusMessage) -> Result<()> {
+        // No subscribers just means no other instance is listening yet
+        let _ = self.sender.send(message);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<BusMessage> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for InMemoryBus {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
@@ -0,0 +1,105 @@
+//! Opaque short tokens standing in for component ids on the wire
+
+use crate::types::*;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bidirectional map assigning each component id a compact token (`c1`, `c2`, ...)
+///
+/// Tokens are never reused, so a stale token from a removed component can't resolve
+/// to a different component later.
+pub struct ComponentTokenMap {
+    next: AtomicU64,
+    by_id: DashMap<ComponentId, String>,
+    by_token: DashMap<String, ComponentId>,
+}
+
+impl ComponentTokenMap {
+    pub fn new() -> Self {
+        Self {
+            next: AtomicU64::new(1),
+            by_id: DashMap::new(),
+            by_token: DashMap::new(),
+        }
+    }
+
+    /// Gets the component's token, assigning the next one if it has none
+    pub fn assign(&self, component_id: &str) -> String {
+        self.by_id
+            .entry(component_id.to_string())
+            .or_insert_with(|| {
+                let token = format!("c{}", self.next.fetch_add(1, Ordering::Relaxed));
+                self.by_token.insert(token.clone(), component_id.to_string());
+                token
+            })
+            .clone()
+    }
+
+    /// Gets the component's token without assigning one
+    pub fn token(&self, component_id: &str) -> Option<String> {
+        self.by_id.get(component_id).map(|token| token.clone())
+    }
+
+    /// Gets the component id a token was assigned to
+    pub fn resolve(&self, token: &str) -> Option<ComponentId> {
+        self.by_token.get(token).map(|id| id.clone())
+    }
+
+    /// Forgets a component's token
+    pub fn release(&self, component_id: &str) -> Option<String> {
+        let (_, token) = self.by_id.remove(component_id)?;
+        self.by_token.remove(&token);
+        Some(token)
+    }
+
+    /// Gets the number of components with a token
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Checks if no component has a token
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
+impl Default for ComponentTokenMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign_lookup_and_reverse() {
+        let tokens = ComponentTokenMap::new();
+        let first = "3f2b9c1e-0000-4000-8000-000000000001";
+        let second = "3f2b9c1e-0000-4000-8000-000000000002";
+
+        assert_eq!(tokens.assign(first), "c1");
+        assert_eq!(tokens.assign(second), "c2");
+        // Assigning again returns the existing token
+        assert_eq!(tokens.assign(first), "c1");
+        assert_eq!(tokens.len(), 2);
+
+        assert_eq!(tokens.token(second), Some("c2".to_string()));
+        assert_eq!(tokens.token("unknown"), None);
+        assert_eq!(tokens.resolve("c1"), Some(first.to_string()));
+        assert_eq!(tokens.resolve("c9"), None);
+    }
+
+    #[test]
+    fn test_released_tokens_are_not_reused() {
+        let tokens = ComponentTokenMap::new();
+        tokens.assign("a");
+        assert_eq!(tokens.release("a"), Some("c1".to_string()));
+        assert_eq!(tokens.resolve("c1"), None);
+        assert!(tokens.is_empty());
+
+        assert_eq!(tokens.assign("b"), "c2");
+        assert_eq!(tokens.resolve("c1"), None);
+    }
+}
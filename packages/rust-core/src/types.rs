@@ -1,7 +1,10 @@
 //! Type definitions for the LiveTS core engine
 
 use serde::{Deserialize, Serialize};
+use crate::differ::ParsedDocument;
 use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
 
 /// Unique identifier for a WebSocket connection
 pub type ConnectionId = String;
@@ -30,6 +33,8 @@ pub struct CompactEvent {
     pub value: String,
     pub checked: bool,
     pub tag_name: String,
+    /// Optional 7th field: one `data-*` attribute of the target as `(key, value)`, key without `data-`
+    pub data: Option<(String, String)>,
 }
 
 /// Parsed event ready for processing
@@ -60,7 +65,13 @@ pub enum EventParseResult {
 pub struct CachedComponent {
     pub component_id: String,
     pub current_html: String,
+    /// Parsed form of `current_html`, when the caller stored one
+    pub parsed: Option<Arc<ParsedDocument>>,
+    /// `content_hash` of `current_html`, so unchanged renders can skip the diff
+    pub content_hash: u64,
     pub last_updated: u64,
+    /// How many times `get_html` has read the component, kept across HTML updates
+    pub access_count: Arc<AtomicU64>,
 }
 
 /// Event processing request for TypeScript callback
@@ -89,7 +100,7 @@ pub struct EventTarget {
 }
 
 /// DOM patch operation for updating the client-side DOM
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum DomPatch {
     ReplaceText {
@@ -118,17 +129,52 @@ pub enum DomPatch {
         position: InsertPosition,
         html: String,
     },
+    /// Inserts `html` immediately before the sibling matched by `reference_selector`
+    InsertBefore {
+        reference_selector: String,
+        html: String,
+    },
     RemoveElement {
         selector: String,
     },
+    /// Moves the existing element matched by `selector` to `position` relative to `target`
+    MoveElement {
+        selector: String,
+        target: String,
+        position: InsertPosition,
+    },
     ReplaceInnerHtml {
         selector: String,
         html: String,
     },
+    /// Several attribute writes and optionally the text of one element, stating the selector once
+    UpdateElement {
+        selector: String,
+        attrs: HashMap<String, String>,
+        text: Option<String>,
+    },
+    /// Sets a DOM property, e.g. a `<select>`'s `value`, which attributes don't reach once
+    /// the user has interacted with the element
+    SetProperty {
+        selector: String,
+        property: String,
+        value: String,
+    },
+    /// Adds or removes one class, leaving the element's other classes as they are
+    ToggleClass {
+        selector: String,
+        class: String,
+        add: bool,
+    },
+    /// Asks the client to run a registered command, e.g. `focus` with `{"selector":"#q"}`
+    ClientCommand {
+        command: String,
+        args: serde_json::Value,
+    },
 }
 
 /// Position for inserting new elements
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum InsertPosition {
     BeforeBegin,
     AfterBegin,
@@ -136,6 +182,127 @@ pub enum InsertPosition {
     AfterEnd,
 }
 
+/// `DomPatch` with one-letter keys, for clients that want JSON but small payloads
+///
+/// `o` is the op letter of the compact pipe format (plus `c` for `ReplaceText`, which has no compact form), e.g.
+/// `{"o":"t","s":"#count","x":"6"}`. Fields: `s` selector, `x` text or content,
+/// `a` attribute, property, class or command, `v` value or command args, `y` whether a class is added, `h` HTML, `p` parent or move target, `n` position
+/// (`bb`/`ab`/`be`/`ae`) and `m` the attributes of an `UpdateElement`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "o")]
+pub enum ShortDomPatch {
+    #[serde(rename = "c")]
+    ReplaceText { s: String, x: String },
+    #[serde(rename = "t")]
+    UpdateText { s: String, x: String },
+    #[serde(rename = "a")]
+    SetAttribute { s: String, a: String, v: String },
+    #[serde(rename = "r")]
+    RemoveAttribute { s: String, a: String },
+    #[serde(rename = "e")]
+    ReplaceElement { s: String, h: String },
+    #[serde(rename = "i")]
+    InsertElement {
+        p: String,
+        #[serde(with = "short_position")]
+        n: InsertPosition,
+        h: String,
+    },
+    #[serde(rename = "b")]
+    InsertBefore { s: String, h: String },
+    #[serde(rename = "x")]
+    RemoveElement { s: String },
+    #[serde(rename = "m")]
+    MoveElement {
+        s: String,
+        p: String,
+        #[serde(with = "short_position")]
+        n: InsertPosition,
+    },
+    #[serde(rename = "h")]
+    ReplaceInnerHtml { s: String, h: String },
+    #[serde(rename = "u")]
+    UpdateElement {
+        s: String,
+        m: HashMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        x: Option<String>,
+    },
+    #[serde(rename = "p")]
+    SetProperty { s: String, a: String, v: String },
+    #[serde(rename = "k")]
+    ToggleClass { s: String, a: String, y: bool },
+    #[serde(rename = "q")]
+    ClientCommand { a: String, v: serde_json::Value },
+}
+
+impl From<DomPatch> for ShortDomPatch {
+    fn from(patch: DomPatch) -> Self {
+        match patch {
+            DomPatch::ReplaceText { selector, content } => Self::ReplaceText { s: selector, x: content },
+            DomPatch::UpdateText { selector, text } => Self::UpdateText { s: selector, x: text },
+            DomPatch::SetAttribute { selector, attr, value } => Self::SetAttribute { s: selector, a: attr, v: value },
+            DomPatch::RemoveAttribute { selector, attr } => Self::RemoveAttribute { s: selector, a: attr },
+            DomPatch::ReplaceElement { selector, html } => Self::ReplaceElement { s: selector, h: html },
+            DomPatch::InsertElement { parent, position, html } => Self::InsertElement { p: parent, n: position, h: html },
+            DomPatch::InsertBefore { reference_selector, html } => Self::InsertBefore { s: reference_selector, h: html },
+            DomPatch::RemoveElement { selector } => Self::RemoveElement { s: selector },
+            DomPatch::MoveElement { selector, target, position } => Self::MoveElement { s: selector, p: target, n: position },
+            DomPatch::ReplaceInnerHtml { selector, html } => Self::ReplaceInnerHtml { s: selector, h: html },
+            DomPatch::UpdateElement { selector, attrs, text } => Self::UpdateElement { s: selector, m: attrs, x: text },
+            DomPatch::SetProperty { selector, property, value } => Self::SetProperty { s: selector, a: property, v: value },
+            DomPatch::ToggleClass { selector, class, add } => Self::ToggleClass { s: selector, a: class, y: add },
+            DomPatch::ClientCommand { command, args } => Self::ClientCommand { a: command, v: args },
+        }
+    }
+}
+
+impl From<ShortDomPatch> for DomPatch {
+    fn from(patch: ShortDomPatch) -> Self {
+        match patch {
+            ShortDomPatch::ReplaceText { s, x } => Self::ReplaceText { selector: s, content: x },
+            ShortDomPatch::UpdateText { s, x } => Self::UpdateText { selector: s, text: x },
+            ShortDomPatch::SetAttribute { s, a, v } => Self::SetAttribute { selector: s, attr: a, value: v },
+            ShortDomPatch::RemoveAttribute { s, a } => Self::RemoveAttribute { selector: s, attr: a },
+            ShortDomPatch::ReplaceElement { s, h } => Self::ReplaceElement { selector: s, html: h },
+            ShortDomPatch::InsertElement { p, n, h } => Self::InsertElement { parent: p, position: n, html: h },
+            ShortDomPatch::InsertBefore { s, h } => Self::InsertBefore { reference_selector: s, html: h },
+            ShortDomPatch::RemoveElement { s } => Self::RemoveElement { selector: s },
+            ShortDomPatch::MoveElement { s, p, n } => Self::MoveElement { selector: s, target: p, position: n },
+            ShortDomPatch::ReplaceInnerHtml { s, h } => Self::ReplaceInnerHtml { selector: s, html: h },
+            ShortDomPatch::UpdateElement { s, m, x } => Self::UpdateElement { selector: s, attrs: m, text: x },
+            ShortDomPatch::SetProperty { s, a, v } => Self::SetProperty { selector: s, property: a, value: v },
+            ShortDomPatch::ToggleClass { s, a, y } => Self::ToggleClass { selector: s, class: a, add: y },
+            ShortDomPatch::ClientCommand { a, v } => Self::ClientCommand { command: a, args: v },
+        }
+    }
+}
+
+/// Serializes an `InsertPosition` as its compact code
+mod short_position {
+    use super::InsertPosition;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(position: &InsertPosition, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(match position {
+            InsertPosition::BeforeBegin => "bb",
+            InsertPosition::AfterBegin => "ab",
+            InsertPosition::BeforeEnd => "be",
+            InsertPosition::AfterEnd => "ae",
+        })
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<InsertPosition, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "bb" => Ok(InsertPosition::BeforeBegin),
+            "ab" => Ok(InsertPosition::AfterBegin),
+            "be" => Ok(InsertPosition::BeforeEnd),
+            "ae" => Ok(InsertPosition::AfterEnd),
+            other => Err(serde::de::Error::custom(format!("unknown insert position {:?}", other))),
+        }
+    }
+}
+
 /// WebSocket message types
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -182,4 +349,108 @@ pub enum LiveTSError {
     InvalidInput(String),
 }
 
+impl LiveTSError {
+    /// Stable machine-readable code, for clients to localize or branch on
+    ///
+    /// | Variant | Code |
+    /// |---|---|
+    /// | `ConnectionNotFound` | `E_CONN_NOT_FOUND` |
+    /// | `ComponentNotFound` | `E_COMPONENT_NOT_FOUND` |
+    /// | `WebSocketError` | `E_WEBSOCKET` |
+    /// | `HtmlParsingError` | `E_HTML_PARSE` |
+    /// | `SerializationError` | `E_SERIALIZATION` |
+    /// | `EventRoutingError` | `E_EVENT_ROUTING` |
+    /// | `PubSubError` | `E_PUBSUB` |
+    /// | `InvalidInput` | `E_INVALID_INPUT` |
+    pub fn code(&self) -> &'static str {
+        match self {
+            LiveTSError::ConnectionNotFound(_) => "E_CONN_NOT_FOUND",
+            LiveTSError::ComponentNotFound(_) => "E_COMPONENT_NOT_FOUND",
+            LiveTSError::WebSocketError(_) => "E_WEBSOCKET",
+            LiveTSError::HtmlParsingError(_) => "E_HTML_PARSE",
+            LiveTSError::SerializationError(_) => "E_SERIALIZATION",
+            LiveTSError::EventRoutingError(_) => "E_EVENT_ROUTING",
+            LiveTSError::PubSubError(_) => "E_PUBSUB",
+            LiveTSError::InvalidInput(_) => "E_INVALID_INPUT",
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, LiveTSError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_stable_and_documented() {
+        let cases = [
+            (LiveTSError::ConnectionNotFound(String::new()), "E_CONN_NOT_FOUND"),
+            (LiveTSError::ComponentNotFound(String::new()), "E_COMPONENT_NOT_FOUND"),
+            (LiveTSError::WebSocketError(String::new()), "E_WEBSOCKET"),
+            (LiveTSError::HtmlParsingError(String::new()), "E_HTML_PARSE"),
+            (LiveTSError::SerializationError(String::new()), "E_SERIALIZATION"),
+            (LiveTSError::EventRoutingError(String::new()), "E_EVENT_ROUTING"),
+            (LiveTSError::PubSubError(String::new()), "E_PUBSUB"),
+            (LiveTSError::InvalidInput(String::new()), "E_INVALID_INPUT"),
+        ];
+        let source = include_str!("types.rs");
+        for (error, code) in cases {
+            assert_eq!(error.code(), code);
+            let variant = format!("{:?}", error);
+            let variant = variant.split('(').next().unwrap();
+            assert!(
+                source.contains(&format!("/// | `{}` | `{}` |", variant, code)),
+                "{} is missing from the code table",
+                variant
+            );
+        }
+    }
+
+    #[test]
+    fn test_short_dom_patch_round_trip() {
+        let update = DomPatch::UpdateText { selector: "#count".to_string(), text: "6".to_string() };
+        let json = serde_json::to_string(&ShortDomPatch::from(update.clone())).unwrap();
+        assert_eq!(json, r##"{"o":"t","s":"#count","x":"6"}"##);
+        assert_eq!(DomPatch::from(serde_json::from_str::<ShortDomPatch>(&json).unwrap()), update);
+
+        let patches = vec![
+            DomPatch::ReplaceText { selector: "p".to_string(), content: "hi".to_string() },
+            DomPatch::SetAttribute { selector: "#a".to_string(), attr: "class".to_string(), value: "big".to_string() },
+            DomPatch::RemoveAttribute { selector: "#a".to_string(), attr: "hidden".to_string() },
+            DomPatch::ReplaceElement { selector: "#a".to_string(), html: "<b>x</b>".to_string() },
+            DomPatch::InsertElement {
+                parent: "#list".to_string(),
+                position: InsertPosition::BeforeEnd,
+                html: "<li>new</li>".to_string(),
+            },
+            DomPatch::InsertBefore { reference_selector: "#b".to_string(), html: "<li>a</li>".to_string() },
+            DomPatch::RemoveElement { selector: "#b".to_string() },
+            DomPatch::MoveElement {
+                selector: "#c".to_string(),
+                target: "#d".to_string(),
+                position: InsertPosition::AfterEnd,
+            },
+            DomPatch::ReplaceInnerHtml { selector: "#e".to_string(), html: "<i>y</i>".to_string() },
+            DomPatch::UpdateElement {
+                selector: "#f".to_string(),
+                attrs: HashMap::from([("class".to_string(), "on".to_string())]),
+                text: None,
+            },
+            DomPatch::SetProperty { selector: "#g".to_string(), property: "value".to_string(), value: "b".to_string() },
+            DomPatch::ToggleClass { selector: "#h".to_string(), class: "is-open".to_string(), add: true },
+            DomPatch::ClientCommand { command: "focus".to_string(), args: serde_json::json!({ "selector": "#q" }) },
+        ];
+        for patch in patches {
+            let json = serde_json::to_string(&ShortDomPatch::from(patch.clone())).unwrap();
+            assert!(json.len() < serde_json::to_string(&patch).unwrap().len(), "{}", json);
+            assert_eq!(DomPatch::from(serde_json::from_str::<ShortDomPatch>(&json).unwrap()), patch);
+        }
+
+        let json = r##"{"o":"i","p":"#list","n":"ab","h":"<li>first</li>"}"##;
+        assert!(matches!(
+            DomPatch::from(serde_json::from_str::<ShortDomPatch>(json).unwrap()),
+            DomPatch::InsertElement { position: InsertPosition::AfterBegin, .. }
+        ));
+    }
+}
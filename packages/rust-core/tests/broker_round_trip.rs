@@ -0,0 +1,248 @@
+//! End-to-end test of the WebSocket broker: handshake, event plumbing, patch delivery and shutdown
+
+use futures_util::{SinkExt, StreamExt};
+use livets_core::{EventParser, LiveTSEngine, LiveTSWebSocketBroker};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio_tungstenite::tungstenite::Message;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stands in for the JS layer: consumes broker events and answers events with patches
+struct MockJsHandler {
+    events: UnboundedReceiver<String>,
+    engine: LiveTSEngine,
+    parser: EventParser,
+    html: String,
+}
+
+impl MockJsHandler {
+    fn attach(broker: &LiveTSWebSocketBroker, initial_html: &str) -> Self {
+        let (tx, events) = unbounded_channel();
+        broker.set_event_sender(tx);
+        Self {
+            events,
+            engine: LiveTSEngine::new(),
+            parser: EventParser::new(),
+            html: initial_html.to_string(),
+        }
+    }
+
+    /// Waits for the next broker event of the given type
+    fn expect(&mut self, event_type: &str) -> Value {
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        loop {
+            let json = loop {
+                match self.events.try_recv() {
+                    Ok(json) => break json,
+                    Err(_) if std::time::Instant::now() < deadline => std::thread::sleep(Duration::from_millis(5)),
+                    Err(e) => panic!("no {} event within {:?}: {}", event_type, TIMEOUT, e),
+                }
+            };
+            let event: Value = serde_json::from_str(&json).unwrap();
+            if event["type"] == event_type {
+                return event;
+            }
+        }
+    }
+
+    /// Handles the next client event like a counter component would, sending the patch back
+    fn handle_event(&mut self, broker: &LiveTSWebSocketBroker) -> Value {
+        let event = self.expect("Message");
+        let connection_id = event["connection_id"].as_str().unwrap().to_string();
+        let parsed = self.parser.parse_message(event["data"].as_str().unwrap()).unwrap();
+        assert_eq!(parsed.event_name, "increment");
+
+        let new_html = self.html.replace(">5<", ">6<");
+        let message = self
+            .engine
+            .render_component_message(parsed.component_id, self.html.clone(), new_html.clone())
+            .unwrap();
+        self.html = new_html;
+        broker.send_to_connection(connection_id, message).unwrap();
+        event
+    }
+}
+
+#[test]
+fn test_event_round_trip() {
+    let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+    let mut handler = MockJsHandler::attach(&broker, r#"<div id="count" class="text-4xl">5</div>"#);
+    broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+    let url = format!("ws://127.0.0.1:{}/livets-ws", broker.port().unwrap());
+
+    let client_rt = Runtime::new().unwrap();
+    let (mut ws, _) = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url)))
+        .unwrap()
+        .unwrap();
+
+    let connected = handler.expect("Connected");
+    let connection_id = connected["connection_id"].as_str().unwrap().to_string();
+
+    client_rt
+        .block_on(ws.send(Message::Text(r#""e|counter1|increment||0|button""#.to_string())))
+        .unwrap();
+    let message_event = handler.handle_event(&broker);
+    assert_eq!(message_event["connection_id"], connection_id.as_str());
+
+    let reply = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, ws.next()))
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let patch: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert_eq!(patch["t"], "p");
+    assert_eq!(patch["c"], "counter1");
+    assert_eq!(patch["d"], serde_json::json!(["t|#count|6"]));
+
+    client_rt.block_on(ws.close(None)).unwrap();
+    let closed = handler.expect("Closed");
+    assert_eq!(closed["connection_id"], connection_id.as_str());
+    assert_eq!(closed["cause"], "client_close");
+
+    broker.stop().unwrap();
+    assert_eq!(broker.port(), None);
+}
+
+#[test]
+fn test_update_component_from_cache() {
+    let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+    let mut handler = MockJsHandler::attach(&broker, "");
+    broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+    let url = format!("ws://127.0.0.1:{}/livets-ws", broker.port().unwrap());
+
+    let client_rt = Runtime::new().unwrap();
+    let (mut ws, _) = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url)))
+        .unwrap()
+        .unwrap();
+    let connected = handler.expect("Connected");
+    let connection_id = connected["connection_id"].as_str().unwrap().to_string();
+
+    let component_id = "counter-1234567890".to_string();
+    assert!(broker.update_component(component_id.clone(), "<p>1</p>".to_string()).is_err());
+
+    broker.register_component(component_id.clone(), connection_id).unwrap();
+    broker.cache_component_html(component_id.clone(), r#"<div id="count">5</div>"#.to_string());
+    broker
+        .update_component(component_id.clone(), r#"<div id="count">6</div>"#.to_string())
+        .unwrap();
+
+    let reply = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, ws.next()))
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let patch: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert_eq!(patch["t"], "p");
+    assert_eq!(patch["c"], "counter-");
+    assert_eq!(patch["d"], serde_json::json!(["t|#count|6"]));
+
+    // The cache now holds the new HTML, so the next update diffs from it
+    broker
+        .update_component(component_id, r#"<div id="count">7</div>"#.to_string())
+        .unwrap();
+    let reply = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, ws.next()))
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let patch: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert_eq!(patch["d"], serde_json::json!(["t|#count|7"]));
+
+    broker.stop().unwrap();
+}
+
+#[test]
+fn test_reconnect_token_restores_registrations() {
+    let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+    let mut handler = MockJsHandler::attach(&broker, "");
+    broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+    let url = format!("ws://127.0.0.1:{}/livets-ws", broker.port().unwrap());
+    let client_rt = Runtime::new().unwrap();
+
+    let (mut ws, _) = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url.clone())))
+        .unwrap()
+        .unwrap();
+    let connected = handler.expect("Connected");
+    assert_eq!(connected["resumed"], false);
+    let first_id = connected["connection_id"].as_str().unwrap().to_string();
+    let token = connected["reconnect_token"].as_str().unwrap().to_string();
+    broker.register_component("counter-1".to_string(), first_id.clone()).unwrap();
+    broker.cache_component_html("counter-1".to_string(), r#"<div id="count">5</div>"#.to_string());
+    broker
+        .update_component("counter-1".to_string(), r#"<div id="count">6</div>"#.to_string())
+        .unwrap();
+    let reply = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, ws.next()))
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let patch: Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+    assert_eq!(patch["q"], 1);
+
+    client_rt.block_on(ws.close(None)).unwrap();
+    handler.expect("Closed");
+    let registrations: Value = serde_json::from_str(&broker.export_registrations().unwrap()).unwrap();
+    assert!(registrations.get("counter-1").is_none());
+
+    let (mut ws, _) = client_rt
+        .block_on(tokio::time::timeout(
+            TIMEOUT,
+            tokio_tungstenite::connect_async(format!("{}?reconnect={}", url, token)),
+        ))
+        .unwrap()
+        .unwrap();
+    let reconnected = handler.expect("Connected");
+    assert_eq!(reconnected["resumed"], true);
+    let second_id = reconnected["connection_id"].as_str().unwrap().to_string();
+    assert_ne!(second_id, first_id);
+    assert_ne!(reconnected["reconnect_token"].as_str().unwrap(), token);
+
+    let registrations: Value = serde_json::from_str(&broker.export_registrations().unwrap()).unwrap();
+    assert_eq!(registrations["counter-1"], serde_json::json!([second_id]));
+
+    // The client asks for what it missed after the last sequence number it saw
+    let mut replay = |request: &str| -> Value {
+        client_rt.block_on(ws.send(Message::Text(request.to_string()))).unwrap();
+        let reply = client_rt
+            .block_on(tokio::time::timeout(TIMEOUT, ws.next()))
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(reply.to_text().unwrap()).unwrap()
+    };
+    assert_eq!(replay(r#""r|0""#), patch);
+    assert_eq!(replay(r#""r|9""#), serde_json::json!({"t": "r"}));
+
+    broker.stop().unwrap();
+}
+
+#[test]
+fn test_upgrades_beyond_max_connections_are_rejected() {
+    let mut broker = LiveTSWebSocketBroker::new(None).unwrap();
+    let mut handler = MockJsHandler::attach(&broker, "");
+    broker.set_max_connections(1);
+    broker.listen("127.0.0.1".to_string(), 0, None).unwrap();
+    let url = format!("ws://127.0.0.1:{}/livets-ws", broker.port().unwrap());
+    let client_rt = Runtime::new().unwrap();
+
+    let (_ws, _) = client_rt
+        .block_on(tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url.clone())))
+        .unwrap()
+        .unwrap();
+    handler.expect("Connected");
+
+    match client_rt.block_on(tokio::time::timeout(TIMEOUT, tokio_tungstenite::connect_async(url))).unwrap() {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), 503),
+        other => panic!("expected the second upgrade to be refused, got {:?}", other.map(|(_, response)| response)),
+    }
+    let health: Value = serde_json::from_str(&broker.health().unwrap()).unwrap();
+    assert_eq!(health["connections"], 1);
+
+    broker.stop().unwrap();
+}
@@ -0,0 +1,36 @@
+//! The core types used from plain Rust, with no Node.js bindings involved
+//!
+//! Run with `cargo test --no-default-features --test core_without_napi` to check the
+//! crate still builds and works without the `napi` feature.
+
+use livets_core::{ComponentCache, DomPatch, EventParser, HtmlDiffer, LiveTSError, PubSubSystem};
+
+#[test]
+fn diff_parse_and_cache() {
+    let differ = HtmlDiffer::new();
+    let patches = differ
+        .diff(r#"<div id="count">5</div>"#, r#"<div id="count">6</div>"#)
+        .unwrap();
+    assert!(matches!(&patches[..], [DomPatch::UpdateText { text, .. }] if text == "6"));
+    assert_eq!(differ.patches_to_compact(patches), vec!["t|#count|6".to_string()]);
+
+    let parser = EventParser::new();
+    let event = parser.parse_message(r#""e|counter1|increment||0|button""#).unwrap();
+    assert_eq!(event.component_id, "counter1");
+    assert!(matches!(parser.parse_message("nonsense"), Err(LiveTSError::InvalidInput(_))));
+
+    let cache = ComponentCache::new(10);
+    cache.set_html("counter1", r#"<div id="count">6</div>"#.to_string());
+    assert_eq!(cache.get_html("counter1").as_deref(), Some(r#"<div id="count">6</div>"#));
+}
+
+#[tokio::test]
+async fn pubsub_round_trip() {
+    let pubsub = PubSubSystem::new();
+    let channel = "news".to_string();
+    pubsub.subscribe(&channel, &"ticker".to_string()).await.unwrap();
+
+    let mut receiver = pubsub.create_receiver(&channel).unwrap();
+    pubsub.broadcast(&channel, "hello".to_string()).await.unwrap();
+    assert_eq!(receiver.recv().await.unwrap(), "hello");
+}
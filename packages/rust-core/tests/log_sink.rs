@@ -0,0 +1,35 @@
+//! The log sink installs a process-wide subscriber, so it runs in its own test binary
+//! where it can't leak into the broker's unit tests
+
+use livets_core::{init_logging, LiveTSWebSocketBroker, LogRecord, LogSinkFn};
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn log_sink_replaces_stdout_logging_and_filters_by_level() {
+    let records: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let collected = records.clone();
+    // The broker logs through the same subscriber, so only this test's records count
+    let sink: Arc<LogSinkFn> = Arc::new(move |record: &LogRecord| {
+        if record.message.starts_with("log sink test") {
+            collected.lock().unwrap().push(format!("{} {}", record.level, record.message));
+        }
+    });
+
+    // server.ts turns on stdout logging when it loads, before any sink is set
+    init_logging();
+    let broker = LiveTSWebSocketBroker::new(None).unwrap();
+    broker.set_log_sink_fn(sink.clone(), tracing::Level::WARN).unwrap();
+    tracing::info!("log sink test: below");
+    tracing::warn!("log sink test: at level");
+
+    // Setting the sink again changes the level, and a later init_logging leaves it in place
+    broker.set_log_sink_fn(sink, tracing::Level::DEBUG).unwrap();
+    init_logging();
+    tracing::debug!("log sink test: now included");
+    tracing::trace!("log sink test: still below");
+
+    assert_eq!(
+        *records.lock().unwrap(),
+        vec!["WARN log sink test: at level".to_string(), "DEBUG log sink test: now included".to_string()]
+    );
+}